@@ -0,0 +1,326 @@
+//! Async reads for tokio services.
+//!
+//! FIEMAP queries and device reads are blocking system calls. The
+//! [`AsyncBlkReader`] trait and [`AsyncBlkFile`] run them on tokio's
+//! blocking thread pool, so they can be awaited from async code without
+//! stalling the runtime's worker threads.
+//!
+//! Because the work outlives the calling stack frame, buffers are passed by
+//! value and handed back together with the [`State`] of the read. Any owned
+//! buffer type works, including [`AlignedBuf`](crate::AlignedBuf).
+
+use crate::buffer::{BufPool, PooledBuf};
+use crate::options::Options;
+use crate::reader::{self, BlkFile};
+use crate::state::State;
+
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Async counterpart of [`BlkReader`].
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{AsyncBlkReader, Options};
+/// use std::path::Path;
+///
+/// # async fn example() -> std::io::Result<()> {
+/// let path = Path::new("/path/to/file");
+/// let (buf, state) = path
+///     .blk_read_at_opt(vec![0u8; 4096], 0, &Options::new())
+///     .await?;
+/// println!("Read {} bytes", state.bytes_read);
+/// # Ok(())
+/// # }
+/// ```
+pub trait AsyncBlkReader {
+    /// Read data at the specified offset with options.
+    ///
+    /// Reads into `buf` on the blocking thread pool and returns it along
+    /// with the state of the read. See [`BlkReader::blk_read_at_opt`].
+    fn blk_read_at_opt<B>(
+        &self,
+        buf: B,
+        offset: u64,
+        options: &Options,
+    ) -> impl Future<Output = io::Result<(B, State)>> + Send
+    where
+        B: AsMut<[u8]> + Send + 'static;
+
+    /// Read the entire file into a new vector.
+    ///
+    /// See [`BlkReader::blk_read_to_end`].
+    fn blk_read_to_end(
+        &self,
+        options: &Options,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+}
+
+impl AsyncBlkReader for Path {
+    fn blk_read_at_opt<B>(
+        &self,
+        buf: B,
+        offset: u64,
+        options: &Options,
+    ) -> impl Future<Output = io::Result<(B, State)>> + Send
+    where
+        B: AsMut<[u8]> + Send + 'static,
+    {
+        read_path_at(self.to_path_buf(), buf, offset, options.clone())
+    }
+
+    fn blk_read_to_end(
+        &self,
+        options: &Options,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        read_path_to_end(self.to_path_buf(), options.clone())
+    }
+}
+
+impl AsyncBlkReader for PathBuf {
+    fn blk_read_at_opt<B>(
+        &self,
+        buf: B,
+        offset: u64,
+        options: &Options,
+    ) -> impl Future<Output = io::Result<(B, State)>> + Send
+    where
+        B: AsMut<[u8]> + Send + 'static,
+    {
+        read_path_at(self.clone(), buf, offset, options.clone())
+    }
+
+    fn blk_read_to_end(
+        &self,
+        options: &Options,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        read_path_to_end(self.clone(), options.clone())
+    }
+}
+
+impl AsyncBlkReader for File {
+    fn blk_read_at_opt<B>(
+        &self,
+        mut buf: B,
+        offset: u64,
+        options: &Options,
+    ) -> impl Future<Output = io::Result<(B, State)>> + Send
+    where
+        B: AsMut<[u8]> + Send + 'static,
+    {
+        let file = self.try_clone();
+        let options = options.clone();
+        async move {
+            let file = file?;
+            blocking(move || {
+                let state =
+                    reader::BlkReader::blk_read_at_opt(&file, buf.as_mut(), offset, &options)?;
+                Ok((buf, state))
+            })
+            .await
+        }
+    }
+
+    fn blk_read_to_end(
+        &self,
+        options: &Options,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        let file = self.try_clone();
+        let options = options.clone();
+        async move {
+            let file = file?;
+            blocking(move || reader::BlkReader::blk_read_to_end(&file, &options)).await
+        }
+    }
+}
+
+/// Async counterpart of [`BlkFile`].
+///
+/// Cloning is cheap and shares the extent map and device handle, so one
+/// file can be read from many tasks at once.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{AsyncBlkFile, Options};
+///
+/// # async fn example() -> std::io::Result<()> {
+/// let file = AsyncBlkFile::open("/path/to/file").await?;
+/// let (buf, state) = file
+///     .read_at_opt(vec![0u8; 4096], 0, &Options::new())
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AsyncBlkFile {
+    inner: Arc<BlkFile>,
+}
+
+impl AsyncBlkFile {
+    /// Open a file and query its extent map.
+    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = blocking(move || BlkFile::open(path)).await?;
+        Ok(file.into())
+    }
+
+    /// Wrap an already opened file and query its extent map.
+    pub async fn from_file(file: File) -> io::Result<Self> {
+        let file = blocking(move || BlkFile::from_file(file)).await?;
+        Ok(file.into())
+    }
+
+    /// The underlying synchronous file.
+    pub fn get_ref(&self) -> &BlkFile {
+        &self.inner
+    }
+
+    /// Read data at the specified offset with options.
+    ///
+    /// See [`BlkFile::read_at_opt`].
+    pub async fn read_at_opt<B>(
+        &self,
+        mut buf: B,
+        offset: u64,
+        options: &Options,
+    ) -> io::Result<(B, State)>
+    where
+        B: AsMut<[u8]> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        let options = options.clone();
+        blocking(move || {
+            let state = inner.read_at_opt(buf.as_mut(), offset, &options)?;
+            Ok((buf, state))
+        })
+        .await
+    }
+
+    /// Read a range into an owned buffer from `pool`.
+    ///
+    /// See [`BlkFile::read_pooled`].
+    pub async fn read_pooled(
+        &self,
+        pool: &BufPool,
+        offset: u64,
+        length: usize,
+        options: &Options,
+    ) -> io::Result<(PooledBuf, State)> {
+        let inner = Arc::clone(&self.inner);
+        let pool = pool.clone();
+        let options = options.clone();
+        blocking(move || inner.read_pooled(&pool, offset, length, &options)).await
+    }
+
+    /// Read the entire file into a new vector.
+    ///
+    /// See [`BlkFile::read_to_end`].
+    pub async fn read_to_end(&self, options: &Options) -> io::Result<Vec<u8>> {
+        let inner = Arc::clone(&self.inner);
+        let options = options.clone();
+        blocking(move || inner.read_to_end(&options)).await
+    }
+}
+
+impl From<BlkFile> for AsyncBlkFile {
+    fn from(file: BlkFile) -> Self {
+        Self {
+            inner: Arc::new(file),
+        }
+    }
+}
+
+/// Read from the file at `path` on the blocking thread pool.
+async fn read_path_at<B>(
+    path: PathBuf,
+    mut buf: B,
+    offset: u64,
+    options: Options,
+) -> io::Result<(B, State)>
+where
+    B: AsMut<[u8]> + Send + 'static,
+{
+    blocking(move || {
+        let state = reader::BlkReader::blk_read_at_opt(&path, buf.as_mut(), offset, &options)?;
+        Ok((buf, state))
+    })
+    .await
+}
+
+/// Read the whole file at `path` on the blocking thread pool.
+async fn read_path_to_end(path: PathBuf, options: Options) -> io::Result<Vec<u8>> {
+    blocking(move || reader::BlkReader::blk_read_to_end(&path, &options)).await
+}
+
+/// Run blocking work on tokio's blocking thread pool.
+///
+/// Panics in `f` are propagated to the awaiting task.
+async fn blocking<F, T>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_async_read_at_opt() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x11; 4096]).unwrap();
+        temp.write_all(&[0x22; 4096]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let options = Options::new().with_allow_fallback(true);
+        let (buf, state) = temp
+            .path()
+            .blk_read_at_opt(vec![0u8; 4096], 4096, &options)
+            .await
+            .unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert!(buf.iter().all(|&b| b == 0x22));
+
+        let data = temp.as_file().blk_read_to_end(&options).await.unwrap();
+        assert_eq!(data.len(), 8192);
+    }
+
+    #[tokio::test]
+    async fn test_async_blk_file() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x33; 8192]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let file = AsyncBlkFile::open(temp.path()).await.unwrap();
+        assert!(!file.get_ref().extents().is_empty());
+
+        let options = Options::new().with_allow_fallback(true);
+        let (buf, state) = file
+            .clone()
+            .read_at_opt(crate::AlignedBuf::new(4096, 4096), 4096, &options)
+            .await
+            .unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert!(buf.iter().all(|&b| b == 0x33));
+        assert_eq!(file.read_to_end(&options).await.unwrap().len(), 8192);
+
+        let pool = crate::BufPool::new();
+        let (buf, state) = file.read_pooled(&pool, 100, 1000, &options).await.unwrap();
+        assert_eq!((buf.len(), state.bytes_read), (1000, 1000));
+        drop(buf);
+        assert_eq!(pool.idle(), 1);
+    }
+}
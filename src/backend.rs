@@ -0,0 +1,269 @@
+//! Pluggable access to block devices.
+//!
+//! The reader finds the device holding a file's data, opens it and queries
+//! its geometry through a [`DeviceBackend`]. The default backend,
+//! [`BlockDeviceBackend`], does so for the system's block devices, which
+//! needs a real device and usually root. Other backends can serve any file
+//! as the "device", so that the extent walk can be tested without either.
+//!
+//! Backends only provide the device's file; reads from it are issued by the
+//! configured [`IoEngine`](crate::IoEngine), as for real devices.
+//! [`MemDevice`] serves a buffer in memory, for tests reading synthetic
+//! extent maps with
+//! [`BlkReader::blk_read_with_extents`](crate::BlkReader::blk_read_with_extents).
+//! [`DeviceSource::Image`] serves a captured image of the device, for
+//! recovery without the live device, loop devices or root, and
+//! [`DeviceSource::Device`] a device given explicitly.
+
+use crate::state::DeviceInfo;
+use crate::sys;
+
+use blkmap::{ExtentFlags, FiemapExtent};
+use blkpath::ResolveDevice;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Access to the devices holding the data of files.
+///
+/// Only [`open`](DeviceBackend::open) is required; device resolution and
+/// geometry default to those of [`BlockDeviceBackend`].
+pub trait DeviceBackend: fmt::Debug + Send + Sync {
+    /// Path of the device holding the data of `file`.
+    ///
+    /// Physical offsets reported by FIEMAP for `file` are offsets on this
+    /// device.
+    fn resolve(&self, file: &File) -> io::Result<PathBuf> {
+        file.resolve_device()
+    }
+
+    /// Open the device at `path` for reading, with `O_DIRECT` if `direct`
+    /// is set.
+    fn open(&self, path: &Path, direct: bool) -> io::Result<File>;
+
+    /// Sector sizes and size of an opened device, or `None` if unknown.
+    ///
+    /// Reads are only checked against the bounds and alignment of devices
+    /// with known geometry.
+    fn info(&self, device: &File) -> Option<DeviceInfo> {
+        query_device_info(device)
+    }
+
+    /// Byte offset in the opened file at which the device starts.
+    ///
+    /// Physical offsets are shifted by it before reading, as for a
+    /// partition within an image of the whole disk.
+    fn base_offset(&self) -> u64 {
+        0
+    }
+}
+
+/// The system's block devices, resolved with `blkpath` and opened by path.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockDeviceBackend;
+
+impl DeviceBackend for BlockDeviceBackend {
+    fn open(&self, path: &Path, direct: bool) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(if direct { libc::O_DIRECT } else { 0 })
+            .open(path)
+    }
+}
+
+/// A simulated block device held in memory.
+///
+/// Every file's data is resolved to the device, which is opened without
+/// `O_DIRECT` whatever [`Options::direct`](crate::Options::direct) says.
+/// Clones share the device's contents. Devices are zeroed when created, and
+/// report 512-byte sectors and their size unless told otherwise with
+/// [`with_info`](MemDevice::with_info).
+///
+/// # Example
+///
+/// ```
+/// use blkreader::{BlkReader, ExtentFlags, MemDevice, Options};
+///
+/// let device = MemDevice::new(1 << 20)?;
+/// let data = device.place(0, 8192, &[0xAB; 4096])?;
+/// let mut unwritten = device.place(8192, 65536, &[0xCD; 4096])?;
+/// unwritten.flags = ExtentFlags::UNWRITTEN;
+///
+/// // A hole at 4096, then an unwritten extent
+/// let options = Options::new()
+///     .with_fill_holes(true)
+///     .with_zero_unwritten(true)
+///     .with_device_backend(device);
+/// let file = tempfile::tempfile()?;
+/// let mut buf = vec![0xFF; 12288];
+/// let state = file.blk_read_with_extents(&mut buf, 0, &[data, unwritten], &options)?;
+/// assert_eq!(state.bytes_read, 12288);
+/// assert!(buf[..4096].iter().all(|&b| b == 0xAB));
+/// assert!(buf[4096..].iter().all(|&b| b == 0));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct MemDevice {
+    file: Arc<File>,
+    info: DeviceInfo,
+}
+
+impl MemDevice {
+    /// Path the device is resolved to.
+    pub const PATH: &'static str = "/dev/blkreader-mem";
+
+    /// Create a zeroed device of `size` bytes.
+    pub fn new(size: u64) -> io::Result<Self> {
+        let file = sys::memfd("blkreader-mem")?;
+        file.set_len(size)?;
+        Ok(Self {
+            file: Arc::new(file),
+            info: DeviceInfo {
+                logical_block_size: 512,
+                physical_block_size: 512,
+                size,
+            },
+        })
+    }
+
+    /// Report `info` as the device's geometry.
+    ///
+    /// A size beyond that the device was created with lets reads run past
+    /// its end, where they come up short.
+    pub fn with_info(mut self, info: DeviceInfo) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Write `data` to the device at byte offset `physical`.
+    pub fn write_at(&self, physical: u64, data: &[u8]) -> io::Result<()> {
+        let len = self.file.metadata()?.len();
+        if physical
+            .checked_add(data.len() as u64)
+            .is_none_or(|end| end > len)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write beyond the end of the device",
+            ));
+        }
+        self.file.write_all_at(data, physical)
+    }
+
+    /// Write `data` at byte offset `physical`, returning the extent that
+    /// maps the file range starting at `logical` to it.
+    ///
+    /// Together with the holes between them, the extents returned make up a
+    /// synthetic extent map; their flags can be changed to mark them as
+    /// unwritten, inline and so on.
+    pub fn place(&self, logical: u64, physical: u64, data: &[u8]) -> io::Result<FiemapExtent> {
+        self.write_at(physical, data)?;
+        Ok(FiemapExtent {
+            logical,
+            physical,
+            length: data.len() as u64,
+            flags: ExtentFlags::empty(),
+        })
+    }
+}
+
+impl DeviceBackend for MemDevice {
+    fn resolve(&self, _: &File) -> io::Result<PathBuf> {
+        Ok(PathBuf::from(Self::PATH))
+    }
+
+    fn open(&self, _: &Path, _: bool) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    fn info(&self, _: &File) -> Option<DeviceInfo> {
+        Some(self.info)
+    }
+}
+
+/// A replacement for the device holding the data of files, see
+/// [`Options::with_device_override`](crate::Options::with_device_override).
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkReader, DeviceSource, Options};
+/// use std::path::Path;
+///
+/// // The filesystem's partition starts 1 MiB into the disk image
+/// let options = Options::new().with_device_override(DeviceSource::Image {
+///     path: "/backup/disk.img".into(),
+///     offset: 1 << 20,
+/// });
+/// let mut buf = vec![0u8; 4096];
+/// let state = Path::new("/mnt/data/file").blk_read_at_opt(&mut buf, 0, &options)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DeviceSource {
+    /// A regular file holding an image of the device, such as one captured
+    /// with `dd`, whose contents start `offset` bytes into the file.
+    ///
+    /// The image reports 512-byte sectors and its size past `offset`. With
+    /// Direct I/O, `offset` must be aligned to the sector size of the
+    /// device holding the image.
+    Image {
+        /// Path of the image file.
+        path: PathBuf,
+        /// Byte offset of the device's start in the image.
+        offset: u64,
+    },
+
+    /// A block device, read in place of the device the files' data is
+    /// resolved to, e.g. to read deleted files of a filesystem that is no
+    /// longer mounted.
+    Device(PathBuf),
+}
+
+impl DeviceBackend for DeviceSource {
+    fn resolve(&self, _: &File) -> io::Result<PathBuf> {
+        match self {
+            DeviceSource::Image { path, .. } | DeviceSource::Device(path) => Ok(path.clone()),
+        }
+    }
+
+    fn open(&self, path: &Path, direct: bool) -> io::Result<File> {
+        BlockDeviceBackend.open(path, direct)
+    }
+
+    fn info(&self, device: &File) -> Option<DeviceInfo> {
+        if let DeviceSource::Device(_) = self {
+            return query_device_info(device);
+        }
+        let size = device.metadata().ok()?.len();
+        Some(DeviceInfo {
+            logical_block_size: 512,
+            physical_block_size: 512,
+            size: size.saturating_sub(self.base_offset()),
+        })
+    }
+
+    fn base_offset(&self) -> u64 {
+        match self {
+            DeviceSource::Image { offset, .. } => *offset,
+            DeviceSource::Device(_) => 0,
+        }
+    }
+}
+
+/// Query the sector sizes and size of an opened block device.
+///
+/// Returns `None` if any of the ioctls fails, e.g. for a regular file.
+pub(crate) fn query_device_info(file: &File) -> Option<DeviceInfo> {
+    let fd = file.as_raw_fd();
+    Some(DeviceInfo {
+        logical_block_size: sys::logical_block_size(fd).ok()?,
+        physical_block_size: sys::physical_block_size(fd).ok()?,
+        size: sys::device_size(fd).ok()?,
+    })
+}
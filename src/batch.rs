@@ -0,0 +1,307 @@
+//! Batched reads across many files.
+//!
+//! [`blk_read_many`] groups read requests by the block device backing each
+//! file, so that every group shares a single device handle. With
+//! [`blk_read_many_parallel`], the groups are additionally processed on one
+//! thread per device. [`warm_cache`] prepares a batch of files ahead of
+//! their reads.
+
+use crate::options::Options;
+use crate::reader::{data_file, DeviceHandle, ReadContext};
+use crate::state::State;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::thread;
+
+/// A single read request in a batch.
+#[derive(Debug)]
+pub struct BlkRequest<'a> {
+    /// Path of the file to read.
+    pub path: &'a Path,
+
+    /// Logical offset within the file.
+    pub offset: u64,
+
+    /// Buffer to read into; its length is the number of bytes requested.
+    pub buf: &'a mut [u8],
+}
+
+impl<'a> BlkRequest<'a> {
+    /// Create a request to read `buf.len()` bytes at `offset` of `path`.
+    pub fn new<P: AsRef<Path> + ?Sized>(path: &'a P, offset: u64, buf: &'a mut [u8]) -> Self {
+        Self {
+            path: path.as_ref(),
+            offset,
+            buf,
+        }
+    }
+}
+
+/// An opened request, ready to be read.
+struct Pending<'r, 'a> {
+    index: usize,
+    file: File,
+    request: &'r mut BlkRequest<'a>,
+}
+
+/// Read a batch of requests, sharing device handles between files on the
+/// same block device.
+///
+/// Results are returned in the same order as `requests`; a failing request
+/// does not affect the others.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{blk_read_many, BlkRequest, Options};
+///
+/// let mut a = vec![0u8; 4096];
+/// let mut b = vec![0u8; 4096];
+/// let mut requests = [
+///     BlkRequest::new("/path/to/a", 0, &mut a),
+///     BlkRequest::new("/path/to/b", 0, &mut b),
+/// ];
+/// for result in blk_read_many(&mut requests, &Options::new()) {
+///     println!("{:?}", result.map(|state| state.bytes_read));
+/// }
+/// ```
+pub fn blk_read_many(requests: &mut [BlkRequest<'_>], options: &Options) -> Vec<io::Result<State>> {
+    read_many(requests, options, false)
+}
+
+/// Like [`blk_read_many`], but reads from different devices in parallel.
+///
+/// Requests for the same device are still read sequentially on one thread.
+pub fn blk_read_many_parallel(
+    requests: &mut [BlkRequest<'_>],
+    options: &Options,
+) -> Vec<io::Result<State>> {
+    read_many(requests, options, true)
+}
+
+fn read_many(
+    requests: &mut [BlkRequest<'_>],
+    options: &Options,
+    parallel: bool,
+) -> Vec<io::Result<State>> {
+    let mut results: Vec<Option<io::Result<State>>> = requests.iter().map(|_| None).collect();
+
+    // Open every file and group by device ID
+    let mut groups: BTreeMap<u64, Vec<Pending>> = BTreeMap::new();
+    for (index, request) in requests.iter_mut().enumerate() {
+        let opened = File::open(request.path).and_then(|file| {
+            let file = data_file(file, options)?;
+            let dev = file.metadata()?.dev();
+            Ok((file, dev))
+        });
+        match opened {
+            Ok((file, dev)) => groups.entry(dev).or_default().push(Pending {
+                index,
+                file,
+                request,
+            }),
+            Err(e) => results[index] = Some(Err(e)),
+        }
+    }
+
+    let outputs: Vec<Vec<(usize, io::Result<State>)>> = if parallel && groups.len() > 1 {
+        thread::scope(|scope| {
+            let handles: Vec<_> = groups
+                .into_values()
+                .map(|group| scope.spawn(move || read_group(group, options)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("batch read thread panicked"))
+                .collect()
+        })
+    } else {
+        groups
+            .into_values()
+            .map(|group| read_group(group, options))
+            .collect()
+    };
+
+    for (index, result) in outputs.into_iter().flatten() {
+        results[index] = Some(result);
+    }
+    results
+        .into_iter()
+        .map(|result| result.expect("every request produces a result"))
+        .collect()
+}
+
+/// Prepare `paths` for reading, so that their first reads don't pay for
+/// the setup.
+///
+/// The block device of each file is resolved and opened into the cache
+/// reads with `options` use (see [`Options::cache_handle`]), as are the
+/// devices its extents are translated to. With
+/// [`Options::cache_extents`] or [`Options::disk_extent_cache`], the extent
+/// map of each file is queried and cached as well. Without
+/// [`Options::enable_cache`], or with a custom device backend, devices are
+/// only resolved, since no handle would be kept.
+///
+/// Results are returned in the same order as `paths`; a failing path does
+/// not affect the others.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{warm_cache, Options};
+/// use std::path::PathBuf;
+///
+/// let paths = [PathBuf::from("/data/a"), PathBuf::from("/data/b")];
+/// let options = Options::new().with_cache_extents(true);
+/// for (path, result) in paths.iter().zip(warm_cache(&paths, &options)) {
+///     if let Err(e) = result {
+///         eprintln!("{}: {}", path.display(), e);
+///     }
+/// }
+/// ```
+pub fn warm_cache(paths: &[PathBuf], options: &Options) -> Vec<io::Result<()>> {
+    paths
+        .iter()
+        .map(|path| {
+            let file = data_file(File::open(path)?, options)?;
+            ReadContext::new(&file, options).with_path(path).warm()
+        })
+        .collect()
+}
+
+/// Read all requests of one device, sharing a single device handle.
+fn read_group(group: Vec<Pending>, options: &Options) -> Vec<(usize, io::Result<State>)> {
+    let slot: OnceLock<DeviceHandle> = OnceLock::new();
+    group
+        .into_iter()
+        .map(|pending| {
+            let request = pending.request;
+            let ctx = ReadContext::new(&pending.file, options)
+                .with_path(request.path)
+                .with_device_slot(&slot);
+            (pending.index, ctx.read_at(request.buf, request.offset))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::file_on_image;
+    use std::io::Write;
+
+    #[test]
+    fn test_read_many() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = Vec::new();
+        for (i, byte) in [0x11u8, 0x22].iter().enumerate() {
+            let path = dir.path().join(format!("file{}", i));
+            let mut file = File::create(&path).unwrap();
+            file.write_all(&[*byte; 4096]).unwrap();
+            file.sync_all().unwrap();
+            paths.push(path);
+        }
+        let missing = dir.path().join("missing");
+
+        let options = Options::new().with_allow_fallback(true);
+        for parallel in [false, true] {
+            let mut a = vec![0u8; 4096];
+            let mut b = vec![0u8; 4096];
+            let mut c = vec![0u8; 4096];
+            let mut requests = [
+                BlkRequest::new(&paths[0], 0, &mut a),
+                BlkRequest::new(&missing, 0, &mut c),
+                BlkRequest::new(&paths[1], 0, &mut b),
+            ];
+            let results = read_many(&mut requests, &options, parallel);
+            assert_eq!(results.len(), 3);
+            assert_eq!(
+                results[1].as_ref().unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+
+            assert_eq!(results[0].as_ref().unwrap().bytes_read, 4096);
+            assert_eq!(results[2].as_ref().unwrap().bytes_read, 4096);
+            assert!(a.iter().all(|&x| x == 0x11));
+            assert!(b.iter().all(|&x| x == 0x22));
+        }
+    }
+
+    #[test]
+    fn test_warm_cache() {
+        use crate::cache::BlkCache;
+        use crate::observer::{FiemapEvent, ReadObserver};
+        use crate::reader::BlkReader;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Debug, Default)]
+        struct Queries(AtomicUsize);
+
+        impl ReadObserver for Queries {
+            fn on_fiemap(&self, _: &FiemapEvent<'_>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // The device is an image, so that no privileges are needed
+        let (temp, options) = file_on_image(&[0x33; 8192]);
+        let path = temp.path().to_path_buf();
+        let cache = BlkCache::new();
+        let queries = Arc::new(Queries::default());
+        let options = options
+            .with_cache_handle(&cache)
+            .with_cache_extents(true)
+            .with_observer(queries.clone());
+        let results = warm_cache(&[path.clone(), path.with_extension("missing")], &options);
+        assert_eq!(results.len(), 2);
+        results[0].as_ref().unwrap();
+        assert_eq!(
+            results[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(queries.0.load(Ordering::Relaxed), 1);
+
+        // The read finds the extent map cached
+        let mut buf = vec![0u8; 4096];
+        let state = path
+            .blk_read_at_opt(&mut buf, 4096, &options.clone().with_dry_run(true))
+            .unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert_eq!(queries.0.load(Ordering::Relaxed), 1);
+
+        // Devices opened through a backend are not cached
+        assert_eq!(cache.stats().misses, 0);
+    }
+
+    #[test]
+    #[ignore = "opens the block device holding the temporary directory"]
+    fn test_warm_cache_devices() {
+        use crate::cache::BlkCache;
+        use crate::reader::BlkReader;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[0x33; 8192]).unwrap();
+        file.sync_all().unwrap();
+
+        let cache = BlkCache::new();
+        let options = Options::new().with_cache_handle(&cache);
+        let results = warm_cache(std::slice::from_ref(&path), &options);
+        results[0].as_ref().unwrap();
+        assert_eq!(cache.stats().misses, 1);
+
+        // The read uses the handle opened by warming
+        let mut buf = crate::AlignedBuf::new(4096, 4096);
+        let state = path.blk_read_at_opt(&mut buf, 4096, &options).unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert!(buf.iter().all(|&x| x == 0x33));
+        assert_eq!(cache.stats().misses, 1);
+    }
+}
@@ -5,11 +5,20 @@
 
 use blkmap::Fiemap;
 use blkpath::ResolveDevice;
-use blkreader::{BlkReader, Options};
-use clap::Parser;
+use blkreader::{
+    open_devices_then_drop_opt, open_each_device_then_drop, serve_device_helper, AlignedBuf,
+    BlkReader, DeviceHelper, DeviceSource, EncodedPolicy, HelperPolicy, InlinePolicy, IoEngine,
+    IoPriority, LibaioEngine, NumaNode, Options, PlannedRead, PreadvEngine, PsyncEngine, ReadFlags,
+    Throttle, Timing, UringEngine,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Default chunk size for reading large files (1 MB).
 const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
@@ -21,9 +30,73 @@ const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
 #[derive(Parser, Debug)]
 #[command(name = "blkreader")]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    read: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Read file data from the block device (default when no command is given)
+    Read(Box<Args>),
+
+    /// Report what the kernel, filesystem, and device support for a path
+    Features {
+        /// Path on the filesystem to probe
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+
+    /// Compare a file's data on the block device with its contents
+    Verify(Box<Args>),
+
+    /// Search a file's data on the block device, unwritten extents included, for a pattern
+    Scan {
+        /// Path to the file to search
+        path: PathBuf,
+
+        /// Pattern to search for
+        pattern: String,
+
+        /// The pattern is hexadecimal bytes (e.g. 4a524e4c)
+        #[arg(long)]
+        hex: bool,
+    },
+
+    /// Open block devices for an unprivileged blkreader on stdin (see --device-helper)
+    DeviceHelper {
+        /// Block device the client may open; gives it access to every file on the device
+        #[arg(long, value_name = "DEVICE", required = true)]
+        allow_device: Vec<PathBuf>,
+
+        /// User the client may run as [default: the user running sudo, or the current user]
+        #[arg(long, value_name = "UID")]
+        allow_user: Vec<u32>,
+    },
+}
+
+/// Arguments for reading file data.
+#[derive(clap::Args, Debug)]
 struct Args {
-    /// Path to the file to read
-    path: PathBuf,
+    /// Path to the file to read; give files named like a command, such as `read`, as `./read`
+    #[arg(required_unless_present = "files_from", conflicts_with = "files_from")]
+    path: Option<PathBuf>,
+
+    /// Read the list of files to process from FILE ('-' for stdin)
+    #[arg(long, value_name = "FILE", requires = "output_dir")]
+    files_from: Option<PathBuf>,
+
+    /// Paths in the file list are NUL-separated (as produced by `find -print0`)
+    #[arg(long, requires = "files_from")]
+    null: bool,
+
+    /// Directory receiving one output file per input file when using --files-from
+    #[arg(long, value_name = "DIR", requires = "files_from")]
+    output_dir: Option<PathBuf>,
 
     /// Byte offset to start reading from
     #[arg(short, long, default_value = "0")]
@@ -38,9 +111,17 @@ struct Args {
     verbose: bool,
 
     /// Output file path (default: stdout)
-    #[arg(short = 'O', long)]
+    #[arg(short = 'O', long, conflicts_with = "files_from")]
     output: Option<PathBuf>,
 
+    /// Copy the whole file to the output file, keeping holes instead of writing zeros
+    #[arg(long, requires = "output", conflicts_with = "length")]
+    sparse: bool,
+
+    /// Start from a named option preset; other flags are applied on top
+    #[arg(long, value_enum, default_value_t = Profile::Default)]
+    profile: Profile,
+
     /// Fill holes with zeros instead of stopping
     #[arg(long)]
     fill_holes: bool,
@@ -49,6 +130,14 @@ struct Args {
     #[arg(long)]
     zero_unwritten: bool,
 
+    /// Warn about unwritten extents whose raw device data is not all zeros
+    #[arg(long)]
+    check_unwritten: bool,
+
+    /// Byte used to fill holes and unwritten extents (e.g. 0xDE)
+    #[arg(long, default_value = "0", value_parser = parse_byte)]
+    fill_byte: u8,
+
     /// Allow fallback to regular file I/O when safe
     #[arg(long)]
     allow_fallback: bool,
@@ -57,33 +146,522 @@ struct Args {
     #[arg(long)]
     no_cache: bool,
 
+    /// Read the block device through its page cache instead of with O_DIRECT
+    #[arg(long)]
+    buffered: bool,
+
+    /// Retry device reads rejected by O_DIRECT (EINVAL) through the page cache
+    #[arg(long)]
+    buffered_fallback: bool,
+
     /// Dry run mode - skip actual device reads
     #[arg(long)]
     dry_run: bool,
 
-    /// Alignment for direct IO.
-    #[arg(long, default_value_t = 512)]
-    alignment: u64,
+    /// Issue device reads in physical order (faster on spinning disks)
+    #[arg(long)]
+    sort_physical: bool,
+
+    /// Backend used for device reads
+    #[arg(long, value_enum)]
+    io_engine: Option<Engine>,
+
+    /// Request polled reads (RWF_HIPRI)
+    #[arg(long)]
+    hipri: bool,
+
+    /// Request non-blocking reads (RWF_NOWAIT)
+    #[arg(long)]
+    nowait: bool,
+
+    /// I/O priority of device reads: `idle`, or `be:N` for best effort at level N (0-7)
+    #[arg(long, value_name = "PRIORITY", value_parser = parse_io_priority)]
+    io_priority: Option<IoPriority>,
+
+    /// Fill unreadable device ranges instead of failing
+    #[arg(long)]
+    best_effort: bool,
+
+    /// Limit device reads to this many bytes per second
+    #[arg(long, value_name = "BYTES")]
+    max_throughput: Option<u64>,
+
+    /// Fail reads that take longer than this many seconds
+    #[arg(long, value_name = "SECS", value_parser = parse_timeout)]
+    timeout: Option<Duration>,
+
+    /// Report how long FIEMAP, device resolution and the device reads took
+    #[arg(long)]
+    timing: bool,
+
+    /// Flush the file before querying its extents (FIEMAP_FLAG_SYNC)
+    #[arg(long)]
+    fiemap_sync: bool,
+
+    /// Query the extents again after reading and warn if they moved
+    #[arg(long)]
+    verify_extents: bool,
+
+    /// Check one block against a page-cache read before reading the device
+    #[arg(long)]
+    verify_device: bool,
+
+    /// Warn if the range has dirty pages not yet written to the device
+    #[arg(long)]
+    check_dirty: bool,
+
+    /// Fail if the range has dirty pages not yet written to the device
+    #[arg(long)]
+    fail_on_dirty: bool,
+
+    /// Write the file's data out with fdatasync before reading
+    #[arg(long)]
+    sync_first: bool,
+
+    /// Fail on extents stored inline in metadata instead of reading them through the file
+    #[arg(long)]
+    fail_on_inline: bool,
+
+    /// How to read compressed or encrypted extents
+    #[arg(long, value_enum, value_name = "POLICY")]
+    encoded: Option<Encoded>,
+
+    /// Return ciphertext of fscrypt-encrypted files and dm-crypt devices instead of failing
+    #[arg(long)]
+    allow_ciphertext: bool,
+
+    /// Use btrfs extent offsets as device offsets instead of translating them through the chunk tree
+    #[arg(long)]
+    no_btrfs_translate: bool,
+
+    /// Read from the disks below device-mapper linear, striped and mirrored targets (LVM) instead of the dm device
+    #[arg(long)]
+    translate_dm: bool,
+
+    /// Read RAID1 md arrays from an in-sync member instead of the array device (experimental)
+    #[arg(long)]
+    translate_md: bool,
+
+    /// Read loop devices through their backing files instead of the loop device
+    #[arg(long)]
+    translate_loop: bool,
+
+    /// Read overlayfs files through the overlay instead of the layer file holding their data
+    #[arg(long)]
+    no_overlay_resolve: bool,
+
+    /// Read a captured image of the device (e.g. from dd) instead of the device itself
+    #[arg(long, value_name = "PATH")]
+    device_image: Option<PathBuf>,
+
+    /// Byte offset of the device's start in the --device-image, such as that of a partition
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "0",
+        requires = "device_image"
+    )]
+    image_offset: u64,
+
+    /// Ask the kernel to read each next chunk from the device while the current one is written out
+    #[arg(long)]
+    prefetch: bool,
+
+    /// Alignment for direct IO [default: the device's logical sector size]
+    #[arg(long)]
+    alignment: Option<u64>,
+
+    /// Open the needed block devices, then continue as this user and group
+    #[arg(long, value_name = "UID:GID", value_parser = parse_ids)]
+    drop_to: Option<(u32, u32)>,
+
+    /// Leave opening block devices to a privileged helper run with this command (e.g. "sudo blkreader device-helper --allow-device /dev/sda1")
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with_all = ["device_image", "drop_to"]
+    )]
+    device_helper: Option<String>,
+
+    /// Read into a buffer backed by 2 MiB huge pages, one huge page per chunk
+    #[arg(long, conflicts_with = "numa_node")]
+    huge_pages: bool,
+
+    /// Allocate read buffers on this NUMA node: `device` for the one local to the device, or its number
+    #[arg(long, value_name = "NODE", value_parser = parse_numa_node)]
+    numa_node: Option<NumaNode>,
+}
+
+/// Named option presets.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Profile {
+    /// Library defaults
+    Default,
+    /// Raw unwritten data, continue past holes and unreadable sectors, never fall back
+    Recovery,
+    /// Always read the device, zero-fill like the filesystem, require full reads
+    Verify,
+    /// Prefer regular file I/O when safe, cache device handles
+    Fast,
+}
+
+/// Device read backends.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Engine {
+    /// One blocking pread per buffer
+    Psync,
+    /// One blocking preadv per read
+    Preadv,
+    /// Batched reads through io_uring (requires the `uring` feature)
+    Uring,
+    /// Batched reads through Linux native AIO
+    Libaio,
+}
+
+/// Handling of compressed or encrypted extents.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Encoded {
+    /// Fail the read
+    Error,
+    /// Return the raw device blocks
+    Raw,
+    /// Fill with the fill byte
+    Fill,
+}
+
+impl Encoded {
+    /// The library policy the choice selects.
+    fn policy(self) -> EncodedPolicy {
+        match self {
+            Encoded::Error => EncodedPolicy::Error,
+            Encoded::Raw => EncodedPolicy::Raw,
+            Encoded::Fill => EncodedPolicy::Fill,
+        }
+    }
+}
+
+impl Engine {
+    /// The library engine the backend selects.
+    fn engine(self) -> Arc<dyn IoEngine> {
+        match self {
+            Engine::Psync => Arc::new(PsyncEngine),
+            Engine::Preadv => Arc::new(PreadvEngine),
+            Engine::Uring => Arc::new(UringEngine),
+            Engine::Libaio => Arc::new(LibaioEngine),
+        }
+    }
+}
+
+impl Profile {
+    /// Options the preset starts from.
+    fn options(self) -> Options {
+        match self {
+            Profile::Default => Options::new(),
+            Profile::Recovery => Options::recovery(),
+            Profile::Verify => Options::verify(),
+            Profile::Fast => Options::fast(),
+        }
+    }
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Some(Command::Read(args)) => run(args),
+        Some(Command::Features { path }) => print_features(path),
+        Some(Command::Verify(args)) => verify(args),
+        Some(Command::Scan { path, pattern, hex }) => scan(path, pattern, *hex),
+        Some(Command::DeviceHelper {
+            allow_device,
+            allow_user,
+        }) => device_helper(allow_device, allow_user),
+        None => run(&cli.read),
+    };
 
-    if let Err(e) = run(&args) {
+    if let Err(e) = result {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
 }
 
-/// Allocate an aligned buffer for Direct I/O.
-fn alloc_aligned_buffer(size: usize, align: usize) -> Vec<u8> {
-    // Allocate with extra space for alignment
-    let layout = std::alloc::Layout::from_size_align(size, align).unwrap();
-    let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
-    if ptr.is_null() {
-        panic!("Failed to allocate aligned buffer");
+/// Serve requests to open the allowed block devices on stdin, a Unix
+/// socket.
+fn device_helper(devices: &[PathBuf], users: &[u32]) -> io::Result<()> {
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
+
+    let users = match users {
+        [] => vec![std::env::var("SUDO_UID")
+            .ok()
+            .and_then(|uid| uid.parse().ok())
+            // SAFETY: getuid has no preconditions and cannot fail.
+            .unwrap_or_else(|| unsafe { libc::getuid() })],
+        users => users.to_vec(),
+    };
+    let mut policy = users
+        .into_iter()
+        .fold(HelperPolicy::new(), HelperPolicy::allow_user);
+    for device in devices {
+        policy = policy
+            .allow_device(device)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", device.display(), e)))?;
+    }
+
+    // SAFETY: stdin is open for the process's lifetime and owned by the
+    // stream from here on.
+    let stream = unsafe { UnixStream::from_raw_fd(0) };
+    serve_device_helper(stream, &policy)
+}
+
+/// Start the helper given with --device-helper.
+fn spawn_device_helper(command: &str) -> io::Result<DeviceHelper> {
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "empty --device-helper command")
+    })?;
+    let mut command = std::process::Command::new(program);
+    command.args(words);
+    DeviceHelper::spawn(command)
+}
+
+/// Print the runtime capability report for a path.
+fn print_features(path: &Path) -> io::Result<()> {
+    let caps = blkreader::capabilities(path)?;
+
+    println!("Path: {}", caps.path.display());
+    println!("FIEMAP: {}", caps.fiemap);
+    println!("FIEMAP_FLAG_SYNC: {}", caps.fiemap_sync);
+    match &caps.device_path {
+        Some(device_path) => println!("Block device: {}", device_path.display()),
+        None => println!("Block device: {}", caps.device),
+    }
+    println!("O_DIRECT on device: {}", caps.direct_io);
+    match caps.dio_alignment {
+        Some((memory, offset)) => {
+            println!("STATX_DIOALIGN: memory {}, offset {}", memory, offset)
+        }
+        None => println!("STATX_DIOALIGN: no (not reported)"),
+    }
+    println!("io_uring: {}", caps.io_uring);
+    println!(
+        "Effective UID: {}{}",
+        caps.effective_uid,
+        if caps.effective_uid == 0 {
+            " (root)"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "Device readable: {}",
+        if caps.device_readable { "yes" } else { "no" }
+    );
+    println!(
+        "Required privileges: {}",
+        if caps.can_read_device() {
+            "none beyond current"
+        } else {
+            "root or read access to the block device (or use --allow-fallback)"
+        }
+    );
+
+    Ok(())
+}
+
+/// Copy a whole file to the output file, keeping its holes.
+fn copy_sparse(args: &Args, path: &Path, options: &Options) -> io::Result<()> {
+    if args.offset != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--sparse copies the whole file, without --offset",
+        ));
+    }
+    let output_path = args.output.as_ref().expect("--sparse requires --output");
+    escalate_if_needed(options)?;
+    drop_privileges(args, &[path.to_path_buf()], options)?;
+
+    // The copy sets the output's size and punches holes where it has data
+    let output = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(output_path)?;
+    let state = path.blk_copy_sparse(&output, options)?;
+    if args.verbose {
+        eprintln!(
+            "Copied {} data bytes to {}",
+            state.bytes_read,
+            output_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Compare a range of a file read from the device and through the file,
+/// printing the ranges that differ.
+fn verify(args: &Args) -> io::Result<()> {
+    let options = build_options(args)?;
+    let Some(path) = &args.path else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "verify takes a single path, not --files-from",
+        ));
+    };
+    let file_size = File::open(path)?.metadata()?.len();
+    let length = match args.length {
+        Some(len) => len,
+        None => file_size.saturating_sub(args.offset),
+    };
+
+    // The device is always read, even with --allow-fallback
+    escalate_if_needed(&Options {
+        allow_fallback: false,
+        ..options.clone()
+    })?;
+    drop_privileges(args, std::slice::from_ref(path), &options)?;
+
+    let mut mismatches = 0usize;
+    let mut offset = args.offset;
+    let end = args.offset.saturating_add(length);
+    while offset < end {
+        let chunk = (end - offset).min(DEFAULT_CHUNK_SIZE as u64);
+        let report = path.blk_verify_at(offset, chunk, &options)?;
+        for mismatch in &report.mismatches {
+            println!("{}", mismatch);
+        }
+        mismatches += report.mismatches.len();
+        if report.state.bytes_read < report.file_bytes {
+            println!(
+                "{:#x}: device returned {} of {} bytes",
+                offset, report.state.bytes_read, report.file_bytes
+            );
+            mismatches += 1;
+        }
+        if report.file_bytes < chunk as usize {
+            break;
+        }
+        offset += chunk;
+    }
+
+    if mismatches > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} range(s) differ between the device and the file",
+                mismatches
+            ),
+        ));
+    }
+    if args.verbose {
+        eprintln!("{} bytes match", offset.min(end) - args.offset);
+    }
+    Ok(())
+}
+
+/// Print the occurrences of a pattern in a file's device data.
+fn scan(path: &Path, pattern: &str, hex: bool) -> io::Result<()> {
+    let pattern = if hex {
+        parse_hex(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+    } else {
+        pattern.as_bytes().to_vec()
+    };
+    let options = Options::new();
+    escalate_if_needed(&options)?;
+
+    for m in path.blk_scan(&pattern, &options)? {
+        println!(
+            "logical 0x{:016x} physical 0x{:016x}{}",
+            m.logical,
+            m.physical,
+            if m.flags.is_unwritten() {
+                " (unwritten)"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Parse bytes given as hexadecimal digits.
+fn parse_hex(value: &str) -> Result<Vec<u8>, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!(
+            "invalid hex pattern '{}': odd number of digits",
+            value
+        ));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            digits
+                .get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("invalid hex pattern '{}'", value))
+        })
+        .collect()
+}
+
+/// Parse a byte value given in decimal or `0x`-prefixed hexadecimal.
+fn parse_byte(value: &str) -> Result<u8, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse::<u8>(),
+    };
+    parsed.map_err(|e| format!("invalid byte value '{}': {}", value, e))
+}
+
+/// Parse an I/O priority: `idle`, or `be:N` for best effort at level N.
+fn parse_io_priority(value: &str) -> Result<IoPriority, String> {
+    match value.split_once(':') {
+        None if value == "idle" => Ok(IoPriority::Idle),
+        Some(("be", level)) => match level.parse::<u8>() {
+            Ok(level) if level <= 7 => Ok(IoPriority::BestEffort(level)),
+            _ => Err(format!(
+                "invalid best-effort level '{}': expected 0-7",
+                level
+            )),
+        },
+        _ => Err(format!(
+            "invalid I/O priority '{}': expected idle or be:N",
+            value
+        )),
     }
-    unsafe { Vec::from_raw_parts(ptr, size, size) }
+}
+
+/// Parse a numeric user and group id pair, `UID:GID`.
+fn parse_ids(value: &str) -> Result<(u32, u32), String> {
+    value
+        .split_once(':')
+        .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+        .ok_or_else(|| format!("invalid user and group '{}': expected UID:GID", value))
+}
+
+/// Parse a NUMA node: `device`, or a node number.
+fn parse_numa_node(value: &str) -> Result<NumaNode, String> {
+    if value == "device" {
+        return Ok(NumaNode::Device);
+    }
+    value
+        .parse::<usize>()
+        .map(NumaNode::Node)
+        .map_err(|_| format!("invalid NUMA node '{}': expected device or N", value))
+}
+
+/// Parse a timeout given in (possibly fractional) seconds.
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .map_err(|e| e.to_string())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).map_err(|e| e.to_string()))
+        .map_err(|e| format!("invalid timeout '{}': {}", value, e))
 }
 
 /// Align offset down to the alignment boundary.
@@ -97,8 +675,308 @@ fn align_up(length: u64, alignment: u64) -> u64 {
 }
 
 fn run(args: &Args) -> io::Result<()> {
+    let options = build_options(args)?;
+
+    let Some(list_path) = &args.files_from else {
+        let path = args
+            .path
+            .as_ref()
+            .expect("path is required without --files-from");
+
+        if args.sparse {
+            return copy_sparse(args, path, &options);
+        }
+
+        // Open output file or use stdout
+        let mut output: Box<dyn Write> = if let Some(output_path) = &args.output {
+            Box::new(File::create(output_path)?)
+        } else {
+            Box::new(io::stdout())
+        };
+
+        read_file(args, path, &options, &mut output)?;
+        if args.verbose {
+            if let Some(output_path) = &args.output {
+                eprintln!("Output written to: {}", output_path.display());
+            }
+        }
+        return Ok(());
+    };
+
+    // Escalate before consuming the file list, since escalation re-executes the process
+    escalate_if_needed(&options)?;
+
+    let output_dir = args
+        .output_dir
+        .as_ref()
+        .expect("--output-dir is required with --files-from");
+    let paths = read_file_list(list_path, args.null)?;
+    let output_paths = output_paths(&paths, output_dir)?;
+    // A file whose device cannot be opened fails on its own, after which
+    // the rest are read unprivileged
+    let opened = drop_privileges_each(args, &paths, &options)?;
+
+    let mut failures = 0usize;
+    for ((path, output_path), opened) in paths.iter().zip(&output_paths).zip(opened) {
+        let result = opened.and_then(|()| read_file_to_path(args, path, &options, output_path));
+
+        match result {
+            Ok(()) if args.verbose => {
+                eprintln!("Output written to: {}", output_path.display());
+                eprintln!();
+            }
+            Ok(()) => {}
+            Err(e) => {
+                eprintln!("Error: {}: {}", path.display(), e);
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(io::Error::other(format!(
+            "{} of {} file(s) failed",
+            failures,
+            paths.len()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Build library options from the command line arguments.
+fn build_options(args: &Args) -> io::Result<Options> {
+    let base = args.profile.options();
+    let mut read_flags = base.read_flags;
+    if args.hipri {
+        read_flags |= ReadFlags::HIPRI;
+    }
+    if args.nowait {
+        read_flags |= ReadFlags::NOWAIT;
+    }
+    let options = Options {
+        enable_cache: base.enable_cache && !args.no_cache,
+        direct: base.direct && !args.buffered,
+        buffered_fallback: base.buffered_fallback || args.buffered_fallback,
+        fill_holes: base.fill_holes || args.fill_holes,
+        zero_unwritten: base.zero_unwritten || args.zero_unwritten,
+        check_unwritten: base.check_unwritten || args.check_unwritten,
+        allow_fallback: base.allow_fallback || args.allow_fallback,
+        dry_run: base.dry_run || args.dry_run,
+        sort_physical: base.sort_physical || args.sort_physical,
+        best_effort: base.best_effort || args.best_effort,
+        io_engine: args
+            .io_engine
+            .map_or_else(|| base.io_engine.clone(), Engine::engine),
+        read_flags,
+        io_priority: args.io_priority.or(base.io_priority),
+        buffer_node: args.numa_node.or(base.buffer_node),
+        max_throughput: args
+            .max_throughput
+            .map(Throttle::new)
+            .or(base.max_throughput.clone()),
+        timeout: args.timeout.or(base.timeout),
+        timing: base.timing || args.timing,
+        fiemap_sync: base.fiemap_sync || args.fiemap_sync,
+        verify_extents: base.verify_extents || args.verify_extents,
+        verify_device: base.verify_device || args.verify_device,
+        check_dirty: base.check_dirty || args.check_dirty,
+        fail_on_dirty: base.fail_on_dirty || args.fail_on_dirty,
+        sync_first: base.sync_first || args.sync_first,
+        inline_policy: if args.fail_on_inline {
+            InlinePolicy::Error
+        } else {
+            base.inline_policy
+        },
+        encoded_policy: args.encoded.map_or(base.encoded_policy, Encoded::policy),
+        allow_ciphertext: base.allow_ciphertext || args.allow_ciphertext,
+        translate_btrfs: base.translate_btrfs && !args.no_btrfs_translate,
+        translate_dm: base.translate_dm || args.translate_dm,
+        translate_md: base.translate_md || args.translate_md,
+        translate_loop: base.translate_loop || args.translate_loop,
+        resolve_overlay: base.resolve_overlay && !args.no_overlay_resolve,
+        ..base
+    }
+    .with_fill_byte(args.fill_byte);
+    if let Some(command) = &args.device_helper {
+        return Ok(options.with_device_backend(spawn_device_helper(command)?));
+    }
+    Ok(match &args.device_image {
+        Some(path) => options.with_device_override(DeviceSource::Image {
+            path: path.clone(),
+            offset: args.image_offset,
+        }),
+        None => options,
+    })
+}
+
+/// Whether privileges were dropped with --drop-to, after which escalating
+/// would re-execute the process.
+static PRIVILEGES_DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// With --drop-to, open the devices holding `paths` and drop to the given
+/// user and group, once.
+fn drop_privileges(args: &Args, paths: &[PathBuf], options: &Options) -> io::Result<()> {
+    let Some((uid, gid)) = args.drop_to else {
+        return Ok(());
+    };
+    if PRIVILEGES_DROPPED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    open_devices_then_drop_opt(paths, uid, gid, options)?;
+    PRIVILEGES_DROPPED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// With --drop-to, open the devices holding each of `paths` and drop to the
+/// given user and group, with the result of opening each path.
+fn drop_privileges_each(
+    args: &Args,
+    paths: &[PathBuf],
+    options: &Options,
+) -> io::Result<Vec<io::Result<()>>> {
+    let Some((uid, gid)) = args.drop_to else {
+        return Ok(paths.iter().map(|_| Ok(())).collect());
+    };
+    if PRIVILEGES_DROPPED.load(Ordering::Relaxed) {
+        return Ok(paths.iter().map(|_| Ok(())).collect());
+    }
+    let results = open_each_device_then_drop(paths, uid, gid, options)?;
+    PRIVILEGES_DROPPED.store(true, Ordering::Relaxed);
+    Ok(results)
+}
+
+/// Request sudo privileges unless fallback mode may avoid device access.
+fn escalate_if_needed(options: &Options) -> io::Result<()> {
+    if PRIVILEGES_DROPPED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    // Dry runs resolve the device but never open it, loop devices may be
+    // read through backing files and device images are regular files the
+    // user can read
+    if !options.allow_fallback
+        && !options.dry_run
+        && !options.translate_loop
+        && options.device_backend.is_none()
+    {
+        sudo::escalate_if_needed().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Failed to escalate privileges: {}", e),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Read a newline- or NUL-separated list of paths from a file or stdin.
+fn read_file_list(list_path: &Path, null: bool) -> io::Result<Vec<PathBuf>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let reader: Box<dyn Read> = if list_path == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(list_path)?)
+    };
+
+    let separator = if null { b'\0' } else { b'\n' };
+    let mut paths = Vec::new();
+    for entry in BufReader::new(reader).split(separator) {
+        let entry = entry?;
+        if !entry.is_empty() {
+            paths.push(PathBuf::from(std::ffi::OsStr::from_bytes(&entry)));
+        }
+    }
+    Ok(paths)
+}
+
+/// Map an input path to a path relative to the output directory.
+///
+/// Only normal components are kept, so absolute paths and `..` cannot
+/// escape the output directory. Fails for paths not ending in a file name,
+/// such as `/` or `a/..`.
+fn relative_output_path(path: &Path) -> io::Result<PathBuf> {
+    if !matches!(path.components().next_back(), Some(Component::Normal(_))) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: no file name to name its output by", path.display()),
+        ));
+    }
+    Ok(path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Map each input path to its output path in `output_dir`.
+///
+/// Fails before anything is written if an input has no file name, or if
+/// two inputs, such as `/x/f` and `x/f`, map to the same output.
+fn output_paths(paths: &[PathBuf], output_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut inputs: HashMap<PathBuf, &Path> = HashMap::new();
+    let mut outputs = Vec::with_capacity(paths.len());
+    let mut problems = 0usize;
+    for path in paths {
+        let output_path = match relative_output_path(path) {
+            Ok(relative) => output_dir.join(relative),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                problems += 1;
+                continue;
+            }
+        };
+        if let Some(first) = inputs.insert(output_path.clone(), path) {
+            eprintln!(
+                "Error: {} and {} would both be written to {}",
+                first.display(),
+                path.display(),
+                output_path.display()
+            );
+            problems += 1;
+        }
+        outputs.push(output_path);
+    }
+    if problems > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} file(s) in the list have no distinct output path",
+                problems
+            ),
+        ));
+    }
+    Ok(outputs)
+}
+
+/// Read a single file into a newly created output file.
+fn read_file_to_path(
+    args: &Args,
+    path: &Path,
+    options: &Options,
+    output_path: &Path,
+) -> io::Result<()> {
+    // Make sure the input exists before creating its output
+    std::fs::metadata(path)?;
+
+    if let Some(parent) = output_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut output = File::create(output_path)?;
+    read_file(args, path, options, &mut output)
+}
+
+/// Read a single file and write its data to the output.
+fn read_file(
+    args: &Args,
+    path: &Path,
+    options: &Options,
+    output: &mut dyn Write,
+) -> io::Result<()> {
     // Determine the length to read
-    let file = File::open(&args.path)?;
+    let file = File::open(path)?;
     let file_size = file.metadata()?.len();
 
     let length = match args.length {
@@ -115,45 +993,44 @@ fn run(args: &Args) -> io::Result<()> {
 
     // Request sudo privileges only if not using fallback mode
     // or if we need to access the block device directly
-    if !args.allow_fallback {
-        sudo::escalate_if_needed().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::PermissionDenied,
-                format!("Failed to escalate privileges: {}", e),
-            )
-        })?;
-    }
+    escalate_if_needed(options)?;
+    drop_privileges(args, &[path.to_path_buf()], options)?;
+
+    // Without a device to ask, e.g. in fallback mode without privileges,
+    // 4096 bytes suits both 512-byte and 4K-native devices
+    let alignment = match args.alignment {
+        Some(alignment) => alignment,
+        None => path.blk_required_alignment().unwrap_or(4096),
+    };
 
     // Print verbose information
     if args.verbose {
-        print_verbose_info(&args.path, args.offset, length, args.alignment)?;
+        print_verbose_info(path, args.offset, length, alignment)?;
     }
 
-    // Build options
-    let options = Options::new()
-        .with_cache(!args.no_cache)
-        .with_fill_holes(args.fill_holes)
-        .with_zero_unwritten(args.zero_unwritten)
-        .with_allow_fallback(args.allow_fallback)
-        .with_dry_run(args.dry_run);
-
-    // Open output file or use stdout
-    let mut output: Box<dyn Write> = if let Some(output_path) = &args.output {
-        Box::new(File::create(output_path)?)
-    } else {
-        Box::new(io::stdout())
-    };
-
     // Calculate aligned read parameters for Direct I/O
-    let aligned_offset = align_down(args.offset, args.alignment);
+    let aligned_offset = align_down(args.offset, alignment);
     let offset_adjustment = (args.offset - aligned_offset) as usize;
-    let total_length = align_up(length + offset_adjustment as u64, args.alignment);
+    let total_length = align_up(length + offset_adjustment as u64, alignment);
 
     // Determine chunk size (aligned to ALIGNMENT)
-    let chunk_size = DEFAULT_CHUNK_SIZE;
+    let chunk_size = if args.huge_pages {
+        AlignedBuf::HUGE_PAGE_SIZE
+    } else {
+        DEFAULT_CHUNK_SIZE
+    };
 
     // Allocate aligned buffer.
-    let mut buf = alloc_aligned_buffer(chunk_size, args.alignment as usize);
+    let node = match options.buffer_node {
+        Some(NumaNode::Node(node)) => Some(node),
+        Some(NumaNode::Device) => path.blk_numa_node().ok().flatten(),
+        None => None,
+    };
+    let mut buf = match node {
+        _ if args.huge_pages => AlignedBuf::huge(chunk_size),
+        Some(node) => AlignedBuf::on_node(chunk_size, alignment as usize, node)?,
+        None => AlignedBuf::new(chunk_size, alignment as usize),
+    };
 
     // Read in chunks to handle large files
     let mut total_bytes_read = 0usize;
@@ -161,23 +1038,74 @@ fn run(args: &Args) -> io::Result<()> {
     let mut remaining = total_length;
     let mut first_chunk = true;
     let mut block_device_path = PathBuf::new();
+    let mut device_info = None;
+    let mut lvm = None;
+    let mut md = None;
+    let mut shared_bytes = 0usize;
 
     while remaining > 0 {
         let read_size = std::cmp::min(remaining as usize, chunk_size);
-        let aligned_size = align_up(read_size as u64, args.alignment) as usize;
+        let aligned_size = align_up(read_size as u64, alignment) as usize;
+
+        // Prefetching is advisory, so its failure doesn't fail the read
+        if args.prefetch && remaining > aligned_size as u64 {
+            let next = current_aligned_offset + aligned_size as u64;
+            let _ = path.blk_prefetch(
+                next,
+                (remaining - aligned_size as u64).min(chunk_size as u64),
+                options,
+            );
+        }
 
         // Perform the read
-        let state = args.path.blk_read_at_opt(
-            &mut buf[..aligned_size],
-            current_aligned_offset,
-            &options,
-        )?;
+        let state =
+            path.blk_read_at_opt(&mut buf[..aligned_size], current_aligned_offset, options)?;
 
         if first_chunk {
             block_device_path = state.block_device_path.clone();
+            device_info = state.device_info;
+            lvm = state.lvm.clone();
+            md = state.md.clone();
             first_chunk = false;
         }
 
+        if args.verbose {
+            print_planned_reads(&state.planned);
+        }
+        if let Some(timing) = &state.timing {
+            print_timing(current_aligned_offset, timing);
+        }
+        for range in &state.unreadable {
+            eprintln!(
+                "Warning: unreadable logical 0x{:016x} physical 0x{:016x} length 0x{:x}",
+                range.logical, range.physical, range.length
+            );
+        }
+        for range in &state.unwritten_nonzero {
+            eprintln!(
+                "Warning: nonzero unwritten data at logical 0x{:016x} physical 0x{:016x} length 0x{:x}",
+                range.logical, range.physical, range.length
+            );
+        }
+        if state.possibly_stale {
+            eprintln!(
+                "Warning: range at 0x{:x} has dirty pages; device data may be stale",
+                current_aligned_offset
+            );
+        }
+        if state.ciphertext {
+            eprintln!(
+                "Warning: range at 0x{:x} was read as ciphertext",
+                current_aligned_offset
+            );
+        }
+        if state.possibly_torn {
+            eprintln!(
+                "Warning: extents at 0x{:x} changed during the read; data may be torn",
+                current_aligned_offset
+            );
+        }
+
         if state.bytes_read == 0 {
             break;
         }
@@ -197,6 +1125,7 @@ fn run(args: &Args) -> io::Result<()> {
         if bytes_to_write > 0 {
             output.write_all(&buf[skip..skip + bytes_to_write])?;
             total_bytes_read += bytes_to_write;
+            shared_bytes += state.shared_bytes.min(bytes_to_write);
         }
 
         // Check if we've read enough
@@ -219,15 +1148,91 @@ fn run(args: &Args) -> io::Result<()> {
         if !block_device_path.as_os_str().is_empty() {
             eprintln!("Block device: {}", block_device_path.display());
         }
-        if let Some(output_path) = &args.output {
-            eprintln!("Output written to: {}", output_path.display());
+        if let Some(info) = device_info {
+            eprintln!(
+                "Device: logical sector {} physical sector {} size {}",
+                info.logical_block_size, info.physical_block_size, info.size
+            );
+        }
+        if let Some(volume) = &lvm {
+            let pvs: Vec<_> = volume
+                .physical_volumes
+                .iter()
+                .map(|pv| pv.display().to_string())
+                .collect();
+            eprintln!(
+                "LVM volume: {}/{} ({}) on {}",
+                volume.vg_name,
+                volume.lv_name,
+                if volume.targets.is_empty() {
+                    "unknown layout".to_string()
+                } else {
+                    volume.targets.join(", ")
+                },
+                pvs.join(", ")
+            );
+        }
+        if let Some(array) = &md {
+            let members: Vec<_> = array
+                .members
+                .iter()
+                .map(|member| member.display().to_string())
+                .collect();
+            eprintln!(
+                "md array: {} {}{} on {}",
+                array.device_path.display(),
+                array.level,
+                if array.degraded { " (degraded)" } else { "" },
+                members.join(", ")
+            );
+        }
+        if shared_bytes > 0 {
+            eprintln!("Shared with other files: {} bytes", shared_bytes);
         }
     }
 
     Ok(())
 }
 
-fn print_verbose_info(path: &PathBuf, offset: u64, length: u64, alignment: u64) -> io::Result<()> {
+/// Print the duration of each stage of the read of a chunk.
+fn print_timing(offset: u64, timing: &Timing) {
+    let reads: Duration = timing.reads.iter().map(|read| read.duration).sum();
+    eprintln!(
+        "Timing: offset 0x{:016x} fiemap {:?} device {:?} reads {:?} ({}) total {:?}",
+        offset,
+        timing.fiemap,
+        timing.device_resolve,
+        reads,
+        timing.reads.len(),
+        timing.total
+    );
+}
+
+/// Print the steps a dry run would perform.
+fn print_planned_reads(planned: &[PlannedRead]) {
+    for step in planned {
+        match step {
+            PlannedRead::Device {
+                logical,
+                physical,
+                length,
+            } => eprintln!(
+                "Plan: read  logical 0x{:016x} physical 0x{:016x} length 0x{:x}",
+                logical, physical, length
+            ),
+            PlannedRead::Fill { logical, length } => eprintln!(
+                "Plan: fill  logical 0x{:016x} length 0x{:x}",
+                logical, length
+            ),
+            PlannedRead::File { logical, length } => eprintln!(
+                "Plan: file  logical 0x{:016x} length 0x{:x}",
+                logical, length
+            ),
+        }
+    }
+}
+
+fn print_verbose_info(path: &Path, offset: u64, length: u64, alignment: u64) -> io::Result<()> {
     eprintln!("File: {}", path.display());
     eprintln!("Offset: {} (0x{:x})", offset, offset);
     eprintln!("Length: {} (0x{:x})", length, length);
@@ -0,0 +1,549 @@
+//! Translation of btrfs addresses to member devices.
+//!
+//! On btrfs, the "physical" offsets reported by FIEMAP are addresses in the
+//! filesystem's own logical address space. The chunk tree maps that space
+//! onto stripes of one or more member devices, depending on the block group
+//! profile. Device reads of files on btrfs go through this translation, see
+//! [`Options::translate_btrfs`](crate::Options::translate_btrfs).
+
+use crate::map::Placement;
+use crate::state::is_encoded;
+use crate::sys;
+
+use blkmap::FiemapExtent;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+
+/// `statfs` magic of btrfs.
+pub(crate) const BTRFS_SUPER_MAGIC: i64 = 0x9123683E;
+
+/// Object ID of the chunk tree.
+const CHUNK_TREE_OBJECTID: u64 = 3;
+
+/// Object ID of all chunk items (`BTRFS_FIRST_CHUNK_TREE_OBJECTID`).
+const FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+
+/// Key type of chunk items.
+const CHUNK_ITEM_KEY: u32 = 228;
+
+/// Block group profile bits of a chunk's type (`BTRFS_BLOCK_GROUP_*`).
+const RAID0: u64 = 1 << 3;
+const RAID10: u64 = 1 << 6;
+const RAID5: u64 = 1 << 7;
+const RAID6: u64 = 1 << 8;
+
+/// Size of `struct btrfs_chunk` without its stripes.
+const CHUNK_SIZE: usize = 48;
+
+/// Size of `struct btrfs_stripe`.
+const STRIPE_SIZE: usize = 32;
+
+/// A range of a member device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DeviceRange {
+    /// ID of the member device.
+    pub(crate) devid: u64,
+    /// Byte offset on the device.
+    pub(crate) physical: u64,
+    /// Length of the range, in bytes.
+    pub(crate) length: u64,
+}
+
+/// A copy of a chunk's data on one device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stripe {
+    devid: u64,
+    offset: u64,
+}
+
+/// A range of the logical address space and the stripes holding it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Chunk {
+    /// Logical address of the start of the chunk.
+    logical: u64,
+    length: u64,
+    stripe_len: u64,
+    /// Block group type and profile bits.
+    flags: u64,
+    sub_stripes: u64,
+    stripes: Vec<Stripe>,
+}
+
+impl Chunk {
+    /// Parse a chunk item (`struct btrfs_chunk`) whose key offset is `logical`.
+    fn parse(logical: u64, data: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "truncated btrfs chunk item");
+        let u64_at = |at: usize| -> io::Result<u64> {
+            let bytes = data.get(at..at + 8).ok_or_else(invalid)?;
+            Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let u16_at = |at: usize| -> io::Result<u16> {
+            let bytes = data.get(at..at + 2).ok_or_else(invalid)?;
+            Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        let num_stripes = u16_at(44)? as usize;
+        let stripes = (0..num_stripes)
+            .map(|k| {
+                let at = CHUNK_SIZE + k * STRIPE_SIZE;
+                Ok(Stripe {
+                    devid: u64_at(at)?,
+                    offset: u64_at(at + 8)?,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        if stripes.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            logical,
+            length: u64_at(0)?,
+            stripe_len: u64_at(16)?.max(1),
+            flags: u64_at(24)?,
+            sub_stripes: (u16_at(46)? as u64).max(1),
+            stripes,
+        })
+    }
+
+    /// Logical address of the end of the chunk.
+    fn end(&self) -> u64 {
+        self.logical + self.length
+    }
+
+    /// Append the device ranges of `length` bytes at `logical`, which must
+    /// lie within the chunk.
+    ///
+    /// Mirrored profiles (DUP, RAID1 and its variants) are read from their
+    /// first copy.
+    fn map(&self, logical: u64, length: u64, out: &mut Vec<DeviceRange>) -> io::Result<()> {
+        if self.flags & (RAID5 | RAID6) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "btrfs RAID5/6 chunks are not supported",
+            ));
+        }
+
+        let count = self.stripes.len() as u64;
+        // Number of stripes each data stripe is mirrored to
+        let (factor, mirrors) = if self.flags & RAID10 != 0 {
+            (count / self.sub_stripes, self.sub_stripes)
+        } else if self.flags & RAID0 != 0 {
+            (count, 1)
+        } else {
+            let stripe = self.stripes[0];
+            push_range(
+                out,
+                stripe.devid,
+                stripe.offset + (logical - self.logical),
+                length,
+            );
+            return Ok(());
+        };
+        let factor = factor.max(1);
+
+        let mut offset = logical - self.logical;
+        let end = offset + length;
+        while offset < end {
+            let stripe_nr = offset / self.stripe_len;
+            let within = offset % self.stripe_len;
+            let len = (self.stripe_len - within).min(end - offset);
+            let index = ((stripe_nr % factor) * mirrors) as usize;
+            let stripe = self.stripes.get(index).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "btrfs chunk has too few stripes",
+                )
+            })?;
+            let physical = stripe.offset + (stripe_nr / factor) * self.stripe_len + within;
+            push_range(out, stripe.devid, physical, len);
+            offset += len;
+        }
+        Ok(())
+    }
+}
+
+/// Append a device range, merging it into the previous one if it continues it.
+fn push_range(out: &mut Vec<DeviceRange>, devid: u64, physical: u64, length: u64) {
+    match out.last_mut() {
+        Some(last) if last.devid == devid && last.physical + last.length == physical => {
+            last.length += length
+        }
+        _ => out.push(DeviceRange {
+            devid,
+            physical,
+            length,
+        }),
+    }
+}
+
+/// The chunk map of a btrfs filesystem and the paths of its member devices.
+#[derive(Debug, Default)]
+pub(crate) struct ChunkMap {
+    /// Chunks sorted by logical address.
+    chunks: Vec<Chunk>,
+    /// Paths of the member devices that could be found, by device ID.
+    devices: HashMap<u64, PathBuf>,
+}
+
+impl ChunkMap {
+    /// Read the chunk tree of the filesystem holding `file`.
+    ///
+    /// Requires `CAP_SYS_ADMIN`.
+    pub(crate) fn load(file: &File) -> io::Result<Self> {
+        let fd = file.as_raw_fd();
+        let items = sys::btrfs_tree_search(
+            fd,
+            CHUNK_TREE_OBJECTID,
+            FIRST_CHUNK_TREE_OBJECTID,
+            CHUNK_ITEM_KEY,
+        )?;
+        let chunks = items
+            .iter()
+            .map(|item| Chunk::parse(item.offset, &item.data))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Missing devices are only an error once data on them is read
+        let devids: BTreeSet<u64> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.stripes.iter().map(|stripe| stripe.devid))
+            .collect();
+        let devices = devids
+            .into_iter()
+            .filter_map(|devid| Some((devid, sys::btrfs_device_path(fd, devid).ok()?)))
+            .collect();
+        Ok(Self::new(chunks, devices))
+    }
+
+    fn new(mut chunks: Vec<Chunk>, devices: HashMap<u64, PathBuf>) -> Self {
+        chunks.sort_by_key(|chunk| chunk.logical);
+        Self { chunks, devices }
+    }
+
+    /// Translate `length` bytes at btrfs address `logical` into device ranges.
+    ///
+    /// Fails with [`io::ErrorKind::NotFound`] if part of the range is not
+    /// covered by any chunk, e.g. because the chunk was allocated after the
+    /// map was read.
+    pub(crate) fn map(&self, logical: u64, length: u64) -> io::Result<Vec<DeviceRange>> {
+        let mut ranges = Vec::new();
+        let end = logical + length;
+        let mut current = logical;
+        while current < end {
+            let index = self.chunks.partition_point(|chunk| chunk.end() <= current);
+            let chunk = self
+                .chunks
+                .get(index)
+                .filter(|chunk| chunk.logical <= current)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("btrfs address {:#x} is not mapped by any chunk", current),
+                    )
+                })?;
+            let stop = chunk.end().min(end);
+            chunk.map(current, stop - current, &mut ranges)?;
+            current = stop;
+        }
+        Ok(ranges)
+    }
+
+    /// Path of member device `devid`.
+    pub(crate) fn device_path(&self, devid: u64) -> io::Result<&Path> {
+        self.devices
+            .get(&devid)
+            .map(PathBuf::as_path)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("btrfs device {} is missing", devid),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+impl ChunkMap {
+    /// A map of one RAID0 chunk at `logical`, striped across `devices`
+    /// (device IDs 1, 2, ...) from their start.
+    pub(crate) fn striped(logical: u64, length: u64, stripe_len: u64, devices: &[PathBuf]) -> Self {
+        let stripes = (1..=devices.len() as u64)
+            .map(|devid| Stripe { devid, offset: 0 })
+            .collect();
+        let chunk = Chunk {
+            logical,
+            length,
+            stripe_len,
+            flags: 1 | RAID0,
+            sub_stripes: 1,
+            stripes,
+        };
+        Self::new(vec![chunk], (1..).zip(devices.iter().cloned()).collect())
+    }
+}
+
+/// Split `extents` at device boundaries and translate their physical
+/// offsets.
+///
+/// Extents for which `on_device` is false, such as holes, are passed
+/// through unchanged. The on-disk size of encoded extents is unknown, so
+/// they are translated as a whole from their start address.
+pub(crate) fn place(
+    map: &ChunkMap,
+    extents: &[FiemapExtent],
+    on_device: impl Fn(&FiemapExtent) -> bool,
+) -> io::Result<Placement> {
+    let mut placement = Placement::default();
+    for extent in extents {
+        if !on_device(extent) {
+            placement.push(None, *extent);
+            continue;
+        }
+        let ranges = if is_encoded(&extent.flags) {
+            let start = map.map(extent.physical, 1)?[0];
+            vec![DeviceRange {
+                length: extent.length,
+                ..start
+            }]
+        } else {
+            map.map(extent.physical, extent.length)?
+        };
+        let mut logical = extent.logical;
+        for range in ranges {
+            let piece = FiemapExtent {
+                logical,
+                physical: range.physical,
+                length: range.length,
+                flags: extent.flags,
+            };
+            placement.push(Some(map.device_path(range.devid)?), piece);
+            logical += range.length;
+        }
+    }
+    Ok(placement)
+}
+
+/// Whether `file` is on btrfs.
+pub(crate) fn is_btrfs(file: &File) -> io::Result<bool> {
+    Ok(sys::fs_type(file.as_raw_fd())? == BTRFS_SUPER_MAGIC)
+}
+
+/// Global cache of chunk maps, keyed by the device ID (`stat.st_dev`) of the
+/// filesystem.
+static CHUNK_MAPS: LazyLock<RwLock<HashMap<u64, Arc<ChunkMap>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Get the chunk map of the filesystem holding `file`.
+///
+/// The map is read once and cached if `cached` is set; `reload` replaces
+/// the cached map, e.g. after a lookup missed.
+pub(crate) fn chunk_map(file: &File, cached: bool, reload: bool) -> io::Result<Arc<ChunkMap>> {
+    if !cached {
+        return Ok(Arc::new(ChunkMap::load(file)?));
+    }
+
+    let dev_id = file.metadata()?.dev();
+    if !reload {
+        if let Some(map) = CHUNK_MAPS.read().unwrap().get(&dev_id) {
+            return Ok(Arc::clone(map));
+        }
+    }
+    let map = Arc::new(ChunkMap::load(file)?);
+    CHUNK_MAPS.write().unwrap().insert(dev_id, Arc::clone(&map));
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blkmap::ExtentFlags;
+
+    /// Build a chunk item as stored on disk.
+    fn chunk_item(length: u64, flags: u64, sub_stripes: u16, stripes: &[(u64, u64)]) -> Vec<u8> {
+        let mut data = vec![0u8; CHUNK_SIZE + stripes.len() * STRIPE_SIZE];
+        data[0..8].copy_from_slice(&length.to_le_bytes());
+        data[16..24].copy_from_slice(&65536u64.to_le_bytes());
+        data[24..32].copy_from_slice(&flags.to_le_bytes());
+        data[44..46].copy_from_slice(&(stripes.len() as u16).to_le_bytes());
+        data[46..48].copy_from_slice(&sub_stripes.to_le_bytes());
+        for (k, (devid, offset)) in stripes.iter().enumerate() {
+            let at = CHUNK_SIZE + k * STRIPE_SIZE;
+            data[at..at + 8].copy_from_slice(&devid.to_le_bytes());
+            data[at + 8..at + 16].copy_from_slice(&offset.to_le_bytes());
+        }
+        data
+    }
+
+    fn range(devid: u64, physical: u64, length: u64) -> DeviceRange {
+        DeviceRange {
+            devid,
+            physical,
+            length,
+        }
+    }
+
+    #[test]
+    fn test_parse_chunk() {
+        let data = chunk_item(1 << 30, 1 | RAID0, 1, &[(1, 1 << 20), (2, 2 << 20)]);
+        let chunk = Chunk::parse(8 << 30, &data).unwrap();
+        assert_eq!(chunk.logical, 8 << 30);
+        assert_eq!(chunk.length, 1 << 30);
+        assert_eq!(chunk.stripe_len, 65536);
+        assert_eq!(
+            chunk.stripes,
+            vec![
+                Stripe {
+                    devid: 1,
+                    offset: 1 << 20
+                },
+                Stripe {
+                    devid: 2,
+                    offset: 2 << 20
+                },
+            ]
+        );
+
+        assert!(Chunk::parse(0, &data[..CHUNK_SIZE + STRIPE_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_map_profiles() {
+        let single = Chunk::parse(1 << 30, &chunk_item(1 << 30, 1, 0, &[(3, 1 << 20)])).unwrap();
+        let dup = Chunk::parse(
+            2 << 30,
+            &chunk_item(1 << 30, 1 | (1 << 5), 0, &[(1, 4 << 20), (1, 8 << 20)]),
+        )
+        .unwrap();
+        let raid0 = Chunk::parse(
+            3 << 30,
+            &chunk_item(1 << 30, 1 | RAID0, 1, &[(1, 0), (2, 1 << 20)]),
+        )
+        .unwrap();
+        let raid10 = Chunk::parse(
+            4 << 30,
+            &chunk_item(1 << 30, 1 | RAID10, 2, &[(1, 0), (2, 0), (3, 0), (4, 0)]),
+        )
+        .unwrap();
+        let raid5 = Chunk::parse(
+            5 << 30,
+            &chunk_item(1 << 30, 1 | RAID5, 1, &[(1, 0), (2, 0)]),
+        )
+        .unwrap();
+        let map = ChunkMap::new(
+            vec![raid10, raid5, single, dup, raid0],
+            HashMap::from([(1, PathBuf::from("/dev/fake1"))]),
+        );
+
+        // Single and mirrored profiles read the first stripe
+        assert_eq!(
+            map.map((1 << 30) + 4096, 8192).unwrap(),
+            vec![range(3, (1 << 20) + 4096, 8192)]
+        );
+        assert_eq!(
+            map.map(2 << 30, 4096).unwrap(),
+            vec![range(1, 4 << 20, 4096)]
+        );
+
+        // RAID0 alternates between devices every stripe
+        assert_eq!(
+            map.map((3 << 30) + 61440, 139264).unwrap(),
+            vec![
+                range(1, 61440, 4096),
+                range(2, 1 << 20, 65536),
+                range(1, 65536, 65536),
+                range(2, (1 << 20) + 65536, 4096),
+            ]
+        );
+
+        // RAID10 stripes across mirror pairs
+        assert_eq!(
+            map.map((4 << 30) + 65536, 196608).unwrap(),
+            vec![
+                range(3, 0, 65536),
+                range(1, 65536, 65536),
+                range(3, 65536, 65536),
+            ]
+        );
+
+        let err = map.map(5 << 30, 4096).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let err = map.map(6 << 30, 4096).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        // A range crossing into unmapped space fails as a whole
+        assert!(map.map((4 << 30) - 4096, 8192).is_ok());
+        assert!(map.map((6 << 30) - 4096, 8192).is_err());
+
+        assert_eq!(map.device_path(1).unwrap(), Path::new("/dev/fake1"));
+        assert_eq!(
+            map.device_path(2).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_place_extents() {
+        let raid0 = Chunk::parse(
+            1 << 30,
+            &chunk_item(1 << 30, 1 | RAID0, 1, &[(1, 0), (2, 1 << 20)]),
+        )
+        .unwrap();
+        let map = ChunkMap::new(
+            vec![raid0],
+            HashMap::from([
+                (1, PathBuf::from("/dev/fake1")),
+                (2, PathBuf::from("/dev/fake2")),
+            ]),
+        );
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 1 << 30,
+                length: 131072,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 131072,
+                physical: 0,
+                length: 4096,
+                flags: ExtentFlags::DELALLOC,
+            },
+        ];
+
+        let placement = place(&map, &extents, |extent| !extent.flags.is_delalloc()).unwrap();
+        assert_eq!(
+            placement.devices,
+            vec![PathBuf::from("/dev/fake1"), PathBuf::from("/dev/fake2")]
+        );
+        let summary: Vec<_> = placement
+            .placed
+            .iter()
+            .map(|p| {
+                (
+                    p.device,
+                    p.extent.logical,
+                    p.extent.physical,
+                    p.extent.length,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(0), 0, 0, 65536),
+                (Some(1), 65536, 1 << 20, 65536),
+                (None, 131072, 0, 4096),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_btrfs() {
+        let file = tempfile::tempfile().unwrap();
+        let on_btrfs = sys::fs_type(file.as_raw_fd()).unwrap() == BTRFS_SUPER_MAGIC;
+        assert_eq!(is_btrfs(&file).unwrap(), on_btrfs);
+    }
+}
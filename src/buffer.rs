@@ -0,0 +1,549 @@
+//! Aligned buffers for Direct I/O.
+//!
+//! Reads that go to the block device use `O_DIRECT`, which fails with
+//! `EINVAL` unless the buffer address is suitably aligned. [`AlignedBuf`]
+//! provides such a buffer, and [`BufPool`] recycles them for reads that
+//! hand out owned buffers.
+
+use crate::sys;
+
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::io;
+use std::ops::{Deref, DerefMut, Range};
+use std::ptr::NonNull;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+/// A zero-initialized heap buffer with a guaranteed alignment.
+///
+/// Direct I/O requires the buffer address to be aligned to the device's
+/// logical block size. The buffer is freed with the same layout it was
+/// allocated with.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{AlignedBuf, BlkReader};
+/// use std::path::Path;
+///
+/// let mut buf = AlignedBuf::new(1 << 20, 4096);
+/// let bytes = Path::new("/path/to/file").blk_read_at(&mut buf, 0).unwrap();
+/// ```
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+    /// Size and alignment of the allocation, which may exceed `len`.
+    layout: Layout,
+    /// Whether the memory is mapped with `mmap` rather than allocated from
+    /// the heap.
+    mapped: bool,
+    /// Whether the mapping is backed by hugetlbfs pages.
+    hugetlb: bool,
+    /// NUMA node the memory was allocated on.
+    node: Option<usize>,
+}
+
+// SAFETY: `AlignedBuf` uniquely owns its allocation, like `Box<[u8]>`.
+unsafe impl Send for AlignedBuf {}
+// SAFETY: shared access only hands out `&[u8]`.
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    /// Size of the huge pages backing [`AlignedBuf::huge`] buffers, and
+    /// their alignment.
+    pub const HUGE_PAGE_SIZE: usize = sys::HUGE_PAGE_SIZE;
+
+    /// Allocate a zeroed buffer of `len` bytes aligned to `align`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two or the allocation fails.
+    pub fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len, align).expect("invalid buffer layout");
+        let ptr = if len == 0 {
+            // A dangling but well-aligned pointer is valid for zero-sized slices
+            NonNull::new(align as *mut u8).expect("alignment is non-zero")
+        } else {
+            // SAFETY: the layout has a non-zero size.
+            let ptr = unsafe { alloc::alloc_zeroed(layout) };
+            NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+        };
+        Self {
+            ptr,
+            len,
+            layout,
+            mapped: false,
+            hugetlb: false,
+            node: None,
+        }
+    }
+
+    /// Allocate a zeroed buffer of `len` bytes backed by 2 MiB huge pages,
+    /// aligned to [`HUGE_PAGE_SIZE`](AlignedBuf::HUGE_PAGE_SIZE).
+    ///
+    /// Large buffers spread over 4 KiB pages cost the kernel a page-table
+    /// walk and pin per page on every Direct I/O read, and the CPU a TLB
+    /// entry per page. The buffer is mapped from the hugetlbfs pool
+    /// (`MAP_HUGETLB`) if it has enough free pages, see
+    /// [`is_hugetlb`](AlignedBuf::is_hugetlb); otherwise it is allocated
+    /// from the heap with transparent huge pages requested
+    /// (`MADV_HUGEPAGE`), which the kernel may or may not provide. Either
+    /// way the memory is rounded up to whole huge pages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation fails.
+    pub fn huge(len: usize) -> Self {
+        if len == 0 {
+            return Self::new(0, Self::HUGE_PAGE_SIZE);
+        }
+        let size = align_up(len as u64, Self::HUGE_PAGE_SIZE as u64) as usize;
+        let layout =
+            Layout::from_size_align(size, Self::HUGE_PAGE_SIZE).expect("invalid buffer layout");
+        if let Ok(ptr) = sys::map_huge(size) {
+            return Self {
+                ptr,
+                len,
+                layout,
+                mapped: true,
+                hugetlb: true,
+                node: None,
+            };
+        }
+
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        // Advise before touching the memory, so the zeroing below faults it
+        // in as huge pages; kernels without THP ignore the advice
+        let _ = sys::madvise_hugepage(ptr, size);
+        // SAFETY: `ptr` is valid for writes of `size` bytes.
+        unsafe { ptr.as_ptr().write_bytes(0, size) };
+        Self {
+            ptr,
+            len,
+            layout,
+            mapped: false,
+            hugetlb: false,
+            node: None,
+        }
+    }
+
+    /// Allocate a zeroed buffer of `len` bytes aligned to `align` on NUMA
+    /// node `node`.
+    ///
+    /// On multi-socket hosts, memory on the node local to the device's
+    /// PCIe root spares Direct I/O reads the trip across the interconnect;
+    /// [`BlkReader::blk_numa_node`](crate::BlkReader::blk_numa_node) finds
+    /// that node. The buffer is mapped on its own and its pages allocated
+    /// on the node as long as it has free memory, then on others. The
+    /// memory is rounded up to whole pages.
+    ///
+    /// Fails with `EINVAL` if the node does not exist. Kernels without NUMA
+    /// support only accept node 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two.
+    pub fn on_node(len: usize, align: usize, node: usize) -> io::Result<Self> {
+        assert!(align.is_power_of_two(), "invalid buffer layout");
+        let page = sys::page_size();
+        let size = align_up(len.max(1) as u64, page as u64) as usize;
+        let layout = Layout::from_size_align(size, align.max(page))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let ptr = sys::map_anonymous(size, layout.align())?;
+        match sys::bind_preferred(ptr, size, node) {
+            // All memory is on node 0 without NUMA support
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) && node == 0 => {}
+            Err(e) => {
+                // SAFETY: the mapping was just made and is not referenced.
+                unsafe { sys::unmap(ptr, size) };
+                return Err(e);
+            }
+            Ok(()) => {}
+        }
+        // Fault the pages in on the node now rather than during the first read
+        // SAFETY: `ptr` is valid for writes of `size` bytes.
+        unsafe { ptr.as_ptr().write_bytes(0, size) };
+        Ok(Self {
+            ptr,
+            len,
+            layout,
+            mapped: true,
+            hugetlb: false,
+            node: Some(node),
+        })
+    }
+
+    /// Alignment of the buffer address, in bytes.
+    pub fn alignment(&self) -> usize {
+        self.layout.align()
+    }
+
+    /// Whether the buffer is mapped from the hugetlbfs pool, so backed by
+    /// huge pages for sure.
+    pub fn is_hugetlb(&self) -> bool {
+        self.hugetlb
+    }
+
+    /// NUMA node the buffer was allocated on with
+    /// [`on_node`](AlignedBuf::on_node).
+    pub fn numa_node(&self) -> Option<usize> {
+        self.node
+    }
+}
+
+impl fmt::Debug for AlignedBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlignedBuf")
+            .field("len", &self.len)
+            .field("alignment", &self.layout.align())
+            .field("hugetlb", &self.hugetlb)
+            .field("numa_node", &self.node)
+            .finish()
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for AlignedBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` is valid for `len` initialized bytes.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: `ptr` is valid for `len` initialized bytes and uniquely
+        // borrowed through `&mut self`.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        if self.mapped {
+            // SAFETY: `ptr` was mapped with exactly this size.
+            unsafe { sys::unmap(self.ptr, self.layout.size()) };
+        } else if self.layout.size() != 0 {
+            // SAFETY: `ptr` was allocated with exactly this layout.
+            unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+        }
+    }
+}
+
+/// A pool of aligned buffers for reads returning owned buffers.
+///
+/// [`BlkReader::blk_read_pooled`](crate::BlkReader::blk_read_pooled) takes
+/// a buffer from the pool, reads the aligned range covering the requested
+/// one into it, and returns a [`PooledBuf`] holding just the requested
+/// bytes. The buffer goes back to the pool when the `PooledBuf` is dropped,
+/// so steady-state reads allocate nothing. Cloning is cheap and shares the
+/// pool.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkReader, BufPool, Options};
+/// use std::path::Path;
+///
+/// let pool = BufPool::new();
+/// let (buf, state) = Path::new("/path/to/file")
+///     .blk_read_pooled(&pool, 100, 1000, &Options::new())
+///     .unwrap();
+/// assert_eq!(buf.len(), state.bytes_read);
+/// ```
+#[derive(Clone)]
+pub struct BufPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    idle: Mutex<Vec<AlignedBuf>>,
+    max_idle: usize,
+}
+
+impl BufPool {
+    /// Number of idle buffers kept by default.
+    pub const DEFAULT_MAX_IDLE: usize = 16;
+
+    /// Create an empty pool keeping up to
+    /// [`DEFAULT_MAX_IDLE`](BufPool::DEFAULT_MAX_IDLE) idle buffers.
+    pub fn new() -> Self {
+        Self::with_max_idle(Self::DEFAULT_MAX_IDLE)
+    }
+
+    /// Create an empty pool keeping up to `max_idle` idle buffers; buffers
+    /// returned beyond that are freed.
+    pub fn with_max_idle(max_idle: usize) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                idle: Mutex::new(Vec::new()),
+                max_idle,
+            }),
+        }
+    }
+
+    /// Number of idle buffers in the pool.
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    /// Take a buffer of at least `len` bytes aligned to `align`, on NUMA
+    /// node `node` if given, holding all of it until
+    /// [`PooledBuf::set_range`] narrows it.
+    ///
+    /// Reused buffers are not zeroed again.
+    pub(crate) fn take(
+        &self,
+        len: usize,
+        align: usize,
+        node: Option<usize>,
+    ) -> io::Result<PooledBuf> {
+        let reused = {
+            let mut idle = self.inner.idle.lock().unwrap();
+            idle.iter()
+                .position(|buf| {
+                    buf.len() >= len
+                        && buf.alignment() >= align
+                        && (node.is_none() || buf.numa_node() == node)
+                })
+                .map(|index| idle.swap_remove(index))
+        };
+        let buf = match (reused, node) {
+            (Some(buf), _) => buf,
+            (None, Some(node)) => AlignedBuf::on_node(len, align, node)?,
+            (None, None) => AlignedBuf::new(len, align),
+        };
+        Ok(PooledBuf {
+            range: 0..buf.len(),
+            buf: Some(buf),
+            pool: Arc::clone(&self.inner),
+        })
+    }
+}
+
+impl Default for BufPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for BufPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufPool")
+            .field("idle", &self.idle())
+            .field("max_idle", &self.inner.max_idle)
+            .finish()
+    }
+}
+
+/// Data read into a buffer of a [`BufPool`], returned to the pool on drop.
+///
+/// Dereferences to the bytes read. It is `Send`, `'static` and
+/// `AsRef<[u8]>`, so it can be handed to other tasks as is, or wrapped for
+/// libraries with their own buffer types without copying. With the `bytes`
+/// feature, it converts into `bytes::Bytes`, which returns the buffer to
+/// the pool when its last clone is dropped.
+pub struct PooledBuf {
+    buf: Option<AlignedBuf>,
+    range: Range<usize>,
+    pool: Arc<PoolInner>,
+}
+
+impl PooledBuf {
+    /// The whole underlying buffer.
+    pub(crate) fn full_mut(&mut self) -> &mut [u8] {
+        self.buf.as_mut().expect("buffer is held until drop")
+    }
+
+    /// Expose only `range` of the underlying buffer.
+    pub(crate) fn set_range(&mut self, range: Range<usize>) {
+        self.range = range;
+    }
+}
+
+impl fmt::Debug for PooledBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuf")
+            .field("len", &self.range.len())
+            .finish()
+    }
+}
+
+impl AsRef<[u8]> for PooledBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for PooledBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf.as_ref().expect("buffer is held until drop")[self.range.clone()]
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let range = self.range.clone();
+        &mut self.full_mut()[range]
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl From<PooledBuf> for bytes::Bytes {
+    fn from(buf: PooledBuf) -> Self {
+        bytes::Bytes::from_owner(buf)
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            let mut idle = self.pool.idle.lock().unwrap();
+            if idle.len() < self.pool.max_idle {
+                idle.push(buf);
+            }
+        }
+    }
+}
+
+/// Align `value` up to a multiple of `align`, which must be a power of two.
+pub(crate) fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aligned_buf() {
+        let mut buf = AlignedBuf::new(8192, 4096);
+        assert_eq!(buf.len(), 8192);
+        assert_eq!(buf.as_ptr() as usize % 4096, 0);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        buf[100] = 7;
+        assert_eq!(buf[100], 7);
+        assert_eq!(buf.alignment(), 4096);
+        assert_eq!(buf.as_ref().len(), 8192);
+    }
+
+    #[test]
+    fn test_aligned_buf_empty() {
+        let buf = AlignedBuf::new(0, 512);
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr() as usize % 512, 0);
+    }
+
+    #[test]
+    fn test_aligned_buf_huge() {
+        let len = AlignedBuf::HUGE_PAGE_SIZE + 4096;
+        let mut buf = AlignedBuf::huge(len);
+        assert_eq!(buf.len(), len);
+        assert_eq!(buf.alignment(), AlignedBuf::HUGE_PAGE_SIZE);
+        assert_eq!(buf.as_ptr() as usize % AlignedBuf::HUGE_PAGE_SIZE, 0);
+        assert!(buf.iter().all(|&b| b == 0));
+        buf[len - 1] = 7;
+        assert_eq!(buf[len - 1], 7);
+
+        assert!(AlignedBuf::huge(0).is_empty());
+    }
+
+    #[test]
+    fn test_aligned_buf_on_node() {
+        let mut buf = AlignedBuf::on_node(5000, 2 * sys::page_size(), 0).unwrap();
+        assert_eq!(buf.len(), 5000);
+        assert_eq!(buf.as_ptr() as usize % (2 * sys::page_size()), 0);
+        assert_eq!(buf.numa_node(), Some(0));
+        assert!(buf.iter().all(|&b| b == 0));
+        buf[4999] = 7;
+        assert_eq!(buf[4999], 7);
+        assert_eq!(AlignedBuf::new(512, 512).numa_node(), None);
+
+        // No host has this many nodes
+        assert!(AlignedBuf::on_node(4096, 4096, 1 << 20).is_err());
+
+        // Pools only reuse buffers on the requested node
+        let pool = BufPool::new();
+        drop(pool.take(4096, 512, None).unwrap());
+        let on_node = pool.take(4096, 512, Some(0)).unwrap();
+        assert_eq!(pool.idle(), 1);
+        assert_eq!(on_node.buf.as_ref().unwrap().numa_node(), Some(0));
+    }
+
+    #[test]
+    fn test_buf_pool() {
+        let pool = BufPool::with_max_idle(1);
+        let mut buf = pool.take(8192, 4096, None).unwrap();
+        assert_eq!(buf.len(), 8192);
+        buf.full_mut()[100] = 7;
+        buf.set_range(100..200);
+        assert_eq!((buf.len(), buf[0]), (100, 7));
+        let address = buf.full_mut().as_ptr();
+        drop(buf);
+        assert_eq!(pool.idle(), 1);
+
+        // Reused for requests it is large and aligned enough for
+        let mut reused = pool.take(4096, 512, None).unwrap();
+        assert_eq!(reused.full_mut().as_ptr(), address);
+        assert_eq!(pool.idle(), 0);
+        let other = pool.clone().take(4096, 8192, None).unwrap();
+        assert_eq!(other.as_ptr() as usize % 8192, 0);
+
+        // Only one idle buffer is kept
+        drop(reused);
+        drop(other);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_pooled_buf_into_bytes() {
+        let pool = BufPool::with_max_idle(1);
+        let mut buf = pool.take(8192, 4096, None).unwrap();
+        buf.full_mut()[100..200].fill(7);
+        buf.set_range(100..200);
+        let address = buf.as_ptr();
+
+        // The bytes are not copied, and the buffer is returned with the last clone
+        let bytes = bytes::Bytes::from(buf);
+        assert_eq!((bytes.len(), bytes.as_ptr()), (100, address));
+        assert!(bytes.iter().all(|&b| b == 7));
+        let clone = bytes.slice(10..20);
+        drop(bytes);
+        assert_eq!(pool.idle(), 0);
+        drop(clone);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn test_align_up() {
+        assert_eq!(align_up(0, 512), 0);
+        assert_eq!(align_up(1, 512), 512);
+        assert_eq!(align_up(4096, 4096), 4096);
+    }
+}
@@ -1,84 +1,691 @@
-//! Global block device cache.
+//! Block device cache.
 //!
-//! This module provides a global cache for block device file handles,
-//! keyed by the device ID (major:minor). This allows multiple reads
-//! from files on the same filesystem to share a single file handle
-//! to the underlying block device.
+//! This module provides caches for block device file handles, keyed by
+//! the device ID (major:minor). This allows multiple reads from files on
+//! the same filesystem to share a single file handle to the underlying
+//! block device. Handles opened with and without `O_DIRECT` (see
+//! [`Options::direct`](crate::Options::direct)) are cached separately.
+//!
+//! Reads share a global cache by default; a [`BlkCache`] of their own keeps
+//! the handles of a subsystem apart, with an independent lifetime.
+//!
+//! A cache is unbounded by default. [`configure_cache`] limits the number
+//! of handles it keeps open, evicting the least recently used ones, and can
+//! close handles that have been idle for a while. [`cache_stats`] reports
+//! how often the cache avoided opening a device.
+//!
+//! With [`Options::cache_extents`](crate::Options::cache_extents), a cache
+//! also keeps the extent maps of the files read, keyed by device and inode
+//! number and checked against the file's metadata before every use.
 
-use blkpath::ResolveDevice;
+use crate::backend::{query_device_info, BlockDeviceBackend, DeviceBackend};
+use crate::error::BlkReadError;
+use crate::extent_cache::{is_cacheable, FileVersion};
+use crate::state::DeviceInfo;
+
+use blkmap::FiemapExtent;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
+use std::hash::Hash;
 use std::io;
 use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
-use std::path::PathBuf;
-use std::sync::{Arc, LazyLock, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, LazyLock, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 /// A cached block device entry containing the path and file handle.
 #[derive(Debug)]
 pub struct CachedDevice {
     /// Path to the block device.
     pub path: PathBuf,
-    /// File handle opened with O_DIRECT for reading.
+    /// File handle opened for reading.
     pub file: File,
+    /// Sector sizes and size of the device, queried when it was opened.
+    pub info: Option<DeviceInfo>,
+    /// Whether the file was opened with O_DIRECT, bypassing the page cache.
+    pub direct: bool,
+    /// Byte offset in the file at which the device starts, see
+    /// [`DeviceBackend::base_offset`].
+    pub base_offset: u64,
+    /// Reads in flight on the handle, see
+    /// [`Options::max_in_flight`](crate::Options::max_in_flight).
+    pub(crate) in_flight: InFlight,
 }
 
 impl CachedDevice {
-    /// Create a new cached device entry.
-    fn new(path: PathBuf) -> io::Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .custom_flags(libc::O_DIRECT)
-            .open(&path)?;
-        Ok(Self { path, file })
+    /// Create a new cached device entry, opening the device through
+    /// `backend` with O_DIRECT if `direct` is set.
+    fn open(backend: &dyn DeviceBackend, path: PathBuf, direct: bool) -> io::Result<Self> {
+        match backend.open(&path, direct) {
+            Ok(file) => Ok(Self {
+                info: backend.info(&file),
+                path,
+                file,
+                direct,
+                base_offset: backend.base_offset(),
+                in_flight: InFlight::default(),
+            }),
+            Err(source) => Err(BlkReadError::device_open(path, source, false).into()),
+        }
+    }
+
+    /// Wrap an opened device, querying its geometry.
+    fn from_file(path: PathBuf, file: File, direct: bool) -> Self {
+        let info = query_device_info(&file);
+        Self {
+            path,
+            file,
+            info,
+            direct,
+            base_offset: 0,
+            in_flight: InFlight::default(),
+        }
+    }
+}
+
+/// Counter of the reads in flight on a device handle, shared by the
+/// threads reading through it.
+#[derive(Debug, Default)]
+pub(crate) struct InFlight {
+    reads: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl InFlight {
+    /// Wait until fewer than `max` reads are in flight, then count a new one
+    /// until the returned guard is dropped.
+    ///
+    /// Returns `None` if `deadline` passes first.
+    pub(crate) fn enter(&self, max: usize, deadline: Option<Instant>) -> Option<InFlightGuard<'_>> {
+        let mut reads = self.reads.lock().unwrap();
+        while *reads >= max.max(1) {
+            reads = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.checked_duration_since(Instant::now())?;
+                    self.finished.wait_timeout(reads, timeout).unwrap().0
+                }
+                None => self.finished.wait(reads).unwrap(),
+            };
+        }
+        *reads += 1;
+        Some(InFlightGuard(self))
+    }
+}
+
+/// A read counted by [`InFlight::enter`].
+#[derive(Debug)]
+pub(crate) struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.reads.lock().unwrap() -= 1;
+        // Waiters may be allowed different numbers of reads
+        self.0.finished.notify_all();
     }
 }
 
-/// Global cache for block device handles.
+/// Configuration of a block device cache.
+///
+/// # Example
+///
+/// ```
+/// use blkreader::{configure_cache, CacheConfig};
+/// use std::time::Duration;
 ///
-/// The cache is keyed by the device ID (from `stat.st_dev`), which
-/// uniquely identifies a filesystem. All files on the same filesystem
-/// share the same underlying block device.
-static DEVICE_CACHE: LazyLock<RwLock<HashMap<u64, Arc<CachedDevice>>>> =
-    LazyLock::new(|| RwLock::new(HashMap::new()));
+/// // Keep at most 16 device handles open, closing those unused for 5 minutes
+/// configure_cache(
+///     CacheConfig::new()
+///         .with_max_devices(16)
+///         .with_idle_timeout(Duration::from_secs(300)),
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Maximum number of device handles kept open, or `None` for no limit.
+    ///
+    /// Handles of devices holding files and handles of devices opened by
+    /// path (such as the members of a multi-device filesystem) are limited
+    /// separately. Once a new handle exceeds the limit, the least recently
+    /// used one is evicted; its file descriptor is closed as soon as no
+    /// read is using it. Defaults to `None`.
+    pub max_devices: Option<usize>,
+    /// How long a handle may go unused before it is evicted, or `None` to
+    /// keep it until the limit is reached.
+    ///
+    /// Idle handles are evicted when the cache is next accessed, or by
+    /// [`BlkCache::sweep`], which long-running processes that stop reading
+    /// can call periodically to release devices. Defaults to `None`.
+    pub idle_timeout: Option<Duration>,
+    /// Maximum number of extent maps kept, or `None` for no limit.
+    ///
+    /// Once a new map exceeds the limit, the least recently used one is
+    /// dropped. Defaults to `None`.
+    pub max_extent_maps: Option<usize>,
+}
+
+impl CacheConfig {
+    /// Create a configuration without limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-/// Get or create a cached block device entry for the given file.
+    /// Limit the number of device handles kept open.
+    pub fn with_max_devices(mut self, max_devices: usize) -> Self {
+        self.max_devices = Some(max_devices);
+        self
+    }
+
+    /// Evict device handles unused for longer than `idle_timeout`.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Limit the number of extent maps kept.
+    pub fn with_max_extent_maps(mut self, max_extent_maps: usize) -> Self {
+        self.max_extent_maps = Some(max_extent_maps);
+        self
+    }
+}
+
+/// A cache of block device handles.
 ///
-/// This function resolves the block device path from the file only if
-/// the device is not already cached. This avoids the expensive
-/// `resolve_device()` call on every read operation.
+/// Reads use the global cache ([`BlkCache::global`]) unless given a cache
+/// of their own with
+/// [`Options::with_cache_handle`](crate::Options::with_cache_handle).
+/// Every cache has its own configuration, statistics and handles, which are
+/// closed once the last clone of the cache is dropped and no read is using
+/// them. Clones share the same cache.
 ///
-/// # Arguments
+/// # Example
 ///
-/// * `file` - A reference to an open file
+/// ```
+/// use blkreader::{BlkCache, CacheConfig, Options};
 ///
-/// # Returns
+/// let cache = BlkCache::with_config(CacheConfig::new().with_max_devices(4));
+/// let options = Options::new().with_cache_handle(&cache);
+/// assert_eq!(cache.stats().hits, 0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct BlkCache {
+    inner: Arc<Caches>,
+}
+
+/// The state shared by the clones of a [`BlkCache`].
+#[derive(Debug)]
+struct Caches {
+    config: RwLock<CacheConfig>,
+    /// Handles keyed by the device ID (from `stat.st_dev`), which uniquely
+    /// identifies a filesystem, and whether the handle uses O_DIRECT. All
+    /// files on the same filesystem share the same underlying block device.
+    devices: Handles<(u64, bool)>,
+    /// Handles of devices opened by path, such as the member devices of a
+    /// multi-device btrfs filesystem.
+    paths: Handles<(PathBuf, bool)>,
+    /// Extent maps keyed by the device ID and inode number of their file.
+    extents: Mutex<HashMap<(u64, u64), ExtentEntry>>,
+}
+
+/// A cached extent map.
+#[derive(Debug)]
+struct ExtentEntry {
+    /// The version of the file the map is of.
+    version: FileVersion,
+    extents: Arc<[FiemapExtent]>,
+    /// Value of [`USES`] when the map was last returned.
+    last_used: u64,
+}
+
+/// The cache used by reads without a cache of their own.
+static GLOBAL: LazyLock<BlkCache> = LazyLock::new(BlkCache::new);
+
+impl BlkCache {
+    /// Create an empty cache without limits.
+    pub fn new() -> Self {
+        Self::with_config(CacheConfig::default())
+    }
+
+    /// Create an empty cache configured with `config`.
+    pub fn with_config(config: CacheConfig) -> Self {
+        Self {
+            inner: Arc::new(Caches {
+                config: RwLock::new(config),
+                devices: Handles::new(),
+                paths: Handles::new(),
+                extents: Mutex::default(),
+            }),
+        }
+    }
+
+    /// The global cache, shared by all reads without a cache of their own.
+    pub fn global() -> &'static BlkCache {
+        &GLOBAL
+    }
+
+    /// Configure the cache.
+    ///
+    /// Takes effect immediately: handles and extent maps beyond a new
+    /// limit, or handles idle for longer than a new timeout, are evicted.
+    pub fn configure(&self, config: CacheConfig) {
+        *self.inner.config.write().unwrap() = config;
+        self.sweep();
+        evict_extents(&mut self.inner.extents.lock().unwrap(), config);
+    }
+
+    /// The current configuration of the cache.
+    pub fn config(&self) -> CacheConfig {
+        *self.inner.config.read().unwrap()
+    }
+
+    /// Evict the handles that have been idle for longer than
+    /// [`CacheConfig::idle_timeout`].
+    ///
+    /// Idle handles are otherwise only evicted when the cache is accessed.
+    pub fn sweep(&self) {
+        let config = self.config();
+        self.inner.devices.evict(config, now());
+        self.inner.paths.evict(config, now());
+    }
+
+    /// Evict all handles and extent maps.
+    pub fn clear(&self) {
+        self.inner.devices.clear();
+        self.inner.paths.clear();
+        self.inner.extents.lock().unwrap().clear();
+    }
+
+    /// Drop the cached extent map of `file`, such as after changing its
+    /// layout in ways that leave its metadata alone.
+    pub fn invalidate_extents(&self, file: &File) -> io::Result<()> {
+        let metadata = file.metadata()?;
+        let key = (metadata.dev(), metadata.ino());
+        self.inner.extents.lock().unwrap().remove(&key);
+        Ok(())
+    }
+
+    /// The cached extent map of the file at `version`.
+    ///
+    /// A map of another version of the file is dropped.
+    pub(crate) fn get_extents(&self, version: &FileVersion) -> Option<Arc<[FiemapExtent]>> {
+        let key = (version.dev, version.ino);
+        let mut entries = self.inner.extents.lock().unwrap();
+        let entry = entries.get_mut(&key)?;
+        if entry.version != *version {
+            entries.remove(&key);
+            return None;
+        }
+        entry.last_used = USES.fetch_add(1, Ordering::Relaxed);
+        Some(Arc::clone(&entry.extents))
+    }
+
+    /// Cache `extents`, the extent map of the file at `version`, unless it
+    /// has delayed allocations.
+    pub(crate) fn insert_extents(&self, version: FileVersion, extents: Arc<[FiemapExtent]>) {
+        if !is_cacheable(&extents) {
+            return;
+        }
+        let mut entries = self.inner.extents.lock().unwrap();
+        entries.insert(
+            (version.dev, version.ino),
+            ExtentEntry {
+                version,
+                extents,
+                last_used: USES.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+        evict_extents(&mut entries, self.config());
+    }
+
+    /// A snapshot of the statistics of the cache.
+    ///
+    /// Lookups by file and by device path (such as for the members of a
+    /// multi-device filesystem) are counted together. Uncached opens, with
+    /// [`Options::enable_cache`](crate::Options::enable_cache) unset, are
+    /// not counted.
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        self.inner.devices.counters.add_to(&mut stats);
+        self.inner.paths.counters.add_to(&mut stats);
+        stats
+    }
+
+    /// Get or create a cached block device entry for the given file.
+    ///
+    /// This function resolves the block device path from the file only if
+    /// the device is not already cached. This avoids the expensive
+    /// `resolve_device()` call on every read operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - A reference to an open file
+    /// * `direct` - Whether to open the device with O_DIRECT
+    ///
+    /// # Returns
+    ///
+    /// An `Arc` to the cached device entry, or an error if the device
+    /// could not be resolved or opened.
+    pub(crate) fn get_or_create_device(
+        &self,
+        file: &File,
+        direct: bool,
+    ) -> io::Result<Arc<CachedDevice>> {
+        let dev_id = (file.metadata()?.dev(), direct);
+        let config = self.config();
+
+        // First, try to get from cache with a read lock
+        if let Some(device) = self.inner.devices.get(&dev_id, config, now()) {
+            return Ok(device);
+        }
+
+        // Not in cache, resolve device path before taking the write lock
+        let device_path = resolve_device(&BlockDeviceBackend, file)?;
+        self.inner.devices.get_or_open(dev_id, config, now(), || {
+            CachedDevice::open(&BlockDeviceBackend, device_path, direct)
+        })
+    }
+
+    /// Get or create a cached block device entry for the device at `path`,
+    /// opened with O_DIRECT if `direct` is set.
+    pub(crate) fn get_or_create_device_at(
+        &self,
+        path: &Path,
+        direct: bool,
+    ) -> io::Result<Arc<CachedDevice>> {
+        let key = (path.to_path_buf(), direct);
+        let config = self.config();
+        if let Some(device) = self.inner.paths.get(&key, config, now()) {
+            return Ok(device);
+        }
+        self.inner.paths.get_or_open(key, config, now(), || {
+            CachedDevice::open(&BlockDeviceBackend, path.to_path_buf(), direct)
+        })
+    }
+
+    /// Evict `device`, such as after reads from it failed because the
+    /// device went away, so that the next lookup opens it again.
+    ///
+    /// Reads still using the handle keep it open until they finish.
+    pub(crate) fn invalidate(&self, device: &Arc<CachedDevice>) {
+        if !self.inner.devices.remove(device) {
+            self.inner.paths.remove(device);
+        }
+    }
+}
+
+impl Default for BlkCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Two handles are equal if they share the same cache.
+impl PartialEq for BlkCache {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for BlkCache {}
+
+/// Configure the global block device cache; see [`BlkCache::configure`].
+pub fn configure_cache(config: CacheConfig) {
+    BlkCache::global().configure(config);
+}
+
+/// Evict the handles of the global block device cache that have been idle
+/// for longer than [`CacheConfig::idle_timeout`]; see [`BlkCache::sweep`].
+pub fn sweep_cache() {
+    BlkCache::global().sweep();
+}
+
+/// The current configuration of the global block device cache.
+pub fn cache_config() -> CacheConfig {
+    BlkCache::global().config()
+}
+
+/// Counts of the uses of a block device cache, since it was created.
+///
+/// # Example
 ///
-/// An `Arc` to the cached device entry, or an error if the device
-/// could not be resolved or opened.
-pub fn get_or_create_cached_device(file: &File) -> io::Result<Arc<CachedDevice>> {
-    let dev_id = file.metadata()?.dev();
+/// ```
+/// let stats = blkreader::cache_stats();
+/// println!("{} of {} lookups hit", stats.hits, stats.hits + stats.misses);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups that returned a cached handle.
+    pub hits: u64,
+    /// Lookups that found no cached handle and opened the device.
+    pub misses: u64,
+    /// Devices opened and cached on a miss.
+    pub opens: u64,
+    /// Handles evicted, beyond [`CacheConfig::max_devices`], after
+    /// [`CacheConfig::idle_timeout`] or because their device went away.
+    pub evictions: u64,
+    /// Misses on which the device could not be opened.
+    pub open_errors: u64,
+}
 
-    // First, try to get from cache with a read lock
-    {
-        let cache = DEVICE_CACHE.read().unwrap();
-        if let Some(entry) = cache.get(&dev_id) {
-            return Ok(Arc::clone(entry));
+/// A snapshot of the statistics of the global block device cache; see
+/// [`BlkCache::stats`].
+pub fn cache_stats() -> CacheStats {
+    BlkCache::global().stats()
+}
+
+/// Live counters behind [`CacheStats`].
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    opens: AtomicU64,
+    evictions: AtomicU64,
+    open_errors: AtomicU64,
+}
+
+impl Counters {
+    fn count(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Add the current counts to `stats`.
+    fn add_to(&self, stats: &mut CacheStats) {
+        stats.hits += self.hits.load(Ordering::Relaxed);
+        stats.misses += self.misses.load(Ordering::Relaxed);
+        stats.opens += self.opens.load(Ordering::Relaxed);
+        stats.evictions += self.evictions.load(Ordering::Relaxed);
+        stats.open_errors += self.open_errors.load(Ordering::Relaxed);
+    }
+}
+
+/// Counter ordering the uses of cached handles.
+static USES: AtomicU64 = AtomicU64::new(0);
+
+/// Instant the times of uses are measured from.
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Time since [`EPOCH`] in nanoseconds.
+fn now() -> u64 {
+    EPOCH.elapsed().as_nanos() as u64
+}
+
+/// A cached device handle.
+#[derive(Debug)]
+struct Entry {
+    device: Arc<CachedDevice>,
+    /// Value of [`USES`] when the handle was last returned.
+    last_used: AtomicU64,
+    /// Value of [`now`] when the handle was last returned.
+    used_at: AtomicU64,
+}
+
+impl Entry {
+    fn new(device: CachedDevice) -> Self {
+        Self {
+            device: Arc::new(device),
+            last_used: AtomicU64::new(0),
+            used_at: AtomicU64::new(0),
         }
     }
 
-    // Not in cache, resolve device path and acquire write lock
-    let device_path = file.resolve_device()?;
-    let mut cache = DEVICE_CACHE.write().unwrap();
+    /// Return the handle at time `now`, recording the use.
+    fn use_device(&self, now: u64) -> Arc<CachedDevice> {
+        self.last_used
+            .store(USES.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        self.used_at.store(now, Ordering::Relaxed);
+        Arc::clone(&self.device)
+    }
 
-    // Double-check in case another thread added it
-    if let Some(entry) = cache.get(&dev_id) {
-        return Ok(Arc::clone(entry));
+    /// Whether the handle has been unused for longer than `idle_timeout` at
+    /// time `now`.
+    fn is_idle(&self, idle_timeout: Option<Duration>, now: u64) -> bool {
+        idle_timeout.is_some_and(|timeout| {
+            let idle = now.saturating_sub(self.used_at.load(Ordering::Relaxed));
+            u128::from(idle) > timeout.as_nanos()
+        })
     }
+}
 
-    // Create new entry
-    let entry = Arc::new(CachedDevice::new(device_path)?);
-    cache.insert(dev_id, Arc::clone(&entry));
-    Ok(entry)
+/// Device handles by key, shared between readers.
+#[derive(Debug)]
+struct Handles<K> {
+    entries: RwLock<HashMap<K, Entry>>,
+    counters: Counters,
+}
+
+impl<K: Hash + Eq + Clone> Handles<K> {
+    fn new() -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Get the handle for `key` at time `now`, if cached and not idle under
+    /// `config`.
+    ///
+    /// An idle handle is left for [`Handles::get_or_open`] to evict. Only
+    /// hits are counted, as misses are followed by [`Handles::get_or_open`].
+    fn get(&self, key: &K, config: CacheConfig, now: u64) -> Option<Arc<CachedDevice>> {
+        let entries = self.entries.read().unwrap();
+        let entry = entries
+            .get(key)
+            .filter(|e| !e.is_idle(config.idle_timeout, now))?;
+        Counters::count(&self.counters.hits, 1);
+        Some(entry.use_device(now))
+    }
+
+    /// Get the handle for `key` at time `now`, opening and caching it with
+    /// `open` if it is not cached, and evicting the handles that `config`
+    /// does not allow to be kept.
+    fn get_or_open(
+        &self,
+        key: K,
+        config: CacheConfig,
+        now: u64,
+        open: impl FnOnce() -> io::Result<CachedDevice>,
+    ) -> io::Result<Arc<CachedDevice>> {
+        let counters = &self.counters;
+        let mut entries = self.entries.write().unwrap();
+        Counters::count(&counters.evictions, evict_from(&mut entries, config, now));
+        // Double-check in case another thread added it
+        if let Some(entry) = entries.get(&key) {
+            Counters::count(&counters.hits, 1);
+            return Ok(entry.use_device(now));
+        }
+        Counters::count(&counters.misses, 1);
+        let entry = match open() {
+            Ok(device) => Entry::new(device),
+            Err(e) => {
+                Counters::count(&counters.open_errors, 1);
+                return Err(e);
+            }
+        };
+        Counters::count(&counters.opens, 1);
+        let device = entry.use_device(now);
+        entries.insert(key, entry);
+        Counters::count(&counters.evictions, evict_from(&mut entries, config, now));
+        Ok(device)
+    }
+
+    /// Evict the handles that `config` does not allow to be kept at time
+    /// `now`.
+    fn evict(&self, config: CacheConfig, now: u64) {
+        let evicted = evict_from(&mut self.entries.write().unwrap(), config, now);
+        Counters::count(&self.counters.evictions, evicted);
+    }
+
+    /// Evict `device`, returning whether it was cached.
+    fn remove(&self, device: &Arc<CachedDevice>) -> bool {
+        let mut entries = self.entries.write().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| !Arc::ptr_eq(&entry.device, device));
+        let evicted = (before - entries.len()) as u64;
+        Counters::count(&self.counters.evictions, evicted);
+        evicted > 0
+    }
+
+    /// Evict all handles.
+    fn clear(&self) {
+        let mut entries = self.entries.write().unwrap();
+        Counters::count(&self.counters.evictions, entries.len() as u64);
+        entries.clear();
+    }
+}
+
+/// Remove the `entries` idle at time `now`, then the least recently used
+/// ones until at most [`CacheConfig::max_devices`] remain. Returns the
+/// number of entries removed.
+fn evict_from<K: Hash + Eq + Clone>(
+    entries: &mut HashMap<K, Entry>,
+    config: CacheConfig,
+    now: u64,
+) -> u64 {
+    let before = entries.len();
+    entries.retain(|_, entry| !entry.is_idle(config.idle_timeout, now));
+    let max_devices = config.max_devices.unwrap_or(usize::MAX);
+    while entries.len() > max_devices {
+        let Some(oldest) = entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+        entries.remove(&oldest);
+    }
+    (before - entries.len()) as u64
+}
+
+/// Drop the least recently used extent maps until at most
+/// [`CacheConfig::max_extent_maps`] remain.
+fn evict_extents(entries: &mut HashMap<(u64, u64), ExtentEntry>, config: CacheConfig) {
+    let max_extent_maps = config.max_extent_maps.unwrap_or(usize::MAX);
+    while entries.len() > max_extent_maps {
+        let Some(oldest) = entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| *key)
+        else {
+            break;
+        };
+        entries.remove(&oldest);
+    }
+}
+
+/// Drop the cached extent map of `file` from the global cache; see
+/// [`BlkCache::invalidate_extents`].
+pub fn invalidate_extents(file: &File) -> io::Result<()> {
+    BlkCache::global().invalidate_extents(file)
+}
+
+/// Open the block device at `path` through `backend` without caching.
+pub fn open_device_uncached_at(
+    backend: &dyn DeviceBackend,
+    path: &Path,
+    direct: bool,
+) -> io::Result<CachedDevice> {
+    CachedDevice::open(backend, path.to_path_buf(), direct)
 }
 
 /// Open a block device without caching.
@@ -87,15 +694,45 @@ pub fn get_or_create_cached_device(file: &File) -> io::Result<Arc<CachedDevice>>
 ///
 /// # Arguments
 ///
+/// * `backend` - The backend resolving and opening the device
 /// * `file` - A reference to an open file
+/// * `direct` - Whether to open the device with O_DIRECT
 ///
 /// # Returns
 ///
 /// A `CachedDevice` entry (not actually cached), or an error if
 /// the device could not be resolved or opened.
-pub fn open_device_uncached(file: &File) -> io::Result<CachedDevice> {
-    let device_path = file.resolve_device()?;
-    CachedDevice::new(device_path)
+pub fn open_device_uncached(
+    backend: &dyn DeviceBackend,
+    file: &File,
+    direct: bool,
+) -> io::Result<CachedDevice> {
+    let device_path = resolve_device(backend, file)?;
+    CachedDevice::open(backend, device_path, direct)
+}
+
+/// Open a block device for writing, bypassing the cache.
+///
+/// Write handles are never cached, so that read-only users of the cache
+/// can't accidentally obtain one.
+pub fn open_device_writable(file: &File) -> io::Result<CachedDevice> {
+    let path = resolve_device(&BlockDeviceBackend, file)?;
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(&path)
+    {
+        Ok(file) => Ok(CachedDevice::from_file(path, file, true)),
+        Err(source) => Err(BlkReadError::device_open(path, source, true).into()),
+    }
+}
+
+/// Resolve the block device holding `file` through `backend`.
+pub(crate) fn resolve_device(backend: &dyn DeviceBackend, file: &File) -> io::Result<PathBuf> {
+    backend
+        .resolve(file)
+        .map_err(|source| BlkReadError::DeviceResolve { source }.into())
 }
 
 /// Clear the global device cache.
@@ -103,8 +740,7 @@ pub fn open_device_uncached(file: &File) -> io::Result<CachedDevice> {
 /// This is mainly useful for testing.
 #[cfg(test)]
 pub fn clear_cache() {
-    let mut cache = DEVICE_CACHE.write().unwrap();
-    cache.clear();
+    BlkCache::global().clear();
 }
 
 #[cfg(test)]
@@ -116,4 +752,241 @@ mod tests {
         // Just test that the cache can be cleared without panicking
         clear_cache();
     }
+
+    #[test]
+    fn test_lru_eviction() {
+        let handles = Handles::new();
+        let none = CacheConfig::new();
+        let open = || {
+            Ok(CachedDevice::from_file(
+                PathBuf::from("/dev/fake"),
+                tempfile::tempfile()?,
+                true,
+            ))
+        };
+        let limit = CacheConfig::new().with_max_devices(2);
+
+        let first = handles.get_or_open(1, none, 0, open).unwrap();
+        handles.get_or_open(2, none, 0, open).unwrap();
+        // Using the first handle makes the second the least recently used
+        assert!(Arc::ptr_eq(&handles.get(&1, none, 0).unwrap(), &first));
+        handles.get_or_open(3, none, 0, open).unwrap();
+        handles.evict(limit, 0);
+        assert!(handles.get(&1, none, 0).is_some());
+        assert!(handles.get(&2, none, 0).is_none());
+        assert!(handles.get(&3, none, 0).is_some());
+
+        // Handles are evicted by identity, not by key
+        let third = handles.get(&3, none, 0).unwrap();
+        assert!(handles.remove(&third));
+        assert!(!handles.remove(&third));
+        assert!(handles.get(&3, none, 0).is_none());
+        let reopened = handles.get_or_open(3, none, 0, open).unwrap();
+        assert!(!Arc::ptr_eq(&reopened, &third));
+
+        // Evicted handles are closed once no longer used
+        handles.evict(CacheConfig::new().with_max_devices(0), 0);
+        assert!(handles.get(&1, none, 0).is_none());
+        assert_eq!(Arc::strong_count(&first), 1);
+
+        // Failed opens are not cached
+        let failed =
+            handles.get_or_open(4, none, 0, || Err(io::Error::from(io::ErrorKind::NotFound)));
+        assert!(failed.is_err());
+        assert!(handles.get(&4, none, 0).is_none());
+    }
+
+    #[test]
+    fn test_idle_eviction() {
+        let handles = Handles::new();
+        let none = CacheConfig::new();
+        let open = || {
+            Ok(CachedDevice::from_file(
+                PathBuf::from("/dev/fake"),
+                tempfile::tempfile()?,
+                true,
+            ))
+        };
+        let secs = |n: u64| Duration::from_secs(n).as_nanos() as u64;
+        let config = CacheConfig::new().with_idle_timeout(Duration::from_secs(60));
+
+        let first = handles.get_or_open(1, none, secs(0), open).unwrap();
+        handles.get_or_open(2, none, secs(30), open).unwrap();
+        // Handles are kept until the timeout has passed since their last use
+        handles.evict(config, secs(60));
+        assert!(handles.get(&1, none, secs(60)).is_some());
+        // Idle handles are missed on lookup before they are evicted
+        assert!(handles.get(&2, config, secs(100)).is_none());
+        handles.evict(config, secs(100));
+        assert!(handles.get(&1, none, secs(100)).is_some());
+        assert!(handles.get(&2, none, secs(100)).is_none());
+
+        handles.evict(config, secs(200));
+        assert!(handles.get(&1, none, secs(200)).is_none());
+        assert_eq!(Arc::strong_count(&first), 1);
+
+        // Without a timeout, handles are never idle
+        let entry = Entry::new(open().unwrap());
+        assert!(!entry.is_idle(None, u64::MAX));
+        assert!(entry.is_idle(config.idle_timeout, secs(61)));
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let handles = Handles::new();
+        let none = CacheConfig::new();
+        let open = || {
+            Ok(CachedDevice::from_file(
+                PathBuf::from("/dev/fake"),
+                tempfile::tempfile()?,
+                true,
+            ))
+        };
+        let stats = || {
+            let mut stats = CacheStats::default();
+            handles.counters.add_to(&mut stats);
+            stats
+        };
+
+        handles.get_or_open(1, none, 0, open).unwrap();
+        assert!(handles.get(&1, none, 0).is_some());
+        assert!(handles.get(&2, none, 0).is_none());
+        // Found by the double-check after a concurrent open
+        handles.get_or_open(1, none, 0, open).unwrap();
+        let failed =
+            handles.get_or_open(2, none, 0, || Err(io::Error::from(io::ErrorKind::NotFound)));
+        assert!(failed.is_err());
+        handles.evict(CacheConfig::new().with_max_devices(0), 0);
+        assert_eq!(
+            stats(),
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                opens: 1,
+                evictions: 1,
+                open_errors: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_instance_caches() {
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let one = BlkCache::new();
+        let other = BlkCache::with_config(CacheConfig::new().with_max_devices(1));
+
+        // Caches don't share handles, statistics or configuration
+        let device = one.get_or_create_device_at(temp.path(), false).unwrap();
+        let again = one.clone().get_or_create_device_at(temp.path(), false);
+        assert!(Arc::ptr_eq(&device, &again.unwrap()));
+        let separate = other.get_or_create_device_at(temp.path(), false);
+        assert!(!Arc::ptr_eq(&device, &separate.unwrap()));
+        assert_eq!((one.stats().hits, one.stats().opens), (1, 1));
+        assert_eq!((other.stats().hits, other.stats().opens), (0, 1));
+        assert_eq!(one.config(), CacheConfig::new());
+        assert_eq!(one, one.clone());
+        assert_ne!(one, other);
+
+        // Handles are closed with the last clone of their cache
+        drop(one);
+        assert_eq!(Arc::strong_count(&device), 1);
+        other.clear();
+        assert_eq!(other.stats().evictions, 1);
+    }
+
+    #[test]
+    fn test_default_cache_config() {
+        assert_eq!(CacheConfig::new().max_devices, None);
+        assert_eq!(CacheConfig::new().idle_timeout, None);
+        assert_eq!(CacheConfig::new().max_extent_maps, None);
+        assert_eq!(CacheConfig::new().with_max_devices(8).max_devices, Some(8));
+        assert_eq!(
+            CacheConfig::new().with_max_extent_maps(64).max_extent_maps,
+            Some(64)
+        );
+        assert_eq!(
+            CacheConfig::new()
+                .with_idle_timeout(Duration::from_secs(300))
+                .idle_timeout,
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn test_extent_maps() {
+        use blkmap::ExtentFlags;
+
+        let cache = BlkCache::with_config(CacheConfig::new().with_max_extent_maps(2));
+        let files: Vec<File> = (0..3).map(|_| tempfile::tempfile().unwrap()).collect();
+        let versions: Vec<FileVersion> = files
+            .iter()
+            .map(|file| FileVersion::of(file).unwrap())
+            .collect();
+        let extents: Arc<[FiemapExtent]> = Arc::new([FiemapExtent {
+            logical: 0,
+            physical: 4096,
+            length: 4096,
+            flags: ExtentFlags::LAST,
+        }]);
+
+        cache.insert_extents(versions[0], extents.clone());
+        assert_eq!(cache.get_extents(&versions[0]), Some(extents.clone()));
+        // Other versions of the file miss, and drop the map
+        let changed = FileVersion {
+            size: 1,
+            ..versions[0]
+        };
+        assert_eq!(cache.get_extents(&changed), None);
+        assert_eq!(cache.get_extents(&versions[0]), None);
+
+        // The least recently used map is dropped beyond the limit
+        for version in &versions[..2] {
+            cache.insert_extents(*version, extents.clone());
+        }
+        assert!(cache.get_extents(&versions[0]).is_some());
+        cache.insert_extents(versions[2], extents.clone());
+        assert!(cache.get_extents(&versions[0]).is_some());
+        assert!(cache.get_extents(&versions[1]).is_none());
+        assert!(cache.get_extents(&versions[2]).is_some());
+
+        // Explicit invalidation, and maps with delayed allocations
+        cache.invalidate_extents(&files[2]).unwrap();
+        assert!(cache.get_extents(&versions[2]).is_none());
+        let delalloc: Arc<[FiemapExtent]> = Arc::new([FiemapExtent {
+            flags: ExtentFlags::DELALLOC | ExtentFlags::UNKNOWN,
+            ..extents[0]
+        }]);
+        cache.insert_extents(versions[1], delalloc);
+        assert!(cache.get_extents(&versions[1]).is_none());
+
+        cache.configure(CacheConfig::new().with_max_extent_maps(0));
+        assert!(cache.get_extents(&versions[0]).is_none());
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let in_flight = InFlight::default();
+        let first = in_flight.enter(2, None).unwrap();
+        let _second = in_flight.enter(2, None).unwrap();
+        // A full device makes new reads wait until their deadline
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert!(in_flight.enter(2, Some(deadline)).is_none());
+        // Callers allowing more reads are not held up
+        assert!(in_flight.enter(3, Some(deadline)).is_some());
+
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| in_flight.enter(2, None).is_some());
+            std::thread::sleep(Duration::from_millis(10));
+            drop(first);
+            assert!(waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_device_info_of_regular_file() {
+        // Block device ioctls fail on regular files
+        let file = tempfile::tempfile().unwrap();
+        let device = CachedDevice::from_file(PathBuf::from("/dev/fake"), file, true);
+        assert!(device.info.is_none());
+    }
 }
@@ -0,0 +1,304 @@
+//! Runtime capability report.
+//!
+//! This module probes what the current kernel, filesystem, and block
+//! device support, so that hosts can be checked before scheduling
+//! recovery work on them.
+
+use crate::sys;
+
+use blkmap::Fiemap;
+use blkpath::ResolveDevice;
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Whether a capability is available, with the reason when it is not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Support {
+    /// The capability is available.
+    Supported,
+    /// The capability is not available, for the given reason.
+    Unsupported(String),
+}
+
+impl Support {
+    /// Returns true if the capability is available.
+    pub fn is_supported(&self) -> bool {
+        matches!(self, Support::Supported)
+    }
+
+    fn from_result<T>(result: io::Result<T>) -> Self {
+        match result {
+            Ok(_) => Support::Supported,
+            Err(e) => Support::Unsupported(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Support {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Support::Supported => write!(f, "yes"),
+            Support::Unsupported(reason) => write!(f, "no ({})", reason),
+        }
+    }
+}
+
+/// `CAP_DAC_OVERRIDE`, bypassing file permission checks.
+const CAP_DAC_OVERRIDE: u32 = 1;
+
+/// `CAP_DAC_READ_SEARCH`, bypassing file read permission checks.
+const CAP_DAC_READ_SEARCH: u32 = 2;
+
+/// The access of the process to a block device it was denied opening.
+///
+/// Probed when opening a device fails with `EACCES` or `EPERM`, and kept in
+/// [`BlkReadError::DeviceOpen`](crate::BlkReadError::DeviceOpen), whose
+/// message then tells what is missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceAccess {
+    /// Whether the device was opened for writing.
+    pub write: bool,
+
+    /// Whether the process has a capability bypassing the device node's
+    /// permissions: `CAP_DAC_READ_SEARCH` or `CAP_DAC_OVERRIDE` to read,
+    /// `CAP_DAC_OVERRIDE` to write. `None` if the capabilities are unknown.
+    pub capability: Option<bool>,
+
+    /// Whether the device node's permissions grant the access to the
+    /// process without capabilities.
+    pub permitted: bool,
+
+    /// Group owning the device node, such as `disk`, if the node's
+    /// permissions grant it the access and the process is not a member.
+    pub group: Option<String>,
+}
+
+impl DeviceAccess {
+    /// Probe the access of the process to the device node at `path`.
+    pub(crate) fn probe(path: &Path, write: bool) -> Self {
+        let (user_bit, group_bit, other_bit) = match write {
+            true => (libc::S_IWUSR, libc::S_IWGRP, libc::S_IWOTH),
+            false => (libc::S_IRUSR, libc::S_IRGRP, libc::S_IROTH),
+        };
+        let capability = sys::effective_capabilities().ok().map(|caps| {
+            let has = |cap: u32| caps & (1 << cap) != 0;
+            has(CAP_DAC_OVERRIDE) || (!write && has(CAP_DAC_READ_SEARCH))
+        });
+
+        let Ok(metadata) = fs::metadata(path) else {
+            return Self {
+                write,
+                capability,
+                permitted: false,
+                group: None,
+            };
+        };
+        let mode = metadata.mode();
+        let member = sys::group_ids().is_ok_and(|ids| ids.contains(&metadata.gid()));
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        let permitted = if unsafe { libc::geteuid() } == metadata.uid() {
+            mode & user_bit != 0
+        } else if member {
+            mode & group_bit != 0
+        } else {
+            mode & other_bit != 0
+        };
+        let group = (!member && mode & group_bit != 0).then(|| group_name(metadata.gid()));
+        Self {
+            write,
+            capability,
+            permitted,
+            group,
+        }
+    }
+
+    /// Whether the process lacks both the capability and the permissions
+    /// for the access, as opposed to being denied by a policy such as a
+    /// device cgroup or a security module.
+    pub fn is_missing(&self) -> bool {
+        self.capability != Some(true) && !self.permitted
+    }
+}
+
+/// Name of group `gid` in `/etc/group`, or its number.
+fn group_name(gid: u32) -> String {
+    let groups = fs::read_to_string("/etc/group").unwrap_or_default();
+    groups
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            (fields.nth(1)?.parse() == Ok(gid)).then(|| name.to_string())
+        })
+        .unwrap_or_else(|| gid.to_string())
+}
+
+/// Report of the capabilities available for reading a given path.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// Path the capabilities were probed for.
+    pub path: PathBuf,
+
+    /// Whether the filesystem supports the `FIEMAP` ioctl.
+    pub fiemap: Support,
+
+    /// Whether the filesystem accepts `FIEMAP_FLAG_SYNC`.
+    pub fiemap_sync: Support,
+
+    /// Whether the underlying block device could be resolved.
+    pub device: Support,
+
+    /// Path of the underlying block device, if resolved.
+    pub device_path: Option<PathBuf>,
+
+    /// Whether the block device can be opened with `O_DIRECT`.
+    pub direct_io: Support,
+
+    /// Direct I/O alignment of the block device as `(memory, offset)`,
+    /// if reported via `STATX_DIOALIGN`.
+    pub dio_alignment: Option<(u32, u32)>,
+
+    /// Whether `io_uring` can be set up by this process.
+    pub io_uring: Support,
+
+    /// Effective user ID of the process.
+    pub effective_uid: u32,
+
+    /// Whether the effective user may read the block device node.
+    pub device_readable: bool,
+}
+
+impl Capabilities {
+    /// Whether direct block device reads are expected to work.
+    pub fn can_read_device(&self) -> bool {
+        self.fiemap.is_supported() && self.direct_io.is_supported()
+    }
+}
+
+/// Probe the capabilities available for reading the given path.
+///
+/// Only the initial open of `path` can fail; every other probe is
+/// recorded in the returned report.
+///
+/// # Example
+///
+/// ```no_run
+/// let caps = blkreader::capabilities("/path/to/file").unwrap();
+/// println!("FIEMAP: {}", caps.fiemap);
+/// println!("O_DIRECT: {}", caps.direct_io);
+/// ```
+pub fn capabilities<P: AsRef<Path>>(path: P) -> io::Result<Capabilities> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+
+    let fiemap = Support::from_result(file.fiemap_range(0, 1));
+    let fiemap_sync =
+        Support::from_result(sys::fiemap_count(file.as_raw_fd(), sys::FIEMAP_FLAG_SYNC));
+
+    let (device, device_path) = match file.resolve_device() {
+        Ok(device_path) => (Support::Supported, Some(device_path)),
+        Err(e) => (Support::Unsupported(e.to_string()), None),
+    };
+
+    let (direct_io, dio_alignment, device_readable) = match &device_path {
+        Some(device_path) => (
+            Support::from_result(
+                OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_DIRECT)
+                    .open(device_path),
+            ),
+            sys::statx_dio_align(device_path).ok().flatten(),
+            sys::can_read(device_path),
+        ),
+        None => (
+            Support::Unsupported("block device not resolved".to_string()),
+            None,
+            false,
+        ),
+    };
+
+    let io_uring = if sys::io_uring_available() {
+        Support::Supported
+    } else {
+        Support::Unsupported("io_uring_setup failed".to_string())
+    };
+
+    Ok(Capabilities {
+        path: path.to_path_buf(),
+        fiemap,
+        fiemap_sync,
+        device,
+        device_path,
+        direct_io,
+        dio_alignment,
+        io_uring,
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        effective_uid: unsafe { libc::geteuid() },
+        device_readable,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_support_display() {
+        assert_eq!(Support::Supported.to_string(), "yes");
+        assert_eq!(
+            Support::Unsupported("nope".to_string()).to_string(),
+            "no (nope)"
+        );
+        assert!(!Support::from_result::<()>(Err(io::Error::other("x"))).is_supported());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let caps = capabilities(temp.path()).unwrap();
+        assert_eq!(caps.path, temp.path());
+        assert_eq!(caps.device.is_supported(), caps.device_path.is_some());
+        if caps.device_path.is_none() {
+            assert!(!caps.direct_io.is_supported());
+            assert!(!caps.device_readable);
+        }
+    }
+
+    #[test]
+    fn test_capabilities_missing_path() {
+        assert!(capabilities("/nonexistent/path").is_err());
+    }
+
+    #[test]
+    fn test_device_access_probe() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mode = |mode| {
+            fs::set_permissions(file.path(), fs::Permissions::from_mode(mode)).unwrap();
+        };
+        mode(0o600);
+        let access = DeviceAccess::probe(file.path(), false);
+        assert!(access.permitted);
+        assert!(!access.is_missing());
+        assert_eq!(access.group, None);
+
+        // The owner's bits apply even when the group's would grant it
+        mode(0o260);
+        assert!(!DeviceAccess::probe(file.path(), false).permitted);
+        assert!(DeviceAccess::probe(file.path(), true).permitted);
+
+        let access = DeviceAccess::probe(Path::new("/nonexistent"), false);
+        assert!(!access.permitted);
+        assert_eq!(
+            access.capability.is_some(),
+            sys::effective_capabilities().is_ok()
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Digests of the data returned by reads (`checksum` feature).
+//!
+//! [`Checksum`] names the algorithms reads can compute digests of their
+//! data with, see [`Options::with_checksum`](crate::Options::with_checksum).
+//! The digests themselves are computed by `crc32fast`, `xxhash-rust` and
+//! `sha2`.
+
+use sha2::Digest as _;
+use std::fmt;
+
+/// Algorithm of a digest of the data returned by a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Checksum {
+    /// CRC-32 (IEEE 802.3), as computed by zlib and `cksum -a crc32b`.
+    Crc32,
+    /// 64-bit xxHash with seed 0, as computed by `xxhsum -H64`.
+    XxHash64,
+    /// SHA-256, as computed by `sha256sum`.
+    Sha256,
+}
+
+impl Checksum {
+    /// The digest of `data`.
+    pub fn digest(self, data: &[u8]) -> Digest {
+        let mut hasher = Hasher::new(self);
+        hasher.update(data);
+        hasher.finish()
+    }
+}
+
+/// Parts of a read digested on their own, besides the whole read.
+///
+/// See [`Options::with_checksum_unit`](crate::Options::with_checksum_unit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChecksumUnit {
+    /// The part of each extent within the read; holes between extents are
+    /// not digested.
+    Extent,
+    /// Blocks of this many bytes at multiples of the size in the file, so
+    /// that reads at different offsets digest the same blocks; the first
+    /// and last blocks are cut to the read. A size of 0 digests no blocks.
+    Block(u64),
+}
+
+/// The digest of a part of a read, see [`ChecksumUnit`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnitChecksum {
+    /// Logical file offset of the part.
+    pub logical: u64,
+    /// Length of the part in bytes.
+    pub length: u64,
+    /// The digest of the part's data.
+    pub digest: Digest,
+}
+
+/// A digest of data, in the byte order its algorithm's tools print it.
+///
+/// Formats as lowercase hexadecimal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Digest {
+    /// The algorithm computing the digest.
+    pub algorithm: Checksum,
+    /// The digest: 4 bytes for CRC-32, 8 for xxHash64, 32 for SHA-256.
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.bytes {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Incremental digest of one of the [`Checksum`] algorithms.
+#[derive(Clone)]
+pub(crate) enum Hasher {
+    Crc32(crc32fast::Hasher),
+    XxHash64(xxhash_rust::xxh64::Xxh64),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: Checksum) -> Self {
+        match algorithm {
+            Checksum::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            Checksum::XxHash64 => Hasher::XxHash64(xxhash_rust::xxh64::Xxh64::new(0)),
+            Checksum::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    /// Add `data` to the digest.
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(crc) => crc.update(data),
+            Hasher::XxHash64(xxh) => xxh.update(data),
+            Hasher::Sha256(sha) => sha.update(data),
+        }
+    }
+
+    /// The digest of the data added so far.
+    pub(crate) fn finish(self) -> Digest {
+        let (algorithm, bytes) = match self {
+            Hasher::Crc32(crc) => (Checksum::Crc32, crc.finalize().to_be_bytes().to_vec()),
+            Hasher::XxHash64(xxh) => (Checksum::XxHash64, xxh.digest().to_be_bytes().to_vec()),
+            Hasher::Sha256(sha) => (Checksum::Sha256, sha.finalize().to_vec()),
+        };
+        Digest { algorithm, bytes }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_digests() {
+        let digest = |algorithm: Checksum, data: &[u8]| algorithm.digest(data).to_string();
+        assert_eq!(digest(Checksum::Crc32, b""), "00000000");
+        assert_eq!(digest(Checksum::Crc32, b"123456789"), "cbf43926");
+        assert_eq!(digest(Checksum::XxHash64, b""), "ef46db3751d8e999");
+        assert_eq!(
+            digest(
+                Checksum::XxHash64,
+                b"The quick brown fox jumps over the lazy dog"
+            ),
+            "0b242d361fda71bc"
+        );
+        assert_eq!(
+            digest(Checksum::Sha256, b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_split_updates() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        for algorithm in [Checksum::Crc32, Checksum::XxHash64, Checksum::Sha256] {
+            let mut split = Hasher::new(algorithm);
+            for chunk in data.chunks(13) {
+                split.update(chunk);
+            }
+            assert_eq!(split.finish(), algorithm.digest(&data));
+        }
+    }
+
+    #[test]
+    fn test_digest() {
+        let digest = Checksum::Crc32.digest(b"123456789");
+        assert_eq!(digest.algorithm, Checksum::Crc32);
+        assert_eq!(digest.bytes, [0xCB, 0xF4, 0x39, 0x26]);
+        assert_eq!(digest.to_string(), "cbf43926");
+        assert_eq!(
+            Checksum::XxHash64.digest(b"abc").to_string(),
+            "44bc2cf5ad770999"
+        );
+    }
+}
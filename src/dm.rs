@@ -0,0 +1,651 @@
+//! Translation of device-mapper targets to the underlying devices.
+//!
+//! LVM volumes are device-mapper devices whose tables map consecutive
+//! ranges of the volume onto ranges of other block devices: linear targets
+//! for plain volumes, striped targets for striped ones and mirror or raid1
+//! targets for mirrored ones. With
+//! [`Options::translate_dm`](crate::Options::translate_dm), device reads
+//! are sent to those devices instead, e.g. to read a failing drive
+//! directly rather than through the volume.
+
+use crate::error::BlkReadError;
+use crate::lvm;
+use crate::map::Placement;
+use crate::sys;
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Size of `struct dm_target_spec`, which precedes each target's parameters.
+const TARGET_SPEC_SIZE: usize = 40;
+
+/// Maximum number of stacked device-mapper devices followed.
+pub(crate) const MAX_DEPTH: usize = 16;
+
+/// Prefix of the device-mapper uuid of dm-crypt mappings.
+const CRYPT_UUID_PREFIX: &str = "CRYPT-";
+
+/// Block device number (major, minor).
+pub(crate) type DevNum = (u32, u32);
+
+/// The table of a device-mapper device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Table {
+    /// Whether the device is an LVM logical volume.
+    lvm: bool,
+    targets: Vec<Target>,
+}
+
+/// A target of a device-mapper table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Target {
+    /// Byte offset of the start of the target on the mapped device.
+    start: u64,
+    length: u64,
+    /// Target type, such as `linear` or `crypt`.
+    kind: String,
+    params: String,
+}
+
+impl Target {
+    /// Where the target maps `length` bytes at byte `offset` from its start.
+    ///
+    /// Returns the lower device, the byte offset on it and how many of the
+    /// bytes continue there, or `None` if the target type is not
+    /// translated. Mirrors are read from their first leg.
+    fn lower(&self, offset: u64, length: u64) -> Option<(DevNum, u64, u64)> {
+        let params: Vec<&str> = self.params.split_whitespace().collect();
+        let sectors = |at: usize| Some(params.get(at)?.parse::<u64>().ok()? * 512);
+        let device = |at: usize| parse_dev(params.get(at)?);
+        match self.kind.as_str() {
+            // <dev> <offset>
+            "linear" => Some((device(0)?, sectors(1)? + offset, length)),
+            // <dev>, read at the same offset
+            "snapshot-origin" => Some((device(0)?, offset, length)),
+            // <#stripes> <chunk size> <dev> <offset>...
+            "striped" => {
+                let stripes: u64 = params.first()?.parse().ok()?;
+                let chunk = sectors(1)?;
+                if stripes == 0 || chunk == 0 {
+                    return None;
+                }
+                let index = offset / chunk;
+                let stripe = (index % stripes) as usize;
+                let within = offset % chunk;
+                Some((
+                    device(2 + 2 * stripe)?,
+                    sectors(3 + 2 * stripe)? + index / stripes * chunk + within,
+                    length.min(chunk - within),
+                ))
+            }
+            // <log type> <#log args> <log args>... <#devs> <dev> <offset>...
+            "mirror" => {
+                let log_args: usize = params.get(1)?.parse().ok()?;
+                let leg = 3 + log_args;
+                Some((device(leg)?, sectors(leg + 1)? + offset, length))
+            }
+            // raid1 <#params> <chunk size> [<key> <value>]... <#devs>
+            // <metadata dev> <data dev>...
+            "raid" if params.first() == Some(&"raid1") => {
+                let count: usize = params.get(1)?.parse().ok()?;
+                let args = params.get(2..2 + count)?;
+                let data_offset = match args.iter().position(|&arg| arg == "data_offset") {
+                    Some(at) => args.get(at + 1)?.parse::<u64>().ok()? * 512,
+                    None => 0,
+                };
+                // Legs that failed are listed as "-"
+                let data = params
+                    .get(3 + count..)?
+                    .chunks(2)
+                    .find_map(|pair| parse_dev(pair.get(1)?))?;
+                Some((data, data_offset + offset, length))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parse a `major:minor` device number.
+fn parse_dev(device: &str) -> Option<DevNum> {
+    let (major, minor) = device.split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Parse the result area of a `DM_TABLE_STATUS` query with `count` targets.
+fn parse_table(count: u32, data: &[u8]) -> io::Result<Vec<Target>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "truncated device-mapper table");
+    let mut targets = Vec::with_capacity(count as usize);
+    let mut pos = 0;
+    for _ in 0..count {
+        let spec = data.get(pos..pos + TARGET_SPEC_SIZE).ok_or_else(invalid)?;
+        let field = |at: usize| u64::from_ne_bytes(spec[at..at + 8].try_into().unwrap());
+        let next = u32::from_ne_bytes(spec[20..24].try_into().unwrap()) as usize;
+        let name = |bytes: &[u8]| {
+            let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..len]).into_owned()
+        };
+        targets.push(Target {
+            start: field(0) * 512,
+            length: field(8) * 512,
+            kind: name(&spec[24..40]),
+            params: name(data.get(pos + TARGET_SPEC_SIZE..).ok_or_else(invalid)?),
+        });
+        // `next` is relative to the start of the result area
+        if next <= pos {
+            break;
+        }
+        pos = next;
+    }
+    Ok(targets)
+}
+
+/// Append the ranges of the devices below `dev` holding `length` bytes at
+/// `physical`, following translated targets.
+///
+/// `table` returns the table of a device, or `None` if it is not a
+/// device-mapper device. Ranges of other targets stay on `dev`, except on
+/// LVM volumes, whose other layouts are rejected: their devices hold data
+/// the volume does not expose as is, such as RAID parity or thin pool
+/// blocks, and reading the volume itself is not what was asked for.
+fn map_range(
+    dev: DevNum,
+    physical: u64,
+    length: u64,
+    table: &mut impl FnMut(DevNum) -> io::Result<Option<Table>>,
+    device_path: &impl Fn(DevNum) -> io::Result<PathBuf>,
+    depth: usize,
+    out: &mut Vec<(DevNum, u64, u64)>,
+) -> io::Result<()> {
+    let mapped = match table(dev)? {
+        Some(mapped) if depth < MAX_DEPTH => mapped,
+        _ => {
+            push_range(out, dev, physical, length);
+            return Ok(());
+        }
+    };
+
+    let end = physical + length;
+    let mut current = physical;
+    while current < end {
+        let target = mapped
+            .targets
+            .iter()
+            .find(|target| target.start <= current && current < target.start + target.length)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "offset {:#x} of device {}:{} is not mapped by its table",
+                        current, dev.0, dev.1
+                    ),
+                )
+            })?;
+        let stop = (target.start + target.length).min(end);
+        match target.lower(current - target.start, stop - current) {
+            Some((lower, offset, len)) => {
+                map_range(lower, offset, len, table, device_path, depth + 1, out)?;
+                current += len;
+            }
+            None if mapped.lvm => {
+                return Err(BlkReadError::UnsupportedLvmLayout {
+                    device_path: device_path(dev)?,
+                    target: target.kind.clone(),
+                }
+                .into());
+            }
+            None => {
+                push_range(out, dev, current, stop - current);
+                current = stop;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Append a device range, merging it into the previous one if it continues it.
+fn push_range(out: &mut Vec<(DevNum, u64, u64)>, dev: DevNum, physical: u64, length: u64) {
+    match out.last_mut() {
+        Some((last, start, len)) if *last == dev && *start + *len == physical => *len += length,
+        _ => out.push((dev, physical, length)),
+    }
+}
+
+/// Get the table of device `dev`, or `None` if it is not a device-mapper
+/// device.
+fn read_table(dev: DevNum) -> io::Result<Option<Table>> {
+    let dm = format!("/sys/dev/block/{}:{}/dm", dev.0, dev.1);
+    let name = match std::fs::read_to_string(format!("{}/name", dm)) {
+        Ok(name) => name,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let uuid = std::fs::read_to_string(format!("{}/uuid", dm)).unwrap_or_default();
+    Ok(Some(Table {
+        lvm: uuid.starts_with(lvm::UUID_PREFIX),
+        targets: targets(name.trim_end())?,
+    }))
+}
+
+/// Get the targets of the table of the device-mapper device `name`.
+fn targets(name: &str) -> io::Result<Vec<Target>> {
+    let (count, data) = sys::dm_table(name)?;
+    parse_table(count, &data)
+}
+
+/// Target types of the table of the device-mapper device `name`, in order.
+pub(crate) fn target_kinds(name: &str) -> io::Result<Vec<String>> {
+    let mut kinds: Vec<String> = Vec::new();
+    for target in targets(name)? {
+        if !kinds.contains(&target.kind) {
+            kinds.push(target.kind);
+        }
+    }
+    Ok(kinds)
+}
+
+/// Device number of the block device at `path`.
+pub(crate) fn dev_num(path: &Path) -> io::Result<DevNum> {
+    let rdev = std::fs::metadata(path)?.rdev();
+    Ok((libc::major(rdev), libc::minor(rdev)))
+}
+
+/// Kernel name of block device `dev`, such as `dm-0`, found through sysfs.
+pub(crate) fn device_name(dev: DevNum) -> io::Result<OsString> {
+    let target = std::fs::read_link(format!("/sys/dev/block/{}:{}", dev.0, dev.1))?;
+    match target.file_name() {
+        Some(name) => Ok(name.to_os_string()),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no device node for {}:{}", dev.0, dev.1),
+        )),
+    }
+}
+
+/// Kernel name of the block device at `path`, such as `dm-0`.
+pub(crate) fn block_name(path: &Path) -> io::Result<OsString> {
+    device_name(dev_num(path)?)
+}
+
+/// Path of block device `dev`, found through sysfs.
+fn device_path(dev: DevNum) -> io::Result<PathBuf> {
+    Ok(Path::new("/dev").join(device_name(dev)?))
+}
+
+/// The dm-crypt mapping that decrypts the block device at `path`, if any.
+///
+/// Data read from such a device is ciphertext.
+pub(crate) fn crypt_mapping(path: &Path) -> Option<PathBuf> {
+    let name = block_name(path).ok()?;
+    let holder = crypt_holder_in(Path::new("/sys"), &name.to_string_lossy())?;
+    Some(Path::new("/dev").join(holder))
+}
+
+/// The dm-crypt mapping among the holders of block device `name` in the
+/// sysfs tree at `sys`.
+fn crypt_holder_in(sys: &Path, name: &str) -> Option<String> {
+    // Unlike /sys/block, /sys/class/block also lists partitions
+    let block = sys.join("class/block");
+    std::fs::read_dir(block.join(name).join("holders"))
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .find(|holder| {
+            std::fs::read_to_string(block.join(holder).join("dm/uuid"))
+                .is_ok_and(|uuid| uuid.starts_with(CRYPT_UUID_PREFIX))
+        })
+}
+
+/// Translate the pieces of `placement` on device-mapper devices down
+/// through their targets.
+///
+/// Returns `None` if no piece is on a translated device-mapper target.
+pub(crate) fn place(placement: &Placement) -> io::Result<Option<Placement>> {
+    let mut tables: HashMap<DevNum, Option<Table>> = HashMap::new();
+    let mut table = |dev: DevNum| match tables.get(&dev) {
+        Some(table) => Ok(table.clone()),
+        None => {
+            let table = read_table(dev)?;
+            tables.insert(dev, table.clone());
+            Ok(table)
+        }
+    };
+    place_with(placement, dev_num, &mut table, device_path)
+}
+
+/// [`place`] with the device lookups supplied by the caller.
+fn place_with(
+    placement: &Placement,
+    dev_num: impl Fn(&Path) -> io::Result<DevNum>,
+    table: &mut impl FnMut(DevNum) -> io::Result<Option<Table>>,
+    device_path: impl Fn(DevNum) -> io::Result<PathBuf>,
+) -> io::Result<Option<Placement>> {
+    let mut translated = Placement::default();
+    let mut changed = false;
+    for placed in &placement.placed {
+        let Some(path) = placement.path(placed) else {
+            translated.push(None, placed.extent);
+            continue;
+        };
+
+        let dev = dev_num(path)?;
+        let mut ranges = Vec::new();
+        map_range(
+            dev,
+            placed.extent.physical,
+            placed.extent.length,
+            table,
+            &device_path,
+            0,
+            &mut ranges,
+        )?;
+        let mut logical = placed.extent.logical;
+        for (lower, physical, length) in ranges {
+            let lower_path = if lower == dev {
+                path.to_path_buf()
+            } else {
+                changed = true;
+                device_path(lower)?
+            };
+            let piece = blkmap::FiemapExtent {
+                logical,
+                physical,
+                length,
+                ..placed.extent
+            };
+            translated.push(Some(&lower_path), piece);
+            logical += length;
+        }
+    }
+    Ok(changed.then_some(translated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blkmap::{ExtentFlags, FiemapExtent};
+
+    fn target(start: u64, length: u64, kind: &str, params: &str) -> Target {
+        Target {
+            start: start * 512,
+            length: length * 512,
+            kind: kind.to_string(),
+            params: params.to_string(),
+        }
+    }
+
+    fn table(lvm: bool, targets: Vec<Target>) -> Table {
+        Table { lvm, targets }
+    }
+
+    fn extent(logical: u64, physical: u64, length: u64) -> FiemapExtent {
+        FiemapExtent {
+            logical,
+            physical,
+            length,
+            flags: ExtentFlags::empty(),
+        }
+    }
+
+    /// Translate one extent on `/dev/dm-0` (253:0) through `tables`.
+    fn place_one(
+        tables: &HashMap<DevNum, Table>,
+        physical: u64,
+        length: u64,
+    ) -> io::Result<Vec<(String, u64, u64)>> {
+        let mut placement = Placement::default();
+        placement.push(Some(Path::new("/dev/dm-0")), extent(0, physical, length));
+        let translated = place_with(
+            &placement,
+            |_: &Path| Ok((253, 0)),
+            &mut |dev: DevNum| Ok(tables.get(&dev).cloned()),
+            |dev: DevNum| Ok(PathBuf::from(format!("/dev/{}:{}", dev.0, dev.1))),
+        )?
+        .unwrap_or(placement);
+        Ok(translated
+            .placed
+            .iter()
+            .map(|p| {
+                let path = translated.path(p).unwrap().to_str().unwrap();
+                (path.to_string(), p.extent.physical, p.extent.length)
+            })
+            .collect())
+    }
+
+    #[test]
+    fn test_parse_table() {
+        // Two targets as laid out by the kernel, 8-byte aligned
+        let mut data = Vec::new();
+        for (start, length, kind, params) in [
+            (0u64, 2048u64, "linear", "8:16 2048"),
+            (2048, 4096, "crypt", "aes-xts-plain64 - 0 8:32 4096"),
+        ] {
+            let spec_start = data.len();
+            data.extend_from_slice(&start.to_ne_bytes());
+            data.extend_from_slice(&length.to_ne_bytes());
+            data.extend_from_slice(&0i32.to_ne_bytes());
+            data.extend_from_slice(&0u32.to_ne_bytes());
+            let mut name = [0u8; 16];
+            name[..kind.len()].copy_from_slice(kind.as_bytes());
+            data.extend_from_slice(&name);
+            data.extend_from_slice(params.as_bytes());
+            data.push(0);
+            while data.len() % 8 != 0 {
+                data.push(0);
+            }
+            let next = data.len() as u32;
+            data[spec_start + 20..spec_start + 24].copy_from_slice(&next.to_ne_bytes());
+        }
+
+        let targets = parse_table(2, &data).unwrap();
+        assert_eq!(
+            targets,
+            vec![
+                target(0, 2048, "linear", "8:16 2048"),
+                target(2048, 4096, "crypt", "aes-xts-plain64 - 0 8:32 4096"),
+            ]
+        );
+        assert_eq!(
+            targets[0].lower(512, 4096),
+            Some(((8, 16), 2049 * 512, 4096))
+        );
+        assert_eq!(targets[1].lower(0, 4096), None);
+
+        assert!(parse_table(3, &data).is_err());
+    }
+
+    #[test]
+    fn test_place_through_linear_targets() {
+        // 253:0 concatenates two disks, followed by a linear target onto
+        // 253:1, a crypt device that is not translated further
+        let tables = HashMap::from([
+            (
+                (253, 0),
+                table(
+                    true,
+                    vec![
+                        target(0, 8, "linear", "8:16 2048"),
+                        target(8, 8, "linear", "8:32 0"),
+                        target(16, 8, "linear", "253:1 16"),
+                    ],
+                ),
+            ),
+            (
+                (253, 1),
+                table(false, vec![target(0, 64, "crypt", "aes - 0 8:48 0")]),
+            ),
+        ]);
+        let mut table = |dev: DevNum| Ok(tables.get(&dev).cloned());
+        let dev_num = |path: &Path| match path.to_str().unwrap() {
+            "/dev/dm-0" => Ok((253, 0)),
+            _ => Ok((8, 64)),
+        };
+        let device_path =
+            |dev: DevNum| Ok(PathBuf::from(format!("/dev/block/{}:{}", dev.0, dev.1)));
+
+        let mut placement = Placement::default();
+        placement.push(Some(Path::new("/dev/dm-0")), extent(0, 2048, 10240));
+        placement.push(None, extent(10240, 0, 2048));
+
+        let translated = place_with(&placement, dev_num, &mut table, device_path)
+            .unwrap()
+            .unwrap();
+        let summary: Vec<_> = translated
+            .placed
+            .iter()
+            .map(|p| {
+                (
+                    translated.path(p).map(Path::to_path_buf),
+                    p.extent.logical,
+                    p.extent.physical,
+                    p.extent.length,
+                )
+            })
+            .collect();
+        let path = |s: &str| Some(PathBuf::from(s));
+        assert_eq!(
+            summary,
+            vec![
+                (path("/dev/block/8:16"), 0, 2048 * 512 + 2048, 2048),
+                (path("/dev/block/8:32"), 2048, 0, 4096),
+                (path("/dev/block/253:1"), 6144, 16 * 512, 4096),
+                (None, 10240, 0, 2048),
+            ]
+        );
+
+        // Devices that are not device-mapper devices are left alone
+        let mut plain = Placement::default();
+        plain.push(Some(Path::new("/dev/sda")), extent(0, 0, 4096));
+        assert!(place_with(&plain, dev_num, &mut table, device_path)
+            .unwrap()
+            .is_none());
+
+        // Ranges beyond the table are rejected
+        let mut beyond = Placement::default();
+        beyond.push(Some(Path::new("/dev/dm-0")), extent(0, 24 * 512, 512));
+        assert!(place_with(&beyond, dev_num, &mut table, device_path).is_err());
+    }
+
+    #[test]
+    fn test_place_through_striped_targets() {
+        // Two stripes of 8 KiB chunks, the second starting 1 MiB into 8:32
+        let tables = HashMap::from([(
+            (253, 0),
+            table(
+                true,
+                vec![target(0, 64, "striped", "2 16 8:16 0 8:32 2048")],
+            ),
+        )]);
+        let chunk = 8192;
+        assert_eq!(
+            place_one(&tables, 4096, 3 * chunk).unwrap(),
+            vec![
+                ("/dev/8:16".into(), 4096, 4096),
+                ("/dev/8:32".into(), 1 << 20, chunk),
+                ("/dev/8:16".into(), chunk, chunk),
+                ("/dev/8:32".into(), (1 << 20) + chunk, 4096),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_place_through_mirrored_targets() {
+        // A legacy mirror with a disk log, and a raid1 volume whose first
+        // leg failed, both over linear images
+        let tables = HashMap::from([
+            (
+                (253, 0),
+                table(
+                    true,
+                    vec![
+                        target(0, 8, "mirror", "disk 2 253:1 1024 2 253:2 0 253:3 0"),
+                        target(8, 8, "raid", "raid1 3 0 data_offset 2048 2 - - 253:5 253:6"),
+                    ],
+                ),
+            ),
+            (
+                (253, 2),
+                table(true, vec![target(0, 8, "linear", "8:16 64")]),
+            ),
+            (
+                (253, 6),
+                table(true, vec![target(0, 2056, "linear", "8:32 0")]),
+            ),
+        ]);
+        assert_eq!(
+            place_one(&tables, 512, 7 * 512).unwrap(),
+            vec![("/dev/8:16".into(), 65 * 512, 7 * 512)]
+        );
+        assert_eq!(
+            place_one(&tables, 2048, 4096).unwrap(),
+            vec![
+                ("/dev/8:16".into(), 68 * 512, 2048),
+                ("/dev/8:32".into(), 2048 * 512, 2048)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_lvm_layout() {
+        let tables = HashMap::from([(
+            (253, 0),
+            table(
+                true,
+                vec![
+                    target(0, 8, "linear", "8:16 0"),
+                    target(8, 8, "raid", "raid5_ls 1 128 3 - 8:16 - 8:32 - 8:48"),
+                ],
+            ),
+        )]);
+        assert!(place_one(&tables, 0, 4096).is_ok());
+        let err = place_one(&tables, 0, 8192).unwrap_err();
+        match BlkReadError::from_io_error(&err) {
+            Some(BlkReadError::UnsupportedLvmLayout {
+                device_path,
+                target,
+            }) => {
+                assert_eq!(device_path, Path::new("/dev/253:0"));
+                assert_eq!(target, "raid");
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        // The same target outside LVM is read through the device itself
+        let tables = HashMap::from([((253, 0), table(false, tables[&(253, 0)].targets.clone()))]);
+        assert_eq!(
+            place_one(&tables, 0, 8192).unwrap(),
+            vec![
+                ("/dev/8:16".into(), 0, 4096),
+                ("/dev/dm-0".into(), 4096, 4096)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_crypt_holder_in_sysfs() {
+        // sda2 is a LUKS volume opened as dm-1; sda1 holds an LVM volume
+        let sys = tempfile::tempdir().unwrap();
+        let block = sys.path().join("class/block");
+        let device = |name: &str, uuid: Option<&str>, holders: &[&str]| {
+            let dir = block.join(name);
+            std::fs::create_dir_all(dir.join("holders")).unwrap();
+            for holder in holders {
+                std::fs::create_dir(dir.join("holders").join(holder)).unwrap();
+            }
+            if let Some(uuid) = uuid {
+                std::fs::create_dir(dir.join("dm")).unwrap();
+                std::fs::write(dir.join("dm/uuid"), uuid).unwrap();
+            }
+        };
+        device("sda1", None, &["dm-0"]);
+        device("sda2", None, &["dm-1"]);
+        device("dm-0", Some("LVM-abc\n"), &[]);
+        device("dm-1", Some("CRYPT-LUKS2-abc-luks\n"), &[]);
+
+        assert_eq!(crypt_holder_in(sys.path(), "sda2").as_deref(), Some("dm-1"));
+        assert_eq!(crypt_holder_in(sys.path(), "sda1"), None);
+        assert_eq!(crypt_holder_in(sys.path(), "dm-1"), None);
+        assert_eq!(crypt_holder_in(sys.path(), "sdb"), None);
+    }
+}
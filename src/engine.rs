@@ -0,0 +1,308 @@
+//! Pluggable backends for device reads.
+//!
+//! The reader plans which device ranges to read from the extent map, then
+//! hands them to an [`IoEngine`] as one batch. Engines only decide how the
+//! reads are submitted to the kernel, so new backends can be added without
+//! touching the extent walk.
+
+use crate::sys;
+
+use std::fmt;
+use std::io::{self, IoSliceMut};
+use std::ops::{BitOr, BitOrAssign};
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+/// Per-read flags passed to `preadv2` (`RWF_*`).
+///
+/// Engines that have no way to pass flags ignore them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReadFlags(i32);
+
+impl ReadFlags {
+    /// Poll for completion instead of waiting for an interrupt
+    /// (`RWF_HIPRI`). Only effective for Direct I/O on devices with
+    /// polled queues, such as NVMe.
+    pub const HIPRI: ReadFlags = ReadFlags(libc::RWF_HIPRI);
+
+    /// Fail with [`ErrorKind::WouldBlock`](io::ErrorKind::WouldBlock)
+    /// instead of blocking (`RWF_NOWAIT`), e.g. when buffered data is not
+    /// in the page cache.
+    pub const NOWAIT: ReadFlags = ReadFlags(libc::RWF_NOWAIT);
+
+    /// No flags.
+    pub const fn empty() -> Self {
+        ReadFlags(0)
+    }
+
+    /// The raw `RWF_*` bits.
+    pub const fn bits(self) -> i32 {
+        self.0
+    }
+
+    /// Whether no flags are set.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether all flags in `other` are set.
+    pub const fn contains(self, other: ReadFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for ReadFlags {
+    type Output = ReadFlags;
+
+    fn bitor(self, rhs: ReadFlags) -> ReadFlags {
+        ReadFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for ReadFlags {
+    fn bitor_assign(&mut self, rhs: ReadFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// A device read: buffers filled from consecutive device locations.
+#[derive(Debug)]
+pub struct DeviceRead<'a> {
+    /// Physical byte offset on the device of the first buffer.
+    pub offset: u64,
+
+    /// Buffers to fill, in device order.
+    pub bufs: Vec<IoSliceMut<'a>>,
+
+    /// Flags for the read.
+    pub flags: ReadFlags,
+}
+
+impl DeviceRead<'_> {
+    /// Total number of bytes requested.
+    pub fn len(&self) -> usize {
+        self.bufs.iter().map(|buf| buf.len()).sum()
+    }
+
+    /// Whether the read requests no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Callback receiving the index and result of each completed read.
+///
+/// The result is the number of bytes read, which may be short at the end of
+/// the device. Returning an error aborts the batch.
+pub type Completion<'c> = dyn FnMut(usize, io::Result<usize>) -> io::Result<()> + 'c;
+
+/// A backend that submits batches of device reads.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkReader, Options, UringEngine};
+/// use std::path::Path;
+///
+/// let options = Options::new().with_io_engine(UringEngine);
+/// let mut buf = vec![0u8; 1 << 20];
+/// let state = Path::new("/path/to/file").blk_read_at_opt(&mut buf, 0, &options).unwrap();
+/// ```
+pub trait IoEngine: fmt::Debug + Send + Sync {
+    /// Short name of the engine, such as `"psync"`.
+    fn name(&self) -> &str;
+
+    /// Perform all `reads` on the device `fd`.
+    ///
+    /// Reads may be performed in any order and concurrently; `complete` is
+    /// called once for every finished read, in completion order. Once
+    /// `complete` returns an error, no new reads are started, reads in
+    /// flight are waited for, and the error is returned.
+    ///
+    /// The buffers may hold uninitialized memory, as for reads through
+    /// [`blk_read_buf_at`](crate::blk_read_buf_at), so engines must only
+    /// write to them.
+    fn read_batch(
+        &self,
+        fd: BorrowedFd<'_>,
+        reads: &mut [DeviceRead<'_>],
+        complete: &mut Completion<'_>,
+    ) -> io::Result<()>;
+}
+
+/// One blocking `pread` per buffer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PsyncEngine;
+
+impl IoEngine for PsyncEngine {
+    fn name(&self) -> &str {
+        "psync"
+    }
+
+    fn read_batch(
+        &self,
+        fd: BorrowedFd<'_>,
+        reads: &mut [DeviceRead<'_>],
+        complete: &mut Completion<'_>,
+    ) -> io::Result<()> {
+        for (index, read) in reads.iter_mut().enumerate() {
+            let mut result = Ok(0);
+            let mut offset = read.offset;
+            for buf in read.bufs.iter_mut() {
+                match sys::pread(fd.as_raw_fd(), buf, offset, read.flags.bits()) {
+                    Ok(n) => {
+                        result = result.map(|total| total + n);
+                        offset += n as u64;
+                        if n < buf.len() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            complete(index, result)?;
+        }
+        Ok(())
+    }
+}
+
+/// One blocking `preadv` per read (the default).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PreadvEngine;
+
+impl IoEngine for PreadvEngine {
+    fn name(&self) -> &str {
+        "preadv"
+    }
+
+    fn read_batch(
+        &self,
+        fd: BorrowedFd<'_>,
+        reads: &mut [DeviceRead<'_>],
+        complete: &mut Completion<'_>,
+    ) -> io::Result<()> {
+        for (index, read) in reads.iter_mut().enumerate() {
+            complete(
+                index,
+                sys::preadv(
+                    fd.as_raw_fd(),
+                    &mut read.bufs,
+                    read.offset,
+                    read.flags.bits(),
+                ),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// All reads of a batch submitted at once through `io_uring`.
+///
+/// Single-buffer reads use buffers registered with the ring for the
+/// duration of the batch. Requires the `uring` feature; without it, reads
+/// fail with [`ErrorKind::Unsupported`](io::ErrorKind::Unsupported).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UringEngine;
+
+impl IoEngine for UringEngine {
+    fn name(&self) -> &str {
+        "io_uring"
+    }
+
+    #[cfg(feature = "uring")]
+    fn read_batch(
+        &self,
+        fd: BorrowedFd<'_>,
+        reads: &mut [DeviceRead<'_>],
+        complete: &mut Completion<'_>,
+    ) -> io::Result<()> {
+        crate::uring::read_batch(fd.as_raw_fd(), reads, complete)
+    }
+
+    #[cfg(not(feature = "uring"))]
+    fn read_batch(
+        &self,
+        _fd: BorrowedFd<'_>,
+        _reads: &mut [DeviceRead<'_>],
+        _complete: &mut Completion<'_>,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the io_uring engine requires the `uring` feature",
+        ))
+    }
+}
+
+/// All reads of a batch submitted at once through Linux native AIO.
+///
+/// Native AIO is only asynchronous for `O_DIRECT` files, which device
+/// reads always are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LibaioEngine;
+
+/// Number of reads submitted to the AIO context at once.
+const AIO_DEPTH: usize = 64;
+
+impl IoEngine for LibaioEngine {
+    fn name(&self) -> &str {
+        "libaio"
+    }
+
+    fn read_batch(
+        &self,
+        fd: BorrowedFd<'_>,
+        reads: &mut [DeviceRead<'_>],
+        complete: &mut Completion<'_>,
+    ) -> io::Result<()> {
+        let ctx = sys::AioContext::new(AIO_DEPTH.min(reads.len()).max(1))?;
+        let mut next = 0;
+        let mut in_flight = 0;
+        let mut failure = None;
+
+        while (next < reads.len() && failure.is_none()) || in_flight > 0 {
+            if failure.is_none() {
+                let count = (reads.len() - next).min(AIO_DEPTH - in_flight);
+                let iocbs: Vec<sys::Iocb> = reads[next..next + count]
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(k, read)| {
+                        sys::Iocb::preadv(
+                            fd.as_raw_fd(),
+                            &mut read.bufs,
+                            read.offset,
+                            read.flags.bits(),
+                            next + k,
+                        )
+                    })
+                    .collect();
+                // SAFETY: the buffers outlive `ctx`, whose drop waits for
+                // any reads still in flight.
+                match unsafe { ctx.submit(&iocbs) } {
+                    Ok(submitted) => {
+                        next += submitted;
+                        in_flight += submitted;
+                    }
+                    Err(e) => failure = Some(e),
+                }
+            }
+            if in_flight == 0 {
+                break;
+            }
+
+            for event in ctx.get_events(1, in_flight)? {
+                in_flight -= 1;
+                if failure.is_some() {
+                    continue;
+                }
+                if let Err(e) = complete(event.index(), event.result()) {
+                    failure = Some(e);
+                }
+            }
+        }
+
+        failure.map_or(Ok(()), Err)
+    }
+}
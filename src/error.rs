@@ -0,0 +1,1025 @@
+//! Error types for blkreader operations.
+//!
+//! All public APIs return [`std::io::Error`]. When a failure can be
+//! attributed to a specific stage of the read, the `io::Error` wraps a
+//! [`BlkReadError`] naming that stage, which can be recovered with
+//! [`BlkReadError::from_io_error`]. The wrapping `io::Error` keeps the kind
+//! of the underlying error, but not its OS error code: its
+//! [`raw_os_error`](io::Error::raw_os_error) is `None`, and the
+//! `raw_os_error` of the recovered [`BlkReadError`], [`DeviceReadError`] or
+//! [`PartialReadError`] returns the code, e.g. `EIO` of a failed device
+//! read.
+
+use crate::capabilities::DeviceAccess;
+use crate::state::State;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The stage of a read that failed, with its context.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkReadError, BlkReader, Options};
+/// use std::path::Path;
+///
+/// let mut buf = vec![0u8; 4096];
+/// if let Err(e) = Path::new("/path/to/file").blk_read_at_opt(&mut buf, 0, &Options::new()) {
+///     match BlkReadError::from_io_error(&e) {
+///         Some(BlkReadError::FiemapFailed { .. }) => eprintln!("no extent map: {}", e),
+///         Some(BlkReadError::DeviceRead(read)) => eprintln!("bad read at {:#x}", read.physical_offset),
+///         _ => eprintln!("{}", e),
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BlkReadError {
+    /// Querying the extent map of the file with FIEMAP failed.
+    ///
+    /// Fails with `EOPNOTSUPP` on filesystems without FIEMAP support.
+    FiemapFailed {
+        /// Path of the file, if known.
+        file_path: Option<PathBuf>,
+        /// The underlying I/O error.
+        source: io::Error,
+    },
+
+    /// The block device holding the file could not be determined.
+    DeviceResolve {
+        /// The underlying I/O error.
+        source: io::Error,
+    },
+
+    /// The block device could not be opened.
+    ///
+    /// Usually fails with `EACCES` or `EPERM` without root privileges.
+    DeviceOpen {
+        /// Path of the block device.
+        device_path: PathBuf,
+        /// The underlying I/O error.
+        source: io::Error,
+        /// The access of the process to the device, probed if opening it
+        /// failed with `EACCES` or `EPERM`.
+        access: Option<DeviceAccess>,
+    },
+
+    /// Reading from the block device failed.
+    DeviceRead(DeviceReadError),
+
+    /// A read is not aligned to the sector size of the device.
+    ///
+    /// Direct I/O requires the physical offset and length (and the buffer
+    /// address) to be aligned; see
+    /// [`Options::auto_align`](crate::Options::auto_align).
+    Unaligned {
+        /// Path of the block device.
+        device_path: PathBuf,
+        /// Physical offset of the rejected read.
+        physical_offset: u64,
+        /// Length of the rejected read in bytes.
+        length: usize,
+        /// Sector size of the device.
+        alignment: u64,
+    },
+
+    /// An extent maps beyond the end of the block device.
+    ///
+    /// This usually means the wrong device was resolved, e.g. the whole
+    /// disk instead of the partition holding the filesystem.
+    BeyondDevice {
+        /// Path of the block device.
+        device_path: PathBuf,
+        /// Index of the extent in the extent list.
+        extent_index: usize,
+        /// Physical offset of the read from the extent.
+        physical_offset: u64,
+        /// Length of the read in bytes.
+        length: usize,
+        /// Size of the device in bytes.
+        device_size: u64,
+    },
+
+    /// The range has dirty pages that have not been written to the device.
+    ///
+    /// Only returned with
+    /// [`Options::fail_on_dirty`](crate::Options::fail_on_dirty).
+    DirtyPages {
+        /// Path of the file, if known.
+        file_path: Option<PathBuf>,
+        /// Logical offset of the range.
+        offset: u64,
+        /// Length of the range in bytes.
+        length: u64,
+        /// Number of dirty (or, without `cachestat`, cached) pages.
+        pages: u64,
+    },
+
+    /// An extent stores its data inline in filesystem metadata.
+    ///
+    /// Only returned with [`InlinePolicy::Error`](crate::InlinePolicy::Error).
+    InlineExtent {
+        /// Path of the file, if known.
+        file_path: Option<PathBuf>,
+        /// Index of the extent in the extent list.
+        extent_index: usize,
+        /// Logical file offset of the extent.
+        logical_offset: u64,
+        /// Length of the extent in bytes.
+        length: u64,
+    },
+
+    /// An extent's data is encoded on disk, e.g. compressed or encrypted.
+    ///
+    /// Only returned with [`EncodedPolicy::Error`](crate::EncodedPolicy::Error).
+    EncodedExtent {
+        /// Path of the file, if known.
+        file_path: Option<PathBuf>,
+        /// Index of the extent in the extent list.
+        extent_index: usize,
+        /// Logical file offset of the extent.
+        logical_offset: u64,
+        /// Length of the extent in bytes.
+        length: u64,
+    },
+
+    /// An LVM logical volume uses a layout whose data can't be translated
+    /// to its physical volumes, such as RAID5 or a thin pool.
+    ///
+    /// Only returned with
+    /// [`Options::translate_dm`](crate::Options::translate_dm).
+    UnsupportedLvmLayout {
+        /// Path of the device-mapper device of the volume.
+        device_path: PathBuf,
+        /// Type of the target that can't be translated, such as `raid`.
+        target: String,
+    },
+
+    /// The device blocks to be read hold encrypted data, which would be
+    /// returned as ciphertext.
+    ///
+    /// Not returned with
+    /// [`Options::allow_ciphertext`](crate::Options::allow_ciphertext).
+    Ciphertext {
+        /// Path of the file, if known.
+        file_path: Option<PathBuf>,
+        /// Path of the device that would be read.
+        device_path: PathBuf,
+        /// How the data is encrypted.
+        encryption: Encryption,
+    },
+
+    /// The file is on a filesystem without a local block device, such as
+    /// NFS, CIFS or FUSE, whose extents can't be read from a device.
+    UnsupportedFilesystem {
+        /// Path of the file, if known.
+        file_path: Option<PathBuf>,
+        /// Type of the filesystem, such as `nfs`.
+        fstype: String,
+    },
+
+    /// Data read from the device does not match the file's data at the
+    /// same extent, so the extents are not relative to the device.
+    ///
+    /// This usually means the wrong device was resolved, e.g. the whole
+    /// disk instead of the partition holding the filesystem. Only returned
+    /// with [`Options::verify_device`](crate::Options::verify_device).
+    DeviceMismatch {
+        /// Path of the file, if known.
+        file_path: Option<PathBuf>,
+        /// Path of the block device.
+        device_path: PathBuf,
+        /// Logical file offset of the compared block.
+        logical_offset: u64,
+        /// Physical offset of the compared block on the device.
+        physical_offset: u64,
+    },
+
+    /// The requested length could not be fully read.
+    ShortRead(ShortReadError),
+}
+
+impl BlkReadError {
+    /// Extract a `BlkReadError` from an [`io::Error`] returned by this crate.
+    ///
+    /// Looks through a [`PartialReadError`] to the error that stopped the read.
+    pub fn from_io_error(err: &io::Error) -> Option<&Self> {
+        let inner = err.get_ref()?;
+        match inner.downcast_ref::<PartialReadError>() {
+            Some(partial) => Self::from_io_error(&partial.source),
+            None => inner.downcast_ref::<Self>(),
+        }
+    }
+
+    /// A [`DeviceOpen`](Self::DeviceOpen) error, probing the access of the
+    /// process to the device if opening it for reading, or for writing if
+    /// `write`, was denied.
+    pub(crate) fn device_open(device_path: PathBuf, source: io::Error, write: bool) -> Self {
+        let access = matches!(source.raw_os_error(), Some(libc::EACCES | libc::EPERM))
+            .then(|| DeviceAccess::probe(&device_path, write));
+        BlkReadError::DeviceOpen {
+            device_path,
+            source,
+            access,
+        }
+    }
+
+    /// OS error code of the underlying error, if it has one.
+    ///
+    /// The `io::Error` wrapping this error has no code of its own, so code
+    /// matching on [`io::Error::raw_os_error`] of errors returned by this
+    /// crate looks through the context instead:
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReadError, BlkReader};
+    /// use std::path::Path;
+    ///
+    /// let mut buf = vec![0u8; 4096];
+    /// if let Err(e) = Path::new("/path/to/file").blk_read_at(&mut buf, 0) {
+    ///     let code = BlkReadError::from_io_error(&e).map_or(e.raw_os_error(), |e| e.raw_os_error());
+    ///     if code == Some(libc::EIO) {
+    ///         eprintln!("media error: {}", e);
+    ///     }
+    /// }
+    /// ```
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            BlkReadError::FiemapFailed { source, .. }
+            | BlkReadError::DeviceResolve { source }
+            | BlkReadError::DeviceOpen { source, .. } => source.raw_os_error(),
+            BlkReadError::DeviceRead(err) => err.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// Kind of the `io::Error` wrapping this error.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            BlkReadError::FiemapFailed { source, .. }
+            | BlkReadError::DeviceResolve { source }
+            | BlkReadError::DeviceOpen { source, .. } => source.kind(),
+            BlkReadError::DeviceRead(err) => err.source.kind(),
+            BlkReadError::Unaligned { .. } => io::ErrorKind::InvalidInput,
+            BlkReadError::BeyondDevice { .. } | BlkReadError::DeviceMismatch { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            BlkReadError::DirtyPages { .. } => io::ErrorKind::ResourceBusy,
+            BlkReadError::InlineExtent { .. }
+            | BlkReadError::EncodedExtent { .. }
+            | BlkReadError::UnsupportedLvmLayout { .. }
+            | BlkReadError::Ciphertext { .. }
+            | BlkReadError::UnsupportedFilesystem { .. } => io::ErrorKind::Unsupported,
+            BlkReadError::ShortRead(_) => io::ErrorKind::UnexpectedEof,
+        }
+    }
+}
+
+impl fmt::Display for BlkReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlkReadError::FiemapFailed { file_path, source } => {
+                write!(f, "failed to query extents")?;
+                if let Some(path) = file_path {
+                    write!(f, " of {}", path.display())?;
+                }
+                write!(f, ": {}", source)
+            }
+            BlkReadError::DeviceResolve { source } => {
+                write!(f, "failed to resolve block device: {}", source)
+            }
+            BlkReadError::DeviceOpen {
+                device_path,
+                source,
+                access,
+            } => {
+                write!(
+                    f,
+                    "failed to open block device {}: {}",
+                    device_path.display(),
+                    source
+                )?;
+                match access {
+                    Some(access) => write_access_hint(f, device_path, access),
+                    None => Ok(()),
+                }
+            }
+            BlkReadError::DeviceRead(err) => err.fmt(f),
+            BlkReadError::Unaligned {
+                device_path,
+                physical_offset,
+                length,
+                alignment,
+            } => write!(
+                f,
+                "read of {} bytes from {} at physical offset {:#x} is not aligned to {} bytes",
+                length,
+                device_path.display(),
+                physical_offset,
+                alignment
+            ),
+            BlkReadError::BeyondDevice {
+                device_path,
+                extent_index,
+                physical_offset,
+                length,
+                device_size,
+            } => write!(
+                f,
+                "extent {} maps beyond end of {} ({} bytes at physical offset {:#x}, device size {:#x})",
+                extent_index,
+                device_path.display(),
+                length,
+                physical_offset,
+                device_size
+            ),
+            BlkReadError::DirtyPages {
+                file_path,
+                offset,
+                length,
+                pages,
+            } => {
+                write!(f, "{} dirty pages in range {:#x}+{:#x}", pages, offset, length)?;
+                if let Some(path) = file_path {
+                    write!(f, " of {}", path.display())?;
+                }
+                write!(f, " have not been written to the device")
+            }
+            BlkReadError::InlineExtent {
+                file_path,
+                extent_index,
+                logical_offset,
+                length,
+            } => {
+                write!(
+                    f,
+                    "extent {} ({} bytes at logical offset {:#x})",
+                    extent_index, length, logical_offset
+                )?;
+                if let Some(path) = file_path {
+                    write!(f, " of {}", path.display())?;
+                }
+                write!(f, " is stored inline, not on the block device")
+            }
+            BlkReadError::EncodedExtent {
+                file_path,
+                extent_index,
+                logical_offset,
+                length,
+            } => {
+                write!(
+                    f,
+                    "extent {} ({} bytes at logical offset {:#x})",
+                    extent_index, length, logical_offset
+                )?;
+                if let Some(path) = file_path {
+                    write!(f, " of {}", path.display())?;
+                }
+                write!(f, " is encoded (compressed or encrypted) on the block device")
+            }
+            BlkReadError::UnsupportedLvmLayout {
+                device_path,
+                target,
+            } => write!(
+                f,
+                "unsupported LVM layout: {} target of {} can't be translated to physical volumes",
+                target,
+                device_path.display()
+            ),
+            BlkReadError::Ciphertext {
+                file_path,
+                device_path,
+                encryption,
+            } => {
+                write!(f, "{} holds ciphertext", device_path.display())?;
+                if let Some(path) = file_path {
+                    write!(f, " of {}", path.display())?;
+                }
+                match encryption {
+                    Encryption::Fscrypt => write!(
+                        f,
+                        ": the file is encrypted with fscrypt; read it through the filesystem"
+                    ),
+                    Encryption::DmCrypt { mapping } => write!(
+                        f,
+                        ": the device is decrypted by the dm-crypt mapping {}; read through it",
+                        mapping.display()
+                    ),
+                }
+            }
+            BlkReadError::UnsupportedFilesystem { file_path, fstype } => {
+                match file_path {
+                    Some(path) => write!(f, "{}", path.display())?,
+                    None => write!(f, "file")?,
+                }
+                write!(
+                    f,
+                    " is on {}, which has no local block device; read it through the filesystem",
+                    fstype
+                )
+            }
+            BlkReadError::DeviceMismatch {
+                file_path,
+                device_path,
+                logical_offset,
+                physical_offset,
+            } => {
+                write!(
+                    f,
+                    "data at physical offset {:#x} of {} does not match logical offset {:#x}",
+                    physical_offset,
+                    device_path.display(),
+                    logical_offset
+                )?;
+                if let Some(path) = file_path {
+                    write!(f, " of {}", path.display())?;
+                }
+                write!(
+                    f,
+                    "; the extents are relative to another device, such as a partition"
+                )
+            }
+            BlkReadError::ShortRead(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Tell what the process needs to open `device_path`, given its `access`.
+fn write_access_hint(
+    f: &mut fmt::Formatter<'_>,
+    device_path: &Path,
+    access: &DeviceAccess,
+) -> fmt::Result {
+    let (capability, mode) = match access.write {
+        true => ("CAP_DAC_OVERRIDE", "write"),
+        false => ("CAP_DAC_READ_SEARCH", "read"),
+    };
+    if access.is_missing() {
+        write!(
+            f,
+            "; needs {} or {} access to {}",
+            capability,
+            mode,
+            device_path.display()
+        )?;
+        if let Some(group) = &access.group {
+            write!(f, " (e.g. as a member of group {})", group)?;
+        }
+    } else {
+        let has = match access.permitted {
+            true => format!("{} access to {}", mode, device_path.display()),
+            false => capability.to_string(),
+        };
+        write!(
+            f,
+            "; the process has {}, so the open was denied by a policy such as \
+             a device cgroup, a security module or a nodev mount",
+            has
+        )?;
+    }
+    match (access.write, access.is_missing()) {
+        (true, _) => Ok(()),
+        (false, true) => write!(f, ", or run with allow_fallback"),
+        (false, false) => write!(f, "; run with allow_fallback to read through the file"),
+    }
+}
+
+impl Error for BlkReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BlkReadError::FiemapFailed { source, .. }
+            | BlkReadError::DeviceResolve { source }
+            | BlkReadError::DeviceOpen { source, .. } => Some(source),
+            BlkReadError::DeviceRead(err) => err.source(),
+            BlkReadError::Unaligned { .. }
+            | BlkReadError::BeyondDevice { .. }
+            | BlkReadError::DirtyPages { .. }
+            | BlkReadError::InlineExtent { .. }
+            | BlkReadError::EncodedExtent { .. }
+            | BlkReadError::UnsupportedLvmLayout { .. }
+            | BlkReadError::Ciphertext { .. }
+            | BlkReadError::UnsupportedFilesystem { .. }
+            | BlkReadError::DeviceMismatch { .. }
+            | BlkReadError::ShortRead(_) => None,
+        }
+    }
+}
+
+/// Wraps the error, keeping its [`kind`](BlkReadError::kind).
+///
+/// The OS error code is not kept: `io::Error` holds either a code or a
+/// wrapped error, and the context is what [`BlkReadError::from_io_error`]
+/// recovers. So [`io::Error::raw_os_error`] of the result is `None`, also
+/// when the underlying failure has a code; it is
+/// [`BlkReadError::raw_os_error`] of the recovered error.
+impl From<BlkReadError> for io::Error {
+    fn from(err: BlkReadError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// How the data on a block device is encrypted; see
+/// [`BlkReadError::Ciphertext`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Encryption {
+    /// The file is encrypted with fscrypt, and only decrypted when read
+    /// through the filesystem.
+    Fscrypt,
+
+    /// The device is the encrypted store of a dm-crypt mapping, such as a
+    /// LUKS volume, which decrypts it.
+    DmCrypt {
+        /// Path of the device-mapper device of the mapping.
+        mapping: PathBuf,
+    },
+}
+
+/// Error raised when reading from the block device fails.
+///
+/// Carries the location of the failed read so that it can be diagnosed
+/// without tracing the process.
+#[derive(Debug)]
+pub struct DeviceReadError {
+    /// Path of the file being read, if known.
+    pub file_path: Option<PathBuf>,
+
+    /// Path of the block device the read was issued against.
+    pub device_path: PathBuf,
+
+    /// Index of the extent (in the queried extent list) being read.
+    pub extent_index: usize,
+
+    /// Logical offset in the file where the failed read started.
+    pub logical_offset: u64,
+
+    /// Physical offset on the device where the failed read started.
+    pub physical_offset: u64,
+
+    /// Length of the failed read in bytes.
+    pub length: usize,
+
+    /// The underlying I/O error.
+    pub source: io::Error,
+}
+
+impl DeviceReadError {
+    /// Extract a `DeviceReadError` from an [`io::Error`] returned by this crate.
+    pub fn from_io_error(err: &io::Error) -> Option<&Self> {
+        match BlkReadError::from_io_error(err)? {
+            BlkReadError::DeviceRead(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// OS error code of the failed read, if it has one.
+    ///
+    /// See [`BlkReadError::raw_os_error`].
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+}
+
+impl fmt::Display for DeviceReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to read {} bytes from {} at physical offset {:#x} (",
+            self.length,
+            self.device_path.display(),
+            self.physical_offset
+        )?;
+        if let Some(path) = &self.file_path {
+            write!(f, "file {}, ", path.display())?;
+        }
+        write!(
+            f,
+            "logical offset {:#x}, extent {}): {}",
+            self.logical_offset, self.extent_index, self.source
+        )
+    }
+}
+
+impl Error for DeviceReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<DeviceReadError> for io::Error {
+    fn from(err: DeviceReadError) -> Self {
+        BlkReadError::DeviceRead(err).into()
+    }
+}
+
+/// Error raised when [`Options::read_exact`](crate::Options::read_exact) is
+/// set and the requested length cannot be fully satisfied.
+///
+/// This happens on a hole (without `fill_holes`), at the end of the file or
+/// on a short device read. The wrapping `io::Error` has kind
+/// [`io::ErrorKind::UnexpectedEof`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShortReadError {
+    /// Number of bytes requested.
+    pub expected: usize,
+
+    /// Number of bytes actually read (or filled) into the buffer.
+    pub bytes_read: usize,
+}
+
+impl ShortReadError {
+    /// Extract a `ShortReadError` from an [`io::Error`] returned by this crate.
+    pub fn from_io_error(err: &io::Error) -> Option<&Self> {
+        match BlkReadError::from_io_error(err)? {
+            BlkReadError::ShortRead(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ShortReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to fill entire buffer: expected {} bytes, got {} bytes",
+            self.expected, self.bytes_read
+        )
+    }
+}
+
+impl Error for ShortReadError {}
+
+impl From<ShortReadError> for io::Error {
+    fn from(err: ShortReadError) -> Self {
+        BlkReadError::ShortRead(err).into()
+    }
+}
+
+/// Error raised when a read fails after part of the data was read.
+///
+/// `state` describes the buffer up to the failure: its `bytes_read` bytes
+/// hold valid data, so the caller can report them or resume the read after
+/// them. Data placed beyond that prefix, e.g. by reads issued out of order,
+/// is not reported. The wrapping `io::Error` has the kind of `source`.
+#[derive(Debug)]
+pub struct PartialReadError {
+    /// State of the read up to the failure.
+    pub state: State,
+
+    /// The error that stopped the read.
+    pub source: io::Error,
+}
+
+impl PartialReadError {
+    /// Extract a `PartialReadError` from an [`io::Error`] returned by this crate.
+    pub fn from_io_error(err: &io::Error) -> Option<&Self> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<Self>())
+    }
+
+    /// OS error code of the error that stopped the read, if it has one,
+    /// looking through the [`BlkReadError`] it may wrap.
+    ///
+    /// See [`BlkReadError::raw_os_error`].
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match BlkReadError::from_io_error(&self.source) {
+            Some(err) => err.raw_os_error(),
+            None => self.source.raw_os_error(),
+        }
+    }
+}
+
+impl fmt::Display for PartialReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (after reading {} bytes)",
+            self.source, self.state.bytes_read
+        )
+    }
+}
+
+impl Error for PartialReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<PartialReadError> for io::Error {
+    fn from(err: PartialReadError) -> Self {
+        io::Error::new(err.source.kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_read_error_roundtrip() {
+        let err: io::Error = DeviceReadError {
+            file_path: Some(PathBuf::from("/data/file")),
+            device_path: PathBuf::from("/dev/sda1"),
+            extent_index: 3,
+            logical_offset: 0x2000,
+            physical_offset: 0x10000,
+            length: 4096,
+            source: io::Error::from_raw_os_error(libc::EIO),
+        }
+        .into();
+
+        let message = err.to_string();
+        assert!(message.contains("/dev/sda1"));
+        assert!(message.contains("physical offset 0x10000"));
+        assert!(message.contains("file /data/file"));
+        assert!(message.contains("logical offset 0x2000"));
+        assert!(message.contains("extent 3"));
+
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::DeviceRead(_))
+        ));
+        let context = DeviceReadError::from_io_error(&err).unwrap();
+        assert_eq!(context.extent_index, 3);
+        assert_eq!(context.raw_os_error(), Some(libc::EIO));
+
+        // The code is only reachable through the context
+        assert_eq!(err.raw_os_error(), None);
+        assert_eq!(
+            BlkReadError::from_io_error(&err).unwrap().raw_os_error(),
+            Some(libc::EIO)
+        );
+        let partial: io::Error = PartialReadError {
+            state: State::new(PathBuf::from("/dev/sda1"), Vec::new(), 0, false),
+            source: err,
+        }
+        .into();
+        assert_eq!(partial.raw_os_error(), None);
+        assert_eq!(
+            BlkReadError::from_io_error(&partial)
+                .unwrap()
+                .raw_os_error(),
+            Some(libc::EIO)
+        );
+        assert_eq!(
+            PartialReadError::from_io_error(&partial)
+                .unwrap()
+                .raw_os_error(),
+            Some(libc::EIO)
+        );
+
+        // Also when the read stopped at a plain OS error
+        let partial: io::Error = PartialReadError {
+            state: State::new(PathBuf::from("/dev/sda1"), Vec::new(), 0, false),
+            source: io::Error::from_raw_os_error(libc::ENOSPC),
+        }
+        .into();
+        assert!(BlkReadError::from_io_error(&partial).is_none());
+        assert_eq!(
+            PartialReadError::from_io_error(&partial)
+                .unwrap()
+                .raw_os_error(),
+            Some(libc::ENOSPC)
+        );
+    }
+
+    #[test]
+    fn test_from_io_error_plain() {
+        let err = io::Error::other("plain");
+        assert!(BlkReadError::from_io_error(&err).is_none());
+        assert!(DeviceReadError::from_io_error(&err).is_none());
+        assert!(ShortReadError::from_io_error(&err).is_none());
+    }
+
+    #[test]
+    fn test_blk_read_error_stages() {
+        let err: io::Error = BlkReadError::FiemapFailed {
+            file_path: Some(PathBuf::from("/data/file")),
+            source: io::Error::from_raw_os_error(libc::EOPNOTSUPP),
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("extents of /data/file"));
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::FiemapFailed { .. })
+        ));
+        assert!(DeviceReadError::from_io_error(&err).is_none());
+
+        let err: io::Error = BlkReadError::DeviceOpen {
+            device_path: PathBuf::from("/dev/sda1"),
+            source: io::Error::from_raw_os_error(libc::EACCES),
+            access: None,
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("open block device /dev/sda1"));
+        assert!(err.get_ref().unwrap().source().is_some());
+
+        let err: io::Error = BlkReadError::Unaligned {
+            device_path: PathBuf::from("/dev/sda1"),
+            physical_offset: 0x1001,
+            length: 4096,
+            alignment: 512,
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("not aligned to 512 bytes"));
+
+        let err: io::Error = BlkReadError::BeyondDevice {
+            device_path: PathBuf::from("/dev/sdb"),
+            extent_index: 3,
+            physical_offset: 1 << 30,
+            length: 4096,
+            device_size: 1 << 20,
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err
+            .to_string()
+            .starts_with("extent 3 maps beyond end of /dev/sdb"));
+
+        let err: io::Error = BlkReadError::DirtyPages {
+            file_path: Some(PathBuf::from("/data/file")),
+            offset: 0,
+            length: 8192,
+            pages: 2,
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert!(err
+            .to_string()
+            .starts_with("2 dirty pages in range 0x0+0x2000 of /data/file"));
+
+        let err: io::Error = BlkReadError::InlineExtent {
+            file_path: None,
+            extent_index: 0,
+            logical_offset: 0,
+            length: 60,
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err
+            .to_string()
+            .ends_with("is stored inline, not on the block device"));
+
+        let err: io::Error = BlkReadError::EncodedExtent {
+            file_path: Some(PathBuf::from("/data/file")),
+            extent_index: 2,
+            logical_offset: 0x20000,
+            length: 131072,
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().starts_with(
+            "extent 2 (131072 bytes at logical offset 0x20000) of /data/file is encoded"
+        ));
+
+        let err: io::Error = BlkReadError::UnsupportedLvmLayout {
+            device_path: PathBuf::from("/dev/dm-0"),
+            target: "raid".to_string(),
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err
+            .to_string()
+            .starts_with("unsupported LVM layout: raid target of /dev/dm-0"));
+
+        let err: io::Error = BlkReadError::Ciphertext {
+            file_path: Some(PathBuf::from("/data/file")),
+            device_path: PathBuf::from("/dev/sda2"),
+            encryption: Encryption::DmCrypt {
+                mapping: PathBuf::from("/dev/dm-1"),
+            },
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(
+            err.to_string(),
+            "/dev/sda2 holds ciphertext of /data/file: the device is decrypted by the \
+             dm-crypt mapping /dev/dm-1; read through it"
+        );
+
+        let err: io::Error = BlkReadError::UnsupportedFilesystem {
+            file_path: Some(PathBuf::from("/mnt/nfs/file")),
+            fstype: "nfs".to_string(),
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err
+            .to_string()
+            .starts_with("/mnt/nfs/file is on nfs, which has no local block device"));
+
+        let err: io::Error = BlkReadError::DeviceMismatch {
+            file_path: Some(PathBuf::from("/data/file")),
+            device_path: PathBuf::from("/dev/sda"),
+            logical_offset: 0,
+            physical_offset: 0x100000,
+        }
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with(
+            "data at physical offset 0x100000 of /dev/sda does not match logical offset 0x0 \
+             of /data/file"
+        ));
+    }
+
+    #[test]
+    fn test_device_open_access_hint() {
+        // Access is only probed when it was denied
+        let device_path = PathBuf::from("/nonexistent");
+        let enoent = io::Error::from_raw_os_error(libc::ENOENT);
+        let err = BlkReadError::device_open(device_path.clone(), enoent, false);
+        assert!(matches!(err, BlkReadError::DeviceOpen { access: None, .. }));
+        let eperm = io::Error::from_raw_os_error(libc::EPERM);
+        match BlkReadError::device_open(device_path, eperm, true) {
+            BlkReadError::DeviceOpen {
+                access: Some(access),
+                ..
+            } => assert!(access.write && !access.permitted),
+            err => panic!("unexpected {:?}", err),
+        }
+
+        let denied = |access| {
+            BlkReadError::DeviceOpen {
+                device_path: PathBuf::from("/dev/nvme0n1p2"),
+                source: io::Error::from_raw_os_error(libc::EACCES),
+                access: Some(access),
+            }
+            .to_string()
+        };
+        let access = DeviceAccess {
+            write: false,
+            capability: Some(false),
+            permitted: false,
+            group: Some("disk".to_string()),
+        };
+        assert!(denied(access.clone()).ends_with(
+            "; needs CAP_DAC_READ_SEARCH or read access to /dev/nvme0n1p2 \
+             (e.g. as a member of group disk), or run with allow_fallback"
+        ));
+
+        let writing = DeviceAccess {
+            write: true,
+            group: None,
+            ..access.clone()
+        };
+        assert!(
+            denied(writing).ends_with("; needs CAP_DAC_OVERRIDE or write access to /dev/nvme0n1p2")
+        );
+
+        // Denied despite the capability
+        let policy = DeviceAccess {
+            capability: Some(true),
+            ..access
+        };
+        let message = denied(policy);
+        assert!(message.contains("the process has CAP_DAC_READ_SEARCH, so the open was denied"));
+        assert!(message.ends_with("; run with allow_fallback to read through the file"));
+    }
+
+    #[test]
+    fn test_partial_read_error() {
+        let source: io::Error = DeviceReadError {
+            file_path: None,
+            device_path: PathBuf::from("/dev/sda1"),
+            extent_index: 1,
+            logical_offset: 0x1000,
+            physical_offset: 0x8000,
+            length: 4096,
+            source: io::Error::from_raw_os_error(libc::EIO),
+        }
+        .into();
+        let state = State::new(PathBuf::from("/dev/sda1"), Vec::new(), 4096, false);
+        let err: io::Error = PartialReadError { state, source }.into();
+
+        assert!(err.to_string().contains("after reading 4096 bytes"));
+        assert_eq!(
+            PartialReadError::from_io_error(&err)
+                .unwrap()
+                .state
+                .bytes_read,
+            4096
+        );
+        assert_eq!(
+            DeviceReadError::from_io_error(&err).unwrap().extent_index,
+            1
+        );
+    }
+
+    #[test]
+    fn test_short_read_error_roundtrip() {
+        let err: io::Error = ShortReadError {
+            expected: 4096,
+            bytes_read: 1024,
+        }
+        .into();
+
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(err
+            .to_string()
+            .contains("expected 4096 bytes, got 1024 bytes"));
+        let short = ShortReadError::from_io_error(&err).unwrap();
+        assert_eq!(short.bytes_read, 1024);
+    }
+}
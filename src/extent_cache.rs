@@ -0,0 +1,328 @@
+//! Caching of extent maps across runs.
+//!
+//! Services restarting frequently would otherwise query FIEMAP for every
+//! file again after each start. A [`DiskExtentCache`], set with
+//! [`Options::with_disk_extent_cache`](crate::Options::with_disk_extent_cache),
+//! keeps the extent map of each file read in a directory, keyed by the
+//! file's device and inode number and checked against the file's metadata
+//! before use.
+
+use crate::sys;
+
+use blkmap::{ExtentFlags, FiemapExtent};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// What a file's extent map is checked against before it is reused.
+///
+/// Writes, truncation and `fallocate` change the file's ctime, and a new
+/// file reusing the inode number has another generation, so a map cached
+/// for one version of the file is never used for another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct FileVersion {
+    pub(crate) dev: u64,
+    pub(crate) ino: u64,
+    /// Inode generation, 0 on filesystems not reporting one.
+    pub(crate) generation: u64,
+    pub(crate) size: u64,
+    pub(crate) mtime: (i64, i64),
+    pub(crate) ctime: (i64, i64),
+}
+
+impl FileVersion {
+    /// Number of `u64` fields in the encoded form.
+    const FIELDS: usize = 8;
+
+    pub(crate) fn of(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            generation: sys::inode_generation(file.as_raw_fd()).map_or(0, u64::from),
+            size: metadata.len(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        })
+    }
+
+    fn encode(&self) -> [u64; Self::FIELDS] {
+        [
+            self.dev,
+            self.ino,
+            self.generation,
+            self.size,
+            self.mtime.0 as u64,
+            self.mtime.1 as u64,
+            self.ctime.0 as u64,
+            self.ctime.1 as u64,
+        ]
+    }
+}
+
+/// Whether an extent map can be reused while the file's version is
+/// unchanged.
+///
+/// Delayed allocations get their location when written back, which leaves
+/// the file's times alone.
+pub(crate) fn is_cacheable(extents: &[FiemapExtent]) -> bool {
+    extents
+        .iter()
+        .all(|extent| !extent.flags.is_delalloc() && !extent.flags.is_unknown())
+}
+
+/// Extent maps cached in a directory, surviving restarts of the process.
+///
+/// Entries are checked against the file's device, inode number and
+/// generation, size, mtime and ctime, and only used if all of them are
+/// unchanged; maps with delayed allocations are not cached. Corrupted or
+/// unreadable entries count as misses, and failures to store entries are
+/// ignored, so the cache never fails a read. Several processes may share
+/// the directory.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkReader, DiskExtentCache, Options};
+/// use std::path::Path;
+///
+/// let cache = DiskExtentCache::new("/var/cache/myservice/extents")?;
+/// let options = Options::new().with_disk_extent_cache(cache);
+/// let mut buf = vec![0u8; 4096];
+/// Path::new("/data/file").blk_read_at_opt(&mut buf, 0, &options)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskExtentCache {
+    dir: PathBuf,
+}
+
+impl DiskExtentCache {
+    /// Magic number and format version at the start of every entry.
+    const MAGIC: &'static [u8; 8] = b"BLKEXTC1";
+
+    /// Use `dir` for the cache, creating it if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// The directory holding the cache.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The cached extent map of `file`, if there is one for its current
+    /// version.
+    pub fn get(&self, file: &File) -> io::Result<Option<Vec<FiemapExtent>>> {
+        Ok(self.lookup(&FileVersion::of(file)?))
+    }
+
+    /// Cache `extents`, the complete extent map of `file`.
+    ///
+    /// Maps with delayed allocations are not stored.
+    pub fn insert(&self, file: &File, extents: &[FiemapExtent]) -> io::Result<()> {
+        self.store(&FileVersion::of(file)?, extents)
+    }
+
+    /// The cached extent map of the file at `version`.
+    pub(crate) fn lookup(&self, version: &FileVersion) -> Option<Vec<FiemapExtent>> {
+        let entry = fs::read(self.entry_path(version)).ok()?;
+        Self::decode(&entry, version)
+    }
+
+    /// Cache `extents`, the extent map of the file at `version`.
+    ///
+    /// The version is to be taken before querying the extents, so that a
+    /// change in between leaves a stale version rather than a stale map.
+    pub(crate) fn store(&self, version: &FileVersion, extents: &[FiemapExtent]) -> io::Result<()> {
+        if !is_cacheable(extents) {
+            return Ok(());
+        }
+        let path = self.entry_path(version);
+
+        // Entries are replaced whole, so concurrent readers never see a
+        // partial one
+        static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let temp = self.dir.join(format!(
+            ".{}.{}.{}.tmp",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp, Self::encode(version, extents))?;
+        fs::rename(&temp, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })
+    }
+
+    /// Remove the entry of `file`, if any.
+    pub fn remove(&self, file: &File) -> io::Result<()> {
+        match fs::remove_file(self.entry_path(&FileVersion::of(file)?)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove all entries.
+    pub fn clear(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().ends_with(".extents") {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entry_path(&self, version: &FileVersion) -> PathBuf {
+        self.dir
+            .join(format!("{:x}-{}.extents", version.dev, version.ino))
+    }
+
+    fn encode(version: &FileVersion, extents: &[FiemapExtent]) -> Vec<u8> {
+        let mut entry = Self::MAGIC.to_vec();
+        let fields = version
+            .encode()
+            .into_iter()
+            .chain([extents.len() as u64])
+            .chain(extents.iter().flat_map(|extent| {
+                [
+                    extent.logical,
+                    extent.physical,
+                    extent.length,
+                    extent.flags.bits() as u64,
+                ]
+            }));
+        for field in fields {
+            entry.extend_from_slice(&field.to_le_bytes());
+        }
+        let crc = crc32fast::hash(&entry);
+        entry.extend_from_slice(&crc.to_le_bytes());
+        entry
+    }
+
+    /// Decode an entry, if it is intact and for `version`.
+    fn decode(entry: &[u8], version: &FileVersion) -> Option<Vec<FiemapExtent>> {
+        let (body, crc) = entry.split_at_checked(entry.len().checked_sub(4)?)?;
+        if crc32fast::hash(body).to_le_bytes() != crc {
+            return None;
+        }
+        let fields: Vec<u64> = body
+            .strip_prefix(Self::MAGIC)?
+            .chunks(8)
+            .map(|chunk| Some(u64::from_le_bytes(chunk.try_into().ok()?)))
+            .collect::<Option<_>>()?;
+        let (stored, rest) = fields.split_at_checked(FileVersion::FIELDS)?;
+        if stored != version.encode() {
+            return None;
+        }
+        let (count, extents) = rest.split_first()?;
+        if extents.len() as u64 != count.checked_mul(4)? {
+            return None;
+        }
+        extents
+            .chunks(4)
+            .map(|fields| {
+                Some(FiemapExtent {
+                    logical: fields[0],
+                    physical: fields[1],
+                    length: fields[2],
+                    flags: ExtentFlags::from_bits_retain(u32::try_from(fields[3]).ok()?),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn extents() -> Vec<FiemapExtent> {
+        vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 1 << 20,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 8192,
+                physical: 1 << 30,
+                length: 4096,
+                flags: ExtentFlags::UNWRITTEN | ExtentFlags::LAST,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_disk_extent_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskExtentCache::new(dir.path().join("cache")).unwrap();
+        let temp = tempfile::NamedTempFile::new_in(dir.path()).unwrap();
+        let mut file = temp.as_file();
+        file.write_all(&[1; 4096]).unwrap();
+
+        assert_eq!(cache.get(file).unwrap(), None);
+        cache.insert(file, &extents()).unwrap();
+        assert_eq!(cache.get(file).unwrap(), Some(extents()));
+
+        // Another handle to the file, as after a restart
+        let cache = DiskExtentCache::new(cache.dir()).unwrap();
+        let reopened = File::open(temp.path()).unwrap();
+        assert_eq!(cache.get(&reopened).unwrap(), Some(extents()));
+
+        // Changing the file invalidates the entry
+        file.write_all(&[2; 4096]).unwrap();
+        assert_eq!(cache.get(file).unwrap(), None);
+
+        cache.insert(file, &extents()).unwrap();
+        cache.remove(file).unwrap();
+        assert_eq!(cache.get(file).unwrap(), None);
+        cache.remove(file).unwrap();
+
+        cache.insert(file, &extents()).unwrap();
+        cache.clear().unwrap();
+        assert_eq!(cache.get(file).unwrap(), None);
+        assert_eq!(fs::read_dir(cache.dir()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_uncacheable() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskExtentCache::new(dir.path()).unwrap();
+        let file = tempfile::tempfile_in(dir.path()).unwrap();
+        let mut delalloc = extents();
+        delalloc[1].flags |= ExtentFlags::DELALLOC | ExtentFlags::UNKNOWN;
+        cache.insert(&file, &delalloc).unwrap();
+        assert_eq!(cache.get(&file).unwrap(), None);
+    }
+
+    #[test]
+    fn test_decode_rejects() {
+        let version = FileVersion::of(&tempfile::tempfile().unwrap()).unwrap();
+        let entry = DiskExtentCache::encode(&version, &extents());
+        assert_eq!(DiskExtentCache::decode(&entry, &version), Some(extents()));
+
+        // Corruption, truncation and other versions of the file are misses
+        let mut corrupted = entry.clone();
+        corrupted[20] ^= 1;
+        assert_eq!(DiskExtentCache::decode(&corrupted, &version), None);
+        assert_eq!(
+            DiskExtentCache::decode(&entry[..entry.len() - 1], &version),
+            None
+        );
+        assert_eq!(DiskExtentCache::decode(&[], &version), None);
+        let other = FileVersion {
+            size: version.size + 1,
+            ..version
+        };
+        assert_eq!(DiskExtentCache::decode(&entry, &other), None);
+    }
+}
@@ -0,0 +1,418 @@
+//! Saved extent maps.
+//!
+//! An extent map captured while a file is intact lets its data be read back
+//! with [`BlkReader::blk_read_with_extents`](crate::BlkReader::blk_read_with_extents)
+//! or [`ExtentMap::read_at`] after the file was truncated, deleted or its
+//! filesystem damaged.
+//! [`ExtentMap`] is the crate's file format for keeping such maps: binary,
+//! versioned, and checksummed so that a corrupted map is rejected instead
+//! of directing reads to the wrong blocks.
+
+use crate::options::Options;
+use crate::reader::{fiemap_file, read_path_with_extents};
+use crate::state::State;
+
+use blkmap::{ExtentFlags, FiemapExtent};
+use blkpath::ResolveDevice;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Identifies the device extents were captured on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceIdentity {
+    /// Path of the block device.
+    pub path: PathBuf,
+
+    /// Device number (`st_dev`) of the filesystem holding the file.
+    pub id: u64,
+}
+
+impl DeviceIdentity {
+    /// Whether `file` is on this device.
+    pub fn matches(&self, file: &File) -> io::Result<bool> {
+        Ok(file.metadata()?.dev() == self.id)
+    }
+}
+
+/// The extent map of a file, with what is needed to check it still applies.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{ExtentMap, Options};
+/// use std::fs::File;
+///
+/// // After fallocate and fdatasync
+/// let map = ExtentMap::capture(&File::open("/data/file")?)?;
+/// map.save("/data/file.extents")?;
+///
+/// // Later, possibly after the file was deleted
+/// let map = ExtentMap::load("/data/file.extents")?;
+/// let mut buf = vec![0u8; map.file_size as usize];
+/// map.read_at("/data/file", &mut buf, 0, &Options::new())?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExtentMap {
+    /// The file's extents, in logical order.
+    #[cfg_attr(feature = "serde", serde(with = "crate::persist::extents"))]
+    pub extents: Vec<FiemapExtent>,
+
+    /// Size of the file in bytes.
+    pub file_size: u64,
+
+    /// The device holding the extents.
+    pub device: DeviceIdentity,
+}
+
+impl ExtentMap {
+    /// Version of the format written by [`save`](ExtentMap::save).
+    pub const VERSION: u64 = 1;
+
+    /// Magic number at the start of saved files.
+    const MAGIC: &'static [u8; 8] = b"BLKEXTMP";
+
+    /// Capture the extent map of `file`, resolving its block device.
+    pub fn capture(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(Self {
+            extents: fiemap_file(file)?,
+            file_size: metadata.len(),
+            device: DeviceIdentity {
+                path: file.resolve_device()?,
+                id: metadata.dev(),
+            },
+        })
+    }
+
+    /// Read the file at `path` through the map, also if it was deleted.
+    ///
+    /// Like [`BlkReader::blk_read_with_extents`](crate::BlkReader::blk_read_with_extents)
+    /// with the map's extents, but the file, or if it is gone its nearest
+    /// existing parent directory, must be on the map's device. This stops
+    /// at a mount boundary left by an unmounted filesystem instead of
+    /// reading the device of the filesystem below it.
+    pub fn read_at(
+        &self,
+        path: impl AsRef<Path>,
+        buf: &mut [u8],
+        offset: u64,
+        options: &Options,
+    ) -> io::Result<State> {
+        read_path_with_extents(
+            path.as_ref(),
+            buf,
+            offset,
+            &self.extents,
+            Some(&self.device),
+            options,
+        )
+    }
+
+    /// Encode the map in the format written by [`save`](ExtentMap::save).
+    ///
+    /// The encoding starts with a magic number and the format version,
+    /// followed by the file size, the device number, the device path and
+    /// the extents as little-endian integers, and ends with a CRC-32 of
+    /// everything before it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let path = self.device.path.as_os_str().as_bytes();
+        let mut out = Self::MAGIC.to_vec();
+        let fields = [
+            Self::VERSION,
+            self.file_size,
+            self.device.id,
+            path.len() as u64,
+        ];
+        for field in fields {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+        out.extend_from_slice(path);
+        out.extend_from_slice(&(self.extents.len() as u64).to_le_bytes());
+        for extent in &self.extents {
+            let fields = [
+                extent.logical,
+                extent.physical,
+                extent.length,
+                extent.flags.bits() as u64,
+            ];
+            for field in fields {
+                out.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        let crc = crc32fast::hash(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// Decode a map encoded with [`to_bytes`](ExtentMap::to_bytes).
+    ///
+    /// Fails with [`InvalidData`](io::ErrorKind::InvalidData) if the data
+    /// is not such a map, is of another version, or its checksum does not
+    /// match its contents.
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid extent map: {}", what),
+            )
+        };
+        let body = data
+            .strip_prefix(Self::MAGIC)
+            .ok_or_else(|| invalid("not an extent map"))?;
+        let (body, crc) = body
+            .split_at_checked(body.len().saturating_sub(4))
+            .filter(|(_, crc)| crc.len() == 4)
+            .ok_or_else(|| invalid("truncated"))?;
+        let mut fields = Fields(body);
+        let version = fields.u64().ok_or_else(|| invalid("truncated"))?;
+        if version != Self::VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        if crc32fast::hash(&data[..data.len() - 4]).to_le_bytes() != crc {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let truncated = || invalid("truncated");
+        let file_size = fields.u64().ok_or_else(truncated)?;
+        let id = fields.u64().ok_or_else(truncated)?;
+        let path_len = fields.u64().ok_or_else(truncated)?;
+        let path = fields.bytes(path_len).ok_or_else(truncated)?;
+        let count = fields.u64().ok_or_else(truncated)?;
+        let extents = (0..count)
+            .map(|_| {
+                let mut field = || fields.u64().ok_or_else(truncated);
+                Ok(FiemapExtent {
+                    logical: field()?,
+                    physical: field()?,
+                    length: field()?,
+                    flags: ExtentFlags::from_bits_retain(
+                        u32::try_from(field()?).map_err(|_| invalid("malformed flags"))?,
+                    ),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        if !fields.0.is_empty() {
+            return Err(invalid("trailing data"));
+        }
+        Ok(Self {
+            extents,
+            file_size,
+            device: DeviceIdentity {
+                path: PathBuf::from(OsStr::from_bytes(path)),
+                id,
+            },
+        })
+    }
+
+    /// Save the map to `path`, durably.
+    ///
+    /// The map is written to a temporary file next to `path`, synced and
+    /// renamed over `path`, so a crash leaves either the old or the new map.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let data = self.to_bytes();
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp = path.with_file_name(temp_name);
+
+        let mut file = File::create(&temp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+
+    /// Load a map saved with [`save`](ExtentMap::save).
+    ///
+    /// See [`from_bytes`](ExtentMap::from_bytes) for the checks made.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+/// Cursor over the fields of an encoded map.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, len: u64) -> Option<&'a [u8]> {
+        let (head, rest) = self.0.split_at_checked(usize::try_from(len).ok()?)?;
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ExtentMap {
+        ExtentMap {
+            extents: vec![
+                FiemapExtent {
+                    logical: 0,
+                    physical: 1 << 20,
+                    length: 4096,
+                    flags: ExtentFlags::empty(),
+                },
+                FiemapExtent {
+                    logical: 8192,
+                    physical: 1 << 30,
+                    length: 8192,
+                    flags: ExtentFlags::UNWRITTEN | ExtentFlags::LAST,
+                },
+            ],
+            file_size: 16000,
+            device: DeviceIdentity {
+                path: PathBuf::from("/dev/disk \"one\""),
+                id: 0x803,
+            },
+        }
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let map = sample();
+        let data = map.to_bytes();
+        assert!(data.starts_with(b"BLKEXTMP"));
+        assert_eq!(ExtentMap::from_bytes(&data).unwrap(), map);
+
+        let empty = ExtentMap {
+            extents: Vec::new(),
+            ..map
+        };
+        assert_eq!(ExtentMap::from_bytes(&empty.to_bytes()).unwrap(), empty);
+
+        // Device paths need not be valid UTF-8
+        let raw = ExtentMap {
+            device: DeviceIdentity {
+                path: PathBuf::from(OsStr::from_bytes(b"/dev/\xff")),
+                id: 1,
+            },
+            ..empty
+        };
+        assert_eq!(ExtentMap::from_bytes(&raw.to_bytes()).unwrap(), raw);
+    }
+
+    #[test]
+    fn test_from_bytes_rejects() {
+        let data = sample().to_bytes();
+        let reject = |data: &[u8], what: &str| {
+            let err = ExtentMap::from_bytes(data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(what), "{}", err);
+        };
+
+        // A flipped bit in the extents
+        let mut flipped = data.clone();
+        flipped[data.len() - 20] ^= 1;
+        reject(&flipped, "checksum mismatch");
+        let mut newer = data.clone();
+        newer[8] = 2;
+        reject(&newer, "unsupported version 2");
+        let mut other = data.clone();
+        other[..8].copy_from_slice(b"BLKEXTC1");
+        reject(&other, "not an extent map");
+        reject(&data[..10], "truncated");
+
+        // Fields not matching their counts, under a valid checksum
+        let resealed = |body: &[u8]| {
+            let mut data = body.to_vec();
+            let crc = crc32fast::hash(&data);
+            data.extend_from_slice(&crc.to_le_bytes());
+            data
+        };
+        let body = &data[..data.len() - 4];
+        reject(&resealed(&body[..body.len() - 8]), "truncated");
+        reject(&resealed(&[body, &[0; 8]].concat()), "trailing data");
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.extents");
+        let map = sample();
+        map.save(&path).unwrap();
+        assert_eq!(ExtentMap::load(&path).unwrap(), map);
+        assert!(!dir.path().join("file.extents.tmp").exists());
+
+        // Saving again replaces the map
+        let other = ExtentMap {
+            file_size: 4096,
+            ..map
+        };
+        other.save(&path).unwrap();
+        assert_eq!(ExtentMap::load(&path).unwrap(), other);
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x42; 8192]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let map = ExtentMap::capture(temp.as_file()).unwrap();
+        assert_eq!(map.file_size, 8192);
+        assert!(!map.extents.is_empty());
+        assert!(map.device.matches(temp.as_file()).unwrap());
+        assert_eq!(map.device.path, temp.as_file().resolve_device().unwrap());
+    }
+
+    #[test]
+    fn test_read_at_deleted() {
+        use crate::buffer::AlignedBuf;
+        use crate::test_support::image_of;
+        use crate::BlkReader;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, [0x5a; 4096]).unwrap();
+        let file = File::open(&path).unwrap();
+        file.sync_all().unwrap();
+        let mut map = ExtentMap::capture(&file).unwrap();
+        let (_image, options) = image_of(&file);
+        drop(file);
+        fs::remove_file(&path).unwrap();
+
+        // The parent directory is still on the map's filesystem
+        let mut buf = AlignedBuf::new(4096, 4096);
+        let state = map.read_at(&path, &mut buf, 0, &options).unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert!(buf.iter().all(|&b| b == 0x5a));
+
+        // A parent on another filesystem means it is no longer mounted
+        map.device.id ^= 1;
+        let err = map.read_at(&path, &mut buf, 0, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // Without the map's device, only a device given explicitly is read
+        let err = path
+            .blk_read_with_extents(&mut buf, 0, &map.extents, &Options::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("with_device_override"));
+        let state = path
+            .blk_read_with_extents(&mut buf, 0, &map.extents, &options)
+            .unwrap();
+        assert_eq!(state.bytes_read, 4096);
+
+        // An existing file on another device does not match the map
+        File::create(&path).unwrap();
+        let err = map.read_at(&path, &mut buf, 0, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
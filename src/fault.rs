@@ -0,0 +1,162 @@
+//! Fault injection for device reads.
+//!
+//! Applications handling failed and partial reads (with
+//! [`Options::retry`](crate::Options::retry),
+//! [`Options::best_effort`](crate::Options::best_effort) or their own logic)
+//! need those failures to test against. With the `fault-injection` feature,
+//! [`Options::with_fault_injection`](crate::Options::with_fault_injection)
+//! takes a [`FaultPlan`] naming the file ranges whose device reads fail with
+//! a chosen errno or come up short.
+
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// What happens to a device read hit by a [`Fault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    /// Fail the read with this errno, such as `libc::EIO`.
+    Error(i32),
+    /// Return at most this many bytes of the read.
+    Short(usize),
+}
+
+/// A fault hitting the device reads of a logical file range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    /// Logical file range; reads covering any of it are hit.
+    pub logical: Range<u64>,
+    /// What happens to the reads.
+    pub action: FaultAction,
+    /// How many reads are hit, `None` for all of them.
+    pub times: Option<usize>,
+}
+
+impl Fault {
+    /// Fail the reads of `logical` with `errno`.
+    pub fn error(logical: Range<u64>, errno: i32) -> Self {
+        Self {
+            logical,
+            action: FaultAction::Error(errno),
+            times: None,
+        }
+    }
+
+    /// Cut the reads of `logical` short after `bytes` bytes.
+    pub fn short(logical: Range<u64>, bytes: usize) -> Self {
+        Self {
+            logical,
+            action: FaultAction::Short(bytes),
+            times: None,
+        }
+    }
+
+    /// Only hit the first `times` reads, as for a transient failure.
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = Some(times);
+        self
+    }
+}
+
+/// Faults to inject into device reads.
+///
+/// Clones share the counts of reads hit, so a fault with
+/// [`times`](Fault::times) fires that often across all of them.
+///
+/// # Example
+///
+/// ```
+/// use blkreader::{Fault, FaultPlan, Options};
+///
+/// // The first read of the second block fails, later ones succeed
+/// let plan = FaultPlan::new().with_fault(Fault::error(4096..8192, libc::EIO).times(1));
+/// let options = Options::new().with_fault_injection(plan);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FaultPlan {
+    faults: Vec<(Fault, Arc<AtomicUsize>)>,
+}
+
+impl FaultPlan {
+    /// Create a plan without faults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `fault` to the plan. Reads hit by several faults get the first.
+    pub fn with_fault(mut self, fault: Fault) -> Self {
+        self.faults.push((fault, Arc::default()));
+        self
+    }
+
+    /// The faults of the plan.
+    pub fn faults(&self) -> impl Iterator<Item = &Fault> {
+        self.faults.iter().map(|(fault, _)| fault)
+    }
+
+    /// Apply the plan to `result`, that of a device read covering the
+    /// `logical` file ranges.
+    pub(crate) fn apply(
+        &self,
+        logical: &[Range<u64>],
+        result: io::Result<usize>,
+    ) -> io::Result<usize> {
+        let hit = |fault: &Fault| {
+            logical
+                .iter()
+                .any(|range| range.start < fault.logical.end && fault.logical.start < range.end)
+        };
+        let Some((fault, _)) = self.faults.iter().find(|(fault, hits)| {
+            hit(fault)
+                && hits
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                        (n < fault.times.unwrap_or(usize::MAX)).then_some(n + 1)
+                    })
+                    .is_ok()
+        }) else {
+            return result;
+        };
+        match fault.action {
+            FaultAction::Error(errno) => Err(io::Error::from_raw_os_error(errno)),
+            FaultAction::Short(bytes) => result.map(|n| n.min(bytes)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fault_plan() {
+        let plan = FaultPlan::new()
+            .with_fault(Fault::error(4096..8192, libc::EIO).times(2))
+            .with_fault(Fault::short(0..16384, 100));
+        let errno = |result: io::Result<usize>| result.map_err(|e| e.raw_os_error());
+        let one = |range: Range<u64>| [range];
+
+        // Reads outside any fault pass through
+        assert_eq!(errno(plan.apply(&one(16384..20480), Ok(4096))), Ok(4096));
+        // The first fault fires twice, across clones, then the second takes over
+        let clone = plan.clone();
+        assert_eq!(
+            errno(plan.apply(&[0..1024, 8000..9000], Ok(4096))),
+            Err(Some(libc::EIO))
+        );
+        assert_eq!(
+            errno(clone.apply(&one(4096..8192), Ok(4096))),
+            Err(Some(libc::EIO))
+        );
+        assert_eq!(errno(plan.apply(&one(4096..8192), Ok(4096))), Ok(100));
+        // Errors of the read itself are kept by short faults
+        assert_eq!(
+            errno(plan.apply(
+                &one(0..4096),
+                Err(io::Error::from_raw_os_error(libc::ENXIO))
+            )),
+            Err(Some(libc::ENXIO))
+        );
+        assert_eq!(plan.faults().count(), 2);
+    }
+}
@@ -0,0 +1,411 @@
+//! Opening block devices in a privileged helper process.
+//!
+//! Instead of running as root, an application can leave opening devices
+//! to a small privileged helper, such as `sudo blkreader device-helper` or
+//! a root daemon calling [`serve_device_helper`], which receives device
+//! paths over a Unix socket and passes the opened descriptors back
+//! (`SCM_RIGHTS`). [`DeviceHelper`] is the application's side, a
+//! [`DeviceBackend`] reads use like any other.
+//!
+//! A descriptor on a block device gives access to every file on it, so
+//! the helper only serves the users and devices its [`HelperPolicy`]
+//! allows.
+//!
+//! Each request is a byte telling whether to open the device with
+//! `O_DIRECT`, the path's length as a little-endian `u32`, and the path.
+//! Each response is a little-endian `i32`: 0 with the descriptor attached,
+//! or the `errno` opening failed with.
+
+use crate::backend::DeviceBackend;
+use crate::sys;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+
+/// Longest device path a helper accepts.
+const MAX_PATH_LEN: usize = libc::PATH_MAX as usize;
+
+/// A device backend receiving device handles from a privileged helper.
+///
+/// Devices are resolved in this process, which needs no privileges, and
+/// opened by the helper. Received descriptors are kept, so each device is
+/// only requested once per `O_DIRECT` setting. Reads with
+/// [`Options::with_device_backend`](crate::Options::with_device_backend)
+/// set to the helper then never need root; the files themselves must be
+/// readable.
+///
+/// Requests for devices the helper's [`HelperPolicy`] does not allow fail
+/// with `EACCES`.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkReader, DeviceHelper, Options};
+/// use std::path::Path;
+/// use std::process::Command;
+///
+/// let mut command = Command::new("sudo");
+/// command.args(["blkreader", "device-helper", "--allow-device", "/dev/nvme0n1p2"]);
+/// let options = Options::new().with_device_backend(DeviceHelper::spawn(command)?);
+/// let data = Path::new("/data/file").blk_read_to_end(&options)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct DeviceHelper {
+    stream: Mutex<UnixStream>,
+    handles: Mutex<HashMap<(PathBuf, bool), File>>,
+    child: Option<Child>,
+}
+
+impl DeviceHelper {
+    /// Use the helper at the other end of `stream`, e.g. a daemon the
+    /// application connected to.
+    pub fn new(stream: UnixStream) -> Self {
+        Self {
+            stream: Mutex::new(stream),
+            handles: Mutex::new(HashMap::new()),
+            child: None,
+        }
+    }
+
+    /// Run `command` as the helper, serving on its standard input.
+    ///
+    /// The helper exits once the `DeviceHelper` is dropped, which waits
+    /// for it.
+    pub fn spawn(mut command: Command) -> io::Result<Self> {
+        let (ours, theirs) = UnixStream::pair()?;
+        let child = command.stdin(Stdio::from(OwnedFd::from(theirs))).spawn()?;
+        let mut helper = Self::new(ours);
+        helper.child = Some(child);
+        Ok(helper)
+    }
+
+    /// Ask the helper to open the device at `path`.
+    fn request(&self, path: &Path, direct: bool) -> io::Result<File> {
+        let path = path.as_os_str().as_bytes();
+        let mut request = vec![direct as u8];
+        request.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        request.extend_from_slice(path);
+
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(&request)?;
+        let mut status = [0u8; 4];
+        let mut received = 0;
+        let mut file = None;
+        while received < status.len() {
+            let (len, fd) = sys::recv_with_fd(stream.as_raw_fd(), &mut status[received..])?;
+            if len == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "device helper exited",
+                ));
+            }
+            received += len;
+            file = file.or(fd);
+        }
+        match (i32::from_le_bytes(status), file) {
+            (0, Some(file)) => Ok(file),
+            (0, None) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "device helper sent no descriptor",
+            )),
+            (errno, _) => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+impl DeviceBackend for DeviceHelper {
+    fn open(&self, path: &Path, direct: bool) -> io::Result<File> {
+        let key = (path.to_path_buf(), direct);
+        if let Some(file) = self.handles.lock().unwrap().get(&key) {
+            return file.try_clone();
+        }
+        let file = self.request(path, direct)?;
+        let clone = file.try_clone()?;
+        self.handles.lock().unwrap().insert(key, file);
+        Ok(clone)
+    }
+}
+
+impl Drop for DeviceHelper {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            // The helper exits at the end of its input
+            if let Ok(stream) = self.stream.get_mut() {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+            let _ = child.wait();
+        }
+    }
+}
+
+/// The users and block devices a device helper serves.
+///
+/// Nothing is allowed by default: a descriptor on a block device exposes
+/// every file on it, so only users who may read the whole device should be
+/// allowed, and only the devices they need.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{serve_device_helper, HelperPolicy};
+/// use std::os::unix::net::UnixListener;
+///
+/// let policy = HelperPolicy::new()
+///     .allow_user(1000)
+///     .allow_device("/dev/nvme0n1p2")?;
+/// let listener = UnixListener::bind("/run/blkreader.sock")?;
+/// for stream in listener.incoming() {
+///     serve_device_helper(stream?, &policy)?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HelperPolicy {
+    users: HashSet<u32>,
+    devices: HashSet<u64>,
+}
+
+impl HelperPolicy {
+    /// A policy allowing no users and no devices.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve processes running as user `uid`, as reported for the socket's
+    /// peer with `SO_PEERCRED`.
+    pub fn allow_user(mut self, uid: u32) -> Self {
+        self.users.insert(uid);
+        self
+    }
+
+    /// Allow opening the block device at `path`, identified by its device
+    /// number, whichever path it is later requested by.
+    ///
+    /// Fails with `ENOTBLK` if `path` is not a block device.
+    pub fn allow_device(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        self.devices
+            .insert(block_device_number(&fs::metadata(path)?)?);
+        Ok(self)
+    }
+
+    /// Check that the file `metadata` describes is an allowed block device.
+    fn check_device(&self, metadata: &fs::Metadata) -> io::Result<()> {
+        match self.devices.contains(&block_device_number(metadata)?) {
+            true => Ok(()),
+            false => Err(io::Error::from_raw_os_error(libc::EACCES)),
+        }
+    }
+}
+
+/// Device number of the block device `metadata` describes.
+fn block_device_number(metadata: &fs::Metadata) -> io::Result<u64> {
+    match metadata.file_type().is_block_device() {
+        true => Ok(metadata.rdev()),
+        false => Err(io::Error::from_raw_os_error(libc::ENOTBLK)),
+    }
+}
+
+/// Serve requests to open block devices on `stream` until the other end
+/// closes it.
+///
+/// This is the helper's side of [`DeviceHelper`], to be run with the
+/// privileges to open the devices. Requests are answered with `EACCES`
+/// unless the peer's user and the device are allowed by `policy`, and with
+/// `ENOTBLK` for files that are not block devices. Devices are only opened
+/// for reading. Malformed requests end serving with an `InvalidData`
+/// error.
+pub fn serve_device_helper(stream: UnixStream, policy: &HelperPolicy) -> io::Result<()> {
+    let user_allowed =
+        sys::peer_uid(stream.as_raw_fd()).is_ok_and(|uid| policy.users.contains(&uid));
+    serve_with(stream, |path, direct| match user_allowed {
+        true => open_block_device(path, direct, policy),
+        false => Err(io::Error::from_raw_os_error(libc::EACCES)),
+    })
+}
+
+/// Serve requests on `stream`, opening devices with `open`.
+fn serve_with(
+    mut stream: UnixStream,
+    open: impl Fn(&Path, bool) -> io::Result<File>,
+) -> io::Result<()> {
+    loop {
+        let mut header = [0u8; 5];
+        match stream.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let len = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+        if header[0] > 1 || len > MAX_PATH_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed device helper request",
+            ));
+        }
+        let mut path = vec![0u8; len];
+        stream.read_exact(&mut path)?;
+
+        let opened = open(Path::new(OsStr::from_bytes(&path)), header[0] == 1);
+        let errno = match &opened {
+            Ok(_) => 0,
+            Err(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        };
+        let status = i32::to_le_bytes(errno);
+        let fd = opened.as_ref().ok().map(|file| file.as_raw_fd());
+        let sent = sys::send_with_fd(stream.as_raw_fd(), &status, fd)?;
+        stream.write_all(&status[sent..])?;
+    }
+}
+
+/// Open the block device at `path` for reading, if `policy` allows it.
+fn open_block_device(path: &Path, direct: bool, policy: &HelperPolicy) -> io::Result<File> {
+    // Checked before opening, as opening some files has side effects
+    policy.check_device(&fs::metadata(path)?)?;
+    // Opening a FIFO or a terminal, which the path may have been replaced
+    // by, would block or change its state before the file type is checked
+    let flags = libc::O_NONBLOCK | libc::O_NOCTTY;
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(flags | if direct { libc::O_DIRECT } else { 0 })
+        .open(path)?;
+    // And again for the file opened: the path may have been replaced since
+    policy.check_device(&file.metadata()?)?;
+    sys::clear_nonblock(file.as_raw_fd())?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+    use crate::reader::BlkReader;
+    use crate::test_support::file_on_image;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_device_helper() {
+        let (temp, _) = file_on_image(&[0x88; 8192]);
+
+        // A helper serving the image in place of every device
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = Arc::clone(&requests);
+        let image_path = temp.image.path().to_path_buf();
+        let server = thread::spawn(move || {
+            serve_with(theirs, |path, _| {
+                served.fetch_add(1, Ordering::Relaxed);
+                match path.starts_with("/dev") {
+                    true => File::open(&image_path),
+                    false => Err(io::Error::from_raw_os_error(libc::ENOTBLK)),
+                }
+            })
+        });
+
+        let helper = DeviceHelper::new(ours);
+        let err = helper.open(Path::new("/etc/passwd"), false).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTBLK));
+
+        let options = Options::new()
+            .with_direct(false)
+            .with_device_backend(helper);
+        for _ in 0..2 {
+            let mut buf = vec![0u8; 8192];
+            let state = temp.path().blk_read_at_opt(&mut buf, 0, &options).unwrap();
+            assert_eq!(state.bytes_read, 8192);
+            assert!(!state.used_fallback);
+            assert!(buf.iter().all(|&b| b == 0x88));
+        }
+        // The device's descriptor is kept after the first read
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+
+        drop(options);
+        server.join().unwrap().unwrap();
+    }
+
+    /// Paths of two block devices of the host, if it has them.
+    fn block_devices() -> Option<(PathBuf, PathBuf)> {
+        let mut devices = fs::read_dir("/dev")
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_block_device()))
+            .map(|entry| entry.path());
+        Some((devices.next()?, devices.next()?))
+    }
+
+    /// Serve `policy` on a thread, returning the client's side.
+    fn serve(policy: HelperPolicy) -> (DeviceHelper, thread::JoinHandle<io::Result<()>>) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || serve_device_helper(theirs, &policy));
+        (DeviceHelper::new(ours), server)
+    }
+
+    #[test]
+    fn test_serve_device_helper() {
+        // SAFETY: getuid has no preconditions and cannot fail.
+        let uid = unsafe { libc::getuid() };
+        let policy = HelperPolicy::new().allow_user(uid);
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let err = policy.clone().allow_device(file.path()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTBLK));
+
+        let devices = block_devices();
+        let policy = match &devices {
+            Some((allowed, _)) => policy.allow_device(allowed).unwrap(),
+            None => policy,
+        };
+        let (helper, server) = serve(policy.clone());
+
+        // Only block devices are opened
+        let err = helper.request(file.path(), false).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTBLK));
+        let err = helper.request(Path::new("/dev/null"), false).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTBLK));
+        let err = helper.request(Path::new("/nonexistent"), true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        // And only allowed ones
+        if let Some((allowed, other)) = &devices {
+            let err = helper.request(other, false).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+            // Served as this process could open it itself
+            let direct = File::open(allowed)
+                .map(|_| ())
+                .map_err(|e| e.raw_os_error());
+            let served = helper.request(allowed, false);
+            assert_eq!(served.map(|_| ()).map_err(|e| e.raw_os_error()), direct);
+        }
+        drop(helper);
+        server.join().unwrap().unwrap();
+
+        // Users not allowed are refused everything
+        let (helper, server) = serve(HelperPolicy {
+            users: HashSet::new(),
+            ..policy
+        });
+        let err = helper.request(file.path(), false).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        if let Some((allowed, _)) = &devices {
+            let err = helper.request(allowed, false).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        }
+        drop(helper);
+        server.join().unwrap().unwrap();
+
+        // Malformed requests end serving
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || serve_device_helper(theirs, &HelperPolicy::new()));
+        ours.write_all(&[7, 0, 0, 0, 0]).unwrap();
+        let err = server.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,289 @@
+//! File layout validation.
+//!
+//! Some consumers — swapfiles, hypervisor backing files, and the
+//! fallocate + fdatasync recovery scheme this crate is built for — require
+//! that a file is fully allocated, hole-free, not subject to copy-on-write,
+//! and sometimes physically contiguous. This module checks those
+//! constraints using the file's extent map.
+
+use crate::btrfs::is_btrfs;
+use crate::reader::{fiemap_failed, fiemap_file};
+use crate::sys;
+
+use blkmap::FiemapExtent;
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// A single violation of the layout constraints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutViolation {
+    /// The file is empty.
+    Empty,
+    /// A range of the file is not backed by any extent.
+    Hole { logical: u64, length: u64 },
+    /// An extent is allocated but unwritten.
+    Unwritten { logical: u64, length: u64 },
+    /// An extent has no stable physical location yet (delalloc or unknown).
+    Unmapped { logical: u64, length: u64 },
+    /// An extent is shared with other files (reflink or dedup).
+    Shared { logical: u64, length: u64 },
+    /// An extent is inline, encoded, or encrypted and cannot be read raw.
+    NotRaw { logical: u64, length: u64 },
+    /// The file lives on a copy-on-write filesystem without `NOCOW` set.
+    CopyOnWrite,
+    /// An extent does not physically follow the previous one.
+    Discontiguous {
+        logical: u64,
+        expected_physical: u64,
+        physical: u64,
+    },
+}
+
+impl fmt::Display for LayoutViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutViolation::Empty => write!(f, "file is empty"),
+            LayoutViolation::Hole { logical, length } => {
+                write!(f, "hole at {:#x} ({} bytes)", logical, length)
+            }
+            LayoutViolation::Unwritten { logical, length } => {
+                write!(f, "unwritten extent at {:#x} ({} bytes)", logical, length)
+            }
+            LayoutViolation::Unmapped { logical, length } => {
+                write!(f, "unmapped extent at {:#x} ({} bytes)", logical, length)
+            }
+            LayoutViolation::Shared { logical, length } => {
+                write!(f, "shared extent at {:#x} ({} bytes)", logical, length)
+            }
+            LayoutViolation::NotRaw { logical, length } => write!(
+                f,
+                "inline, encoded or encrypted extent at {:#x} ({} bytes)",
+                logical, length
+            ),
+            LayoutViolation::CopyOnWrite => {
+                write!(f, "file is subject to copy-on-write (NOCOW not set)")
+            }
+            LayoutViolation::Discontiguous {
+                logical,
+                expected_physical,
+                physical,
+            } => write!(
+                f,
+                "extent at {:#x} is at physical {:#x}, expected {:#x}",
+                logical, physical, expected_physical
+            ),
+        }
+    }
+}
+
+/// Result of validating a file's layout.
+#[derive(Debug, Clone)]
+pub struct LayoutReport {
+    /// Size of the file in bytes.
+    pub file_size: u64,
+
+    /// The file's extents.
+    pub extents: Vec<FiemapExtent>,
+
+    /// All violations found, in logical order.
+    pub violations: Vec<LayoutViolation>,
+}
+
+impl LayoutReport {
+    /// Returns true if no violations were found.
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Inode flag disabling copy-on-write (`FS_NOCOW_FL`).
+const FS_NOCOW_FL: u32 = 0x00800000;
+
+/// Validate that a file is fully allocated, hole-free, and non-COW.
+///
+/// When `require_contiguous` is set, the file's extents must additionally
+/// be physically contiguous, as required by swapfiles.
+///
+/// # Example
+///
+/// ```no_run
+/// let report = blkreader::blk_validate_contiguous("/swapfile", true).unwrap();
+/// for violation in &report.violations {
+///     println!("{}", violation);
+/// }
+/// ```
+pub fn blk_validate_contiguous<P: AsRef<Path>>(
+    path: P,
+    require_contiguous: bool,
+) -> io::Result<LayoutReport> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let extents = fiemap_file(&file).map_err(fiemap_failed(&file, Some(path)))?;
+
+    let mut violations = Vec::new();
+    if is_btrfs(&file)? && sys::inode_flags(file.as_raw_fd())? & FS_NOCOW_FL == 0 {
+        violations.push(LayoutViolation::CopyOnWrite);
+    }
+    violations.extend(check_extents(&extents, file_size, require_contiguous));
+
+    Ok(LayoutReport {
+        file_size,
+        extents,
+        violations,
+    })
+}
+
+/// Check an extent map against the layout constraints.
+fn check_extents(
+    extents: &[FiemapExtent],
+    file_size: u64,
+    require_contiguous: bool,
+) -> Vec<LayoutViolation> {
+    let mut violations = Vec::new();
+    if file_size == 0 {
+        violations.push(LayoutViolation::Empty);
+        return violations;
+    }
+
+    let mut current = 0u64;
+    let mut previous: Option<&FiemapExtent> = None;
+
+    for extent in extents {
+        if current >= file_size {
+            break;
+        }
+
+        let logical = extent.logical;
+        let length = extent.length;
+
+        if logical > current {
+            violations.push(LayoutViolation::Hole {
+                logical: current,
+                length: logical - current,
+            });
+        }
+
+        let flags = extent.flags;
+        if flags.is_unwritten() {
+            violations.push(LayoutViolation::Unwritten { logical, length });
+        }
+        if flags.is_unknown() || flags.is_delalloc() {
+            violations.push(LayoutViolation::Unmapped { logical, length });
+        }
+        if flags.is_shared() {
+            violations.push(LayoutViolation::Shared { logical, length });
+        }
+        if flags.is_inline() || flags.is_encoded() || flags.is_encrypted() {
+            violations.push(LayoutViolation::NotRaw { logical, length });
+        }
+
+        if let Some(prev) = previous.filter(|_| require_contiguous) {
+            let expected_physical = prev.physical + prev.length;
+            if extent.physical != expected_physical {
+                violations.push(LayoutViolation::Discontiguous {
+                    logical,
+                    expected_physical,
+                    physical: extent.physical,
+                });
+            }
+        }
+
+        current = current.max(logical + length);
+        previous = Some(extent);
+    }
+
+    if current < file_size {
+        violations.push(LayoutViolation::Hole {
+            logical: current,
+            length: file_size - current,
+        });
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blkmap::ExtentFlags;
+
+    fn extent(logical: u64, physical: u64, length: u64, flags: ExtentFlags) -> FiemapExtent {
+        FiemapExtent {
+            logical,
+            physical,
+            length,
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_check_extents_valid() {
+        let extents = vec![
+            extent(0, 4096, 4096, ExtentFlags::empty()),
+            extent(4096, 8192, 4096, ExtentFlags::LAST),
+        ];
+        assert!(check_extents(&extents, 8192, true).is_empty());
+    }
+
+    #[test]
+    fn test_check_extents_violations() {
+        let extents = vec![
+            extent(0, 4096, 4096, ExtentFlags::UNWRITTEN),
+            extent(8192, 65536, 4096, ExtentFlags::SHARED),
+        ];
+        let violations = check_extents(&extents, 16384, true);
+        assert_eq!(
+            violations,
+            vec![
+                LayoutViolation::Unwritten {
+                    logical: 0,
+                    length: 4096
+                },
+                LayoutViolation::Hole {
+                    logical: 4096,
+                    length: 4096
+                },
+                LayoutViolation::Shared {
+                    logical: 8192,
+                    length: 4096
+                },
+                LayoutViolation::Discontiguous {
+                    logical: 8192,
+                    expected_physical: 8192,
+                    physical: 65536
+                },
+                LayoutViolation::Hole {
+                    logical: 12288,
+                    length: 4096
+                },
+            ]
+        );
+
+        // Discontiguity is only reported when requested
+        assert_eq!(check_extents(&extents, 16384, false).len(), 4);
+    }
+
+    #[test]
+    fn test_check_extents_empty() {
+        assert_eq!(check_extents(&[], 0, false), vec![LayoutViolation::Empty]);
+    }
+
+    #[test]
+    fn test_validate_sparse_file() {
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        temp.as_file().set_len(1 << 20).unwrap();
+
+        let report = blk_validate_contiguous(temp.path(), false).unwrap();
+        assert_eq!(report.file_size, 1 << 20);
+        assert!(!report.is_valid());
+        assert!(report
+            .violations
+            .iter()
+            .any(|v| matches!(v, LayoutViolation::Hole { .. })));
+    }
+}
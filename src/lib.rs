@@ -17,23 +17,96 @@
 //!
 //! - Query file extent information using `FIEMAP` ioctl via [`blkmap`]
 //! - Resolve block device paths using [`blkpath`]
-//! - Read data directly from block devices using Direct I/O
-//! - Global block device cache for improved performance
-//! - Configurable handling of holes and unwritten extents
+//! - Read data directly from block devices using Direct I/O, or through the
+//!   device's page cache
+//! - Global block device cache for improved performance, with an optional
+//!   limit on open handles and an idle timeout via [`configure_cache`], and
+//!   hit/miss statistics via [`cache_stats`]; isolated caches via [`BlkCache`]
+//! - Configurable handling of holes and unwritten extents, and detection of
+//!   uncommitted data in unwritten extents via [`Options::check_unwritten`]
 //! - Fallback to regular file I/O when safe
+//! - Progress callbacks for long-running reads
+//! - Observer hooks for FIEMAP queries, device reads, fills and fallbacks via
+//!   [`ReadObserver`], spans and events for the `tracing` crate (with the
+//!   `tracing` feature), and histograms for the `metrics` crate (with the
+//!   `metrics` feature)
+//! - Pluggable device access via [`DeviceBackend`], a simulated device in
+//!   memory, [`MemDevice`], for tests without a real block device, and reads
+//!   from captured device images via [`DeviceSource`]
+//! - Runtime capability report via [`capabilities`]
+//! - Process-wide counters of bytes read and filled, FIEMAP queries and
+//!   errors via [`stats`], published through the `metrics` crate via
+//!   `publish_stats` (with the `metrics` feature)
+//! - Batched reads across many files via [`blk_read_many`], and warming of
+//!   device handles and extent maps ahead of them via [`warm_cache`]
+//! - Logical to physical translation via [`BlkReader::blk_map`]
+//! - Sparse copies keeping holes and, optionally, unwritten extents via
+//!   [`BlkReader::blk_copy_sparse`]
+//! - Prefetching of the device data of upcoming reads via
+//!   [`BlkReader::blk_prefetch`]
+//! - Searching a file's device data, unwritten extents included, for a byte
+//!   pattern via [`BlkReader::blk_scan`]
+//! - Translation of btrfs addresses to member devices through the chunk tree,
+//!   including RAID0 and RAID10 striping across devices
+//! - Reading from the disks below device-mapper linear, striped and mirrored
+//!   targets (LVM), and reporting the LVM volume a read went through
+//! - Detection of fscrypt and dm-crypt ciphertext before it is returned
+//! - Detection of md software RAID arrays, with experimental reads from a
+//!   healthy RAID1 member
+//! - Reading filesystem images mounted from loop devices through their
+//!   backing files
+//! - Resolution of overlayfs files to the layer holding their data
+//! - Early detection of network and FUSE filesystems, which have no local
+//!   block device
+//! - Digests of the returned data (CRC-32, xxHash64 or SHA-256) via
+//!   `Options::checksum`, also per extent or per block via
+//!   `Options::checksum_unit` (with the `checksum` feature)
+//! - Swapfile-style layout validation via [`blk_validate_contiguous`]
+//! - Verification of the device's data against the file's via
+//!   [`BlkReader::blk_verify_at`]
+//! - A versioned, checksummed binary format for saved extent maps via
+//!   [`ExtentMap`]
+//! - Extent maps cached in the process (see [`Options::cache_extents`]) and
+//!   on disk across runs via [`DiskExtentCache`]
+//! - Writing through extents for data repair via [`BlkWriter`] (opt-in)
+//! - Pluggable device read backends via [`IoEngine`], including batched
+//!   `io_uring` reads (with the `uring` feature) and native AIO
+//! - Reads of fragmented ranges spread across threads (see
+//!   [`Options::parallelism`]), kept between reads via [`ReadPool`]
+//! - Permission errors telling which capability or device access is missing
+//!   (see [`DeviceAccess`])
+//! - Opening the needed block devices as root, then continuing unprivileged,
+//!   via [`open_devices_then_drop`] or, per file of a batch,
+//!   [`open_each_device_then_drop`], or leaving opening them to a privileged
+//!   helper process via [`DeviceHelper`]
+//! - Reads through `O_PATH` descriptors, reopened via `/proc/self/fd`
+//! - Recovery of deleted files still held open, through `/proc/<pid>/fd`
+//!   or a pidfd (see [`BlkFile::open_proc_fd`])
+//! - Huge-page-backed read buffers via [`AlignedBuf::huge`], and buffers on
+//!   the NUMA node local to the device (see [`Options::buffer_node`])
+//! - Reads into owned, pooled aligned buffers via [`BlkReader::blk_read_pooled`]
+//!   and [`BufPool`], wrappable without copying, and conversion into
+//!   `bytes::Bytes` and reads into `bytes::BytesMut` (with the `bytes`
+//!   feature)
+//! - Tokio integration via `AsyncBlkReader` (with the `async` feature)
+//! - Serializable read results, options and extent maps (with the `serde`
+//!   feature), e.g. for persisting extent maps as JSON
 //!
 //! ## Direct I/O Alignment Requirements
 //!
 //! When reading directly from block devices (not using fallback mode), the following
 //! alignment requirements must be met for Direct I/O:
 //!
-//! - **Buffer alignment**: The buffer must be aligned to at least 512 bytes (sector size).
-//!   For optimal performance on modern devices, 4096-byte alignment is recommended.
-//! - **Offset alignment**: The read offset should be aligned to 512 bytes.
-//! - **Length alignment**: The read length should be aligned to 512 bytes.
+//! - **Buffer alignment**: The buffer must be aligned to at least the device's logical
+//!   sector size. For optimal performance on modern devices, 4096-byte alignment is
+//!   recommended.
+//! - **Offset alignment**: The read offset should be aligned to the logical sector size.
+//! - **Length alignment**: The read length should be aligned to the logical sector size.
 //!
-//! If alignment requirements are not met, the underlying read may fail with an
-//! `EINVAL` error. The CLI tool handles alignment automatically.
+//! The logical sector size is 512 bytes on most drives and 4096 bytes on 4K-native
+//! ones; [`BlkReader::blk_required_alignment`] queries it. Unaligned reads fail
+//! with [`BlkReadError::Unaligned`]. [`AlignedBuf`] allocates suitably aligned
+//! buffers. The CLI tool handles alignment automatically.
 //!
 //! ## Example
 //!
@@ -59,12 +132,105 @@
 //! This crate requires root privileges to read from block devices. The CLI tool
 //! automatically requests sudo permissions when needed.
 
+#[cfg(feature = "async")]
+mod async_reader;
+mod backend;
+mod batch;
+mod btrfs;
+mod buffer;
 mod cache;
+mod capabilities;
+#[cfg(feature = "checksum")]
+mod checksum;
+mod dm;
+mod engine;
+mod error;
+mod extent_cache;
+mod extent_map;
+#[cfg(feature = "fault-injection")]
+mod fault;
+mod helper;
+mod layout;
+mod loopdev;
+mod lvm;
+mod map;
+mod md;
+mod numa;
+mod observer;
 mod options;
+mod overlay;
+#[cfg(feature = "serde")]
+mod persist;
+mod pool;
+mod privilege;
+mod progress;
 mod reader;
+mod scan;
 mod state;
+mod stats;
+mod sys;
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "test-util")]
+mod test_util;
+mod throttle;
+#[cfg(feature = "uring")]
+mod uring;
+mod verify;
+mod writer;
 
+#[cfg(feature = "async")]
+pub use async_reader::{AsyncBlkFile, AsyncBlkReader};
+pub use backend::{BlockDeviceBackend, DeviceBackend, DeviceSource, MemDevice};
+pub use batch::{blk_read_many, blk_read_many_parallel, warm_cache, BlkRequest};
+pub use blkmap::ExtentFlags;
 pub use blkmap::FiemapExtent as Extent;
-pub use options::Options;
-pub use reader::BlkReader;
-pub use state::State;
+pub use buffer::{AlignedBuf, BufPool, PooledBuf};
+pub use cache::{
+    cache_config, cache_stats, configure_cache, invalidate_extents, sweep_cache, BlkCache,
+    CacheConfig, CacheStats,
+};
+pub use capabilities::{capabilities, Capabilities, DeviceAccess, Support};
+#[cfg(feature = "checksum")]
+pub use checksum::{Checksum, ChecksumUnit, Digest, UnitChecksum};
+pub use engine::{
+    Completion, DeviceRead, IoEngine, LibaioEngine, PreadvEngine, PsyncEngine, ReadFlags,
+    UringEngine,
+};
+pub use error::{BlkReadError, DeviceReadError, Encryption, PartialReadError, ShortReadError};
+pub use extent_cache::DiskExtentCache;
+pub use extent_map::{DeviceIdentity, ExtentMap};
+#[cfg(feature = "fault-injection")]
+pub use fault::{Fault, FaultAction, FaultPlan};
+pub use helper::{serve_device_helper, DeviceHelper, HelperPolicy};
+pub use layout::{blk_validate_contiguous, LayoutReport, LayoutViolation};
+pub use map::MappedRange;
+pub use observer::{ExtentReadEvent, FallbackEvent, FiemapEvent, FillEvent, ReadObserver};
+pub use options::{
+    EncodedPolicy, InlinePolicy, IoPriority, NumaNode, Options, RetryPolicy, Validator,
+};
+#[cfg(feature = "serde")]
+pub use persist::SerdeExtent;
+pub use pool::ReadPool;
+pub use privilege::{
+    open_devices_then_drop, open_devices_then_drop_opt, open_each_device_then_drop,
+};
+pub use progress::{ProgressCallback, ProgressEvent};
+#[cfg(feature = "bytes")]
+pub use reader::blk_read_bytes;
+pub use reader::{blk_read_buf_at, borrow_raw_fd, BlkFile, BlkReader, BlkSource};
+pub use scan::Match;
+pub use state::{
+    DeviceInfo, LvmVolume, MdArray, NonzeroRange, PlannedRead, ReadTiming, Segment, SegmentSource,
+    State, Timing, UnreadableRange,
+};
+pub use stats::{stats, Stats};
+#[cfg(feature = "metrics")]
+pub use telemetry::publish_stats;
+#[cfg(feature = "test-util")]
+pub use test_util::LoopFixture;
+pub use throttle::Throttle;
+pub use verify::{Mismatch, VerifyReport};
+pub use writer::BlkWriter;
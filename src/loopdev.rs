@@ -0,0 +1,126 @@
+//! Translation of loop devices to their backing files.
+//!
+//! A filesystem image mounted through `/dev/loopN` keeps its data in the
+//! loop device's backing file, starting at the loop offset. With
+//! [`Options::translate_loop`](crate::Options::translate_loop), device
+//! reads are sent to the backing file instead, which needs no access to
+//! the loop device itself.
+
+use crate::dm;
+use crate::map::Placement;
+use crate::sys;
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Suffix the kernel appends to the name of a deleted backing file.
+const DELETED_SUFFIX: &str = " (deleted)";
+
+/// The backing file of loop device `name` in the sysfs tree at `sys`, with
+/// the byte offset of the device's data in it.
+///
+/// Returns `None` if `name` is not a loop device, or has no backing file.
+fn backing_in(sys: &Path, name: &str) -> io::Result<Option<(PathBuf, u64)>> {
+    let attrs = sys.join("block").join(name).join("loop");
+    let path = match sys::read_attr(&attrs.join("backing_file")) {
+        Ok(path) => path,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if path.ends_with(DELETED_SUFFIX) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("backing file {} of {} was deleted", path, name),
+        ));
+    }
+    let offset = sys::read_attr(&attrs.join("offset"))?
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid loop offset"))?;
+    Ok(Some((PathBuf::from(path), offset)))
+}
+
+/// Translate the pieces of `placement` on loop devices to their backing
+/// files.
+///
+/// Returns `None` if no piece is on a loop device.
+pub(crate) fn place(placement: &Placement) -> io::Result<Option<Placement>> {
+    place_with(placement, Path::new("/sys"), dm::block_name)
+}
+
+/// [`place`] with the sysfs tree and device names supplied by the caller.
+fn place_with(
+    placement: &Placement,
+    sys: &Path,
+    device_name: impl Fn(&Path) -> io::Result<OsString>,
+) -> io::Result<Option<Placement>> {
+    placement.relocate(|path| backing_in(sys, &device_name(path)?.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blkmap::{ExtentFlags, FiemapExtent};
+    use std::fs;
+
+    fn loop_at(sys: &Path, name: &str, backing_file: &str, offset: u64) {
+        let attrs = sys.join("block").join(name).join("loop");
+        fs::create_dir_all(&attrs).unwrap();
+        fs::write(attrs.join("backing_file"), format!("{}\n", backing_file)).unwrap();
+        fs::write(attrs.join("offset"), format!("{}\n", offset)).unwrap();
+    }
+
+    #[test]
+    fn test_backing_in_sysfs() {
+        let sys = tempfile::tempdir().unwrap();
+        loop_at(sys.path(), "loop0", "/images/disk.img", 1 << 20);
+        loop_at(sys.path(), "loop1", "/images/old.img (deleted)", 0);
+        fs::create_dir_all(sys.path().join("block/loop2")).unwrap();
+
+        assert_eq!(
+            backing_in(sys.path(), "loop0").unwrap(),
+            Some((PathBuf::from("/images/disk.img"), 1 << 20))
+        );
+        let err = backing_in(sys.path(), "loop1").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        // Unbound loop devices and other devices have no backing file
+        assert_eq!(backing_in(sys.path(), "loop2").unwrap(), None);
+        assert_eq!(backing_in(sys.path(), "sda").unwrap(), None);
+    }
+
+    #[test]
+    fn test_place_on_backing_file() {
+        let sys = tempfile::tempdir().unwrap();
+        loop_at(sys.path(), "loop0", "/images/disk.img", 1 << 20);
+        let name = |path: &Path| Ok(path.file_name().unwrap().to_os_string());
+        let extent = |logical, physical| FiemapExtent {
+            logical,
+            physical,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        };
+
+        let mut placement = Placement::default();
+        placement.push(Some(Path::new("/dev/loop0")), extent(0, 8192));
+        placement.push(None, extent(4096, 0));
+        placement.push(Some(Path::new("/dev/sda")), extent(8192, 0));
+        let translated = place_with(&placement, sys.path(), name).unwrap().unwrap();
+        let summary: Vec<_> = translated
+            .placed
+            .iter()
+            .map(|p| (translated.path(p).map(Path::to_path_buf), p.extent.physical))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(PathBuf::from("/images/disk.img")), (1 << 20) + 8192),
+                (None, 0),
+                (Some(PathBuf::from("/dev/sda")), 0),
+            ]
+        );
+
+        let mut plain = Placement::default();
+        plain.push(Some(Path::new("/dev/sda")), extent(0, 0));
+        assert!(place_with(&plain, sys.path(), name).unwrap().is_none());
+    }
+}
@@ -0,0 +1,226 @@
+//! Detection of LVM logical volumes.
+//!
+//! A logical volume is a device-mapper device whose uuid starts with
+//! `LVM-`. Its volume group, name and the physical volumes below it are
+//! found through sysfs and reported in [`State::lvm`](crate::State::lvm),
+//! so that reads through a volume can be traced to the disks holding it.
+
+use crate::dm;
+use crate::state::LvmVolume;
+use crate::sys;
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, RwLock};
+
+/// Prefix of the device-mapper uuid of LVM logical volumes.
+pub(crate) const UUID_PREFIX: &str = "LVM-";
+
+/// Logical volumes by device path, `None` for other devices.
+static VOLUMES: LazyLock<RwLock<HashMap<PathBuf, Option<LvmVolume>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// The logical volume that is the block device at `path`.
+///
+/// Returns `None` if the device is not a logical volume, or if it could not
+/// be inspected. With `cached`, the result is remembered for the path.
+pub(crate) fn volume(path: &Path, cached: bool) -> Option<LvmVolume> {
+    if cached {
+        if let Some(volume) = VOLUMES.read().unwrap().get(path) {
+            return volume.clone();
+        }
+    }
+    let volume = detect(path).ok().flatten();
+    if cached {
+        VOLUMES
+            .write()
+            .unwrap()
+            .insert(path.to_path_buf(), volume.clone());
+    }
+    volume
+}
+
+/// Inspect the block device at `path` through `/sys`.
+fn detect(path: &Path) -> io::Result<Option<LvmVolume>> {
+    let name = dm::block_name(path)?;
+    volume_in(
+        Path::new("/sys"),
+        &name.to_string_lossy(),
+        path,
+        dm::target_kinds,
+    )
+}
+
+/// The logical volume that is block device `name` (such as `dm-0`) in the
+/// sysfs tree at `sys`, with its table's target types from `kinds`.
+fn volume_in(
+    sys: &Path,
+    name: &str,
+    path: &Path,
+    kinds: impl Fn(&str) -> io::Result<Vec<String>>,
+) -> io::Result<Option<LvmVolume>> {
+    let dm = sys.join("block").join(name).join("dm");
+    let uuid = match sys::read_attr(&dm.join("uuid")) {
+        Ok(uuid) => uuid,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if !uuid.starts_with(UUID_PREFIX) {
+        return Ok(None);
+    }
+    let dm_name = sys::read_attr(&dm.join("name"))?;
+    let Some((vg_name, lv_name)) = split_name(&dm_name) else {
+        return Ok(None);
+    };
+
+    let mut physical_volumes = Vec::new();
+    physical_volumes_in(sys, name, 0, &mut physical_volumes)?;
+    Ok(Some(LvmVolume {
+        vg_name,
+        lv_name,
+        device_path: path.to_path_buf(),
+        // The table needs CAP_SYS_ADMIN, the rest of sysfs doesn't
+        targets: kinds(&dm_name).unwrap_or_default(),
+        physical_volumes,
+    }))
+}
+
+/// Append the devices below block device `name` that are not logical
+/// volumes themselves, following those that are.
+fn physical_volumes_in(
+    sys: &Path,
+    name: &str,
+    depth: usize,
+    out: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut slaves: Vec<String> = std::fs::read_dir(sys.join("block").join(name).join("slaves"))?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<io::Result<_>>()?;
+    slaves.sort();
+    for slave in slaves {
+        // Partitions have no entry of their own under /sys/block
+        let uuid =
+            sys::read_attr(&sys.join("block").join(&slave).join("dm/uuid")).unwrap_or_default();
+        if uuid.starts_with(UUID_PREFIX) && depth < dm::MAX_DEPTH {
+            physical_volumes_in(sys, &slave, depth + 1, out)?;
+        } else {
+            let path = Path::new("/dev").join(&slave);
+            if !out.contains(&path) {
+                out.push(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Split a device-mapper name such as `vg--data-root` into the volume
+/// group and volume names, which LVM joins with `-` after doubling any `-`
+/// in them.
+fn split_name(name: &str) -> Option<(String, String)> {
+    let mut vg_name = String::new();
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '-' {
+            vg_name.push(c);
+        } else if chars.peek() == Some(&'-') {
+            chars.next();
+            vg_name.push('-');
+        } else {
+            let lv_name = chars.collect::<String>().replace("--", "-");
+            return (!vg_name.is_empty() && !lv_name.is_empty()).then_some((vg_name, lv_name));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_split_name() {
+        let names = |vg: &str, lv: &str| Some((vg.to_string(), lv.to_string()));
+        assert_eq!(split_name("vg0-root"), names("vg0", "root"));
+        assert_eq!(split_name("vg--data-lv--home"), names("vg-data", "lv-home"));
+        assert_eq!(
+            split_name("vg0-data_rimage_0"),
+            names("vg0", "data_rimage_0")
+        );
+        assert_eq!(split_name("luks-1234"), names("luks", "1234"));
+        assert_eq!(split_name("vg--only"), None);
+        assert_eq!(split_name("-root"), None);
+    }
+
+    #[test]
+    fn test_volume_in_sysfs() {
+        // dm-0 is a raid1 volume over the images dm-1 (on sdb1) and dm-2
+        // (on sdc), next to a crypt device dm-3 that is no volume
+        let sys = tempfile::tempdir().unwrap();
+        let device = |name: &str, uuid: Option<&str>, dm_name: &str, slaves: &[&str]| {
+            let dir = sys.path().join("block").join(name);
+            fs::create_dir_all(dir.join("slaves")).unwrap();
+            for slave in slaves {
+                fs::create_dir(dir.join("slaves").join(slave)).unwrap();
+            }
+            if let Some(uuid) = uuid {
+                fs::create_dir(dir.join("dm")).unwrap();
+                fs::write(dir.join("dm/uuid"), format!("{}\n", uuid)).unwrap();
+                fs::write(dir.join("dm/name"), format!("{}\n", dm_name)).unwrap();
+            }
+        };
+        device(
+            "dm-0",
+            Some("LVM-abc"),
+            "vg--data-mirror",
+            &["dm-2", "dm-1"],
+        );
+        device(
+            "dm-1",
+            Some("LVM-abd"),
+            "vg--data-mirror_rimage_0",
+            &["sdb1"],
+        );
+        device(
+            "dm-2",
+            Some("LVM-abe"),
+            "vg--data-mirror_rimage_1",
+            &["sdc"],
+        );
+        device("dm-3", Some("CRYPT-LUKS2-abc"), "luks-abc", &["sdd"]);
+        device("sdc", None, "", &[]);
+
+        let kinds = |name: &str| {
+            assert_eq!(name, "vg--data-mirror");
+            Ok(vec!["raid".to_string()])
+        };
+        let volume = volume_in(sys.path(), "dm-0", Path::new("/dev/dm-0"), kinds)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            volume,
+            LvmVolume {
+                vg_name: "vg-data".to_string(),
+                lv_name: "mirror".to_string(),
+                device_path: PathBuf::from("/dev/dm-0"),
+                targets: vec!["raid".to_string()],
+                physical_volumes: vec![PathBuf::from("/dev/sdb1"), PathBuf::from("/dev/sdc")],
+            }
+        );
+
+        // Without access to the table, the volume is still reported
+        let denied = |_: &str| Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        let volume = volume_in(sys.path(), "dm-0", Path::new("/dev/dm-0"), denied)
+            .unwrap()
+            .unwrap();
+        assert!(volume.targets.is_empty());
+
+        let none = |_: &str| Ok(Vec::new());
+        for name in ["dm-3", "sdc", "sde"] {
+            assert!(volume_in(sys.path(), name, Path::new("/dev/x"), none)
+                .unwrap()
+                .is_none());
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Logical to physical address translation.
+//!
+//! [`BlkReader::blk_map`](crate::BlkReader::blk_map) translates a logical
+//! file range into the physical device ranges backing it, without performing
+//! any I/O on the device itself.
+
+use blkmap::{ExtentFlags, FiemapExtent};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A physical device range backing part of a logical file range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedRange {
+    /// Path to the block device holding the data.
+    pub device_path: PathBuf,
+
+    /// Logical file offset of the start of the range.
+    pub logical: u64,
+
+    /// Physical byte offset on the device of the start of the range.
+    pub physical: u64,
+
+    /// Length of the range, in bytes.
+    pub length: u64,
+
+    /// Flags of the extent the range belongs to.
+    pub flags: ExtentFlags,
+}
+
+impl MappedRange {
+    /// Whether the range is shared with other files (`FIEMAP_EXTENT_SHARED`).
+    pub fn is_shared(&self) -> bool {
+        self.flags.is_shared()
+    }
+}
+
+/// Extents translated to the devices holding their data, when the
+/// filesystem's device is not where the data is, e.g. on btrfs or
+/// device-mapper.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Placement {
+    /// Paths of the devices, indexed by [`Placed::device`].
+    pub(crate) devices: Vec<PathBuf>,
+    /// Pieces of the extents, in logical order.
+    pub(crate) placed: Vec<Placed>,
+}
+
+/// Part of an extent, with its physical offset on the device holding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Placed {
+    /// Index of the device, or `None` for data not on any device.
+    pub(crate) device: Option<usize>,
+    pub(crate) extent: FiemapExtent,
+}
+
+impl Placement {
+    /// Append a piece of an extent on the device at `path`, if any.
+    pub(crate) fn push(&mut self, path: Option<&Path>, extent: FiemapExtent) {
+        let device = path.map(
+            |path| match self.devices.iter().position(|device| device == path) {
+                Some(index) => index,
+                None => {
+                    self.devices.push(path.to_path_buf());
+                    self.devices.len() - 1
+                }
+            },
+        );
+        self.placed.push(Placed { device, extent });
+    }
+
+    /// Path of the device holding `placed`, if any.
+    pub(crate) fn path(&self, placed: &Placed) -> Option<&Path> {
+        placed.device.map(|index| self.devices[index].as_path())
+    }
+
+    /// Move the pieces on each device that `relocate` maps to another
+    /// device and the byte offset of its data there.
+    ///
+    /// Returns `None` if `relocate` maps no device.
+    pub(crate) fn relocate(
+        &self,
+        mut relocate: impl FnMut(&Path) -> io::Result<Option<(PathBuf, u64)>>,
+    ) -> io::Result<Option<Placement>> {
+        let targets = self
+            .devices
+            .iter()
+            .map(|path| relocate(path))
+            .collect::<io::Result<Vec<_>>>()?;
+        if targets.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+
+        let mut relocated = Placement::default();
+        for placed in &self.placed {
+            let mut extent = placed.extent;
+            let path = placed.device.map(|index| match &targets[index] {
+                Some((path, offset)) => {
+                    extent.physical += offset;
+                    path.as_path()
+                }
+                None => self.devices[index].as_path(),
+            });
+            relocated.push(path, extent);
+        }
+        Ok(Some(relocated))
+    }
+}
+
+/// Clip the extents to `offset..offset + length` and tag them with the device.
+///
+/// Holes are not represented; they appear as gaps between consecutive ranges.
+pub(crate) fn map_extents(
+    device_path: &Path,
+    extents: &[FiemapExtent],
+    offset: u64,
+    length: u64,
+) -> Vec<MappedRange> {
+    let end = offset.saturating_add(length);
+    extents
+        .iter()
+        .filter_map(|extent| {
+            let start = extent.logical.max(offset);
+            let stop = (extent.logical + extent.length).min(end);
+            (start < stop).then(|| MappedRange {
+                device_path: device_path.to_path_buf(),
+                logical: start,
+                physical: extent.physical + (start - extent.logical),
+                length: stop - start,
+                flags: extent.flags,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(logical: u64, physical: u64, length: u64, flags: ExtentFlags) -> FiemapExtent {
+        FiemapExtent {
+            logical,
+            physical,
+            length,
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_map_extents_clips_to_range() {
+        let device = PathBuf::from("/dev/fake");
+        let extents = vec![
+            extent(0, 1 << 20, 8192, ExtentFlags::empty()),
+            extent(16384, 4 << 20, 8192, ExtentFlags::UNWRITTEN),
+        ];
+
+        let ranges = map_extents(&device, &extents, 4096, 16384);
+        assert_eq!(
+            ranges,
+            vec![
+                MappedRange {
+                    device_path: device.clone(),
+                    logical: 4096,
+                    physical: (1 << 20) + 4096,
+                    length: 4096,
+                    flags: ExtentFlags::empty(),
+                },
+                MappedRange {
+                    device_path: device.clone(),
+                    logical: 16384,
+                    physical: 4 << 20,
+                    length: 4096,
+                    flags: ExtentFlags::UNWRITTEN,
+                },
+            ]
+        );
+
+        assert!(ranges.iter().all(|range| !range.is_shared()));
+
+        // A range entirely inside the hole maps to nothing
+        assert!(map_extents(&device, &extents, 8192, 8192).is_empty());
+    }
+}
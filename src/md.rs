@@ -0,0 +1,251 @@
+//! Detection of Linux software RAID (md) arrays and translation of RAID1
+//! reads to their members.
+//!
+//! Reads through `/dev/mdN` return the array's data for every RAID level.
+//! Each member of a RAID1 array holds a full copy of that data after its
+//! data offset, so with [`Options::translate_md`](crate::Options::translate_md)
+//! reads can be sent to a healthy member directly, e.g. to recover data
+//! when the array misbehaves. Members of striped and parity levels do not
+//! hold the data as is and are only read through the array.
+
+use crate::dm;
+use crate::map::Placement;
+use crate::state::MdArray;
+use crate::sys;
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A member device of an array, from `/sys/block/mdN/md/dev-*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Member {
+    path: PathBuf,
+    /// Byte offset of the array's data on the member.
+    offset: u64,
+    /// Whether the member is a complete, working copy of the array.
+    in_sync: bool,
+    /// Whether reads should avoid the member, e.g. a slow disk.
+    write_mostly: bool,
+}
+
+/// An array's level and members, in name order.
+fn members_in(sys: &Path, name: &str) -> io::Result<Option<(String, Vec<Member>)>> {
+    let md = sys.join("block").join(name).join("md");
+    let level = match sys::read_attr(&md.join("level")) {
+        Ok(level) => level,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut members = Vec::new();
+    for entry in std::fs::read_dir(&md)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(device) = file_name.to_str().and_then(|n| n.strip_prefix("dev-")) else {
+            continue;
+        };
+        let state = sys::read_attr(&entry.path().join("state"))?;
+        let flags: Vec<&str> = state.split(',').collect();
+        let offset: u64 = sys::read_attr(&entry.path().join("offset"))?
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid md data offset"))?;
+        members.push(Member {
+            path: Path::new("/dev").join(device),
+            offset: offset * 512,
+            in_sync: flags.contains(&"in_sync") && !flags.contains(&"faulty"),
+            write_mostly: flags.contains(&"write_mostly"),
+        });
+    }
+    members.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(Some((level, members)))
+}
+
+/// The member a RAID1 array `name` is read from, or `None` if `name` is
+/// not a RAID1 array.
+///
+/// Any in-sync member holds all of the data; those marked write-mostly
+/// are only used if no other is.
+fn mirror_in(sys: &Path, name: &str) -> io::Result<Option<Member>> {
+    let Some((level, members)) = members_in(sys, name)? else {
+        return Ok(None);
+    };
+    if level != "raid1" {
+        return Ok(None);
+    }
+    let healthy = members
+        .iter()
+        .filter(|member| member.in_sync)
+        .min_by_key(|member| member.write_mostly);
+    match healthy {
+        Some(member) => Ok(Some(member.clone())),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("md array {} has no in-sync member", name),
+        )),
+    }
+}
+
+/// The md array that is block device `name` in the sysfs tree at `sys`.
+fn array_in(sys: &Path, name: &str, path: &Path) -> io::Result<Option<MdArray>> {
+    let Some((level, members)) = members_in(sys, name)? else {
+        return Ok(None);
+    };
+    // Only redundant levels report whether they are degraded
+    let degraded = sys::read_attr(&sys.join("block").join(name).join("md/degraded"))
+        .map(|degraded| degraded != "0")
+        .unwrap_or(false);
+    Ok(Some(MdArray {
+        device_path: path.to_path_buf(),
+        level,
+        members: members.into_iter().map(|member| member.path).collect(),
+        degraded,
+    }))
+}
+
+/// The md array that is the block device at `path`.
+///
+/// Returns `None` if the device is not an md array, or if it could not be
+/// inspected.
+pub(crate) fn array(path: &Path) -> Option<MdArray> {
+    let name = dm::block_name(path).ok()?;
+    array_in(Path::new("/sys"), &name.to_string_lossy(), path)
+        .ok()
+        .flatten()
+}
+
+/// Translate the pieces of `placement` on RAID1 arrays to a healthy member.
+///
+/// Returns `None` if no piece is on a RAID1 array.
+pub(crate) fn place(placement: &Placement) -> io::Result<Option<Placement>> {
+    place_with(placement, Path::new("/sys"), dm::block_name)
+}
+
+/// [`place`] with the sysfs tree and device names supplied by the caller.
+fn place_with(
+    placement: &Placement,
+    sys: &Path,
+    device_name: impl Fn(&Path) -> io::Result<OsString>,
+) -> io::Result<Option<Placement>> {
+    placement.relocate(|path| {
+        let member = mirror_in(sys, &device_name(path)?.to_string_lossy())?;
+        Ok(member.map(|member| (member.path, member.offset)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blkmap::{ExtentFlags, FiemapExtent};
+    use std::fs;
+
+    /// Create array `name` with `members` as (device, state, offset in
+    /// sectors) in the sysfs tree at `sys`.
+    fn array_at(sys: &Path, name: &str, level: &str, members: &[(&str, &str, u64)]) {
+        let md = sys.join("block").join(name).join("md");
+        fs::create_dir_all(&md).unwrap();
+        fs::write(md.join("level"), format!("{}\n", level)).unwrap();
+        for (device, state, offset) in members {
+            let dir = md.join(format!("dev-{}", device));
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("state"), format!("{}\n", state)).unwrap();
+            fs::write(dir.join("offset"), format!("{}\n", offset)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_array_in_sysfs() {
+        let sys = tempfile::tempdir().unwrap();
+        array_at(
+            sys.path(),
+            "md0",
+            "raid1",
+            &[("sdb1", "in_sync", 2048), ("sda1", "faulty", 2048)],
+        );
+        fs::write(sys.path().join("block/md0/md/degraded"), "1\n").unwrap();
+        fs::create_dir_all(sys.path().join("block/sdc")).unwrap();
+
+        let array = array_in(sys.path(), "md0", Path::new("/dev/md0"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            array,
+            MdArray {
+                device_path: PathBuf::from("/dev/md0"),
+                level: "raid1".to_string(),
+                members: vec![PathBuf::from("/dev/sda1"), PathBuf::from("/dev/sdb1")],
+                degraded: true,
+            }
+        );
+        assert!(array_in(sys.path(), "sdc", Path::new("/dev/sdc"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_mirror_in_sysfs() {
+        let sys = tempfile::tempdir().unwrap();
+        array_at(
+            sys.path(),
+            "md0",
+            "raid1",
+            &[
+                ("sda1", "in_sync,write_mostly", 8),
+                ("sdb1", "faulty", 16),
+                ("sdc1", "in_sync", 2048),
+                ("sdd1", "spare", 0),
+            ],
+        );
+        array_at(sys.path(), "md1", "raid1", &[("sde1", "faulty", 8)]);
+        array_at(
+            sys.path(),
+            "md2",
+            "raid5",
+            &[("sdf", "in_sync", 8), ("sdg", "in_sync", 8)],
+        );
+
+        let member = mirror_in(sys.path(), "md0").unwrap().unwrap();
+        assert_eq!(member.path, Path::new("/dev/sdc1"));
+        assert_eq!(member.offset, 2048 * 512);
+        assert!(mirror_in(sys.path(), "md1").is_err());
+        assert!(mirror_in(sys.path(), "md2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_place_on_mirror() {
+        let sys = tempfile::tempdir().unwrap();
+        array_at(sys.path(), "md0", "raid1", &[("sdb1", "in_sync", 2048)]);
+        array_at(sys.path(), "md1", "raid0", &[("sdc", "in_sync", 0)]);
+        let name = |path: &Path| Ok(path.file_name().unwrap().to_os_string());
+        let extent = |logical, physical| FiemapExtent {
+            logical,
+            physical,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        };
+
+        let mut placement = Placement::default();
+        placement.push(Some(Path::new("/dev/md0")), extent(0, 8192));
+        placement.push(None, extent(4096, 0));
+        placement.push(Some(Path::new("/dev/md1")), extent(8192, 0));
+        let translated = place_with(&placement, sys.path(), name).unwrap().unwrap();
+        let summary: Vec<_> = translated
+            .placed
+            .iter()
+            .map(|p| (translated.path(p).map(Path::to_path_buf), p.extent.physical))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(PathBuf::from("/dev/sdb1")), 2048 * 512 + 8192),
+                (None, 0),
+                (Some(PathBuf::from("/dev/md1")), 0),
+            ]
+        );
+
+        // Arrays of other levels are read as they are
+        let mut striped = Placement::default();
+        striped.push(Some(Path::new("/dev/md1")), extent(0, 0));
+        assert!(place_with(&striped, sys.path(), name).unwrap().is_none());
+    }
+}
@@ -0,0 +1,118 @@
+//! NUMA node of block devices.
+//!
+//! On multi-socket hosts each PCIe root belongs to one NUMA node, and
+//! Direct I/O into memory on another node crosses the interconnect. The
+//! node of a device is found through sysfs, from the `numa_node` attribute
+//! of the nearest PCI device above it, so that
+//! [`Options::buffer_node`](crate::Options::buffer_node) can place read
+//! buffers next to it.
+
+use crate::dm::{self, DevNum};
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+/// Device-mapper stacks deeper than this are not followed.
+const MAX_DEPTH: usize = 8;
+
+/// NUMA node of the block device at `path`.
+///
+/// Returns `None` for files that are not block devices, such as device
+/// images, and for devices whose node the kernel does not know, as on
+/// single-node hosts.
+pub(crate) fn device_node(path: &Path) -> io::Result<Option<usize>> {
+    if !fs::metadata(path)?.file_type().is_block_device() {
+        return Ok(None);
+    }
+    node_in(Path::new("/sys"), dm::dev_num(path)?)
+}
+
+/// NUMA node of block device `dev` in the sysfs tree at `sys`.
+fn node_in(sys: &Path, dev: DevNum) -> io::Result<Option<usize>> {
+    let sys = fs::canonicalize(sys)?;
+    let device = fs::canonicalize(sys.join(format!("dev/block/{}:{}", dev.0, dev.1)))?;
+    Ok(node_of(&sys, &device, MAX_DEPTH))
+}
+
+/// NUMA node of the block device at sysfs directory `device`, or of the
+/// first device below it with one for stacked devices such as
+/// device-mapper volumes. `sys` is the canonical path of the sysfs tree.
+fn node_of(sys: &Path, device: &Path, depth: usize) -> Option<usize> {
+    // Partitions and disks sit below the controller they are attached to
+    for dir in device.ancestors().take_while(|dir| *dir != sys) {
+        if let Ok(node) = fs::read_to_string(dir.join("numa_node")) {
+            // -1 if the platform reports no node
+            return node.trim().parse().ok();
+        }
+    }
+
+    if depth == 0 {
+        return None;
+    }
+    let mut slaves: Vec<_> = fs::read_dir(device.join("slaves"))
+        .ok()?
+        .filter_map(|entry| entry.ok()?.path().canonicalize().ok())
+        .collect();
+    slaves.sort();
+    slaves
+        .iter()
+        .find_map(|slave| node_of(sys, slave, depth - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// Add block device `name` with device number `dev` at `path` in the
+    /// sysfs tree at `sys`.
+    fn block_at(sys: &Path, path: &str, name: &str, dev: &str) {
+        let dir = sys.join(path);
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(sys.join("dev/block")).unwrap();
+        fs::create_dir_all(sys.join("class/block")).unwrap();
+        symlink(&dir, sys.join("dev/block").join(dev)).unwrap();
+        symlink(&dir, sys.join("class/block").join(name)).unwrap();
+    }
+
+    #[test]
+    fn test_node_in_sysfs() {
+        let sys = tempfile::tempdir().unwrap();
+        let sys = sys.path();
+        let pci = "devices/pci0000:80/0000:80:01.0";
+        let disk = format!("{}/nvme/nvme0/nvme0n1", pci);
+        block_at(sys, &disk, "nvme0n1", "259:0");
+        fs::write(sys.join(pci).join("numa_node"), "1\n").unwrap();
+        // A partition, below its disk
+        block_at(sys, &format!("{}/nvme0n1p1", disk), "nvme0n1p1", "259:1");
+        // A platform without NUMA information
+        let virtio = "devices/pci0000:00/0000:00:05.0";
+        block_at(
+            sys,
+            &format!("{}/virtio2/block/vda", virtio),
+            "vda",
+            "253:0",
+        );
+        fs::write(sys.join(virtio).join("numa_node"), "-1\n").unwrap();
+
+        assert_eq!(node_in(sys, (259, 0)).unwrap(), Some(1));
+        assert_eq!(node_in(sys, (259, 1)).unwrap(), Some(1));
+        assert_eq!(node_in(sys, (253, 0)).unwrap(), None);
+        assert!(node_in(sys, (8, 0)).is_err());
+
+        // A device-mapper volume on the partition
+        block_at(sys, "devices/virtual/block/dm-0", "dm-0", "252:0");
+        let slaves = sys.join("devices/virtual/block/dm-0/slaves");
+        fs::create_dir_all(&slaves).unwrap();
+        symlink(sys.join("class/block/nvme0n1p1"), slaves.join("nvme0n1p1")).unwrap();
+        assert_eq!(node_in(sys, (252, 0)).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_device_node_of_image() {
+        let image = tempfile::NamedTempFile::new().unwrap();
+        assert_eq!(device_node(image.path()).unwrap(), None);
+    }
+}
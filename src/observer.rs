@@ -0,0 +1,118 @@
+//! Observation of the stages of a read.
+//!
+//! Applications feeding their own metrics or log sinks can register a
+//! [`ReadObserver`] via
+//! [`Options::with_observer`](crate::Options::with_observer). The reader
+//! calls it as it queries extents, reads from the device, fills holes and
+//! falls back to regular file I/O. The `tracing` feature adds a built-in
+//! observer emitting `tracing` events, called before the registered one.
+
+use crate::state::SegmentSource;
+
+use blkmap::FiemapExtent;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// A completed FIEMAP query.
+#[derive(Debug, Clone, Copy)]
+pub struct FiemapEvent<'a> {
+    /// Logical offset of the queried range.
+    pub offset: u64,
+
+    /// Length of the queried range.
+    pub length: u64,
+
+    /// The extents reported for the range.
+    pub extents: &'a [FiemapExtent],
+
+    /// How long the query took.
+    pub duration: Duration,
+}
+
+/// A read from the block device.
+///
+/// Coalesced reads, which service several extents at once, are reported as
+/// one read starting at the first of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtentReadEvent<'a> {
+    /// Path of the block device.
+    pub device: &'a Path,
+
+    /// Logical file offset of the first byte read.
+    pub logical: u64,
+
+    /// Physical byte offset on the device.
+    pub physical: u64,
+
+    /// Number of bytes requested from the device.
+    pub length: u64,
+}
+
+/// A range filled in rather than read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FillEvent {
+    /// Logical file offset of the range.
+    pub logical: u64,
+
+    /// Length of the range.
+    pub length: u64,
+
+    /// Why the range was filled: a hole or an unwritten extent.
+    pub source: SegmentSource,
+}
+
+/// A range read through regular file I/O instead of the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FallbackEvent {
+    /// Logical file offset of the range.
+    pub offset: u64,
+
+    /// Length of the range.
+    pub length: u64,
+
+    /// Number of bytes actually read.
+    pub bytes_read: u64,
+}
+
+/// Callbacks for the stages of a read.
+///
+/// All methods do nothing by default, so observers only implement those
+/// they are interested in. They are called on the thread doing the read
+/// and should return quickly.
+pub trait ReadObserver: fmt::Debug + Send + Sync {
+    /// Called after the extents of a range were queried with FIEMAP.
+    fn on_fiemap(&self, event: &FiemapEvent<'_>) {
+        let _ = event;
+    }
+
+    /// Called before a read is submitted to the device.
+    fn on_extent_read_start(&self, event: &ExtentReadEvent<'_>) {
+        let _ = event;
+    }
+
+    /// Called when a device read completes with `result`, the number of
+    /// bytes read or the error, `duration` after its batch was submitted.
+    ///
+    /// Reads retried after transient errors are reported again.
+    fn on_extent_read_end(
+        &self,
+        event: &ExtentReadEvent<'_>,
+        result: Result<usize, &io::Error>,
+        duration: Duration,
+    ) {
+        let _ = (event, result, duration);
+    }
+
+    /// Called after a hole or unwritten extent was filled.
+    fn on_hole_fill(&self, event: &FillEvent) {
+        let _ = event;
+    }
+
+    /// Called after a range was read through the file instead of the
+    /// device (see [`Options::allow_fallback`](crate::Options::allow_fallback)).
+    fn on_fallback(&self, event: &FallbackEvent) {
+        let _ = event;
+    }
+}
@@ -1,15 +1,68 @@
 //! Configuration options for blkreader operations.
 
+use crate::backend::{BlockDeviceBackend, DeviceBackend, DeviceSource};
+use crate::cache::BlkCache;
+#[cfg(feature = "checksum")]
+use crate::checksum::{Checksum, ChecksumUnit};
+use crate::engine::{IoEngine, PreadvEngine, ReadFlags};
+use crate::extent_cache::DiskExtentCache;
+#[cfg(feature = "fault-injection")]
+use crate::fault::FaultPlan;
+use crate::observer::ReadObserver;
+use crate::pool::ReadPool;
+use crate::progress::{ProgressCallback, ProgressEvent};
+use crate::sys;
+use crate::throttle::Throttle;
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
 /// Options for controlling the read behavior.
+///
+/// With the `serde` feature, options can be serialized. The progress
+/// callback, observer, validator, I/O engine, device backend, read pool,
+/// disk extent cache and cache handle are skipped, and take their default
+/// values when deserializing, as do any missing fields.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Options {
     /// Enable global block device cache.
     ///
-    /// When enabled, block device file handles are cached globally
-    /// based on the device ID, improving performance for repeated reads
-    /// from files on the same filesystem.
+    /// When enabled, block device file handles are cached globally (or in
+    /// [`cache_handle`](Options::cache_handle)) based on the device ID,
+    /// improving performance for repeated reads from files on the same
+    /// filesystem.
     pub enable_cache: bool,
 
+    /// Cache holding the block device handles, in place of the global one.
+    ///
+    /// See [`BlkCache`]. `None` (default) uses [`BlkCache::global`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cache_handle: Option<BlkCache>,
+
+    /// Read the block device with Direct I/O (`O_DIRECT`).
+    ///
+    /// Direct I/O bypasses the page cache of the device, so reads return
+    /// what is on disk, but must be aligned to its logical sector size.
+    /// When disabled, the device is read through its page cache instead,
+    /// which accepts any offset and length and benefits from the kernel's
+    /// readahead, e.g. on spinning disks. Device blocks read before may then
+    /// be returned from the device's page cache, which is not updated by
+    /// later writes through the filesystem. Defaults to `true`.
+    pub direct: bool,
+
+    /// Read through the device's page cache where Direct I/O is rejected.
+    ///
+    /// Direct I/O fails with `EINVAL` when a buffer is not aligned as the
+    /// device requires, or when the device does not support `O_DIRECT` at
+    /// all. With this flag, such device reads (and opens) are retried
+    /// once without `O_DIRECT`, as with [`direct`](Options::direct)
+    /// disabled. Defaults to `false`.
+    pub buffered_fallback: bool,
+
     /// Fill holes in file extents with zeros.
     ///
     /// When disabled, reading a hole will cause an early EOF return.
@@ -25,6 +78,28 @@ pub struct Options {
     /// normal filesystem read behavior).
     pub zero_unwritten: bool,
 
+    /// Check the raw device data of unwritten extents for nonzero bytes.
+    ///
+    /// Unwritten extents read as zeros through the filesystem, but their
+    /// device blocks may hold data written before a crash that the
+    /// filesystem never marked as written. With this flag, the unwritten
+    /// extents read raw from the device (without
+    /// [`zero_unwritten`](Options::zero_unwritten), which fills them
+    /// instead) are scanned, and the 512-byte sectors holding nonzero bytes
+    /// are listed in
+    /// [`State::unwritten_nonzero`](crate::State::unwritten_nonzero).
+    /// Defaults to `false`.
+    pub check_unwritten: bool,
+
+    /// Byte used to fill synthesized regions.
+    ///
+    /// Holes (when [`fill_holes`](Options::fill_holes) is enabled) and
+    /// unwritten extents (when [`zero_unwritten`](Options::zero_unwritten)
+    /// is enabled) are filled with this byte. Defaults to `0`, matching
+    /// normal filesystem read behavior; a recognizable pattern such as
+    /// `0xDE` makes synthesized bytes easy to tell apart from real zeros.
+    pub fill_byte: u8,
+
     /// Allow fallback to regular file read when safe.
     ///
     /// When enabled, if the queried extents fully cover the read range
@@ -36,7 +111,9 @@ pub struct Options {
     /// Require reading the exact requested length.
     ///
     /// When enabled, the read operation will return an error if the amount
-    /// of data read is less than the requested buffer size.
+    /// of data read is less than the requested buffer size, for example on a
+    /// hole without `fill_holes`. The error has kind `UnexpectedEof` and wraps
+    /// a [`ShortReadError`](crate::ShortReadError) carrying the bytes read.
     /// This is similar to the behavior of [`std::io::Read::read_exact`].
     ///
     /// When disabled, partial reads are allowed and the actual number of
@@ -46,26 +123,586 @@ pub struct Options {
     /// Dry run mode - skip actual device reads.
     ///
     /// When enabled, no actual I/O operations are performed on block devices
-    /// or files. Instead, the operation pretends to successfully read the
-    /// requested amount of data.
+    /// or files. FIEMAP is still queried and the block device path is
+    /// resolved, but the device is never opened. The operation pretends to
+    /// successfully read the requested amount of data, and the returned
+    /// [`State::planned`](crate::State::planned) lists exactly what would be
+    /// read from the device and which regions would be filled.
     ///
-    /// This is useful for testing the extent mapping logic and validating
-    /// that a file's extents are accessible without performing time-consuming
-    /// I/O operations.
+    /// This is useful for pre-flight validation of a file's extents without
+    /// device access or time-consuming I/O operations.
     ///
     /// When disabled (default), normal read operations are performed.
     pub dry_run: bool,
+
+    /// Callback invoked as the read makes progress.
+    ///
+    /// When set, the callback receives a [`ProgressEvent`] after every
+    /// device read and every synthesized fill, allowing embedding
+    /// applications to surface progress of long reads in their own UIs.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub progress: Option<ProgressCallback>,
+
+    /// Observer notified of the stages of a read.
+    ///
+    /// When set, the [`ReadObserver`] is called for every FIEMAP query,
+    /// device read, fill and fallback read, so that applications can feed
+    /// their own metrics or logs.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub observer: Option<Arc<dyn ReadObserver>>,
+
+    /// Quick validity check applied to the data returned by a read.
+    ///
+    /// When set, the validator is called with the bytes placed into the
+    /// buffer. If it rejects them, the read fails with
+    /// [`std::io::ErrorKind::InvalidData`], unless
+    /// [`refresh_on_stale`](Options::refresh_on_stale) allows a retry.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub validator: Option<Validator>,
+
+    /// Re-query the extent map and retry once if the read looks stale.
+    ///
+    /// A read looks stale if its data fails the [`validator`](Options::validator)
+    /// or a device read fails with `EIO`. Copy-on-write filesystems may move
+    /// extents between the FIEMAP query and the device read; refreshing the
+    /// map picks up the new physical locations.
+    pub refresh_on_stale: bool,
+
+    /// Handle Direct I/O alignment internally.
+    ///
+    /// When enabled, reads whose buffer address, offset or length are not
+    /// aligned to the Direct I/O requirements of the file's block device
+    /// are performed through an aligned bounce buffer covering the
+    /// enclosing aligned range, and the requested slice is copied back. The
+    /// [`validator`](Options::validator) and progress events then see the
+    /// enclosing aligned range.
+    ///
+    /// When disabled (default), unaligned reads may fail with `EINVAL`.
+    pub auto_align: bool,
+
+    /// NUMA node to allocate the library's read buffers on.
+    ///
+    /// Covers the buffers reads allocate themselves: bounce buffers of
+    /// [`auto_align`](Options::auto_align), the chunks of whole-file reads
+    /// and copies, and new buffers of a
+    /// [`BufPool`](crate::BufPool). [`NumaNode::Device`] picks the node
+    /// local to the device's PCIe root, which spares multi-socket hosts
+    /// Direct I/O across the interconnect; devices without a known node get
+    /// ordinary buffers. Defaults to `None`, allocating without a node
+    /// preference.
+    pub buffer_node: Option<NumaNode>,
+
+    /// Allow [`BlkWriter`](crate::BlkWriter) to write to the block device.
+    ///
+    /// Writing through extents bypasses the filesystem entirely, so it is
+    /// refused unless this flag is set explicitly.
+    pub allow_write: bool,
+
+    /// Issue device reads in order of physical offset.
+    ///
+    /// When enabled, the extents of a read are serviced sorted by their
+    /// physical location (elevator order) and the data is placed at the
+    /// correct logical positions in the buffer. On spinning disks with
+    /// fragmented files this reduces seeking considerably.
+    pub sort_physical: bool,
+
+    /// Merge device reads separated by small physical gaps.
+    ///
+    /// When set, consecutive device reads whose physical ranges are at most
+    /// this many bytes apart are issued as a single vectored read, with the
+    /// gaps read into a discard buffer. On fast devices one large read is
+    /// cheaper than several small ones. `None` (default) disables merging.
+    pub coalesce_gap: Option<u64>,
+
+    /// Number of threads used to read the extents of a single call.
+    ///
+    /// With more than one thread, the device reads of a fragmented range are
+    /// dispatched across threads, each writing into its own slice of the
+    /// buffer, which keeps more requests in flight on fast devices. Defaults
+    /// to 1 (sequential reads).
+    ///
+    /// Without a [`read_pool`](Options::read_pool), every call spawns and
+    /// joins its threads, which costs tens of microseconds per thread and
+    /// outweighs the gain for small reads; set a pool when making many
+    /// parallel reads.
+    pub parallelism: usize,
+
+    /// Threads servicing parallel reads.
+    ///
+    /// See [`ReadPool`]. `None` (default) spawns the threads of each
+    /// parallel read for that read only.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub read_pool: Option<ReadPool>,
+
+    /// Maximum number of reads in flight on a device.
+    ///
+    /// Threads reading through the same device handle (such as a cached
+    /// one) wait for a free slot before handing their device reads to the
+    /// I/O engine, so that many concurrent readers don't overwhelm a
+    /// spinning disk. A batch of reads handed to the engine at once counts
+    /// as one read. The wait is bounded by [`timeout`](Options::timeout).
+    /// `None` (default) does not limit reads.
+    pub max_in_flight: Option<usize>,
+
+    /// Backend used to issue device reads.
+    ///
+    /// See [`IoEngine`]. Defaults to [`PreadvEngine`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub io_engine: Arc<dyn IoEngine>,
+
+    /// Access to the devices holding the data of files.
+    ///
+    /// See [`DeviceBackend`]. `None` (default) reads the system's block
+    /// devices, as [`BlockDeviceBackend`]. Devices opened through another
+    /// backend are not cached.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub device_backend: Option<Arc<dyn DeviceBackend>>,
+
+    /// Flags passed to `preadv2` for device and fallback reads.
+    ///
+    /// [`ReadFlags::HIPRI`] requests polled completion for Direct I/O on
+    /// NVMe; [`ReadFlags::NOWAIT`] makes buffered fallback reads fail with
+    /// [`WouldBlock`](std::io::ErrorKind::WouldBlock) instead of waiting
+    /// for the disk. Defaults to no flags.
+    pub read_flags: ReadFlags,
+
+    /// I/O priority of device reads.
+    ///
+    /// While it reads the device, the reading thread's I/O priority
+    /// (`ioprio_set`) is switched to this one and restored afterwards, so
+    /// that background scans with [`IoPriority::Idle`] don't starve other
+    /// I/O to the same disk. Only I/O schedulers supporting priorities, such
+    /// as BFQ, honor it. `None` (default) leaves the priority unchanged.
+    pub io_priority: Option<IoPriority>,
+
+    /// Maximum rate of device reads.
+    ///
+    /// Device reads wait for the [`Throttle`] to admit their size before
+    /// they are issued, capping the bandwidth taken from a device that also
+    /// serves other users, such as a live database during recovery. Reads
+    /// through options cloned from one another share the limit. The wait is
+    /// bounded by [`timeout`](Options::timeout). `None` (default) reads at
+    /// full speed.
+    pub max_throughput: Option<Throttle>,
+
+    /// Maximum duration of a single call.
+    ///
+    /// Once it has passed, no further device reads are started and the call
+    /// fails with [`TimedOut`](std::io::ErrorKind::TimedOut), so a read
+    /// that stalls on a dying disk does not hang its caller forever. A
+    /// device read already in progress cannot be interrupted and is waited
+    /// for. Defaults to no timeout.
+    pub timeout: Option<Duration>,
+
+    /// How device reads failing with a transient error are retried.
+    ///
+    /// See [`RetryPolicy`]. Defaults to no retries.
+    pub retry: RetryPolicy,
+
+    /// Fill device ranges that cannot be read instead of failing.
+    ///
+    /// When a device read fails with a media error (`EIO` or `ENODATA`),
+    /// even after [`retry`](Options::retry), the failed range is read
+    /// again in ever smaller aligned pieces, down to the device's sector
+    /// size, to isolate the bad sectors. Only sectors that still cannot be
+    /// read are filled with [`fill_byte`](Options::fill_byte) and listed in
+    /// [`State::unreadable`](crate::State::unreadable), and the read
+    /// continues. This recovers as much data as possible from a failing
+    /// disk. Defaults to `false`.
+    pub best_effort: bool,
+
+    /// Faults injected into device reads, for testing how failed and short
+    /// reads are handled.
+    ///
+    /// See [`FaultPlan`]. Requires the `fault-injection` feature. `None`
+    /// (default) injects nothing.
+    #[cfg(feature = "fault-injection")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub fault_injection: Option<FaultPlan>,
+
+    /// Measure how long each stage of a read takes.
+    ///
+    /// The durations of the FIEMAP query, resolving the block device, each
+    /// device read and the whole call are reported in
+    /// [`State::timing`](crate::State::timing). Defaults to `false`.
+    pub timing: bool,
+
+    /// Compute a digest of the data returned by each read.
+    ///
+    /// The digest of the bytes placed in the buffer, `0..bytes_read`, is
+    /// reported in [`State::checksum`](crate::State::checksum), for audit
+    /// or for comparison with the file's contents read through the page
+    /// cache. Reads streaming data elsewhere
+    /// ([`blk_read_extents`](crate::BlkReader::blk_read_extents),
+    /// [`blk_copy_to`](crate::BlkReader::blk_copy_to)) compute none.
+    /// Requires the `checksum` feature. `None` (default) computes no
+    /// digest.
+    #[cfg(feature = "checksum")]
+    pub checksum: Option<Checksum>,
+
+    /// Also digest each extent, or each fixed-size block, of the returned
+    /// data on its own.
+    ///
+    /// The digests are reported in
+    /// [`State::unit_checksums`](crate::State::unit_checksums), computed
+    /// with the [`checksum`](Options::checksum) algorithm, without which
+    /// this has no effect. Comparing them with those of a known-good copy
+    /// of the file, or of other files, shows which extents differ or which
+    /// blocks are duplicated. Requires the `checksum` feature. `None`
+    /// (default) digests only the whole read.
+    #[cfg(feature = "checksum")]
+    pub checksum_unit: Option<ChecksumUnit>,
+
+    /// Flush the file before querying its extent map (`FIEMAP_FLAG_SYNC`).
+    ///
+    /// Data recently written through the page cache may not have been
+    /// allocated on disk yet, and is then reported as a delayed-allocation
+    /// extent whose device contents are stale. With this flag the
+    /// filesystem writes out and allocates those ranges first, so the
+    /// extent map matches the data. Defaults to `false`.
+    pub fiemap_sync: bool,
+
+    /// Cache of extent maps kept on disk across runs.
+    ///
+    /// When set, the complete extent map of a file is queried once and
+    /// stored in the [`DiskExtentCache`]; later reads of the file, also by
+    /// later processes, take their extents from there while the file is
+    /// unchanged. Reads with an attached extent map, and the repeated
+    /// queries of [`verify_extents`](Options::verify_extents) and
+    /// [`refresh_on_stale`](Options::refresh_on_stale), bypass it. `None`
+    /// (default) queries FIEMAP for every read.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub disk_extent_cache: Option<DiskExtentCache>,
+
+    /// Keep the extent maps of the files read in the device cache.
+    ///
+    /// When enabled, the complete extent map of a file is queried once and
+    /// kept in [`cache_handle`](Options::cache_handle) (or the global
+    /// cache), keyed by its device and inode number; later reads of the
+    /// file take their extents from there while its inode generation, size,
+    /// mtime and ctime are unchanged. Layout changes that leave those alone,
+    /// such as a btrfs balance, are not noticed; drop such maps with
+    /// [`BlkCache::invalidate_extents`]. Defaults to `false`.
+    pub cache_extents: bool,
+
+    /// Re-query the extent map after the device reads and compare.
+    ///
+    /// Defragmentation or copy-on-write relocation between the FIEMAP query
+    /// and the device reads leaves the returned data a mix of old and new
+    /// locations. With this flag, a read whose extent map changed in the
+    /// meantime is flagged in
+    /// [`State::possibly_torn`](crate::State::possibly_torn). Defaults to
+    /// `false`.
+    pub verify_extents: bool,
+
+    /// Check that the extents address the device being read.
+    ///
+    /// FIEMAP reports physical offsets relative to the device holding the
+    /// filesystem, so resolving the wrong one, such as the whole disk
+    /// instead of its partition, returns other data without any error. With
+    /// this flag, one block of a written extent is read from the device and
+    /// compared with the same block read through the page cache before the
+    /// device reads, failing with
+    /// [`BlkReadError::DeviceMismatch`](crate::BlkReadError::DeviceMismatch)
+    /// if they differ. Defaults to `false`.
+    pub verify_device: bool,
+
+    /// Probe the page cache for dirty pages in the range before reading.
+    ///
+    /// Data written through the page cache reaches the device only once it
+    /// is written back, so device reads of dirty ranges return old data.
+    /// With this flag, such reads are flagged in
+    /// [`State::possibly_stale`](crate::State::possibly_stale). Uses
+    /// `cachestat` (Linux 6.5+); on older kernels `mincore` is used, which
+    /// cannot tell dirty from clean pages, so any cached page counts.
+    /// Defaults to `false`.
+    pub check_dirty: bool,
+
+    /// Fail reads of ranges with dirty pages instead of flagging them.
+    ///
+    /// Like [`check_dirty`](Options::check_dirty), but the read fails with
+    /// [`BlkReadError::DirtyPages`](crate::BlkReadError::DirtyPages) before
+    /// the device is read. Defaults to `false`.
+    pub fail_on_dirty: bool,
+
+    /// Write the file's data out with `fdatasync` before querying extents.
+    ///
+    /// For callers that want the current contents of a file that may have
+    /// been written recently, rather than whatever is on disk. Unlike
+    /// [`fiemap_sync`](Options::fiemap_sync), this works on every
+    /// filesystem, but fails if the data cannot be written back. Defaults
+    /// to `false`.
+    pub sync_first: bool,
+
+    /// How to read extents whose data is stored inline in filesystem
+    /// metadata (`FIEMAP_EXTENT_DATA_INLINE`).
+    ///
+    /// Their physical offset does not point at file data, so reading them
+    /// from the device returns garbage. Defaults to
+    /// [`InlinePolicy::ReadFile`].
+    pub inline_policy: InlinePolicy,
+
+    /// How to read extents whose data is encoded on disk, e.g. compressed
+    /// by btrfs (`FIEMAP_EXTENT_ENCODED`) or encrypted by fscrypt
+    /// (`FIEMAP_EXTENT_DATA_ENCRYPTED`).
+    ///
+    /// Their device blocks do not hold the file's data as-is. Defaults to
+    /// [`EncodedPolicy::Error`].
+    pub encoded_policy: EncodedPolicy,
+
+    /// Return the device blocks of encrypted data as they are.
+    ///
+    /// Reading a file encrypted with fscrypt, or a device that is the
+    /// encrypted store of a dm-crypt mapping, from the block device returns
+    /// ciphertext, which is easily mistaken for corruption. By default such
+    /// reads fail with
+    /// [`BlkReadError::Ciphertext`](crate::BlkReadError::Ciphertext); with
+    /// this flag, they return the ciphertext and set
+    /// [`State::ciphertext`](crate::State::ciphertext). Defaults to `false`.
+    pub allow_ciphertext: bool,
+
+    /// Translate the physical offsets of extents on btrfs to member devices.
+    ///
+    /// FIEMAP on btrfs reports addresses in the filesystem's logical address
+    /// space, which only the chunk tree maps to device offsets, possibly on
+    /// several devices. With this flag, device reads of files on btrfs are
+    /// translated through the chunk tree, which requires `CAP_SYS_ADMIN`.
+    /// Defaults to `true`.
+    pub translate_btrfs: bool,
+
+    /// Read from the devices below device-mapper linear, striped and
+    /// mirrored targets.
+    ///
+    /// A filesystem on an LVM volume is read through the `/dev/dm-N` device
+    /// by default. With this flag, reads are sent to the underlying disks
+    /// the volume's table maps them to, following stacked targets; mirrors
+    /// are read from their first leg. Other LVM layouts fail with
+    /// [`BlkReadError::UnsupportedLvmLayout`](crate::BlkReadError::UnsupportedLvmLayout),
+    /// while ranges of other targets, such as `crypt`, are still read from
+    /// the device-mapper device. Requires `CAP_SYS_ADMIN`. Defaults to
+    /// `false`.
+    pub translate_dm: bool,
+
+    /// Read RAID1 md arrays from one of their members (experimental).
+    ///
+    /// A filesystem on an md array is read through the `/dev/mdN` device by
+    /// default, which is correct for every RAID level. With this flag, reads
+    /// of RAID1 arrays are sent to an in-sync member instead, after its data
+    /// offset; members of other levels do not hold the data as is, so those
+    /// arrays are still read through the array device. Defaults to `false`.
+    pub translate_md: bool,
+
+    /// Read loop devices through their backing files.
+    ///
+    /// A filesystem mounted from an image through `/dev/loopN` is read
+    /// through the loop device by default, which usually requires root.
+    /// With this flag, reads are sent to the loop device's backing file
+    /// instead, shifted by the loop offset, which only requires read access
+    /// to the image. Defaults to `false`.
+    pub translate_loop: bool,
+
+    /// Read files on overlayfs through the layer file holding their data.
+    ///
+    /// The extents of an overlayfs file are those of a regular file in its
+    /// upper or one of its lower layers. With this flag, that file is found
+    /// from the mount's layers, following metacopy redirects, and read in
+    /// place of the overlay file. Defaults to `true`.
+    pub resolve_overlay: bool,
+}
+
+/// Handling of inline extents, see [`Options::inline_policy`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InlinePolicy {
+    /// Read inline ranges through regular file I/O, reported as
+    /// [`SegmentSource::Fallback`](crate::SegmentSource::Fallback).
+    #[default]
+    ReadFile,
+
+    /// Fail with [`BlkReadError::InlineExtent`](crate::BlkReadError::InlineExtent).
+    Error,
+}
+
+/// NUMA node for read buffers, see [`Options::buffer_node`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NumaNode {
+    /// The node local to the file's block device, see
+    /// [`BlkReader::blk_numa_node`](crate::BlkReader::blk_numa_node).
+    Device,
+
+    /// The given node.
+    Node(usize),
+}
+
+/// Handling of encoded extents, see [`Options::encoded_policy`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EncodedPolicy {
+    /// Fail with [`BlkReadError::EncodedExtent`](crate::BlkReadError::EncodedExtent).
+    #[default]
+    Error,
+
+    /// Return the raw, still encoded, device blocks.
+    Raw,
+
+    /// Fill encoded ranges with [`fill_byte`](Options::fill_byte), reported
+    /// as [`SegmentSource::Encoded`](crate::SegmentSource::Encoded).
+    Fill,
+}
+
+/// I/O scheduling class of device reads, see [`Options::io_priority`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IoPriority {
+    /// Serviced only when no other I/O needs the disk.
+    Idle,
+
+    /// The default class, at a level from 0 (highest) to 7 (lowest). Levels
+    /// above 7 are treated as 7.
+    BestEffort(u8),
+}
+
+impl IoPriority {
+    /// The priority as passed to `ioprio_set`.
+    pub(crate) fn ioprio(self) -> u16 {
+        match self {
+            IoPriority::Idle => sys::IOPRIO_CLASS_IDLE << sys::IOPRIO_CLASS_SHIFT,
+            IoPriority::BestEffort(level) => {
+                sys::IOPRIO_CLASS_BE << sys::IOPRIO_CLASS_SHIFT | u16::from(level.min(7))
+            }
+        }
+    }
+}
+
+/// Retries for device reads that fail with `EIO` or `EAGAIN`.
+///
+/// Flaky links and failing disks often return an error for a read that
+/// succeeds when issued again. Only the failed device reads are repeated;
+/// once all attempts are used up, the last error is returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RetryPolicy {
+    /// Total number of attempts for each device read, including the first.
+    ///
+    /// Values of 0 and 1 disable retries.
+    pub attempts: u32,
+
+    /// Delay before each retry, cut short by the
+    /// [`timeout`](Options::timeout).
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub const fn none() -> Self {
+        Self {
+            attempts: 1,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    /// Whether a read that failed with `error` on attempt `attempt`
+    /// (starting at 1) should be issued again.
+    pub(crate) fn should_retry(&self, error: &io::Error, attempt: u32) -> bool {
+        attempt < self.attempts && matches!(error.raw_os_error(), Some(libc::EIO | libc::EAGAIN))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+/// Signature of the closure wrapped by [`Validator`].
+type ValidateFn = dyn Fn(&[u8]) -> bool + Send + Sync;
+
+/// A shareable validity check for read data.
+///
+/// This wraps the user-provided closure so that [`Options`] can remain
+/// `Clone` and `Debug`.
+#[derive(Clone)]
+pub struct Validator(Arc<ValidateFn>);
+
+impl Validator {
+    /// Wrap a closure as a validator.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// Check whether the given data is valid.
+    pub fn validate(&self, data: &[u8]) -> bool {
+        (self.0)(data)
+    }
+}
+
+impl fmt::Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Validator(..)")
+    }
 }
 
 impl Default for Options {
     fn default() -> Self {
         Self {
             enable_cache: true,
+            cache_handle: None,
+            direct: true,
+            buffered_fallback: false,
             fill_holes: false,
             zero_unwritten: false,
+            check_unwritten: false,
+            fill_byte: 0,
             allow_fallback: false,
             read_exact: false,
             dry_run: false,
+            progress: None,
+            observer: None,
+            validator: None,
+            refresh_on_stale: false,
+            auto_align: false,
+            buffer_node: None,
+            allow_write: false,
+            sort_physical: false,
+            coalesce_gap: None,
+            parallelism: 1,
+            read_pool: None,
+            max_in_flight: None,
+            io_engine: Arc::new(PreadvEngine),
+            device_backend: None,
+            read_flags: ReadFlags::empty(),
+            io_priority: None,
+            max_throughput: None,
+            timeout: None,
+            retry: RetryPolicy::none(),
+            best_effort: false,
+            #[cfg(feature = "fault-injection")]
+            fault_injection: None,
+            timing: false,
+            #[cfg(feature = "checksum")]
+            checksum: None,
+            #[cfg(feature = "checksum")]
+            checksum_unit: None,
+            fiemap_sync: false,
+            disk_extent_cache: None,
+            cache_extents: false,
+            verify_extents: false,
+            verify_device: false,
+            check_dirty: false,
+            fail_on_dirty: false,
+            sync_first: false,
+            inline_policy: InlinePolicy::ReadFile,
+            encoded_policy: EncodedPolicy::Error,
+            allow_ciphertext: false,
+            translate_btrfs: true,
+            translate_dm: false,
+            translate_md: false,
+            translate_loop: false,
+            resolve_overlay: true,
         }
     }
 }
@@ -76,12 +713,76 @@ impl Options {
         Self::default()
     }
 
+    /// Preset for recovering raw data from the block device.
+    ///
+    /// Unwritten extents are read raw from the device, holes are filled so
+    /// the read continues past them, fallback is disabled so that every
+    /// byte comes from the device rather than the page cache, and
+    /// [`best_effort`](Options::best_effort) fills the sectors that cannot
+    /// be read instead of failing the read.
+    pub fn recovery() -> Self {
+        Self::default()
+            .with_fill_holes(true)
+            .with_zero_unwritten(false)
+            .with_allow_fallback(false)
+            .with_read_exact(false)
+            .with_best_effort(true)
+    }
+
+    /// Preset for verifying that on-disk data matches the file contents.
+    ///
+    /// The device is always read (no fallback), holes and unwritten extents
+    /// are filled with zeros to match normal filesystem read semantics, and
+    /// short reads are reported as errors.
+    pub fn verify() -> Self {
+        Self::default()
+            .with_fill_holes(true)
+            .with_zero_unwritten(true)
+            .with_allow_fallback(false)
+            .with_read_exact(true)
+    }
+
+    /// Preset favoring speed over raw device access.
+    ///
+    /// Regular file I/O is used whenever it is safe, and block device
+    /// handles are cached.
+    pub fn fast() -> Self {
+        Self::default().with_cache(true).with_allow_fallback(true)
+    }
+
     /// Enable or disable the global block device cache.
     pub fn with_cache(mut self, enable: bool) -> Self {
         self.enable_cache = enable;
         self
     }
 
+    /// Cache block device handles in `cache` instead of the global cache.
+    pub fn with_cache_handle(mut self, cache: &BlkCache) -> Self {
+        self.cache_handle = Some(cache.clone());
+        self
+    }
+
+    /// The cache block device handles are kept in.
+    pub(crate) fn cache(&self) -> &BlkCache {
+        match &self.cache_handle {
+            Some(cache) => cache,
+            None => BlkCache::global(),
+        }
+    }
+
+    /// Enable or disable Direct I/O for device reads.
+    pub fn with_direct(mut self, direct: bool) -> Self {
+        self.direct = direct;
+        self
+    }
+
+    /// Enable or disable retrying device reads rejected by Direct I/O
+    /// through the page cache.
+    pub fn with_buffered_fallback(mut self, buffered_fallback: bool) -> Self {
+        self.buffered_fallback = buffered_fallback;
+        self
+    }
+
     /// Enable or disable filling holes with zeros.
     pub fn with_fill_holes(mut self, fill: bool) -> Self {
         self.fill_holes = fill;
@@ -97,6 +798,20 @@ impl Options {
         self
     }
 
+    /// Enable or disable checking unwritten extents for nonzero data.
+    ///
+    /// See [`check_unwritten`](Options::check_unwritten).
+    pub fn with_check_unwritten(mut self, check: bool) -> Self {
+        self.check_unwritten = check;
+        self
+    }
+
+    /// Set the byte used to fill holes and unwritten extents.
+    pub fn with_fill_byte(mut self, byte: u8) -> Self {
+        self.fill_byte = byte;
+        self
+    }
+
     /// Enable or disable fallback to regular file read.
     pub fn with_allow_fallback(mut self, allow: bool) -> Self {
         self.allow_fallback = allow;
@@ -114,46 +829,534 @@ impl Options {
 
     /// Enable or disable dry run mode.
     ///
-    /// When enabled, no actual I/O operations are performed. Instead, the
-    /// operation pretends to successfully read the requested amount of data.
-    /// This is useful for testing extent mapping logic without performing
-    /// time-consuming I/O operations.
+    /// When enabled, no actual I/O operations are performed and the device is
+    /// never opened. Instead, the operation pretends to successfully read the
+    /// requested amount of data and reports its plan in
+    /// [`State::planned`](crate::State::planned).
     pub fn with_dry_run(mut self, dry_run: bool) -> Self {
         self.dry_run = dry_run;
         self
     }
+
+    /// Register a callback to be notified of read progress.
+    pub fn with_progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(ProgressEvent) + Send + Sync + 'static,
+    {
+        self.progress = Some(ProgressCallback::new(f));
+        self
+    }
+
+    /// Register an observer to be notified of the stages of a read.
+    pub fn with_observer(mut self, observer: Arc<dyn ReadObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Set a quick validity check for the data returned by a read.
+    pub fn with_validator<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.validator = Some(Validator::new(f));
+        self
+    }
+
+    /// Enable or disable refreshing the extent map when a read looks stale.
+    pub fn with_refresh_on_stale(mut self, refresh: bool) -> Self {
+        self.refresh_on_stale = refresh;
+        self
+    }
+
+    /// Enable or disable internal handling of Direct I/O alignment.
+    ///
+    /// When enabled, unaligned reads go through an aligned bounce buffer
+    /// instead of failing with `EINVAL`.
+    pub fn with_auto_align(mut self, auto_align: bool) -> Self {
+        self.auto_align = auto_align;
+        self
+    }
+
+    /// Set the NUMA node to allocate read buffers on.
+    pub fn with_buffer_node(mut self, node: NumaNode) -> Self {
+        self.buffer_node = Some(node);
+        self
+    }
+
+    /// Enable or disable writing to the block device via [`BlkWriter`](crate::BlkWriter).
+    pub fn with_allow_write(mut self, allow_write: bool) -> Self {
+        self.allow_write = allow_write;
+        self
+    }
+
+    /// Enable or disable issuing device reads in physical order.
+    pub fn with_sort_physical(mut self, sort_physical: bool) -> Self {
+        self.sort_physical = sort_physical;
+        self
+    }
+
+    /// Merge device reads separated by physical gaps of at most `bytes`.
+    ///
+    /// The gap data is read and discarded.
+    pub fn with_coalesce_gap(mut self, bytes: u64) -> Self {
+        self.coalesce_gap = Some(bytes);
+        self
+    }
+
+    /// Set the number of threads used to read the extents of a single call.
+    ///
+    /// Values below 1 are treated as 1.
+    pub fn with_parallelism(mut self, threads: usize) -> Self {
+        self.parallelism = threads;
+        self
+    }
+
+    /// Service parallel reads with the threads of `pool`, instead of
+    /// spawning threads for every read.
+    pub fn with_read_pool(mut self, pool: ReadPool) -> Self {
+        self.read_pool = Some(pool);
+        self
+    }
+
+    /// Limit the reads in flight on a device to `reads`.
+    ///
+    /// Values below 1 are treated as 1.
+    pub fn with_max_in_flight(mut self, reads: usize) -> Self {
+        self.max_in_flight = Some(reads);
+        self
+    }
+
+    /// Set the backend used to issue device reads.
+    pub fn with_io_engine<E: IoEngine + 'static>(mut self, engine: E) -> Self {
+        self.io_engine = Arc::new(engine);
+        self
+    }
+
+    /// Set the backend resolving, opening and describing devices.
+    pub fn with_device_backend<B: DeviceBackend + 'static>(mut self, backend: B) -> Self {
+        self.device_backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// Read the data of files from `source` instead of their device.
+    ///
+    /// Physical offsets from FIEMAP or caller-supplied extents are read from
+    /// the source, so that files can be recovered from a captured image of
+    /// their disk or partition. This replaces any
+    /// [`device_backend`](Options::device_backend).
+    pub fn with_device_override(self, source: DeviceSource) -> Self {
+        self.with_device_backend(source)
+    }
+
+    /// The backend devices are accessed through.
+    pub(crate) fn backend(&self) -> &dyn DeviceBackend {
+        match &self.device_backend {
+            Some(backend) => backend.as_ref(),
+            None => &BlockDeviceBackend,
+        }
+    }
+
+    /// Set the flags passed to `preadv2` for device and fallback reads.
+    pub fn with_read_flags(mut self, flags: ReadFlags) -> Self {
+        self.read_flags = flags;
+        self
+    }
+
+    /// Limit device reads to `bytes_per_sec` bytes per second.
+    pub fn with_max_throughput(mut self, bytes_per_sec: u64) -> Self {
+        self.max_throughput = Some(Throttle::new(bytes_per_sec));
+        self
+    }
+
+    /// Set the I/O priority of device reads.
+    pub fn with_io_priority(mut self, priority: IoPriority) -> Self {
+        self.io_priority = Some(priority);
+        self
+    }
+
+    /// Set the maximum duration of a single call.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retry device reads that fail with a transient error.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Enable or disable filling unreadable device ranges instead of failing.
+    pub fn with_best_effort(mut self, best_effort: bool) -> Self {
+        self.best_effort = best_effort;
+        self
+    }
+
+    /// Inject the faults of `plan` into device reads.
+    #[cfg(feature = "fault-injection")]
+    pub fn with_fault_injection(mut self, plan: FaultPlan) -> Self {
+        self.fault_injection = Some(plan);
+        self
+    }
+
+    /// Enable or disable measuring the duration of each stage of a read.
+    pub fn with_timing(mut self, timing: bool) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /// Compute a digest of the returned data with `checksum`.
+    #[cfg(feature = "checksum")]
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// Also digest the parts of the returned data given by `unit`.
+    #[cfg(feature = "checksum")]
+    pub fn with_checksum_unit(mut self, unit: ChecksumUnit) -> Self {
+        self.checksum_unit = Some(unit);
+        self
+    }
+
+    /// Enable or disable flushing the file before querying its extent map.
+    pub fn with_fiemap_sync(mut self, fiemap_sync: bool) -> Self {
+        self.fiemap_sync = fiemap_sync;
+        self
+    }
+
+    /// Keep extent maps in `cache` across runs.
+    pub fn with_disk_extent_cache(mut self, cache: DiskExtentCache) -> Self {
+        self.disk_extent_cache = Some(cache);
+        self
+    }
+
+    /// Enable or disable keeping extent maps in the device cache.
+    pub fn with_cache_extents(mut self, cache_extents: bool) -> Self {
+        self.cache_extents = cache_extents;
+        self
+    }
+
+    /// Enable or disable re-checking the extent map after the device reads.
+    pub fn with_verify_extents(mut self, verify_extents: bool) -> Self {
+        self.verify_extents = verify_extents;
+        self
+    }
+
+    /// Enable or disable checking that the extents address the device.
+    pub fn with_verify_device(mut self, verify_device: bool) -> Self {
+        self.verify_device = verify_device;
+        self
+    }
+
+    /// Enable or disable probing the page cache for dirty pages.
+    pub fn with_check_dirty(mut self, check_dirty: bool) -> Self {
+        self.check_dirty = check_dirty;
+        self
+    }
+
+    /// Enable or disable failing reads of ranges with dirty pages.
+    pub fn with_fail_on_dirty(mut self, fail_on_dirty: bool) -> Self {
+        self.fail_on_dirty = fail_on_dirty;
+        self
+    }
+
+    /// Enable or disable writing the file's data out before reading.
+    pub fn with_sync_first(mut self, sync_first: bool) -> Self {
+        self.sync_first = sync_first;
+        self
+    }
+
+    /// Set how extents with inline data are read.
+    pub fn with_inline_policy(mut self, inline_policy: InlinePolicy) -> Self {
+        self.inline_policy = inline_policy;
+        self
+    }
+
+    /// Set how extents with encoded data are read.
+    pub fn with_encoded_policy(mut self, encoded_policy: EncodedPolicy) -> Self {
+        self.encoded_policy = encoded_policy;
+        self
+    }
+
+    /// Allow or refuse returning the device blocks of encrypted data.
+    pub fn with_allow_ciphertext(mut self, allow_ciphertext: bool) -> Self {
+        self.allow_ciphertext = allow_ciphertext;
+        self
+    }
+
+    /// Enable or disable translating btrfs addresses to member devices.
+    pub fn with_translate_btrfs(mut self, translate_btrfs: bool) -> Self {
+        self.translate_btrfs = translate_btrfs;
+        self
+    }
+
+    /// Enable or disable reading from the devices below device-mapper
+    /// targets.
+    pub fn with_translate_dm(mut self, translate_dm: bool) -> Self {
+        self.translate_dm = translate_dm;
+        self
+    }
+
+    /// Enable or disable reading RAID1 md arrays from one of their members.
+    pub fn with_translate_md(mut self, translate_md: bool) -> Self {
+        self.translate_md = translate_md;
+        self
+    }
+
+    /// Enable or disable reading loop devices through their backing files.
+    pub fn with_translate_loop(mut self, translate_loop: bool) -> Self {
+        self.translate_loop = translate_loop;
+        self
+    }
+
+    /// Enable or disable reading overlayfs files through their layers.
+    pub fn with_resolve_overlay(mut self, resolve_overlay: bool) -> Self {
+        self.resolve_overlay = resolve_overlay;
+        self
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[derive(Debug)]
+    struct NoopObserver;
+
+    impl ReadObserver for NoopObserver {}
+
     #[test]
     fn test_default_options() {
         let opts = Options::default();
         assert!(opts.enable_cache);
+        assert!(opts.cache_handle.is_none());
+        assert!(opts.direct);
+        assert!(!opts.buffered_fallback);
         assert!(!opts.fill_holes);
         assert!(!opts.zero_unwritten);
+        assert!(!opts.check_unwritten);
+        assert_eq!(opts.fill_byte, 0);
         assert!(!opts.allow_fallback);
         assert!(!opts.read_exact);
         assert!(!opts.dry_run);
+        assert!(opts.progress.is_none());
+        assert!(opts.observer.is_none());
+        assert!(opts.validator.is_none());
+        assert!(!opts.refresh_on_stale);
+        assert!(!opts.auto_align);
+        assert_eq!(opts.buffer_node, None);
+        assert!(!opts.allow_write);
+        assert!(!opts.sort_physical);
+        assert!(opts.coalesce_gap.is_none());
+        assert_eq!(opts.parallelism, 1);
+        assert!(opts.read_pool.is_none());
+        assert_eq!(opts.max_in_flight, None);
+        assert_eq!(opts.io_engine.name(), "preadv");
+        assert!(opts.device_backend.is_none());
+        assert!(opts.read_flags.is_empty());
+        assert_eq!(opts.io_priority, None);
+        assert!(opts.max_throughput.is_none());
+        assert!(opts.timeout.is_none());
+        assert_eq!(opts.retry, RetryPolicy::none());
+        assert!(!opts.best_effort);
+        #[cfg(feature = "fault-injection")]
+        assert!(opts.fault_injection.is_none());
+        assert!(!opts.timing);
+        #[cfg(feature = "checksum")]
+        assert!(opts.checksum.is_none());
+        #[cfg(feature = "checksum")]
+        assert!(opts.checksum_unit.is_none());
+        assert!(!opts.fiemap_sync);
+        assert!(opts.disk_extent_cache.is_none());
+        assert!(!opts.cache_extents);
+        assert!(!opts.verify_extents);
+        assert!(!opts.verify_device);
+        assert!(!opts.check_dirty);
+        assert!(!opts.fail_on_dirty);
+        assert!(!opts.sync_first);
+        assert_eq!(opts.inline_policy, InlinePolicy::ReadFile);
+        assert_eq!(opts.encoded_policy, EncodedPolicy::Error);
+        assert!(!opts.allow_ciphertext);
+        assert!(opts.translate_btrfs);
+        assert!(!opts.translate_dm);
+        assert!(!opts.translate_md);
+        assert!(!opts.translate_loop);
+        assert!(opts.resolve_overlay);
+    }
+
+    #[test]
+    fn test_presets() {
+        let recovery = Options::recovery();
+        assert!(recovery.fill_holes);
+        assert!(!recovery.zero_unwritten);
+        assert!(!recovery.allow_fallback);
+        assert!(!recovery.read_exact);
+        assert!(recovery.best_effort);
+
+        let verify = Options::verify();
+        assert!(verify.fill_holes);
+        assert!(verify.zero_unwritten);
+        assert!(!verify.allow_fallback);
+        assert!(verify.read_exact);
+
+        let fast = Options::fast();
+        assert!(fast.enable_cache);
+        assert!(fast.allow_fallback);
     }
 
     #[test]
     fn test_builder_pattern() {
+        let cache = BlkCache::new();
+        let extent_dir = tempfile::tempdir().unwrap();
         let opts = Options::new()
             .with_cache(false)
+            .with_cache_handle(&cache)
             .with_fill_holes(true)
             .with_zero_unwritten(true)
+            .with_check_unwritten(true)
+            .with_fill_byte(0xDE)
             .with_allow_fallback(true)
             .with_read_exact(true)
-            .with_dry_run(true);
+            .with_dry_run(true)
+            .with_progress(|_| {})
+            .with_observer(Arc::new(NoopObserver))
+            .with_validator(|data| !data.is_empty())
+            .with_refresh_on_stale(true)
+            .with_auto_align(true)
+            .with_buffer_node(NumaNode::Node(1))
+            .with_allow_write(true)
+            .with_sort_physical(true)
+            .with_coalesce_gap(4096)
+            .with_parallelism(4)
+            .with_read_pool(ReadPool::new(2).unwrap())
+            .with_max_in_flight(2)
+            .with_io_engine(crate::engine::UringEngine)
+            .with_device_backend(BlockDeviceBackend)
+            .with_read_flags(ReadFlags::HIPRI | ReadFlags::NOWAIT)
+            .with_io_priority(IoPriority::Idle)
+            .with_max_throughput(1 << 20)
+            .with_timeout(Duration::from_secs(30))
+            .with_retry(RetryPolicy {
+                attempts: 3,
+                backoff: Duration::from_millis(10),
+            })
+            .with_best_effort(true)
+            .with_timing(true)
+            .with_fiemap_sync(true)
+            .with_disk_extent_cache(DiskExtentCache::new(extent_dir.path()).unwrap())
+            .with_cache_extents(true)
+            .with_verify_extents(true)
+            .with_verify_device(true)
+            .with_check_dirty(true)
+            .with_fail_on_dirty(true)
+            .with_sync_first(true)
+            .with_inline_policy(InlinePolicy::Error)
+            .with_encoded_policy(EncodedPolicy::Fill)
+            .with_allow_ciphertext(true)
+            .with_translate_btrfs(false)
+            .with_translate_dm(true)
+            .with_translate_md(true)
+            .with_translate_loop(true)
+            .with_resolve_overlay(false)
+            .with_direct(false)
+            .with_buffered_fallback(true);
 
         assert!(!opts.enable_cache);
+        assert_eq!(opts.cache_handle, Some(cache));
+        assert!(!opts.direct);
+        assert!(opts.buffered_fallback);
         assert!(opts.fill_holes);
         assert!(opts.zero_unwritten);
+        assert!(opts.check_unwritten);
+        assert_eq!(opts.fill_byte, 0xDE);
         assert!(opts.allow_fallback);
         assert!(opts.read_exact);
         assert!(opts.dry_run);
+        assert!(opts.progress.is_some());
+        assert!(opts.observer.is_some());
+        assert!(opts.validator.as_ref().unwrap().validate(&[0]));
+        assert!(opts.refresh_on_stale);
+        assert!(opts.auto_align);
+        assert_eq!(opts.buffer_node, Some(NumaNode::Node(1)));
+        assert!(opts.allow_write);
+        assert!(opts.sort_physical);
+        assert_eq!(opts.coalesce_gap, Some(4096));
+        assert_eq!(opts.parallelism, 4);
+        assert_eq!(opts.read_pool.as_ref().map(ReadPool::threads), Some(2));
+        assert_eq!(opts.max_in_flight, Some(2));
+        assert_eq!(opts.io_engine.name(), "io_uring");
+        assert!(opts.device_backend.is_some());
+        assert!(opts.read_flags.contains(ReadFlags::HIPRI));
+        assert!(opts.read_flags.contains(ReadFlags::NOWAIT));
+        assert_eq!(opts.io_priority, Some(IoPriority::Idle));
+        assert_eq!(
+            opts.max_throughput.map(|t| t.bytes_per_sec()),
+            Some(1 << 20)
+        );
+        assert_eq!(opts.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(opts.retry.attempts, 3);
+        assert_eq!(opts.retry.backoff, Duration::from_millis(10));
+        assert!(opts.best_effort);
+        #[cfg(feature = "fault-injection")]
+        {
+            let plan = FaultPlan::new().with_fault(crate::fault::Fault::short(0..4096, 512));
+            let opts = Options::new().with_fault_injection(plan);
+            assert_eq!(opts.fault_injection.unwrap().faults().count(), 1);
+        }
+        assert!(opts.timing);
+        #[cfg(feature = "checksum")]
+        {
+            let opts = Options::new()
+                .with_checksum(Checksum::Sha256)
+                .with_checksum_unit(ChecksumUnit::Block(4096));
+            assert_eq!(opts.checksum, Some(Checksum::Sha256));
+            assert_eq!(opts.checksum_unit, Some(ChecksumUnit::Block(4096)));
+        }
+        assert!(opts.fiemap_sync);
+        assert_eq!(
+            opts.disk_extent_cache.as_ref().map(DiskExtentCache::dir),
+            Some(extent_dir.path())
+        );
+        assert!(opts.cache_extents);
+        assert!(opts.verify_extents);
+        assert!(opts.verify_device);
+        assert!(opts.check_dirty);
+        assert!(opts.fail_on_dirty);
+        assert!(opts.sync_first);
+        assert_eq!(opts.inline_policy, InlinePolicy::Error);
+        assert_eq!(opts.encoded_policy, EncodedPolicy::Fill);
+        assert!(opts.allow_ciphertext);
+        assert!(!opts.translate_btrfs);
+        assert!(opts.translate_dm);
+        assert!(opts.translate_md);
+        assert!(opts.translate_loop);
+        assert!(!opts.resolve_overlay);
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let eio = io::Error::from_raw_os_error(libc::EIO);
+        let eagain = io::Error::from_raw_os_error(libc::EAGAIN);
+        let enoent = io::Error::from_raw_os_error(libc::ENOENT);
+
+        assert!(!RetryPolicy::none().should_retry(&eio, 1));
+
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::ZERO,
+        };
+        assert!(policy.should_retry(&eio, 1));
+        assert!(policy.should_retry(&eagain, 2));
+        assert!(!policy.should_retry(&eio, 3));
+        assert!(!policy.should_retry(&enoent, 1));
+    }
+
+    #[test]
+    fn test_io_priority() {
+        assert_eq!(IoPriority::Idle.ioprio(), 3 << 13);
+        assert_eq!(IoPriority::BestEffort(0).ioprio(), 2 << 13);
+        assert_eq!(IoPriority::BestEffort(4).ioprio(), 2 << 13 | 4);
+        assert_eq!(IoPriority::BestEffort(200).ioprio(), 2 << 13 | 7);
     }
 }
@@ -0,0 +1,261 @@
+//! Resolution of overlayfs files to the layer holding their data.
+//!
+//! A file opened through an overlayfs mount belongs to the overlay, whose
+//! extents FIEMAP either can't report or reports for a different inode.
+//! The data lives in a regular file of the upper or one of the lower
+//! layers. With [`Options::resolve_overlay`](crate::Options::resolve_overlay),
+//! that file is found from the mount's layers and its `overlay.metacopy` and
+//! `overlay.redirect` xattrs, and read in place of the overlay file.
+
+use crate::sys;
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+
+/// `f_type` of overlayfs in `statfs`.
+pub(crate) const OVERLAYFS_SUPER_MAGIC: i64 = 0x794c7630;
+
+/// The layers of an overlayfs mount, from `/proc/self/mountinfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Layers {
+    mount_point: PathBuf,
+    upper: Option<PathBuf>,
+    /// Lower layers, topmost first.
+    lower: Vec<PathBuf>,
+    /// Prefix of the xattrs holding overlay metadata.
+    xattr_prefix: &'static str,
+}
+
+/// Replace the octal escapes (such as `\040`) of a mountinfo field.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match octal {
+            Some(digits) => {
+                let value = digits.iter().fold(0u32, |n, d| n * 8 + u32::from(d - b'0'));
+                out.push(value as u8);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Split a `lowerdir` value at the `:` not escaped by a backslash. Empty
+/// entries, as around the `::` before data-only layers, are dropped.
+fn split_layers(value: &str) -> Vec<PathBuf> {
+    let mut layers = Vec::new();
+    let mut layer = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => layer.extend(chars.next()),
+            ':' => layers.push(std::mem::take(&mut layer)),
+            c => layer.push(c),
+        }
+    }
+    layers.push(layer);
+    layers
+        .into_iter()
+        .filter(|layer| !layer.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Parse a line of `/proc/self/mountinfo`, returning `None` if it is not an
+/// overlayfs mount.
+fn parse_mount(line: &str) -> Option<Layers> {
+    let (mount, fs) = line.split_once(" - ")?;
+    let mount_point = mount.split(' ').nth(4)?;
+    let mut fs = fs.split(' ');
+    if fs.next()? != "overlay" {
+        return None;
+    }
+    let options = fs.nth(1)?;
+
+    let mut layers = Layers {
+        mount_point: PathBuf::from(unescape(mount_point)),
+        upper: None,
+        lower: Vec::new(),
+        xattr_prefix: "trusted.overlay.",
+    };
+    for option in options.split(',').map(unescape) {
+        let (key, value) = option.split_once('=').unwrap_or((&option, ""));
+        match key {
+            "upperdir" => layers.upper = Some(PathBuf::from(value)),
+            "lowerdir" => layers.lower.extend(split_layers(value)),
+            "lowerdir+" | "datadir+" => layers.lower.push(PathBuf::from(value)),
+            "userxattr" => layers.xattr_prefix = "user.overlay.",
+            _ => {}
+        }
+    }
+    Some(layers)
+}
+
+/// Find the file holding the data of `relative` (a path below the mount
+/// point) in `layers`, reading overlay metadata with `xattr`.
+///
+/// Files in upper layers that only carry a copy of the metadata
+/// (`metacopy`) are skipped, following their `redirect` to the lower file.
+fn find_in(
+    layers: &Layers,
+    relative: &Path,
+    xattr: impl Fn(&Path, &str) -> Option<Vec<u8>>,
+) -> Option<PathBuf> {
+    let mut relative = relative.to_path_buf();
+    for layer in layers.upper.iter().chain(&layers.lower) {
+        let candidate = layer.join(&relative);
+        if !fs::symlink_metadata(&candidate).is_ok_and(|m| m.is_file()) {
+            continue;
+        }
+        let attr = |name: &str| xattr(&candidate, &format!("{}{}", layers.xattr_prefix, name));
+        if attr("metacopy").is_none() {
+            return Some(candidate);
+        }
+        if let Some(redirect) = attr("redirect") {
+            let redirect = PathBuf::from(String::from_utf8_lossy(&redirect).into_owned());
+            relative = match redirect.strip_prefix("/") {
+                Ok(absolute) => absolute.to_path_buf(),
+                Err(_) => relative.with_file_name(redirect),
+            };
+        }
+    }
+    None
+}
+
+/// The overlayfs mount `path` is on: that with the longest mount point
+/// containing it.
+fn mount_of(path: &Path) -> io::Result<Option<Layers>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mountinfo
+        .lines()
+        .filter_map(parse_mount)
+        .filter(|layers| path.starts_with(&layers.mount_point))
+        .max_by_key(|layers| layers.mount_point.components().count()))
+}
+
+/// Open the file holding the data of `file`, if it is on overlayfs.
+///
+/// Returns `None` if `file` is not on overlayfs, or if its data could not be
+/// located in a layer. A layer file whose size or allocation differs from
+/// that reported for `file` is not trusted.
+pub(crate) fn real_file(file: &File) -> io::Result<Option<File>> {
+    if sys::fs_type(file.as_raw_fd())? != OVERLAYFS_SUPER_MAGIC {
+        return Ok(None);
+    }
+    // The name the file was opened by, with any symlinks resolved
+    let Ok(path) = fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())) else {
+        return Ok(None);
+    };
+    let Some(layers) = mount_of(&path).ok().flatten() else {
+        return Ok(None);
+    };
+    let relative: PathBuf = path
+        .strip_prefix(&layers.mount_point)
+        .unwrap_or(&path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    let xattr = |path: &Path, name: &str| sys::getxattr(path, name).ok().flatten();
+    let Some(real_path) = find_in(&layers, &relative, xattr) else {
+        return Ok(None);
+    };
+
+    let real = File::open(real_path)?;
+    let (meta, real_meta) = (file.metadata()?, real.metadata()?);
+    if meta.size() != real_meta.size() || meta.blocks() != real_meta.blocks() {
+        return Ok(None);
+    }
+    Ok(Some(real))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse_mount() {
+        let line = "52 29 0:46 / /var/lib/docker/overlay2/abc/merged rw,relatime shared:27 \
+                    - overlay overlay rw,lowerdir=/l/one:/l/tw\\134:o::/l/data,\
+                    upperdir=/u/with\\040space,workdir=/w";
+        assert_eq!(
+            parse_mount(line),
+            Some(Layers {
+                mount_point: PathBuf::from("/var/lib/docker/overlay2/abc/merged"),
+                upper: Some(PathBuf::from("/u/with space")),
+                lower: vec![
+                    PathBuf::from("/l/one"),
+                    PathBuf::from("/l/tw:o"),
+                    PathBuf::from("/l/data"),
+                ],
+                xattr_prefix: "trusted.overlay.",
+            })
+        );
+
+        let line = "60 29 0:50 / /merged rw - overlay none ro,lowerdir+=/a,lowerdir+=/b,userxattr";
+        let layers = parse_mount(line).unwrap();
+        assert_eq!(layers.upper, None);
+        assert_eq!(layers.lower, vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+        assert_eq!(layers.xattr_prefix, "user.overlay.");
+
+        let line = "25 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw";
+        assert_eq!(parse_mount(line), None);
+    }
+
+    #[test]
+    fn test_find_in_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer = |name: &str, files: &[&str]| {
+            let root = dir.path().join(name);
+            for file in files {
+                let path = root.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, name).unwrap();
+            }
+            root
+        };
+        let layers = Layers {
+            mount_point: PathBuf::from("/merged"),
+            upper: Some(layer("upper", &["a/copied", "a/meta", "a/moved"])),
+            lower: vec![
+                layer("lower1", &["a/copied", "a/meta"]),
+                layer("lower0", &["a/lower", "b/original"]),
+            ],
+            xattr_prefix: "trusted.overlay.",
+        };
+        let mut xattrs = HashMap::new();
+        let upper = layers.upper.clone().unwrap();
+        xattrs.insert((upper.join("a/meta"), "metacopy"), Vec::new());
+        xattrs.insert((upper.join("a/moved"), "metacopy"), Vec::new());
+        xattrs.insert((upper.join("a/moved"), "redirect"), b"/b/original".to_vec());
+        let xattr = |path: &Path, name: &str| {
+            let name = name.strip_prefix("trusted.overlay.").unwrap();
+            xattrs.get(&(path.to_path_buf(), name)).cloned()
+        };
+
+        let find = |relative: &str| {
+            find_in(&layers, Path::new(relative), xattr)
+                .map(|path| path.strip_prefix(dir.path()).unwrap().to_path_buf())
+        };
+        assert_eq!(find("a/copied"), Some(PathBuf::from("upper/a/copied")));
+        assert_eq!(find("a/meta"), Some(PathBuf::from("lower1/a/meta")));
+        assert_eq!(find("a/lower"), Some(PathBuf::from("lower0/a/lower")));
+        assert_eq!(find("a/moved"), Some(PathBuf::from("lower0/b/original")));
+        assert_eq!(find("a/missing"), None);
+        // Directories hold no data
+        assert_eq!(find("a"), None);
+    }
+}
@@ -0,0 +1,120 @@
+//! Serialization of read results and extent maps (`serde` feature).
+//!
+//! [`State`](crate::State) and [`Options`](crate::Options) implement
+//! `Serialize` and `Deserialize` directly. Extents are defined by
+//! [`blkmap`], so they are serialized through the [`SerdeExtent`] newtype,
+//! which lets extent maps be persisted, e.g. as JSON, and handed back to
+//! [`BlkReader::blk_read_with_extents`](crate::BlkReader::blk_read_with_extents)
+//! for offline recovery.
+
+use blkmap::{ExtentFlags, FiemapExtent};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A [`FiemapExtent`] that can be serialized.
+///
+/// The flags are stored as their raw `FIEMAP_EXTENT_*` bits.
+///
+/// # Example
+///
+/// ```
+/// use blkreader::{Extent, ExtentFlags, SerdeExtent};
+///
+/// let extents = vec![Extent {
+///     logical: 0,
+///     physical: 1 << 20,
+///     length: 4096,
+///     flags: ExtentFlags::LAST,
+/// }];
+/// let persisted: Vec<SerdeExtent> = extents.iter().copied().map(SerdeExtent).collect();
+/// let restored: Vec<Extent> = persisted.into_iter().map(Extent::from).collect();
+/// assert_eq!(restored, extents);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "RawExtent", into = "RawExtent")]
+pub struct SerdeExtent(pub FiemapExtent);
+
+impl From<FiemapExtent> for SerdeExtent {
+    fn from(extent: FiemapExtent) -> Self {
+        SerdeExtent(extent)
+    }
+}
+
+impl From<SerdeExtent> for FiemapExtent {
+    fn from(extent: SerdeExtent) -> Self {
+        extent.0
+    }
+}
+
+/// Serialized form of an extent.
+#[derive(Serialize, Deserialize)]
+struct RawExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    flags: u32,
+}
+
+impl From<RawExtent> for SerdeExtent {
+    fn from(raw: RawExtent) -> Self {
+        SerdeExtent(FiemapExtent {
+            logical: raw.logical,
+            physical: raw.physical,
+            length: raw.length,
+            flags: ExtentFlags::from_bits_retain(raw.flags),
+        })
+    }
+}
+
+impl From<SerdeExtent> for RawExtent {
+    fn from(extent: SerdeExtent) -> Self {
+        RawExtent {
+            logical: extent.0.logical,
+            physical: extent.0.physical,
+            length: extent.0.length,
+            flags: extent.0.flags.bits(),
+        }
+    }
+}
+
+/// `serde(with)` adapter for a list of extents.
+pub(crate) mod extents {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        extents: &[FiemapExtent],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(extents.iter().copied().map(SerdeExtent))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<FiemapExtent>, D::Error> {
+        let extents = Vec::<SerdeExtent>::deserialize(deserializer)?;
+        Ok(extents.into_iter().map(|extent| extent.0).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::value::{Error, MapDeserializer};
+
+    #[test]
+    fn test_deserialize_extent() {
+        let fields = vec![
+            ("logical", 4096u64),
+            ("physical", 1 << 20),
+            ("length", 8192),
+            ("flags", 0x801),
+        ];
+        let extent =
+            SerdeExtent::deserialize(MapDeserializer::<_, Error>::new(fields.into_iter())).unwrap();
+        assert_eq!(extent.0.logical, 4096);
+        assert_eq!(extent.0.physical, 1 << 20);
+        assert_eq!(extent.0.length, 8192);
+        assert!(extent.0.flags.contains(ExtentFlags::LAST));
+        assert!(extent.0.flags.contains(ExtentFlags::UNWRITTEN));
+        assert_eq!(RawExtent::from(extent).flags, 0x801);
+    }
+}
@@ -0,0 +1,252 @@
+//! Worker threads shared by parallel reads.
+//!
+//! With [`Options::parallelism`](crate::Options::parallelism) above 1, a
+//! read spawns its worker threads and joins them before returning. A
+//! [`ReadPool`], set with
+//! [`Options::with_read_pool`](crate::Options::with_read_pool), keeps the
+//! workers running between reads instead, so frequent small reads do not
+//! pay for creating threads.
+
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+/// A pool of threads servicing the device reads of parallel reads.
+///
+/// The reads of one call are split into
+/// [`Options::parallelism`](crate::Options::parallelism) parts; the calling
+/// thread reads one of them and the pool's threads the others. Cloning is
+/// cheap and shares the pool; its threads exit once the last clone is
+/// dropped.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkReader, Options, ReadPool};
+/// use std::path::Path;
+///
+/// let options = Options::new()
+///     .with_parallelism(4)
+///     .with_read_pool(ReadPool::new(3)?);
+/// let mut buf = vec![0u8; 1 << 20];
+/// Path::new("/path/to/file").blk_read_at_opt(&mut buf, 0, &options)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct ReadPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    tasks: Mutex<Sender<Task>>,
+    threads: usize,
+}
+
+impl ReadPool {
+    /// Start a pool of `threads` threads.
+    ///
+    /// Values below 1 are treated as 1. Fails if a thread cannot be
+    /// spawned.
+    pub fn new(threads: usize) -> io::Result<Self> {
+        let threads = threads.max(1);
+        let (sender, receiver) = mpsc::channel::<Task>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("blkreader-read-{}", index))
+                .spawn(move || work(&receiver))?;
+        }
+        Ok(Self {
+            inner: Arc::new(PoolInner {
+                tasks: Mutex::new(sender),
+                threads,
+            }),
+        })
+    }
+
+    /// Number of threads in the pool.
+    pub fn threads(&self) -> usize {
+        self.inner.threads
+    }
+
+    /// Run `tasks` on the pool, and `local` on the calling thread, and
+    /// return once all of them finished.
+    ///
+    /// A panic in any of them is resumed on the calling thread.
+    pub(crate) fn scope<'a>(
+        &self,
+        local: impl FnOnce(),
+        tasks: Vec<Box<dyn FnOnce() + Send + 'a>>,
+    ) {
+        let latch = Arc::new(Latch {
+            pending: Mutex::new((0, None)),
+            done: Condvar::new(),
+        });
+        // Waits for the tasks also if `local` panics, as they borrow from
+        // the caller
+        let wait = WaitGuard(&latch);
+        {
+            let sender = self.inner.tasks.lock().unwrap_or_else(|e| e.into_inner());
+            for task in tasks {
+                // SAFETY: the task only runs while `scope` waits for it:
+                // `wait` blocks until every task sent has finished, and
+                // tasks always report finishing, also when they panic.
+                let task: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(task) };
+                latch.start();
+                let finished = Arc::clone(&latch);
+                let task: Task = Box::new(move || {
+                    let result = panic::catch_unwind(AssertUnwindSafe(task));
+                    finished.finish(result.err());
+                });
+                // The workers only exit once the sender is dropped, so this
+                // does not happen
+                if sender.send(task).is_err() {
+                    latch.finish(None);
+                    panic!("read pool workers exited");
+                }
+            }
+        }
+        local();
+        drop(wait);
+        let panicked = latch
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .1
+            .take();
+        if let Some(payload) = panicked {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+impl fmt::Debug for ReadPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadPool")
+            .field("threads", &self.inner.threads)
+            .finish()
+    }
+}
+
+/// Runs tasks until the pool is dropped.
+fn work(receiver: &Mutex<Receiver<Task>>) {
+    loop {
+        let task = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        match task {
+            Ok(task) => task(),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Counts the tasks of a scope still running, and keeps the first panic.
+struct Latch {
+    pending: Mutex<(usize, Option<Box<dyn Any + Send>>)>,
+    done: Condvar,
+}
+
+impl Latch {
+    fn start(&self) {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).0 += 1;
+    }
+
+    fn finish(&self, panic: Option<Box<dyn Any + Send>>) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.0 -= 1;
+        if pending.1.is_none() {
+            pending.1 = panic;
+        }
+        if pending.0 == 0 {
+            self.done.notify_all();
+        }
+    }
+}
+
+/// Blocks until the tasks of a scope finished when dropped.
+struct WaitGuard<'a>(&'a Latch);
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.0.pending.lock().unwrap_or_else(|e| e.into_inner());
+        while pending.0 > 0 {
+            pending = self.0.done.wait(pending).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_scope() {
+        let pool = ReadPool::new(2).unwrap();
+        assert_eq!(pool.threads(), 2);
+
+        // Tasks borrow from the caller, and run on the pool's threads
+        let mut parts = vec![0usize; 5];
+        let (local, rest) = parts.split_first_mut().unwrap();
+        let names = Mutex::new(Vec::new());
+        let tasks: Vec<Box<dyn FnOnce() + Send + '_>> = rest
+            .iter_mut()
+            .enumerate()
+            .map(|(i, part)| {
+                let names = &names;
+                Box::new(move || {
+                    *part = i + 1;
+                    let name = thread::current().name().map(String::from);
+                    names.lock().unwrap().push(name.unwrap_or_default());
+                }) as Box<dyn FnOnce() + Send + '_>
+            })
+            .collect();
+        pool.scope(|| *local = 10, tasks);
+        assert_eq!(parts, [10, 1, 2, 3, 4]);
+        let names = names.into_inner().unwrap();
+        assert_eq!(names.len(), 4);
+        assert!(names.iter().all(|name| name.starts_with("blkreader-read-")));
+
+        // Later scopes reuse the pool
+        let runs = AtomicUsize::new(0);
+        for _ in 0..3 {
+            let runs = &runs;
+            let tasks: Vec<Box<dyn FnOnce() + Send + '_>> = vec![Box::new(move || {
+                runs.fetch_add(1, Ordering::Relaxed);
+            })];
+            pool.scope(|| {}, tasks);
+        }
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_scope_panics() {
+        let pool = ReadPool::new(1).unwrap();
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let finished = &finished;
+            let tasks: Vec<Box<dyn FnOnce() + Send + '_>> = vec![
+                Box::new(|| panic!("task failed")),
+                Box::new(move || {
+                    finished.fetch_add(1, Ordering::Relaxed);
+                }),
+            ];
+            pool.scope(|| {}, tasks);
+        }));
+        assert!(result.is_err());
+        // The other tasks still ran, and the pool keeps working
+        assert_eq!(finished.load(Ordering::Relaxed), 1);
+        let tasks: Vec<Box<dyn FnOnce() + Send + '_>> = vec![Box::new(|| {
+            finished.fetch_add(1, Ordering::Relaxed);
+        })];
+        pool.scope(|| {}, tasks);
+        assert_eq!(finished.load(Ordering::Relaxed), 2);
+    }
+}
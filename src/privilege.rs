@@ -0,0 +1,185 @@
+//! Reading block devices without staying privileged.
+//!
+//! Opening a block device requires root, but reading through an open
+//! handle does not. [`open_devices_then_drop`] opens the devices a job
+//! needs into the device cache while privileged and then switches to an
+//! unprivileged user for good, so that the rest of the job, parsing
+//! untrusted filesystem data included, never runs as root.
+//! [`open_each_device_then_drop`] does the same for batches of files some
+//! of which may fail.
+
+use crate::options::Options;
+use crate::reader::{data_file, ReadContext};
+use crate::sys;
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Open the block devices holding `paths`, then drop to user `uid` and
+/// group `gid`.
+///
+/// Same as [`open_devices_then_drop_opt`] with default options.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{open_devices_then_drop, BlkReader};
+/// use std::path::PathBuf;
+///
+/// let paths = [PathBuf::from("/data/a"), PathBuf::from("/data/b")];
+/// // Started as root; continue as nobody
+/// open_devices_then_drop(&paths, 65534, 65534)?;
+/// let data = paths[0].blk_read_to_end(&Default::default())?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn open_devices_then_drop(paths: &[PathBuf], uid: u32, gid: u32) -> io::Result<()> {
+    open_devices_then_drop_opt(paths, uid, gid, &Options::default())
+}
+
+/// Open the block devices holding `paths` into the device cache of
+/// `options`, then drop to user `uid` and group `gid`.
+///
+/// The device of each file is opened as reads with `options` open it, as
+/// are the devices its extents are translated to, such as the members of
+/// a multi-device btrfs filesystem. Then supplementary groups are cleared
+/// and the real, effective and saved ids are set to `uid` and `gid`, which
+/// requires `CAP_SETUID` and `CAP_SETGID`. Reads with `options` afterwards
+/// use the cached handles; the files themselves must be readable by `uid`.
+///
+/// If a file fails, its error is returned and privileges are kept. Reads
+/// needing a device that was not opened, or whose handle the cache evicted
+/// (see [`CacheConfig`](crate::CacheConfig)), fail with `EACCES` once
+/// privileges are dropped.
+///
+/// Fails with `InvalidInput` without
+/// [`enable_cache`](Options::enable_cache), since no handle would be kept.
+pub fn open_devices_then_drop_opt(
+    paths: &[PathBuf],
+    uid: u32,
+    gid: u32,
+    options: &Options,
+) -> io::Result<()> {
+    require_cache(options)?;
+    for path in paths {
+        open_devices(path, options)?;
+    }
+    sys::drop_privileges(uid, gid)
+}
+
+/// Open the block devices holding each of `paths` into the device cache of
+/// `options`, then drop to user `uid` and group `gid` in any case.
+///
+/// Like [`open_devices_then_drop_opt`], but a file that fails does not stop
+/// the others: the result for each of `paths` is returned, in order, and
+/// privileges are dropped once all of them were tried. Reads of the files
+/// that failed are then unlikely to succeed. Fails only without
+/// [`enable_cache`](Options::enable_cache), or if privileges cannot be
+/// dropped.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{open_each_device_then_drop, Options};
+/// use std::path::PathBuf;
+///
+/// let paths = [PathBuf::from("/data/a"), PathBuf::from("/data/b")];
+/// let results = open_each_device_then_drop(&paths, 65534, 65534, &Options::new())?;
+/// for (path, result) in paths.iter().zip(results) {
+///     if let Err(e) = result {
+///         eprintln!("{}: {}", path.display(), e);
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn open_each_device_then_drop(
+    paths: &[PathBuf],
+    uid: u32,
+    gid: u32,
+    options: &Options,
+) -> io::Result<Vec<io::Result<()>>> {
+    let results = open_each_device(paths, options)?;
+    sys::drop_privileges(uid, gid)?;
+    Ok(results)
+}
+
+/// Open the block devices holding each of `paths`, with one result per
+/// path.
+fn open_each_device(paths: &[PathBuf], options: &Options) -> io::Result<Vec<io::Result<()>>> {
+    require_cache(options)?;
+    Ok(paths
+        .iter()
+        .map(|path| open_devices(path, options))
+        .collect())
+}
+
+/// Fail unless `options` keep opened devices in the cache.
+fn require_cache(options: &Options) -> io::Result<()> {
+    if !options.enable_cache {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "keeping device handles open requires Options::enable_cache",
+        ));
+    }
+    Ok(())
+}
+
+/// Open the block devices holding `path` into the device cache.
+fn open_devices(path: &Path, options: &Options) -> io::Result<()> {
+    let file = data_file(File::open(path)?, options)?;
+    ReadContext::new(&file, options)
+        .with_path(path)
+        .warm_with(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_privileges_kept_on_failure() {
+        // SAFETY: getuid and getgid have no preconditions.
+        let ids = || unsafe { (libc::getuid(), libc::getgid()) };
+        let before = ids();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let paths = [file.path().to_path_buf(), PathBuf::from("/nonexistent")];
+
+        let options = Options::new().with_cache(false);
+        let err = open_devices_then_drop_opt(&paths, 65534, 65534, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = open_devices_then_drop(&paths[1..], 65534, 65534).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(ids(), before);
+    }
+
+    #[test]
+    fn test_open_each_device() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let paths = [
+            PathBuf::from("/nonexistent/a"),
+            file.path().to_path_buf(),
+            PathBuf::from("/nonexistent/b"),
+        ];
+        let options = Options::new();
+
+        // Each path has its own result, failures included
+        let results = open_each_device(&paths, &options).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            results[2].as_ref().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            results[1].as_ref().err().map(|e| e.kind()),
+            open_devices(&paths[1], &options).err().map(|e| e.kind())
+        );
+
+        let options = options.with_cache(false);
+        let err = open_each_device(&paths, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,85 @@
+//! Progress reporting for read operations.
+//!
+//! Long reads can register a callback via
+//! [`Options::with_progress`](crate::Options::with_progress) to be notified
+//! as data is read from the device or synthesized for holes and unwritten
+//! extents.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// A snapshot of the progress of a read operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressEvent {
+    /// Total number of bytes the operation plans to produce.
+    pub bytes_planned: u64,
+
+    /// Number of bytes read from the device (or file, in fallback mode) so far.
+    pub bytes_read: u64,
+
+    /// Number of bytes filled in for holes or unwritten extents so far.
+    pub bytes_filled: u64,
+
+    /// Logical file offset the operation has reached.
+    pub logical_offset: u64,
+}
+
+impl ProgressEvent {
+    /// Total number of bytes produced so far, read or filled.
+    pub fn bytes_done(&self) -> u64 {
+        self.bytes_read + self.bytes_filled
+    }
+}
+
+/// A shareable progress callback.
+///
+/// This wraps the user-provided closure so that [`Options`](crate::Options)
+/// can remain `Clone` and `Debug`.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(ProgressEvent) + Send + Sync>);
+
+impl ProgressCallback {
+    /// Wrap a closure as a progress callback.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(ProgressEvent) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// Invoke the callback with the given event.
+    pub fn report(&self, event: ProgressEvent) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_progress_callback() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        let callback = ProgressCallback::new(move |event| sink.lock().unwrap().push(event));
+
+        let event = ProgressEvent {
+            bytes_planned: 4096,
+            bytes_read: 1024,
+            bytes_filled: 512,
+            logical_offset: 1536,
+        };
+        callback.clone().report(event);
+
+        assert_eq!(events.lock().unwrap().as_slice(), &[event]);
+        assert_eq!(event.bytes_done(), 1536);
+        assert_eq!(format!("{:?}", callback), "ProgressCallback(..)");
+    }
+}
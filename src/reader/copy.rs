@@ -0,0 +1,936 @@
+//! Operations built on the extent walk: multi-range and streaming reads,
+//! copies to descriptors and sparse files, mapping, prefetching, scanning
+//! and verification.
+
+use super::run::ReadOutcome;
+use super::{
+    extents_in_range, fallback_segments, read_full_at, with_borrowed_file, DeviceHandle,
+    ReadContext, READ_ALIGNMENT, READ_CHUNK_SIZE,
+};
+use crate::buffer::align_up;
+use crate::engine::ReadFlags;
+use crate::error::{DeviceReadError, ShortReadError};
+use crate::map::{map_extents, MappedRange};
+use crate::observer::FallbackEvent;
+use crate::options::{EncodedPolicy, Options};
+use crate::scan::{Match, Scanner};
+use crate::state::{is_encoded, SegmentSource, State};
+use crate::stats::{self, Counter};
+use crate::sys;
+use crate::verify::{self, VerifyReport};
+
+use blkmap::FiemapExtent;
+use std::collections::BTreeMap;
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+impl<'a> ReadContext<'a> {
+    /// Read several ranges using one extent query covering all of them.
+    pub(super) fn read_ranges(
+        &self,
+        ranges: &mut [(u64, usize, &mut [u8])],
+    ) -> io::Result<Vec<State>> {
+        if ranges.iter().any(|(_, len, buf)| *len > buf.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range length exceeds its buffer",
+            ));
+        }
+
+        let requested = || ranges.iter().filter(|(_, len, _)| *len > 0);
+        let (Some(start), Some(end)) = (
+            requested().map(|(offset, _, _)| *offset).min(),
+            requested()
+                .map(|(offset, len, _)| offset.saturating_add(*len as u64))
+                .max(),
+        ) else {
+            return Ok(ranges
+                .iter()
+                .map(|_| State::fallback(Vec::new(), 0))
+                .collect());
+        };
+
+        // Query the union once, unless an extent map is already attached
+        let queried;
+        let map = match self.extent_map {
+            Some(map) => map,
+            None => {
+                queried = self.extents(start, end - start)?;
+                &queried
+            }
+        };
+        let local_slot = OnceLock::new();
+        let ctx = ReadContext {
+            extent_map: Some(map),
+            device_slot: Some(self.device_slot.unwrap_or(&local_slot)),
+            ..*self
+        };
+
+        // Issue reads in order of the physical offset of each range's first extent
+        let mut order: Vec<usize> = (0..ranges.len()).collect();
+        order.sort_by_key(|&i| {
+            let (offset, len, _) = &ranges[i];
+            extents_in_range(map, *offset, *len as u64)
+                .first()
+                .map(|extent| extent.physical + offset.saturating_sub(extent.logical))
+                .unwrap_or(u64::MAX)
+        });
+
+        let mut states: Vec<Option<State>> = ranges.iter().map(|_| None).collect();
+        for i in order {
+            let (offset, len, buf) = &mut ranges[i];
+            states[i] = Some(ctx.read_at(&mut buf[..*len], *offset)?);
+        }
+        Ok(states.into_iter().flatten().collect())
+    }
+
+    /// Stream the raw data of each extent in a range to a callback.
+    pub(super) fn visit_extents<F>(&self, offset: u64, length: u64, mut f: F) -> io::Result<State>
+    where
+        F: FnMut(&FiemapExtent, &[u8]) -> io::Result<()>,
+    {
+        let extents = self.extents(offset, length)?;
+        self.check_extents(&extents)?;
+        let end = offset.saturating_add(length);
+        let align = READ_ALIGNMENT as u64;
+
+        self.with_extent_device(&extents, |device, translated| {
+            let mut buf = self.alloc_buf(READ_CHUNK_SIZE + 2 * READ_ALIGNMENT, READ_ALIGNMENT)?;
+            let mut delivered = 0;
+
+            for (index, extent) in translated.iter().enumerate() {
+                // Holes and hole-like extents have no data on the device, and
+                // encoded extents are skipped when they would be filled
+                let flags = &extent.flags;
+                let filled = is_encoded(flags)
+                    && !flags.is_inline()
+                    && self.options.encoded_policy == EncodedPolicy::Fill;
+                if flags.is_unknown() || flags.is_delalloc() || filled {
+                    continue;
+                }
+
+                let mut start = extent.logical.max(offset);
+                let stop = (extent.logical + extent.length).min(end);
+                while start < stop {
+                    self.check_deadline()?;
+                    let len = (stop - start).min(READ_CHUNK_SIZE as u64);
+                    let physical = extent.physical + (start - extent.logical);
+
+                    let (span, skip) = if extent.flags.is_inline() {
+                        // Inline data is not on the device; read it through the file
+                        let span = &mut buf[..len as usize];
+                        let read = read_full_at(self.file, span, start, self.options.read_flags);
+                        stats::count_result(Counter::FallbackBytes, &read);
+                        let read = read?;
+                        (&span[..read], 0)
+                    } else {
+                        // Direct I/O needs an aligned span
+                        let aligned_start = physical - physical % align;
+                        let aligned_end = align_up(physical + len, align);
+                        let span = &mut buf[..(aligned_end - aligned_start) as usize];
+                        let read = device
+                            .read_at(span, aligned_start, self.options, self.deadline)
+                            .map_err(|source| DeviceReadError {
+                                file_path: self.file_path(),
+                                device_path: device.path().clone(),
+                                extent_index: index,
+                                logical_offset: start,
+                                physical_offset: physical,
+                                length: len as usize,
+                                source,
+                            })?;
+                        (
+                            &span[..read.min(span.len())],
+                            (physical - aligned_start) as usize,
+                        )
+                    };
+                    let available = span.len().saturating_sub(skip).min(len as usize);
+                    let piece = FiemapExtent {
+                        logical: start,
+                        physical,
+                        length: available as u64,
+                        flags: extent.flags,
+                    };
+                    f(&piece, &span[skip..skip + available])?;
+                    delivered += available;
+
+                    if available < len as usize {
+                        // Short read at the end of the device (or file)
+                        let extents = extents.clone();
+                        return Ok(device.state(extents, delivered));
+                    }
+                    start += len;
+                }
+            }
+
+            Ok(device.state(extents.clone(), delivered))
+        })
+    }
+
+    /// Copy a range to `out` without passing the data through user space.
+    pub(super) fn copy_to(
+        &self,
+        out: BorrowedFd<'_>,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<State> {
+        if length == 0 {
+            return Ok(State::fallback(Vec::new(), 0));
+        }
+
+        let extents = self.query_extents(offset, length, false)?;
+        if self.options.allow_fallback && self.can_use_fallback(&extents, offset, length) {
+            let copied = self.send(out, self.file, offset, length, Counter::FallbackBytes)?;
+            self.observe(|observer| {
+                observer.on_fallback(&FallbackEvent {
+                    offset,
+                    length,
+                    bytes_read: copied,
+                })
+            });
+            let mut state = State::fallback(extents, copied as usize);
+            state.set_segments(fallback_segments(offset, copied as usize));
+            return Ok(state);
+        }
+
+        self.check_extents(&extents)?;
+        self.with_extent_device(&extents, |device, translated| {
+            let end = offset + length;
+            let mut outcome = ReadOutcome::default();
+            let mut current = offset;
+
+            // Synthesize `len` bytes at `logical`
+            let fill = |outcome: &mut ReadOutcome,
+                        logical: u64,
+                        len: u64,
+                        source: SegmentSource|
+             -> io::Result<()> {
+                if !self.options.dry_run {
+                    write_fill(out, self.options.fill_byte, len)?;
+                    stats::count(Counter::FilledBytes, len);
+                }
+                outcome.record_fill(logical, len as usize, source);
+                Ok(())
+            };
+
+            for extent in translated {
+                if current >= end {
+                    break;
+                }
+
+                if extent.logical > current {
+                    if !self.options.fill_holes {
+                        break;
+                    }
+                    let hole_end = extent.logical.min(end);
+                    fill(
+                        &mut outcome,
+                        current,
+                        hole_end - current,
+                        SegmentSource::Hole,
+                    )?;
+                    current = hole_end;
+                    if current >= end {
+                        break;
+                    }
+                }
+
+                let stop = (extent.logical + extent.length).min(end);
+                let flags = extent.flags;
+                if flags.is_unknown() || flags.is_delalloc() {
+                    if !self.options.fill_holes {
+                        break;
+                    }
+                    fill(&mut outcome, current, stop - current, SegmentSource::Hole)?;
+                } else if flags.is_unwritten() && self.options.zero_unwritten {
+                    fill(
+                        &mut outcome,
+                        current,
+                        stop - current,
+                        SegmentSource::Unwritten,
+                    )?;
+                } else if is_encoded(&flags)
+                    && !flags.is_inline()
+                    && self.options.encoded_policy == EncodedPolicy::Fill
+                {
+                    fill(
+                        &mut outcome,
+                        current,
+                        stop - current,
+                        SegmentSource::Encoded,
+                    )?;
+                } else if flags.is_inline() {
+                    let copied = self.send(
+                        out,
+                        self.file,
+                        current,
+                        stop - current,
+                        Counter::FallbackBytes,
+                    )?;
+                    outcome.record_file(current, copied as usize);
+                    if copied < stop - current {
+                        current += copied;
+                        break;
+                    }
+                } else {
+                    let physical = extent.physical + (current - extent.logical);
+                    // Dry runs never open the device, and `send` does no I/O for them
+                    let in_file = device.file().unwrap_or(self.file);
+                    let copied = self.send(
+                        out,
+                        in_file,
+                        device.base_offset() + physical,
+                        stop - current,
+                        Counter::DeviceBytes,
+                    )?;
+                    outcome.record_read(current, copied as usize, physical);
+                    if copied < stop - current {
+                        // Short copy at the end of the device
+                        current += copied;
+                        break;
+                    }
+                }
+                current = stop;
+                self.report_progress(&outcome, length as usize, current);
+            }
+
+            if current < end
+                && self.options.fill_holes
+                && outcome.bytes_read as u64 == current - offset
+            {
+                fill(&mut outcome, current, end - current, SegmentSource::Hole)?;
+                self.report_progress(&outcome, length as usize, end);
+            }
+
+            if self.options.read_exact && (outcome.bytes_read as u64) < length {
+                return Err(ShortReadError {
+                    expected: length as usize,
+                    bytes_read: outcome.bytes_read,
+                }
+                .into());
+            }
+
+            let mut state = device.state(extents.clone(), outcome.bytes_read);
+            state.synthesized = outcome.synthesized;
+            state.set_segments(outcome.segments);
+            Ok(state)
+        })
+    }
+
+    /// Copy `len` bytes of `file` at `offset` to `out` with `sendfile`.
+    fn send(
+        &self,
+        out: BorrowedFd<'_>,
+        file: &File,
+        offset: u64,
+        len: u64,
+        counter: Counter,
+    ) -> io::Result<u64> {
+        if self.options.dry_run {
+            return Ok(len);
+        }
+
+        let mut copied = 0;
+        while copied < len {
+            self.check_deadline()?;
+            let chunk = (len - copied).min(READ_CHUNK_SIZE as u64) as usize;
+            let result = sys::sendfile(out.as_raw_fd(), file.as_raw_fd(), offset + copied, chunk);
+            stats::count_result(counter, &result);
+            let n = result?;
+            if n == 0 {
+                break;
+            }
+            copied += n as u64;
+        }
+        Ok(copied)
+    }
+
+    /// Copy the whole file to `dest`, leaving holes where it has no data.
+    pub(super) fn copy_sparse(&self, dest: &File) -> io::Result<State> {
+        use std::os::unix::fs::FileExt;
+
+        if self.options.dry_run {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sparse copies need the data, which dry runs do not read",
+            ));
+        }
+        let file_size = self.file.metadata()?.len();
+        // The extents blk_read_extents delivers, less unwritten ones reads
+        // would fill
+        let extents: Vec<FiemapExtent> = self
+            .extents(0, file_size)?
+            .into_iter()
+            .filter(|extent| {
+                let flags = &extent.flags;
+                let filled = is_encoded(flags)
+                    && !flags.is_inline()
+                    && self.options.encoded_policy == EncodedPolicy::Fill;
+                !(flags.is_unknown()
+                    || flags.is_delalloc()
+                    || filled
+                    || flags.is_unwritten() && self.options.zero_unwritten)
+            })
+            .collect();
+
+        // Ranges without data become holes; those past the destination's
+        // old end already are
+        let old_size = dest.metadata()?.len();
+        dest.set_len(file_size)?;
+        let mut current = 0;
+        let data = extents
+            .iter()
+            .map(|extent| (extent.logical, extent.logical + extent.length))
+            .chain([(file_size, file_size)]);
+        for (start, end) in data {
+            let hole_end = start.min(old_size);
+            if current < hole_end {
+                punch_hole(dest, current, hole_end - current)?;
+            }
+            current = current.max(end);
+        }
+
+        let ctx = ReadContext {
+            extent_map: Some(&extents),
+            ..*self
+        };
+        ctx.visit_extents(0, file_size, |piece, data| {
+            dest.write_all_at(data, piece.logical)
+        })
+    }
+
+    /// Translate a logical range into physical device ranges.
+    pub(super) fn map(&self, offset: u64, length: u64) -> io::Result<Vec<MappedRange>> {
+        let extents = self.extents(offset, length)?;
+        if extents.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let Some(placement) = self.placement(&extents)? else {
+            return Ok(map_extents(&self.device_path()?, &extents, offset, length));
+        };
+        let mut ranges = Vec::with_capacity(placement.placed.len());
+        for placed in &placement.placed {
+            let path = match placement.path(placed) {
+                Some(path) => path.to_path_buf(),
+                None => self.device_path()?,
+            };
+            ranges.extend(map_extents(&path, &[placed.extent], offset, length));
+        }
+        Ok(ranges)
+    }
+
+    /// Ask the kernel to read the device data of a logical range ahead.
+    pub(super) fn prefetch(&self, offset: u64, length: u64) -> io::Result<u64> {
+        let mut devices: BTreeMap<PathBuf, Vec<(u64, u64)>> = BTreeMap::new();
+        for range in self.map(offset, length)? {
+            let flags = &range.flags;
+            if flags.is_unwritten()
+                || flags.is_unknown()
+                || flags.is_delalloc()
+                || flags.is_inline()
+            {
+                continue;
+            }
+            devices
+                .entry(range.device_path)
+                .or_default()
+                .push((range.physical, range.length));
+        }
+        let requested = devices.values().flatten().map(|&(_, length)| length).sum();
+        if self.options.dry_run || devices.is_empty() {
+            return Ok(requested);
+        }
+
+        let own = self.device_path()?;
+        for (path, ranges) in &devices {
+            let advise = |device: &DeviceHandle| {
+                let file = device.file().ok_or_else(|| device.not_opened())?;
+                for &(physical, length) in ranges {
+                    sys::fadvise_willneed(
+                        file.as_raw_fd(),
+                        device.base_offset() + physical,
+                        length,
+                    )?;
+                }
+                Ok(())
+            };
+            if *path == own {
+                self.with_device(advise)?;
+            } else {
+                advise(&self.open_with_fallback(|direct| self.open_placed_device(path, direct))?)?;
+            }
+        }
+        Ok(requested)
+    }
+
+    /// Read a logical range from the device and through the file, and
+    /// compare the two.
+    pub(super) fn verify_at(&self, offset: u64, length: u64) -> io::Result<VerifyReport> {
+        if self.options.dry_run {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "verification needs the data, which dry runs do not read",
+            ));
+        }
+        let length = usize::try_from(length).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "range too large to verify")
+        })?;
+
+        // Holes read as zeros through the file, and a fallback read would
+        // compare the file with itself
+        let options = Options {
+            allow_fallback: false,
+            read_exact: false,
+            auto_align: true,
+            fill_holes: true,
+            fill_byte: 0,
+            ..self.options.clone()
+        };
+        let ctx = ReadContext {
+            options: &options,
+            ..*self
+        };
+        let mut device = self.alloc_buf(length, READ_ALIGNMENT)?;
+        let state = ctx.read_at(&mut device, offset)?;
+        let mut file = vec![0u8; length];
+        let file_bytes = read_full_at(self.file, &mut file, offset, ReadFlags::empty())?;
+
+        let device = &device[..state.bytes_read.min(length)];
+        let file = &file[..file_bytes];
+        Ok(VerifyReport {
+            offset,
+            bytes_compared: device.len().min(file.len()),
+            file_bytes,
+            mismatches: verify::compare(offset, device, file, &state),
+            state,
+        })
+    }
+
+    /// Search the device data of the whole file for `pattern`.
+    pub(super) fn scan(&self, pattern: &[u8]) -> io::Result<Vec<Match>> {
+        if pattern.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "scan pattern is empty",
+            ));
+        }
+        let mut scanner = Scanner::new(pattern);
+        self.visit_extents(0, u64::MAX, |piece, data| {
+            // Inline data is not in a physical range of the device
+            if !piece.flags.is_inline() {
+                scanner.feed(piece, data);
+            }
+            Ok(())
+        })?;
+        Ok(scanner.finish())
+    }
+}
+
+/// Write `len` copies of `byte` to `out`.
+fn write_fill(out: BorrowedFd<'_>, byte: u8, len: u64) -> io::Result<()> {
+    use std::io::Write;
+
+    let chunk = vec![byte; len.min(READ_CHUNK_SIZE as u64) as usize];
+    with_borrowed_file(out, |mut file| {
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(chunk.len() as u64) as usize;
+            file.write_all(&chunk[..n])?;
+            remaining -= n as u64;
+        }
+        Ok(())
+    })
+}
+
+/// Make `offset..offset + len` of `file` a hole, or write zeros there on
+/// filesystems that cannot punch holes.
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    match sys::punch_hole(file.as_raw_fd(), offset, len) {
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+            let zeros = vec![0u8; len.min(READ_CHUNK_SIZE as u64) as usize];
+            let mut done = 0;
+            while done < len {
+                let n = (len - done).min(zeros.len() as u64) as usize;
+                file.write_all_at(&zeros[..n], offset + done)?;
+                done += n as u64;
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{fiemap_file, BlkReader};
+    use crate::test_support::{fake_device, file_on_image, image_of};
+
+    #[test]
+    fn test_prefetch() {
+        let (temp, options) = file_on_image(&[0x55; 8192]);
+        let physical = fiemap_file(temp.as_file()).unwrap()[0].physical;
+
+        // Start with none of the image's data cached
+        let image = temp.image.as_file();
+        image.sync_all().unwrap();
+        // SAFETY: posix_fadvise takes integers only.
+        let ret =
+            unsafe { libc::posix_fadvise(image.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        assert_eq!(ret, 0);
+        let resident = || sys::resident_pages(image.as_raw_fd(), physical, 8192).unwrap();
+
+        let dry_run = options.clone().with_dry_run(true);
+        assert_eq!(temp.path().blk_prefetch(0, 8192, &dry_run).unwrap(), 8192);
+        assert_eq!(resident(), 0);
+
+        // The readahead is asynchronous
+        assert_eq!(temp.path().blk_prefetch(0, 8192, &options).unwrap(), 8192);
+        let cached = (0..100).any(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            resident() > 0
+        });
+        assert!(cached);
+
+        // Nothing to read beyond the end of the file
+        let path = temp.path();
+        assert_eq!(path.blk_prefetch(1 << 20, 4096, &options).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_scan() {
+        use std::io::Write;
+        use std::os::unix::fs::FileExt;
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        let mut data = vec![0u8; 8192];
+        data[100..103].copy_from_slice(b"HDR");
+        data[4094..4097].copy_from_slice(b"HDR");
+        temp.write_all(&data).unwrap();
+        temp.as_file().sync_all().unwrap();
+        // Preallocated space past the data is unwritten
+        let fd = temp.as_file().as_raw_fd();
+        // SAFETY: fallocate takes integers only.
+        let ret = unsafe { libc::fallocate(fd, 0, 8192, 4096) };
+        assert_eq!(ret, 0, "fallocate: {}", io::Error::last_os_error());
+        let extents = fiemap_file(temp.as_file()).unwrap();
+        let physical_of = |logical: u64| {
+            let extent = extents
+                .iter()
+                .find(|e| e.logical <= logical && logical < e.logical + e.length)
+                .unwrap();
+            extent.physical + (logical - extent.logical)
+        };
+
+        // An image holding the file's data, stale data written to the
+        // unwritten extent, and a pattern outside the file
+        let (image, options) = image_of(temp.as_file());
+        let device = image.as_file();
+        let end = extents.iter().map(|e| e.physical + e.length).max().unwrap();
+        device.write_all_at(b"HDR", physical_of(8192 + 10)).unwrap();
+        device.write_all_at(b"HDR", end + 4096).unwrap();
+
+        let matches = temp.path().blk_scan(b"HDR", &options).unwrap();
+        let found: Vec<_> = matches.iter().map(|m| (m.logical, m.physical)).collect();
+        let expected: Vec<_> = [100, 4094, 8202]
+            .into_iter()
+            .map(|logical| (logical, physical_of(logical)))
+            .collect();
+        assert_eq!(found, expected);
+        assert!(matches[2].flags.is_unwritten());
+
+        let err = temp.path().blk_scan(b"", &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_copy_sparse() {
+        use std::os::unix::fs::FileExt;
+
+        // Data, a hole, data, and preallocated space
+        let dir = tempfile::tempdir().unwrap();
+        let temp = tempfile::NamedTempFile::new_in(dir.path()).unwrap();
+        let file = temp.as_file();
+        file.write_all_at(&[0x11; 4096], 0).unwrap();
+        file.write_all_at(&[0x22; 4096], 8192).unwrap();
+        file.sync_all().unwrap();
+        // SAFETY: fallocate takes integers only.
+        let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 12288, 4096) };
+        assert_eq!(ret, 0, "fallocate: {}", io::Error::last_os_error());
+        let extents = fiemap_file(file).unwrap();
+        let physical_of = |logical: u64| {
+            let extent = extents
+                .iter()
+                .find(|e| e.logical <= logical && logical < e.logical + e.length)
+                .unwrap();
+            extent.physical + (logical - extent.logical)
+        };
+
+        // An image holding the file's data, and stale data in the unwritten
+        // extent
+        let (image, options) = image_of(file);
+        image
+            .as_file()
+            .write_all_at(&[0x33; 4096], physical_of(12288))
+            .unwrap();
+
+        // Over a larger destination with data everywhere
+        let dest = tempfile::tempfile_in(dir.path()).unwrap();
+        dest.write_all_at(&[0xff; 20000], 0).unwrap();
+        let zeroed = options.clone().with_zero_unwritten(true);
+        let state = temp.path().blk_copy_sparse(&dest, &zeroed).unwrap();
+        assert_eq!(state.bytes_read, 8192);
+        let mut copied = vec![0u8; 16384];
+        assert_eq!(
+            read_full_at(&dest, &mut copied, 0, ReadFlags::empty()).unwrap(),
+            16384
+        );
+        assert_eq!(dest.metadata().unwrap().len(), 16384);
+        assert!(copied[..4096].iter().all(|&b| b == 0x11));
+        assert!(copied[4096..8192].iter().all(|&b| b == 0));
+        assert!(copied[8192..12288].iter().all(|&b| b == 0x22));
+        assert!(copied[12288..].iter().all(|&b| b == 0));
+        dest.sync_all().unwrap();
+        let allocated: u64 = fiemap_file(&dest)
+            .unwrap()
+            .iter()
+            .filter(|e| e.logical < 16384)
+            .map(|e| e.length.min(16384 - e.logical))
+            .sum();
+        assert_eq!(allocated, 8192);
+
+        // Unwritten extents are copied raw by default
+        temp.path().blk_copy_sparse(&dest, &options).unwrap();
+        assert_eq!(
+            read_full_at(&dest, &mut copied, 0, ReadFlags::empty()).unwrap(),
+            16384
+        );
+        assert!(copied[12288..].iter().all(|&b| b == 0x33));
+    }
+
+    #[test]
+    fn test_verify_at() {
+        use crate::state::SegmentSource;
+        use std::os::unix::fs::FileExt;
+
+        // An image holding the file's data, but for a damaged range
+        let (temp, options) = file_on_image(&[0x55; 8192]);
+        let extents = fiemap_file(temp.as_file()).unwrap();
+        let physical = extents[0].physical;
+        let device = temp.image.as_file();
+
+        let report = temp.path().blk_verify_at(0, 8192, &options).unwrap();
+        assert!(report.is_consistent(), "{:?}", report.mismatches);
+        assert_eq!(report.bytes_compared, 8192);
+
+        device.write_all_at(&[0; 10], physical + 100).unwrap();
+        let report = temp.path().blk_verify_at(0, 8192, &options).unwrap();
+        assert!(!report.is_consistent());
+        assert_eq!(report.mismatches.len(), 1);
+        let mismatch = &report.mismatches[0];
+        assert_eq!((mismatch.logical, mismatch.length), (100, 10));
+        assert_eq!(
+            mismatch.source,
+            SegmentSource::Device {
+                physical: physical + 100
+            }
+        );
+        assert_eq!(mismatch.extent, Some(extents[0]));
+
+        // Unaligned ranges, and ranges past the end of the file
+        let report = temp.path().blk_verify_at(105, 5000, &options).unwrap();
+        assert_eq!(report.mismatches[0].logical, 105);
+        assert_eq!(report.mismatches[0].length, 5);
+        let report = temp.path().blk_verify_at(4096, 8192, &options).unwrap();
+        assert_eq!(report.file_bytes, 4096);
+        assert!(report.is_consistent());
+
+        let dry_run = options.with_dry_run(true);
+        let err = temp.path().blk_verify_at(0, 8192, &dry_run).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_read_ranges() {
+        use blkmap::ExtentFlags;
+
+        // Device blocks: 0x00, 0x11, 0x22, 0x33
+        let data: Vec<u8> = (0..4u8).flat_map(|b| [b * 0x11; 4096]).collect();
+        let slot = OnceLock::new();
+        slot.set(fake_device(&data)).unwrap();
+
+        // Logical blocks 0 and 1 are stored in reverse physical order
+        let file = tempfile::tempfile().unwrap();
+        let options = Options::new();
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 3 * 4096,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 4096,
+                physical: 4096,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            },
+        ];
+        let ctx = ReadContext::new(&file, &options)
+            .with_extent_map(&extents)
+            .with_device_slot(&slot);
+
+        let mut a = vec![0u8; 4096];
+        let mut b = vec![0u8; 4096];
+        let mut empty = [0u8; 0];
+        let mut ranges = [
+            (0, 4096, &mut a[..]),
+            (4096, 512, &mut b[..]),
+            (0, 0, &mut empty[..]),
+        ];
+        let states = ctx.read_ranges(&mut ranges).unwrap();
+        assert_eq!(states.len(), 3);
+        assert_eq!(states[0].bytes_read, 4096);
+        assert_eq!(states[1].bytes_read, 512);
+        assert_eq!(states[2].bytes_read, 0);
+        assert!(a.iter().all(|&x| x == 0x33));
+        assert!(b[..512].iter().all(|&x| x == 0x11));
+        assert!(b[512..].iter().all(|&x| x == 0));
+
+        // A length larger than the buffer is rejected
+        let mut small = [0u8; 512];
+        let mut ranges = [(0, 4096, &mut small[..])];
+        let err = ctx.read_ranges(&mut ranges).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_visit_extents() {
+        use blkmap::ExtentFlags;
+
+        // Device: 0x11 block, then a 2.5 MB region of increasing bytes
+        let mut data = vec![0x11u8; 4096];
+        data.extend((0..5 * READ_CHUNK_SIZE / 2).map(|i| i as u8));
+        let slot = OnceLock::new();
+        slot.set(fake_device(&data)).unwrap();
+
+        let file = tempfile::tempfile().unwrap();
+        let options = Options::new();
+        let big = (5 * READ_CHUNK_SIZE / 2) as u64;
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 4096,
+                length: big,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: big + 4096,
+                physical: 0,
+                length: 4096,
+                flags: ExtentFlags::UNWRITTEN,
+            },
+        ];
+        let ctx = ReadContext::new(&file, &options)
+            .with_extent_map(&extents)
+            .with_device_slot(&slot);
+
+        // Start at an unaligned offset to exercise the aligned span handling
+        let mut pieces = Vec::new();
+        let state = ctx
+            .visit_extents(100, big + 8192, |extent, chunk| {
+                let expected = &data[extent.physical as usize..][..chunk.len()];
+                assert_eq!(chunk, expected);
+                pieces.push((extent.logical, extent.length, extent.flags));
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            pieces,
+            vec![
+                (100, READ_CHUNK_SIZE as u64, ExtentFlags::empty()),
+                (
+                    100 + READ_CHUNK_SIZE as u64,
+                    READ_CHUNK_SIZE as u64,
+                    ExtentFlags::empty()
+                ),
+                (
+                    100 + 2 * READ_CHUNK_SIZE as u64,
+                    big - 100 - 2 * READ_CHUNK_SIZE as u64,
+                    ExtentFlags::empty()
+                ),
+                (big + 4096, 4096, ExtentFlags::UNWRITTEN),
+            ]
+        );
+        assert_eq!(state.bytes_read as u64, big - 100 + 4096);
+
+        // Callback errors abort the read
+        let err = ctx
+            .visit_extents(0, 4096, |_, _| Err(io::Error::other("stop")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "stop");
+    }
+
+    #[test]
+    fn test_copy_to() {
+        use blkmap::ExtentFlags;
+        use std::io::{Read, Seek};
+        use std::os::fd::AsFd;
+
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        let slot = OnceLock::new();
+        slot.set(fake_device(&data)).unwrap();
+
+        // data, hole, unwritten, trailing hole
+        let file = tempfile::tempfile().unwrap();
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 4096,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 2048,
+                physical: 0,
+                length: 1024,
+                flags: ExtentFlags::UNWRITTEN,
+            },
+        ];
+        let options = Options::new()
+            .with_fill_holes(true)
+            .with_zero_unwritten(true)
+            .with_fill_byte(0xEE);
+        let ctx = ReadContext::new(&file, &options)
+            .with_extent_map(&extents)
+            .with_device_slot(&slot);
+
+        let mut out = tempfile::tempfile().unwrap();
+        let state = ctx.copy_to(out.as_fd(), 0, 4096).unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert_eq!(state.synthesized, vec![1024..4096]);
+
+        let mut copied = Vec::new();
+        out.rewind().unwrap();
+        out.read_to_end(&mut copied).unwrap();
+        assert_eq!(&copied[..1024], &data[4096..5120]);
+        assert!(copied[1024..].iter().all(|&b| b == 0xEE));
+
+        // Without fill_holes the copy stops at the first hole
+        let options = Options::new();
+        let ctx = ReadContext::new(&file, &options)
+            .with_extent_map(&extents)
+            .with_device_slot(&slot);
+        let out = tempfile::tempfile().unwrap();
+        assert_eq!(ctx.copy_to(out.as_fd(), 0, 4096).unwrap().bytes_read, 1024);
+        assert_eq!(out.metadata().unwrap().len(), 1024);
+    }
+}
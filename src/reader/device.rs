@@ -0,0 +1,1159 @@
+//! Device placement of reads: resolving and opening the devices extents
+//! are on, through device-mapper, MD, LVM, loop and btrfs layouts.
+
+use super::run::ReadOutcome;
+use super::walk::Step;
+use super::{
+    check_filesystem, is_einval, read_full_at, sleep_backoff, ReadContext, READ_ALIGNMENT,
+};
+use crate::btrfs;
+use crate::buffer::AlignedBuf;
+use crate::cache::{
+    open_device_uncached, open_device_uncached_at, resolve_device, CachedDevice, InFlight,
+};
+use crate::dm;
+use crate::engine::{DeviceRead, ReadFlags};
+use crate::error::{BlkReadError, DeviceReadError, PartialReadError, ShortReadError};
+use crate::loopdev;
+use crate::map::{Placed, Placement};
+use crate::md;
+use crate::options::{EncodedPolicy, Options};
+use crate::state::{is_encoded, DeviceInfo, State};
+use crate::stats::{self, Counter};
+use crate::sys;
+
+use blkmap::FiemapExtent;
+
+use std::fs::File;
+use std::io::{self, IoSliceMut};
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+impl<'a> ReadContext<'a> {
+    /// Read the requested range from the devices holding the translated
+    /// extents.
+    ///
+    /// The range is split into windows whose data is on one device each,
+    /// which are read in order; a short window ends the read.
+    pub(super) fn read_placed(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        extents: Vec<FiemapExtent>,
+        placement: &Placement,
+    ) -> io::Result<State> {
+        let end = offset + buf.len() as u64;
+        let mut windows: Vec<Window> = Vec::new();
+        for &Placed { device, extent } in &placement.placed {
+            match windows.last_mut() {
+                Some(window)
+                    if device.is_none() || window.device.is_none() || window.device == device =>
+                {
+                    window.device = window.device.or(device);
+                    window.extents.push(extent);
+                }
+                _ => windows.push(Window {
+                    device,
+                    start: extent.logical.max(offset),
+                    extents: vec![extent],
+                }),
+            }
+        }
+        match windows.first_mut() {
+            Some(window) => window.start = offset,
+            None => windows.push(Window {
+                device: None,
+                start: offset,
+                extents: Vec::new(),
+            }),
+        }
+
+        // Windows may be short; the whole range is checked below
+        let options = Options {
+            read_exact: false,
+            ..self.options.clone()
+        };
+        let ctx = ReadContext {
+            options: &options,
+            ..*self
+        };
+
+        let mut outcome = ReadOutcome::default();
+        let mut resolve = Duration::ZERO;
+        let mut device = None;
+        for (k, window) in windows.iter().enumerate() {
+            let stop = windows.get(k + 1).map_or(end, |next| next.start);
+            let at = (window.start - offset) as usize;
+            let len = (stop - window.start) as usize;
+            let part = ctx.with_placed_device(placement, window.device, |handle, elapsed| {
+                resolve += elapsed;
+                device.get_or_insert_with(|| (handle.path().clone(), handle.info()));
+                ctx.read_from_device(
+                    handle,
+                    &mut buf[at..at + len],
+                    window.start,
+                    &window.extents,
+                )
+            });
+            let part = match part {
+                Ok(part) => part,
+                // The first window starts at `offset`, so its state is that of the read
+                Err(e) if outcome.bytes_read == 0 => return Err(e),
+                Err(e) => {
+                    let source = match PartialReadError::from_io_error(&e) {
+                        Some(_) => {
+                            let inner = e.into_inner().expect("partial reads are custom errors");
+                            inner.downcast::<PartialReadError>().unwrap().source
+                        }
+                        None => e,
+                    };
+                    let (path, info) = device.expect("a window was read");
+                    let state = outcome.into_state(path, info, extents);
+                    return Err(PartialReadError { state, source }.into());
+                }
+            };
+            let short = part.bytes_read < len;
+            outcome.append(part);
+            if short {
+                break;
+            }
+        }
+
+        if self.options.read_exact && outcome.bytes_read < buf.len() {
+            return Err(ShortReadError {
+                expected: buf.len(),
+                bytes_read: outcome.bytes_read,
+            }
+            .into());
+        }
+
+        let reads = mem::take(&mut outcome.reads);
+        let (path, info) = device.expect("a window was read");
+        let state = outcome.into_state(path, info, extents);
+        Ok(self.record_timing(state, |timing| {
+            timing.device_resolve = resolve;
+            timing.reads = reads;
+        }))
+    }
+
+    /// Translate the physical offsets of `extents` to the devices holding
+    /// their data, on btrfs, through device-mapper targets, to RAID1 members
+    /// and to the backing files of loop devices, in that order.
+    ///
+    /// Returns `None` if the extents are read from the file's device as
+    /// they are.
+    pub(super) fn placement(&self, extents: &[FiemapExtent]) -> io::Result<Option<Placement>> {
+        let on_device = |extent: &FiemapExtent| {
+            let flags = &extent.flags;
+            let filled = (flags.is_unwritten() && self.options.zero_unwritten)
+                || (is_encoded(flags) && self.options.encoded_policy == EncodedPolicy::Fill);
+            !(flags.is_unknown() || flags.is_delalloc() || flags.is_inline() || filled)
+        };
+
+        let mut placement = self.btrfs_placement(extents, on_device)?;
+        let layers: [(bool, Translate); 3] = [
+            (self.options.translate_dm, dm::place),
+            (self.options.translate_md, md::place),
+            (self.options.translate_loop, loopdev::place),
+        ];
+        for (enabled, place) in layers {
+            if !enabled {
+                continue;
+            }
+            let translated = match &placement {
+                Some(placement) => place(placement)?,
+                None => {
+                    let path = self.device_path()?;
+                    let mut untranslated = Placement::default();
+                    for extent in extents {
+                        untranslated.push(on_device(extent).then_some(path.as_path()), *extent);
+                    }
+                    place(&untranslated)?
+                }
+            };
+            placement = translated.or(placement);
+        }
+        Ok(placement)
+    }
+
+    /// Translate the physical offsets of `extents` to btrfs member devices.
+    ///
+    /// Returns `None` unless the file is on btrfs and
+    /// [`Options::translate_btrfs`] is set. A cached chunk map that does not
+    /// cover the extents is read again.
+    fn btrfs_placement(
+        &self,
+        extents: &[FiemapExtent],
+        on_device: impl Fn(&FiemapExtent) -> bool + Copy,
+    ) -> io::Result<Option<Placement>> {
+        if !self.options.translate_btrfs || !btrfs::is_btrfs(self.file)? {
+            return Ok(None);
+        }
+
+        let cached = self.options.enable_cache;
+        let map = btrfs::chunk_map(self.file, cached, false)?;
+        match btrfs::place(&map, extents, on_device) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound && cached => {
+                let map = btrfs::chunk_map(self.file, cached, true)?;
+                btrfs::place(&map, extents, on_device).map(Some)
+            }
+            placement => placement.map(Some),
+        }
+    }
+
+    /// Path of the file's device, resolved without opening the device.
+    pub(super) fn device_path(&self) -> io::Result<PathBuf> {
+        match self.device_slot.and_then(OnceLock::get) {
+            Some(device) => Ok(device.path().clone()),
+            None => resolve_device(self.options.backend(), self.file),
+        }
+    }
+
+    /// Run `f` with device `device` of `placement`, or with the file's
+    /// device if `None`.
+    fn with_placed_device<R>(
+        &self,
+        placement: &Placement,
+        device: Option<usize>,
+        f: impl FnOnce(&DeviceHandle, Duration) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let Some(index) = device else {
+            return self.with_timed_device(f);
+        };
+        let started = Instant::now();
+        let path = &placement.devices[index];
+        let device = if self.options.dry_run {
+            DeviceHandle::Planned(path.clone())
+        } else {
+            self.open_with_fallback(|direct| self.open_placed_device(path, direct))?
+        };
+        f(&device, started.elapsed())
+    }
+
+    /// Open the device at `path`, cached or uncached based on options.
+    pub(super) fn open_placed_device(&self, path: &Path, direct: bool) -> io::Result<DeviceHandle> {
+        if self.caches_devices() {
+            let cached = self.options.cache().get_or_create_device_at(path, direct)?;
+            Ok(DeviceHandle::Cached(cached))
+        } else {
+            Ok(DeviceHandle::Uncached(open_device_uncached_at(
+                self.options.backend(),
+                path,
+                direct,
+            )?))
+        }
+    }
+
+    /// Whether device handles are kept in the cache: only those of the
+    /// system's block devices are.
+    pub(super) fn caches_devices(&self) -> bool {
+        self.options.enable_cache && self.options.device_backend.is_none()
+    }
+
+    /// Open a device handle with `open`, which takes whether to use Direct
+    /// I/O, falling back to the page cache if the device rejects `O_DIRECT`
+    /// and [`Options::buffered_fallback`] is set.
+    pub(super) fn open_with_fallback(
+        &self,
+        open: impl Fn(bool) -> io::Result<DeviceHandle>,
+    ) -> io::Result<DeviceHandle> {
+        match open(self.options.direct) {
+            Err(e) if self.options.direct && self.options.buffered_fallback && is_einval(&e) => {
+                open(false)
+            }
+            result => result,
+        }
+    }
+
+    /// Run `f` with the device holding `extents` and their device addresses,
+    /// and describe the device in the state it returns.
+    ///
+    /// If the extents are translated (see [`placement`](Self::placement)),
+    /// their data must be on a single device.
+    pub(super) fn with_extent_device(
+        &self,
+        extents: &[FiemapExtent],
+        f: impl FnOnce(&DeviceHandle, &[FiemapExtent]) -> io::Result<State>,
+    ) -> io::Result<State> {
+        let Some(placement) = self.placement(extents)? else {
+            return self.with_device(|device| {
+                let ciphertext = self.check_ciphertext(std::slice::from_ref(device.path()))?;
+                let mut state = f(device, extents)?;
+                self.describe_device(&mut state, device.path(), ciphertext);
+                Ok(state)
+            });
+        };
+        if placement.devices.len() > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "range spans several devices",
+            ));
+        }
+        let ciphertext = self.check_ciphertext(&placement.devices)?;
+        let device = (!placement.devices.is_empty()).then_some(0);
+        let translated: Vec<FiemapExtent> = placement
+            .placed
+            .iter()
+            .map(|placed| placed.extent)
+            .collect();
+        let mut state =
+            self.with_placed_device(&placement, device, |handle, _| f(handle, &translated))?;
+        self.describe_device(&mut state, &self.device_path()?, ciphertext);
+        Ok(state)
+    }
+
+    /// Run `f` with the device file handle (shared, cached or uncached).
+    pub(super) fn with_device<R>(
+        &self,
+        f: impl FnOnce(&DeviceHandle) -> io::Result<R>,
+    ) -> io::Result<R> {
+        self.with_timed_device(|device, _| f(device))
+    }
+
+    /// Run `f` with the device file handle and the time it took to get it.
+    pub(super) fn with_timed_device<R>(
+        &self,
+        f: impl FnOnce(&DeviceHandle, Duration) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let started = Instant::now();
+        match self.device_slot {
+            Some(slot) => match slot.get() {
+                Some(device) => f(device, started.elapsed()),
+                // A dry-run handle is never opened, so don't share it
+                None if self.options.dry_run => {
+                    let device = self.get_device_handle()?;
+                    f(&device, started.elapsed())
+                }
+                None => {
+                    let _ = slot.set(self.get_device_handle()?);
+                    f(
+                        slot.get().expect("device slot was just set"),
+                        started.elapsed(),
+                    )
+                }
+            },
+            None => {
+                let device = self.get_device_handle()?;
+                f(&device, started.elapsed())
+            }
+        }
+    }
+
+    /// Check that `extents` address `device`, if [`Options::verify_device`]
+    /// is set.
+    ///
+    /// The first block of the first written extent is read both from the
+    /// device and through the page cache, after writing out any dirty pages
+    /// of the block.
+    pub(super) fn verify_device(
+        &self,
+        device: &DeviceHandle,
+        extents: &[FiemapExtent],
+    ) -> io::Result<()> {
+        if !self.options.verify_device || self.options.dry_run {
+            return Ok(());
+        }
+        // A file read by a saved extent map may be gone or truncated
+        let meta = self.file.metadata()?;
+        if !meta.is_file() {
+            return Ok(());
+        }
+        let block = device.sector_size().max(READ_ALIGNMENT as u64);
+        let sample = extents.iter().find(|extent| {
+            let flags = &extent.flags;
+            !(flags.is_unwritten()
+                || flags.is_unknown()
+                || flags.is_delalloc()
+                || flags.is_inline()
+                || is_encoded(flags))
+                && extent.logical < meta.len()
+                && extent.physical.is_multiple_of(block)
+        });
+        let Some(extent) = sample else {
+            return Ok(());
+        };
+        let len = block.min(extent.length).min(meta.len() - extent.logical) as usize;
+        sys::sync_range(self.file.as_raw_fd(), extent.logical, len as u64)?;
+
+        let mut on_device = AlignedBuf::new(block as usize, block as usize);
+        let from_device =
+            device.read_at(&mut on_device, extent.physical, self.options, self.deadline)?;
+        let mut cached = AlignedBuf::new(block as usize, block as usize);
+        let from_cache = read_full_at(
+            self.file,
+            &mut cached[..len],
+            extent.logical,
+            ReadFlags::empty(),
+        )?;
+        let compared = from_device.min(from_cache);
+        if on_device[..compared] != cached[..compared] {
+            return Err(BlkReadError::DeviceMismatch {
+                file_path: self.file_path(),
+                device_path: device.path().clone(),
+                logical_offset: extent.logical,
+                physical_offset: extent.physical,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Get a device handle, either cached or uncached based on options.
+    ///
+    /// Dry runs only resolve the device path without opening it.
+    pub(super) fn get_device_handle(&self) -> io::Result<DeviceHandle> {
+        check_filesystem(self.file, self.path)?;
+        if self.options.dry_run {
+            return Ok(DeviceHandle::Planned(resolve_device(
+                self.options.backend(),
+                self.file,
+            )?));
+        }
+        self.open_with_fallback(|direct| self.open_file_device(direct))
+    }
+
+    /// Open the file's device, cached or uncached based on options.
+    fn open_file_device(&self, direct: bool) -> io::Result<DeviceHandle> {
+        if self.caches_devices() {
+            let cached = self
+                .options
+                .cache()
+                .get_or_create_device(self.file, direct)?;
+            Ok(DeviceHandle::Cached(cached))
+        } else {
+            let uncached = open_device_uncached(self.options.backend(), self.file, direct)?;
+            Ok(DeviceHandle::Uncached(uncached))
+        }
+    }
+
+    /// Open `device` again after it went away, as when a disk is unplugged
+    /// and plugged back in or a loop device is detached and set up again,
+    /// evicting the stale handle from the cache first.
+    ///
+    /// The file's own device is resolved again; other devices are reopened
+    /// by path.
+    pub(super) fn reopen_device(&self, device: &DeviceHandle) -> io::Result<DeviceHandle> {
+        if let DeviceHandle::Cached(cached) = device {
+            self.options.cache().invalidate(cached);
+        }
+        let direct = device.is_direct();
+        let file = device.file().ok_or_else(|| device.not_opened())?;
+        if file.metadata()?.rdev() == self.file.metadata()?.dev() {
+            self.open_file_device(direct)
+        } else {
+            self.open_placed_device(device.path(), direct)
+        }
+    }
+}
+
+/// Handle to a block device, either cached or uncached.
+#[derive(Debug)]
+pub(crate) enum DeviceHandle {
+    Cached(Arc<CachedDevice>),
+    Uncached(CachedDevice),
+    /// Resolved but not opened, for dry runs.
+    Planned(PathBuf),
+}
+
+impl DeviceHandle {
+    /// Get the path of the block device.
+    pub(super) fn path(&self) -> &PathBuf {
+        match self {
+            DeviceHandle::Cached(cached) => &cached.path,
+            DeviceHandle::Uncached(uncached) => &uncached.path,
+            DeviceHandle::Planned(path) => path,
+        }
+    }
+
+    /// Sector sizes and size of the device, if it was opened and queried.
+    pub(super) fn info(&self) -> Option<DeviceInfo> {
+        match self {
+            DeviceHandle::Cached(cached) => cached.info,
+            DeviceHandle::Uncached(uncached) => uncached.info,
+            DeviceHandle::Planned(_) => None,
+        }
+    }
+
+    /// Whether the device is read with Direct I/O, which must be aligned.
+    pub(super) fn is_direct(&self) -> bool {
+        match self {
+            DeviceHandle::Cached(cached) => cached.direct,
+            DeviceHandle::Uncached(uncached) => uncached.direct,
+            DeviceHandle::Planned(_) => true,
+        }
+    }
+
+    /// Byte offset in the device file at which the device starts.
+    pub(super) fn base_offset(&self) -> u64 {
+        match self {
+            DeviceHandle::Cached(cached) => cached.base_offset,
+            DeviceHandle::Uncached(uncached) => uncached.base_offset,
+            DeviceHandle::Planned(_) => 0,
+        }
+    }
+
+    /// Logical sector size of the device, assuming 512 bytes if unknown.
+    pub(super) fn sector_size(&self) -> u64 {
+        self.info()
+            .map_or(512, |info| info.logical_block_size.max(1) as u64)
+    }
+
+    /// Reject reads the device would fail, before issuing any.
+    ///
+    /// Reads must lie within the device, and with Direct I/O be aligned to
+    /// its sector size; an extent beyond its end points to the wrong device
+    /// having been resolved. Only checked if the device geometry is known.
+    pub(super) fn check_steps(&self, steps: &[Step]) -> io::Result<()> {
+        let Some(info) = self.info() else {
+            return Ok(());
+        };
+        let alignment = info.logical_block_size.max(1) as u64;
+        for step in steps {
+            let Step::Device {
+                extent_index,
+                physical,
+                len,
+                ..
+            } = *step
+            else {
+                continue;
+            };
+            if physical + len as u64 > info.size {
+                return Err(BlkReadError::BeyondDevice {
+                    device_path: self.path().clone(),
+                    extent_index,
+                    physical_offset: physical,
+                    length: len,
+                    device_size: info.size,
+                }
+                .into());
+            }
+            let aligned =
+                physical.is_multiple_of(alignment) && (len as u64).is_multiple_of(alignment);
+            if self.is_direct() && !aligned {
+                return Err(BlkReadError::Unaligned {
+                    device_path: self.path().clone(),
+                    physical_offset: physical,
+                    length: len,
+                    alignment,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// State of a read of `bytes_read` bytes from this device.
+    pub(super) fn state(&self, extents: Vec<FiemapExtent>, bytes_read: usize) -> State {
+        let mut state = State::new(self.path().clone(), extents, bytes_read, false);
+        state.device_info = self.info();
+        state
+    }
+
+    /// Wrap a failed read, recognizing reads rejected for their alignment.
+    pub(super) fn read_failed(&self, err: DeviceReadError) -> io::Error {
+        if err.source.raw_os_error() == Some(libc::EINVAL) && self.is_direct() {
+            let alignment = self.sector_size();
+            if !err.physical_offset.is_multiple_of(alignment)
+                || !(err.length as u64).is_multiple_of(alignment)
+            {
+                return BlkReadError::Unaligned {
+                    device_path: err.device_path,
+                    physical_offset: err.physical_offset,
+                    length: err.length,
+                    alignment,
+                }
+                .into();
+            }
+        }
+        err.into()
+    }
+
+    /// Error for I/O on a device that was only resolved.
+    pub(super) fn not_opened(&self) -> io::Error {
+        io::Error::other(format!(
+            "device {} was not opened (dry run)",
+            self.path().display()
+        ))
+    }
+
+    /// Get the open device file, unless the device was only resolved.
+    pub(super) fn file(&self) -> Option<&File> {
+        match self {
+            DeviceHandle::Cached(cached) => Some(&cached.file),
+            DeviceHandle::Uncached(uncached) => Some(&uncached.file),
+            DeviceHandle::Planned(_) => None,
+        }
+    }
+
+    /// Reads in flight on the device, if it was opened.
+    pub(super) fn in_flight(&self) -> Option<&InFlight> {
+        match self {
+            DeviceHandle::Cached(cached) => Some(&cached.in_flight),
+            DeviceHandle::Uncached(uncached) => Some(&uncached.in_flight),
+            DeviceHandle::Planned(_) => None,
+        }
+    }
+
+    /// Read data from the device at the specified physical offset.
+    ///
+    /// The read goes through the configured [`IoEngine`](crate::IoEngine)
+    /// and is retried according to [`Options::retry`], but not once
+    /// `deadline` has passed.
+    pub(super) fn read_at(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        options: &Options,
+        deadline: Option<Instant>,
+    ) -> io::Result<usize> {
+        if options.dry_run {
+            // In dry run mode, simulate read without actual I/O
+            return Ok(buf.len());
+        }
+
+        let file = self.file().ok_or_else(|| self.not_opened())?;
+        for attempt in 1.. {
+            let mut reads = [DeviceRead {
+                offset: self.base_offset() + offset,
+                bufs: vec![IoSliceMut::new(buf)],
+                flags: options.read_flags,
+            }];
+            let mut result = Ok(0);
+            options
+                .io_engine
+                .read_batch(file.as_fd(), &mut reads, &mut |_, read| {
+                    result = read;
+                    Ok(())
+                })?;
+            stats::count_result(Counter::DeviceBytes, &result);
+            match result {
+                Err(e)
+                    if options.retry.should_retry(&e, attempt)
+                        && deadline.is_none_or(|deadline| Instant::now() < deadline) =>
+                {
+                    sleep_backoff(options.retry.backoff, deadline)
+                }
+                result => return result,
+            }
+        }
+        unreachable!("attempts are unbounded")
+    }
+}
+
+/// A translation of extents from the devices they are on to the devices
+/// below, returning `None` if it leaves them where they are.
+type Translate = fn(&Placement) -> io::Result<Option<Placement>>;
+
+/// Part of a read whose data is on one device.
+struct Window {
+    /// Index of the device in the placement, or `None` if no data of the
+    /// window is on a device.
+    device: Option<usize>,
+    /// Logical offset of the start of the window.
+    start: u64,
+    /// Extents of the window, translated to the device.
+    extents: Vec<FiemapExtent>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BlockDeviceBackend;
+    use crate::reader::{fiemap_file, BlkReader};
+    use crate::state::SegmentSource;
+    use crate::test_support::{fake_device, image_at, stand_in_file};
+
+    #[test]
+    fn test_read_placed() {
+        use blkmap::ExtentFlags;
+        use std::io::Write;
+
+        // Two member devices of a RAID0 chunk with 64 KiB stripes
+        const STRIPE: usize = 65536;
+        let devices: Vec<tempfile::NamedTempFile> = [0x11u8, 0x22]
+            .iter()
+            .map(|&byte| {
+                let mut device = tempfile::NamedTempFile::new().unwrap();
+                let data: Vec<u8> = (0..4).flat_map(|k| [byte + k; STRIPE]).collect();
+                device.write_all(&data).unwrap();
+                device
+            })
+            .collect();
+        let paths: Vec<PathBuf> = devices.iter().map(|d| d.path().to_path_buf()).collect();
+        let map = btrfs::ChunkMap::striped(1 << 30, 1 << 30, STRIPE as u64, &paths);
+
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 1 << 30,
+            length: 4 * STRIPE as u64,
+            flags: ExtentFlags::LAST,
+        }];
+        let placement = btrfs::place(&map, &extents, |_| true).unwrap();
+        assert_eq!(placement.placed.len(), 4);
+
+        let file = stand_in_file();
+        let options = Options::new().with_cache(false).with_fill_holes(true);
+        let ctx = ReadContext::new(&file, &options);
+        let mut buf = AlignedBuf::new(5 * STRIPE, READ_ALIGNMENT);
+        let state = ctx
+            .read_placed(&mut buf, 0, extents.clone(), &placement)
+            .unwrap();
+
+        // Stripes alternate between the devices, followed by the filled hole
+        assert_eq!(state.bytes_read, 5 * STRIPE);
+        for (k, byte) in [0x11, 0x22, 0x12, 0x23, 0].into_iter().enumerate() {
+            assert!(buf[k * STRIPE..(k + 1) * STRIPE].iter().all(|&b| b == byte));
+        }
+        assert_eq!(state.extents, extents);
+        assert_eq!(state.block_device_path, paths[0]);
+        assert_eq!(state.synthesized, vec![4 * STRIPE..5 * STRIPE]);
+        let sources: Vec<_> = state
+            .segments
+            .iter()
+            .map(|segment| (segment.range.clone(), segment.source))
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                (0..STRIPE, SegmentSource::Device { physical: 0 }),
+                (STRIPE..2 * STRIPE, SegmentSource::Device { physical: 0 }),
+                (
+                    2 * STRIPE..3 * STRIPE,
+                    SegmentSource::Device {
+                        physical: STRIPE as u64
+                    }
+                ),
+                (
+                    3 * STRIPE..4 * STRIPE,
+                    SegmentSource::Device {
+                        physical: STRIPE as u64
+                    }
+                ),
+                (4 * STRIPE..5 * STRIPE, SegmentSource::Hole),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_device_info() {
+        use blkmap::ExtentFlags;
+        use std::io::Write;
+
+        let info = DeviceInfo {
+            logical_block_size: 4096,
+            physical_block_size: 4096,
+            size: 16384,
+        };
+        let mut data = tempfile::tempfile().unwrap();
+        data.write_all(&[0xAB; 16384]).unwrap();
+        let slot = OnceLock::new();
+        let _ = slot.set(DeviceHandle::Uncached(CachedDevice {
+            path: PathBuf::from("/dev/fake"),
+            file: data,
+            info: Some(info),
+            direct: true,
+            base_offset: 0,
+            in_flight: Default::default(),
+        }));
+        assert_eq!(slot.get().unwrap().sector_size(), 4096);
+
+        let file = stand_in_file();
+        let options = Options::new();
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 4096,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        let mut buf = vec![0u8; 4096];
+        let state = ctx.read_with_caller_extents(&mut buf, 0, &extents).unwrap();
+        assert_eq!(state.device_info, Some(info));
+        assert_eq!(ctx.required_alignment().unwrap(), 4096);
+
+        // Reads that are not sector-aligned are rejected up front
+        let mut buf = vec![0u8; 512];
+        let err = ctx
+            .read_with_caller_extents(&mut buf, 0, &extents)
+            .unwrap_err();
+        match BlkReadError::from_io_error(&err) {
+            Some(BlkReadError::Unaligned {
+                physical_offset,
+                length,
+                alignment,
+                ..
+            }) => assert_eq!((*physical_offset, *length, *alignment), (4096, 512, 4096)),
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // So are extents beyond the end of the device
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 16384,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        let mut buf = vec![0u8; 4096];
+        let err = ctx
+            .read_with_caller_extents(&mut buf, 0, &extents)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::BeyondDevice {
+                extent_index: 0,
+                device_size: 16384,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_buffered_device() {
+        use blkmap::ExtentFlags;
+        use std::io::Write;
+
+        let mut data = tempfile::NamedTempFile::new().unwrap();
+        let bytes: Vec<u8> = (0..16384u32).map(|i| (i % 251) as u8).collect();
+        data.write_all(&bytes).unwrap();
+        let mut device = open_device_uncached_at(&BlockDeviceBackend, data.path(), false).unwrap();
+        assert!(!device.direct);
+        device.info = Some(DeviceInfo {
+            logical_block_size: 4096,
+            physical_block_size: 4096,
+            size: 16384,
+        });
+        let slot = OnceLock::new();
+        let _ = slot.set(DeviceHandle::Uncached(device));
+
+        let file = stand_in_file();
+        let options = Options::new().with_direct(false);
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        assert_eq!(ctx.dio_alignment(), (1, 1));
+
+        // Buffered reads need not be sector-aligned
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 4100,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        let mut buf = vec![0u8; 700];
+        let state = ctx.read_with_caller_extents(&mut buf, 0, &extents).unwrap();
+        assert_eq!(state.bytes_read, 700);
+        assert_eq!(buf, bytes[4100..4800]);
+    }
+
+    #[test]
+    fn test_reopen_gone_device() {
+        use crate::cache::BlkCache;
+        use crate::engine::{Completion, IoEngine, PreadvEngine};
+        use blkmap::ExtentFlags;
+        use std::os::unix::io::BorrowedFd;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Fails the first `failures` reads with `ENXIO`, as reads from an
+        /// unplugged disk do.
+        #[derive(Debug)]
+        struct VanishingEngine {
+            failures: AtomicUsize,
+        }
+
+        impl IoEngine for VanishingEngine {
+            fn name(&self) -> &str {
+                "vanishing"
+            }
+
+            fn read_batch(
+                &self,
+                fd: BorrowedFd<'_>,
+                reads: &mut [DeviceRead<'_>],
+                complete: &mut Completion<'_>,
+            ) -> io::Result<()> {
+                PreadvEngine.read_batch(fd, reads, &mut |index, result| {
+                    let fail = self
+                        .failures
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    if fail {
+                        complete(index, Err(io::Error::from_raw_os_error(libc::ENXIO)))
+                    } else {
+                        complete(index, result)
+                    }
+                })
+            }
+        }
+
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(temp.path(), &data).unwrap();
+        let file = stand_in_file();
+        let extents = [FiemapExtent {
+            logical: 0,
+            physical: 4096,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+
+        // The stale handle is evicted and the read succeeds on a new one
+        let cache = BlkCache::new();
+        let stale = cache.get_or_create_device_at(temp.path(), false).unwrap();
+        let device = DeviceHandle::Cached(Arc::clone(&stale));
+        let options = Options::new()
+            .with_cache_handle(&cache)
+            .with_io_engine(VanishingEngine {
+                failures: AtomicUsize::new(1),
+            });
+        let ctx = ReadContext::new(&file, &options);
+        let mut buf = vec![0u8; 4096];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+        assert_eq!(outcome.bytes_read, 4096);
+        assert_eq!(buf, data[4096..]);
+        let cached = cache.get_or_create_device_at(temp.path(), false).unwrap();
+        assert!(!Arc::ptr_eq(&cached, &stale));
+        assert_eq!(cache.stats().evictions, 1);
+
+        // The read is retried only once
+        let device = DeviceHandle::Cached(cached);
+        let options = Options::new()
+            .with_cache_handle(&cache)
+            .with_io_engine(VanishingEngine {
+                failures: AtomicUsize::new(2),
+            });
+        let ctx = ReadContext::new(&file, &options);
+        let err = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap_err();
+        let context = DeviceReadError::from_io_error(&err).unwrap();
+        assert_eq!(context.source.raw_os_error(), Some(libc::ENXIO));
+    }
+
+    #[test]
+    fn test_device_backend() {
+        use crate::backend::DeviceBackend;
+        use std::io::Write;
+        use std::os::unix::fs::FileExt;
+
+        /// Serves a sparse image file as every file's device.
+        #[derive(Debug)]
+        struct ImageBackend(PathBuf);
+
+        impl DeviceBackend for ImageBackend {
+            fn resolve(&self, _: &File) -> io::Result<PathBuf> {
+                Ok(PathBuf::from("/dev/image"))
+            }
+
+            fn open(&self, path: &Path, _: bool) -> io::Result<File> {
+                assert_eq!(path, Path::new("/dev/image"));
+                File::open(&self.0)
+            }
+        }
+
+        let data: Vec<u8> = (0..16384).map(|i| (i / 7) as u8).collect();
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&data).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        // Place the file's data in the image where FIEMAP says it is
+        let image = tempfile::NamedTempFile::new().unwrap();
+        for extent in fiemap_file(temp.as_file()).unwrap() {
+            let start = extent.logical as usize;
+            let end = (start + extent.length as usize).min(data.len());
+            image
+                .as_file()
+                .write_all_at(&data[start..end], extent.physical)
+                .unwrap();
+        }
+
+        let options = Options::new().with_device_backend(ImageBackend(image.path().into()));
+        let mut buf = AlignedBuf::new(data.len(), READ_ALIGNMENT);
+        let state = temp.path().blk_read_at_opt(&mut buf, 0, &options).unwrap();
+        assert_eq!(state.bytes_read, data.len());
+        assert_eq!(state.block_device_path, PathBuf::from("/dev/image"));
+        assert_eq!(&buf[..], &data[..]);
+    }
+
+    #[test]
+    fn test_mem_device() {
+        use crate::backend::MemDevice;
+
+        // The device holds 8 KiB but claims 16 KiB
+        let device = MemDevice::new(8192).unwrap().with_info(DeviceInfo {
+            logical_block_size: 512,
+            physical_block_size: 4096,
+            size: 16384,
+        });
+        let extent = device.place(0, 4096, &[0x5C; 4096]).unwrap();
+        let extents = [FiemapExtent {
+            length: 8192,
+            ..extent
+        }];
+        let file = tempfile::tempfile().unwrap();
+        let options = Options::new().with_device_backend(device.clone());
+
+        // Reads past the end of the data come up short
+        let mut buf = vec![0u8; 8192];
+        let state = file
+            .blk_read_with_extents(&mut buf, 0, &extents, &options)
+            .unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert_eq!(state.block_device_path, PathBuf::from(MemDevice::PATH));
+        assert_eq!(state.device_info.map(|info| info.size), Some(16384));
+        assert!(buf[..4096].iter().all(|&b| b == 0x5C));
+        let err = file
+            .blk_read_with_extents(
+                &mut buf,
+                0,
+                &extents,
+                &options.clone().with_read_exact(true),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::ShortRead(_))
+        ));
+
+        // Extents beyond the reported size are rejected before any read
+        let beyond = [FiemapExtent {
+            physical: 16384,
+            ..extents[0]
+        }];
+        let err = file
+            .blk_read_with_extents(&mut buf, 0, &beyond, &options)
+            .unwrap_err();
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::BeyondDevice { .. })
+        ));
+        assert!(device.write_at(8000, &[0; 512]).is_err());
+    }
+
+    #[test]
+    fn test_device_override() {
+        use std::io::Write;
+
+        // A partition image starting 1 MiB into a disk image
+        let offset = 1 << 20;
+        let data: Vec<u8> = (0..8192).map(|i| (i % 253) as u8).collect();
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&data).unwrap();
+        temp.as_file().sync_all().unwrap();
+        let (image, options) = image_at(temp.as_file(), offset);
+        let size = image.as_file().metadata().unwrap().len() - offset;
+        let extents = fiemap_file(temp.as_file()).unwrap();
+
+        let file = temp.as_file();
+        let mut buf = AlignedBuf::new(data.len(), READ_ALIGNMENT);
+        let state = file
+            .blk_read_with_extents(&mut buf, 0, &extents, &options)
+            .unwrap();
+        assert_eq!(state.bytes_read, data.len());
+        assert_eq!(state.block_device_path, image.path());
+        assert_eq!(state.device_info.map(|info| info.size), Some(size));
+        assert_eq!(&buf[..], &data[..]);
+
+        // The device ends where the image does
+        let beyond = [FiemapExtent {
+            physical: size,
+            ..extents[0]
+        }];
+        let err = file
+            .blk_read_with_extents(&mut buf, 0, &beyond, &options)
+            .unwrap_err();
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::BeyondDevice { .. })
+        ));
+    }
+
+    #[test]
+    fn test_map_uses_device_slot() {
+        use blkmap::ExtentFlags;
+
+        let slot = OnceLock::new();
+        slot.set(fake_device(&[])).unwrap();
+        let file = tempfile::tempfile().unwrap();
+        let options = Options::new();
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 1 << 20,
+            length: 8192,
+            flags: ExtentFlags::empty(),
+        }];
+
+        let ctx = ReadContext::new(&file, &options)
+            .with_extent_map(&extents)
+            .with_device_slot(&slot);
+        let ranges = ctx.map(4096, 8192).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].device_path, PathBuf::from("/dev/fake"));
+        assert_eq!(ranges[0].physical, (1 << 20) + 4096);
+        assert_eq!(ranges[0].length, 4096);
+
+        // Unmapped ranges translate to nothing
+        assert!(ctx.map(8192, 4096).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_verify_device() {
+        use blkmap::ExtentFlags;
+        use std::io::Write;
+
+        let mut data = vec![0x11u8; 4096];
+        data.extend_from_slice(&[0x22; 4096]);
+        let slot = OnceLock::new();
+        slot.set(fake_device(&data)).unwrap();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[0x22; 4096]).unwrap();
+        let extent = |physical| FiemapExtent {
+            logical: 0,
+            physical,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        };
+
+        let options = Options::new().with_verify_device(true);
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let mut buf = vec![0u8; 4096];
+        ctx.read_with_caller_extents(&mut buf, 0, &[extent(4096)])
+            .unwrap();
+        assert!(buf.iter().all(|&b| b == 0x22));
+
+        // Offsets relative to another device read other data
+        let err = ctx
+            .read_with_caller_extents(&mut buf, 0, &[extent(0)])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::DeviceMismatch {
+                logical_offset: 0,
+                physical_offset: 0,
+                ..
+            })
+        ));
+
+        // Unwritten extents have no data to compare
+        let unwritten = FiemapExtent {
+            flags: ExtentFlags::UNWRITTEN,
+            ..extent(0)
+        };
+        ctx.read_with_caller_extents(&mut buf, 0, &[unwritten])
+            .unwrap();
+
+        // Without the check, the wrong data is returned
+        let options = Options::new();
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        ctx.read_with_caller_extents(&mut buf, 0, &[extent(0)])
+            .unwrap();
+        assert!(buf.iter().all(|&b| b == 0x11));
+    }
+}
@@ -0,0 +1,292 @@
+//! Repeated reads of one file through [`BlkFile`].
+
+use super::{check_filesystem, data_file, fiemap_failed, fiemap_file, DeviceHandle, ReadContext};
+use crate::buffer::{BufPool, PooledBuf};
+use crate::map::MappedRange;
+use crate::options::Options;
+use crate::scan::Match;
+use crate::state::State;
+use crate::sys;
+use crate::verify::VerifyReport;
+
+use blkmap::FiemapExtent;
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// A file opened for repeated block device reads.
+///
+/// Every call to
+/// [`BlkReader::blk_read_at_opt`](crate::BlkReader::blk_read_at_opt)
+/// queries FIEMAP and resolves the block device anew. `BlkFile` instead
+/// queries the complete extent map once when opened and keeps the device
+/// handle after the first device read, so repeated reads of the same file
+/// skip both.
+///
+/// The cached extent map is not updated automatically; call
+/// [`refresh`](BlkFile::refresh) if the file's layout may have changed.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkFile, Options};
+///
+/// let file = BlkFile::open("/path/to/file").unwrap();
+/// let mut buf = vec![0u8; 4096];
+/// for i in 0..16 {
+///     file.read_at(&mut buf, i * 4096).unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct BlkFile {
+    file: File,
+    path: Option<PathBuf>,
+    extents: Vec<FiemapExtent>,
+    device: OnceLock<DeviceHandle>,
+}
+
+impl BlkFile {
+    /// Open a file and query its extent map.
+    ///
+    /// A file on overlayfs is replaced by the layer file holding its data,
+    /// as with [`Options::resolve_overlay`].
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = data_file(File::open(path)?, &Options::default())?;
+        check_filesystem(&file, Some(path))?;
+        let extents = fiemap_file(&file).map_err(fiemap_failed(&file, Some(path)))?;
+        Ok(Self {
+            file,
+            path: Some(path.to_path_buf()),
+            extents,
+            device: OnceLock::new(),
+        })
+    }
+
+    /// Wrap an already opened file and query its extent map.
+    pub fn from_file(file: File) -> io::Result<Self> {
+        let file = data_file(file, &Options::default())?;
+        check_filesystem(&file, None)?;
+        let extents = fiemap_file(&file).map_err(fiemap_failed(&file, None))?;
+        Ok(Self {
+            file,
+            path: None,
+            extents,
+            device: OnceLock::new(),
+        })
+    }
+
+    /// Open file descriptor `fd` of process `pid` and query its extent map.
+    ///
+    /// The descriptor is reopened through `/proc/<pid>/fd/<fd>`, which
+    /// reaches the open file even after its last name was unlinked, so a
+    /// deleted file still held open by a process can be read from the
+    /// device. Reopening requires permission to ptrace the process, such as
+    /// running as its user, and to read the file.
+    pub fn open_proc_fd(pid: u32, fd: RawFd) -> io::Result<Self> {
+        Self::open(format!("/proc/{}/fd/{}", pid, fd))
+    }
+
+    /// Duplicate file descriptor `fd` of the process `pidfd` refers to and
+    /// query its extent map.
+    ///
+    /// Like [`open_proc_fd`](BlkFile::open_proc_fd), but the process is
+    /// named by a pidfd (from `pidfd_open` or `clone3`), which cannot be
+    /// confused with a later process reusing the pid. The descriptor is
+    /// duplicated with `pidfd_getfd`, sharing the process's open file
+    /// rather than opening the file again.
+    pub fn from_pidfd(pidfd: BorrowedFd<'_>, fd: RawFd) -> io::Result<Self> {
+        Self::from_file(sys::pidfd_getfd(pidfd.as_raw_fd(), fd)?)
+    }
+
+    /// The underlying file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// The cached extent map of the file.
+    pub fn extents(&self) -> &[FiemapExtent] {
+        &self.extents
+    }
+
+    /// Re-query the extent map of the file.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        self.extents =
+            fiemap_file(&self.file).map_err(fiemap_failed(&self.file, self.path.as_deref()))?;
+        Ok(())
+    }
+
+    /// Read data at the specified offset with default options.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let state = self.read_at_opt(buf, offset, &Options::default())?;
+        Ok(state.bytes_read)
+    }
+
+    /// Read data at the specified offset with options.
+    ///
+    /// The device handle is opened on the first device read according to
+    /// that read's cache option and reused afterwards.
+    pub fn read_at_opt(&self, buf: &mut [u8], offset: u64, options: &Options) -> io::Result<State> {
+        self.context(options).read_at(buf, offset)
+    }
+
+    /// Read the entire file into a new vector.
+    ///
+    /// See [`BlkReader::blk_read_to_end`](crate::BlkReader::blk_read_to_end).
+    pub fn read_to_end(&self, options: &Options) -> io::Result<Vec<u8>> {
+        self.context(options).read_to_end()
+    }
+
+    /// Read several ranges in one call.
+    ///
+    /// See [`BlkReader::blk_read_ranges`](crate::BlkReader::blk_read_ranges).
+    pub fn read_ranges(
+        &self,
+        ranges: &mut [(u64, usize, &mut [u8])],
+        options: &Options,
+    ) -> io::Result<Vec<State>> {
+        self.context(options).read_ranges(ranges)
+    }
+
+    /// Stream the raw data of each extent in a range to a callback.
+    ///
+    /// See [`BlkReader::blk_read_extents`](crate::BlkReader::blk_read_extents).
+    pub fn read_extents<F>(
+        &self,
+        offset: u64,
+        length: u64,
+        options: &Options,
+        f: F,
+    ) -> io::Result<State>
+    where
+        F: FnMut(&FiemapExtent, &[u8]) -> io::Result<()>,
+    {
+        self.context(options).visit_extents(offset, length, f)
+    }
+
+    /// Copy a range to another file descriptor, zero-copy.
+    ///
+    /// See [`BlkReader::blk_copy_to`](crate::BlkReader::blk_copy_to).
+    pub fn copy_to(
+        &self,
+        out: BorrowedFd<'_>,
+        offset: u64,
+        length: u64,
+        options: &Options,
+    ) -> io::Result<State> {
+        self.context(options).copy_to(out, offset, length)
+    }
+
+    /// Translate a logical range into physical device ranges.
+    ///
+    /// See [`BlkReader::blk_map`](crate::BlkReader::blk_map).
+    pub fn map(&self, offset: u64, length: u64) -> io::Result<Vec<MappedRange>> {
+        let options = Options::new();
+        self.context(&options).map(offset, length)
+    }
+
+    /// Start reading the device data of a logical range ahead of time.
+    ///
+    /// See [`BlkReader::blk_prefetch`](crate::BlkReader::blk_prefetch).
+    pub fn prefetch(&self, offset: u64, length: u64, options: &Options) -> io::Result<u64> {
+        self.context(options).prefetch(offset, length)
+    }
+
+    /// Compare a range read from the block device with the file's contents.
+    ///
+    /// See [`BlkReader::blk_verify_at`](crate::BlkReader::blk_verify_at).
+    pub fn verify_at(
+        &self,
+        offset: u64,
+        length: u64,
+        options: &Options,
+    ) -> io::Result<VerifyReport> {
+        self.context(options).verify_at(offset, length)
+    }
+
+    /// Search the device data of the file for a byte pattern.
+    ///
+    /// See [`BlkReader::blk_scan`](crate::BlkReader::blk_scan).
+    pub fn scan(&self, pattern: &[u8], options: &Options) -> io::Result<Vec<Match>> {
+        self.context(options).scan(pattern)
+    }
+
+    /// Copy the whole file to `dest`, keeping it sparse.
+    ///
+    /// See [`BlkReader::blk_copy_sparse`](crate::BlkReader::blk_copy_sparse).
+    pub fn copy_sparse(&self, dest: &File, options: &Options) -> io::Result<State> {
+        self.context(options).copy_sparse(dest)
+    }
+
+    /// Read a range into an owned buffer from `pool`.
+    ///
+    /// See [`BlkReader::blk_read_pooled`](crate::BlkReader::blk_read_pooled).
+    pub fn read_pooled(
+        &self,
+        pool: &BufPool,
+        offset: u64,
+        length: usize,
+        options: &Options,
+    ) -> io::Result<(PooledBuf, State)> {
+        self.context(options).read_pooled(pool, offset, length)
+    }
+
+    /// Alignment required of offsets and lengths of device reads.
+    ///
+    /// See [`BlkReader::blk_required_alignment`](crate::BlkReader::blk_required_alignment).
+    pub fn required_alignment(&self) -> io::Result<u64> {
+        let options = Options::new();
+        self.context(&options).required_alignment()
+    }
+
+    /// NUMA node local to the file's block device.
+    ///
+    /// See [`BlkReader::blk_numa_node`](crate::BlkReader::blk_numa_node).
+    pub fn numa_node(&self) -> io::Result<Option<usize>> {
+        let options = Options::new();
+        self.context(&options).numa_node()
+    }
+
+    /// Build a read context using the cached extent map and device handle.
+    fn context<'a>(&'a self, options: &'a Options) -> ReadContext<'a> {
+        let ctx = ReadContext::new(&self.file, options)
+            .with_extent_map(&self.extents)
+            .with_device_slot(&self.device);
+        match &self.path {
+            Some(path) => ctx.with_path(path),
+            None => ctx,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blk_file() {
+        use std::io::Write;
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x11; 4096]).unwrap();
+        temp.write_all(&[0x22; 4096]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let file = BlkFile::open(temp.path()).unwrap();
+        assert!(!file.extents().is_empty());
+
+        let options = Options::new().with_allow_fallback(true);
+        let mut buf = vec![0u8; 4096];
+        let state = file.read_at_opt(&mut buf, 4096, &options).unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert!(state.used_fallback);
+        assert!(buf.iter().all(|&b| b == 0x22));
+
+        let state = file.read_at_opt(&mut buf, 0, &options).unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert!(buf.iter().all(|&b| b == 0x11));
+    }
+}
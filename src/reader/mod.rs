@@ -0,0 +1,1519 @@
+//! Core reader trait and implementations.
+//!
+//! This module provides the [`BlkReader`] trait which enables reading file data
+//! directly from the underlying block device using extent information. Reads
+//! go through a `ReadContext`, whose stages live in submodules: the extent
+//! walk in `walk`, the execution of device reads in `run`, device placement
+//! in `device`, and the operations built on reads in `copy`.
+
+mod copy;
+mod device;
+mod file;
+mod run;
+mod walk;
+
+pub use file::BlkFile;
+
+pub(crate) use device::DeviceHandle;
+
+use crate::buffer::{AlignedBuf, BufPool, PooledBuf};
+use crate::engine::ReadFlags;
+use crate::error::BlkReadError;
+use crate::extent_map::DeviceIdentity;
+use crate::map::MappedRange;
+use crate::numa;
+use crate::observer::ReadObserver;
+use crate::options::{NumaNode, Options};
+use crate::overlay;
+use crate::scan::Match;
+use crate::state::{Segment, SegmentSource, State, Timing};
+use crate::stats::{self, Counter};
+use crate::sys;
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+use crate::telemetry;
+use crate::verify::VerifyReport;
+
+use blkmap::FiemapExtent;
+
+use std::fs::File;
+use std::io;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The file a [`BlkReader`] reads.
+#[derive(Debug, Clone, Copy)]
+pub enum BlkSource<'a> {
+    /// A path, opened for each call.
+    Path(&'a Path),
+    /// An open file descriptor, read without taking ownership.
+    Fd(BorrowedFd<'a>),
+}
+
+/// Trait for reading file data directly from block devices.
+///
+/// This trait provides the following methods for reading:
+/// - [`blk_read_at`](BlkReader::blk_read_at): Simple read that returns the number of bytes read
+/// - [`blk_read_at_opt`](BlkReader::blk_read_at_opt): Advanced read with options that returns detailed state
+/// - [`blk_read_to_end`](BlkReader::blk_read_to_end): Read the whole file into a vector
+/// - [`blk_read_with_extents`](BlkReader::blk_read_with_extents): Read using a caller-supplied extent map
+/// - [`blk_read_ranges`](BlkReader::blk_read_ranges): Read many ranges with a single extent query
+/// - [`blk_read_extents`](BlkReader::blk_read_extents): Stream raw extent data to a callback
+/// - [`blk_copy_to`](BlkReader::blk_copy_to): Copy a range to a file descriptor without user-space buffers
+/// - [`blk_map`](BlkReader::blk_map): Translate a logical range to physical ranges without reading
+///
+/// Implementors provide [`blk_read_at_opt`](BlkReader::blk_read_at_opt),
+/// on which [`blk_read_at`](BlkReader::blk_read_at) and
+/// [`blk_read_to_end`](BlkReader::blk_read_to_end) are built. The other
+/// methods need the file itself: they read the path or descriptor returned
+/// by [`blk_source`](BlkReader::blk_source), and fail with
+/// [`Unsupported`](io::ErrorKind::Unsupported) for readers which return
+/// none, so methods added later do not break existing implementations:
+///
+/// ```no_run
+/// use blkreader::{BlkReader, BlkSource, Options, State};
+/// use std::fs::File;
+/// use std::io;
+/// use std::os::unix::io::AsFd;
+///
+/// struct Journal {
+///     file: File,
+/// }
+///
+/// impl BlkReader for Journal {
+///     fn blk_read_at_opt(&self, buf: &mut [u8], offset: u64, options: &Options) -> io::Result<State> {
+///         self.file.blk_read_at_opt(buf, offset, options)
+///     }
+///
+///     fn blk_source(&self) -> Option<BlkSource<'_>> {
+///         Some(BlkSource::Fd(self.file.as_fd()))
+///     }
+/// }
+/// ```
+///
+/// # Direct I/O Alignment Requirements
+///
+/// When reading directly from block devices (not using fallback mode), the following
+/// alignment requirements must be met:
+///
+/// - **Buffer alignment**: The buffer should be aligned to at least 512 bytes (sector size).
+/// - **Offset alignment**: The read offset should be aligned to 512 bytes.
+/// - **Length alignment**: The buffer length should be aligned to 512 bytes.
+///
+/// If alignment requirements are not met, the underlying read may fail with `EINVAL`.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkReader, Options};
+/// use std::path::Path;
+///
+/// let path = Path::new("/path/to/file");
+/// // Use aligned buffer size (4096 is a common block size)
+/// let mut buf = vec![0u8; 4096];
+///
+/// // Simple read (offset 0 is aligned)
+/// let bytes = path.blk_read_at(&mut buf, 0).unwrap();
+///
+/// // Read with options
+/// let opts = Options::new().with_fill_holes(true);
+/// let state = path.blk_read_at_opt(&mut buf, 0, &opts).unwrap();
+/// ```
+pub trait BlkReader {
+    /// Read data from the file at the specified offset.
+    ///
+    /// This is a convenience method that calls [`blk_read_at_opt`](BlkReader::blk_read_at_opt)
+    /// with default options and returns just the number of bytes read.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Buffer to read data into. For Direct I/O, should be aligned to 512 bytes.
+    /// * `offset` - Byte offset in the file to start reading from. Should be aligned to 512 bytes.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes successfully read, or an error.
+    fn blk_read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let state = self.blk_read_at_opt(buf, offset, &Options::default())?;
+        Ok(state.bytes_read)
+    }
+
+    /// Read data from the file at the specified offset with options.
+    ///
+    /// This method queries the file's extent information, resolves the block device,
+    /// and reads data directly from the physical locations on disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Buffer to read data into. For Direct I/O, should be aligned to 512 bytes.
+    /// * `offset` - Byte offset in the file to start reading from. Should be aligned to 512 bytes.
+    /// * `options` - Configuration options for the read operation
+    ///
+    /// # Returns
+    ///
+    /// A [`State`] containing the block device path, extent information,
+    /// and number of bytes read, or an error.
+    fn blk_read_at_opt(&self, buf: &mut [u8], offset: u64, options: &Options) -> io::Result<State>;
+
+    /// The file this reader reads, for the methods which need more than
+    /// [`blk_read_at_opt`](BlkReader::blk_read_at_opt).
+    ///
+    /// Returns `None` by default, in which case those methods fail with
+    /// [`Unsupported`](io::ErrorKind::Unsupported).
+    fn blk_source(&self) -> Option<BlkSource<'_>> {
+        None
+    }
+
+    /// Read the entire file into a new vector.
+    ///
+    /// The file is read in chunks through an internally allocated aligned
+    /// buffer, so Direct I/O alignment is handled automatically. Holes are
+    /// filled with [`Options::fill_byte`] whether or not
+    /// [`Options::fill_holes`] is set, so the returned vector holds exactly
+    /// the file size; a read coming back short before the end, as when the
+    /// file shrinks meanwhile, fails with
+    /// [`UnexpectedEof`](io::ErrorKind::UnexpectedEof). Readers without a
+    /// [`blk_source`](BlkReader::blk_source) are read through
+    /// [`blk_read_at_opt`](BlkReader::blk_read_at_opt) until a chunk comes
+    /// back short.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, Options};
+    /// use std::path::Path;
+    ///
+    /// let data = Path::new("/path/to/file")
+    ///     .blk_read_to_end(&Options::new())
+    ///     .unwrap();
+    /// ```
+    fn blk_read_to_end(&self, options: &Options) -> io::Result<Vec<u8>> {
+        match self.blk_source() {
+            Some(source) => with_context(Some(source), options, |ctx| ctx.read_to_end()),
+            None => read_to_end_with(self, options),
+        }
+    }
+
+    /// Read data using a caller-supplied extent map.
+    ///
+    /// Instead of querying FIEMAP at read time, the physical locations are
+    /// taken from `extents`, typically an extent map persisted earlier. This
+    /// allows reading data even if the file has since been truncated. Paths
+    /// of deleted files can only be read if the device is given with
+    /// [`Options::with_device_override`] or a device backend, or through
+    /// [`ExtentMap::read_at`](crate::ExtentMap::read_at), which checks that
+    /// the nearest existing parent directory is still on the file's
+    /// filesystem; otherwise they fail with `NotFound`.
+    ///
+    /// Fallback to regular file I/O is never used by this method, since the
+    /// file's current contents may no longer match the supplied extents.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, DeviceSource, Extent, Options};
+    /// use std::path::Path;
+    ///
+    /// # fn load_persisted_extents() -> Vec<Extent> { Vec::new() }
+    /// let extents: Vec<Extent> = load_persisted_extents();
+    /// let options = Options::new().with_device_override(DeviceSource::Device("/dev/sda1".into()));
+    /// let mut buf = vec![0u8; 4096];
+    /// let state = Path::new("/path/to/deleted-file")
+    ///     .blk_read_with_extents(&mut buf, 0, &extents, &options)
+    ///     .unwrap();
+    /// ```
+    fn blk_read_with_extents(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        extents: &[FiemapExtent],
+        options: &Options,
+    ) -> io::Result<State> {
+        match self.blk_source() {
+            Some(BlkSource::Path(path)) => {
+                read_path_with_extents(path, buf, offset, extents, None, options)
+            }
+            source => with_context(source, options, |ctx| {
+                ctx.read_with_caller_extents(buf, offset, extents)
+            }),
+        }
+    }
+
+    /// Read several ranges of the file in one call.
+    ///
+    /// Each entry is an `(offset, len, buf)` triple; `len` bytes at `offset`
+    /// are read into `buf[..len]`. A single FIEMAP query covering the union of
+    /// all ranges is issued, the device handle is shared, and the device reads
+    /// are issued in order of physical offset. This is much faster than
+    /// calling [`blk_read_at_opt`](BlkReader::blk_read_at_opt) once per range
+    /// for many small scattered ranges.
+    ///
+    /// The returned states are in the same order as `ranges`. The first
+    /// failing range aborts the whole call.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, Options};
+    /// use std::path::Path;
+    ///
+    /// let mut a = vec![0u8; 4096];
+    /// let mut b = vec![0u8; 4096];
+    /// let mut ranges = [(0, 4096, &mut a[..]), (1 << 20, 4096, &mut b[..])];
+    /// let states = Path::new("/path/to/file")
+    ///     .blk_read_ranges(&mut ranges, &Options::new())
+    ///     .unwrap();
+    /// ```
+    fn blk_read_ranges(
+        &self,
+        ranges: &mut [(u64, usize, &mut [u8])],
+        options: &Options,
+    ) -> io::Result<Vec<State>> {
+        with_context(self.blk_source(), options, |ctx| ctx.read_ranges(ranges))
+    }
+
+    /// Stream the raw data of each extent in a range to a callback.
+    ///
+    /// `f` is invoked once per extent overlapping `offset..offset + length`,
+    /// with the extent clipped to the range and the raw data read from the
+    /// device for it. Extents longer than 1 MB are delivered in several
+    /// pieces of at most 1 MB, so memory use stays bounded regardless of the
+    /// range size. Holes are skipped, and unwritten extents are delivered
+    /// as-is; check the extent flags to tell them apart.
+    ///
+    /// Direct I/O alignment is handled internally. Returning an error from
+    /// `f` stops the read and propagates the error.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, Options};
+    /// use std::path::Path;
+    ///
+    /// let mut total = 0;
+    /// Path::new("/path/to/file")
+    ///     .blk_read_extents(0, 1 << 30, &Options::new(), |extent, data| {
+    ///         assert_eq!(extent.length as usize, data.len());
+    ///         total += data.len();
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// ```
+    fn blk_read_extents<F>(
+        &self,
+        offset: u64,
+        length: u64,
+        options: &Options,
+        f: F,
+    ) -> io::Result<State>
+    where
+        F: FnMut(&FiemapExtent, &[u8]) -> io::Result<()>,
+    {
+        with_context(self.blk_source(), options, |ctx| {
+            ctx.visit_extents(offset, length, f)
+        })
+    }
+
+    /// Copy a range of the file to another file descriptor, zero-copy.
+    ///
+    /// The data is transferred from the block device to `out` (a pipe,
+    /// socket or file) with `sendfile`, so it never passes through a
+    /// user-space buffer. Holes and unwritten extents are handled as for
+    /// [`blk_read_at_opt`](BlkReader::blk_read_at_opt), with fill bytes
+    /// written to `out`. The returned state's `bytes_read` is the number of
+    /// bytes written to `out`.
+    ///
+    /// The offset and length must satisfy the Direct I/O alignment
+    /// requirements of the device.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, Options};
+    /// use std::os::fd::AsFd;
+    /// use std::path::Path;
+    ///
+    /// let stdout = std::io::stdout();
+    /// let state = Path::new("/path/to/file")
+    ///     .blk_copy_to(stdout.as_fd(), 0, 1 << 30, &Options::new())
+    ///     .unwrap();
+    /// ```
+    fn blk_copy_to(
+        &self,
+        out: BorrowedFd<'_>,
+        offset: u64,
+        length: u64,
+        options: &Options,
+    ) -> io::Result<State> {
+        with_context(self.blk_source(), options, |ctx| {
+            ctx.copy_to(out, offset, length)
+        })
+    }
+
+    /// Translate a logical file range into physical device ranges.
+    ///
+    /// No I/O is performed on the block device, so this does not require
+    /// permission to read it. Holes are not represented in the result; they
+    /// show up as gaps between consecutive ranges.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::BlkReader;
+    /// use std::path::Path;
+    ///
+    /// for range in Path::new("/path/to/file").blk_map(0, 1 << 20).unwrap() {
+    ///     println!(
+    ///         "{} {}+{} ({:?})",
+    ///         range.device_path.display(),
+    ///         range.physical,
+    ///         range.length,
+    ///         range.flags
+    ///     );
+    /// }
+    /// ```
+    fn blk_map(&self, offset: u64, length: u64) -> io::Result<Vec<MappedRange>> {
+        with_context(self.blk_source(), &Options::new(), |ctx| {
+            ctx.map(offset, length)
+        })
+    }
+
+    /// Start reading the device data of a logical range ahead of time.
+    ///
+    /// The kernel is asked to read the physical ranges backing the range
+    /// into the device's page cache (`POSIX_FADV_WILLNEED`), without waiting
+    /// for the reads, so that reading the range right after finds the data
+    /// there. Reads without [`Options::direct`] are served from that cache;
+    /// Direct I/O bypasses it, but still finds the data in the drive's own
+    /// cache where it has one. This makes it possible to pipeline recovery:
+    /// prefetch the next range while processing the current one.
+    ///
+    /// Holes, unwritten, delayed and inline extents are skipped. Returns the
+    /// number of device bytes requested.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, Options};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/path/to/file");
+    /// let options = Options::new().with_direct(false);
+    /// let mut buf = vec![0u8; 1 << 20];
+    /// for i in 0..16u64 {
+    ///     path.blk_prefetch((i + 1) << 20, 1 << 20, &options).unwrap();
+    ///     path.blk_read_at_opt(&mut buf, i << 20, &options).unwrap();
+    /// }
+    /// ```
+    fn blk_prefetch(&self, offset: u64, length: u64, options: &Options) -> io::Result<u64> {
+        with_context(self.blk_source(), options, |ctx| {
+            ctx.prefetch(offset, length)
+        })
+    }
+
+    /// Compare a range read from the block device with the file's contents.
+    ///
+    /// The range is read from the device, without falling back to regular
+    /// file I/O and with holes filled with zeros as the file reads them, and
+    /// again through the file. The report lists the ranges that differ,
+    /// split by where the device read took them from, with their extents and
+    /// physical offsets. Unwritten extents are read raw unless
+    /// [`Options::zero_unwritten`] is set, so they differ from the file's
+    /// zeros wherever the device holds other data.
+    ///
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput) for dry
+    /// runs, which read no data.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, Options};
+    /// use std::path::Path;
+    ///
+    /// let report = Path::new("/path/to/file")
+    ///     .blk_verify_at(0, 1 << 20, &Options::new())
+    ///     .unwrap();
+    /// for mismatch in &report.mismatches {
+    ///     println!("{}", mismatch);
+    /// }
+    /// assert!(report.is_consistent());
+    /// ```
+    fn blk_verify_at(
+        &self,
+        offset: u64,
+        length: u64,
+        options: &Options,
+    ) -> io::Result<VerifyReport> {
+        with_context(self.blk_source(), options, |ctx| {
+            ctx.verify_at(offset, length)
+        })
+    }
+
+    /// Search the device data of the file for a byte pattern.
+    ///
+    /// Only the physical ranges backing the file are read, unwritten
+    /// extents included, so data written before a crash but never committed
+    /// is found too. Occurrences spanning logically adjacent extents are
+    /// found, as are overlapping ones. Holes, delayed and inline extents are
+    /// skipped. Returns the occurrences in logical order, with the logical
+    /// and physical offsets of their first byte.
+    ///
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput) for an empty
+    /// pattern.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, Options};
+    /// use std::path::Path;
+    ///
+    /// let matches = Path::new("/path/to/journal")
+    ///     .blk_scan(b"JRNL", &Options::new())
+    ///     .unwrap();
+    /// for m in &matches {
+    ///     println!("logical {:#x} physical {:#x}", m.logical, m.physical);
+    /// }
+    /// ```
+    fn blk_scan(&self, pattern: &[u8], options: &Options) -> io::Result<Vec<Match>> {
+        with_context(self.blk_source(), options, |ctx| ctx.scan(pattern))
+    }
+
+    /// Copy the whole file to `dest`, keeping it sparse.
+    ///
+    /// The device data of the file's extents is written to `dest` at the
+    /// same logical offsets, and the ranges without data are left as holes
+    /// (punched with `FALLOC_FL_PUNCH_HOLE` where `dest` had data, or
+    /// written as zeros on filesystems that cannot punch holes), so `dest`
+    /// ends up with the file's size and logical layout instead of
+    /// materialized zeros. Unwritten extents are copied raw, or become
+    /// holes with [`Options::zero_unwritten`]. Delayed and encoded extents
+    /// that [`blk_read_extents`](BlkReader::blk_read_extents) skips become
+    /// holes as well.
+    ///
+    /// `dest` must be open for writing. The returned state's `bytes_read`
+    /// is the number of data bytes copied. Fails with
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) for dry runs.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, Options};
+    /// use std::fs::File;
+    /// use std::path::Path;
+    ///
+    /// let dest = File::create("/recovered/file").unwrap();
+    /// let options = Options::new().with_zero_unwritten(true);
+    /// Path::new("/path/to/file")
+    ///     .blk_copy_sparse(&dest, &options)
+    ///     .unwrap();
+    /// ```
+    fn blk_copy_sparse(&self, dest: &File, options: &Options) -> io::Result<State> {
+        with_context(self.blk_source(), options, |ctx| ctx.copy_sparse(dest))
+    }
+
+    /// Read `length` bytes at `offset` into an owned buffer from `pool`.
+    ///
+    /// Alignment is handled internally: the aligned range covering the
+    /// request is read into a suitably aligned pooled buffer, which then
+    /// exposes just the requested bytes, so neither the caller nor a bounce
+    /// buffer has to copy them. The returned [`PooledBuf`] goes back to the
+    /// pool when dropped; it can be moved to other tasks or wrapped without
+    /// copying, e.g. as `bytes::Bytes` with the `bytes` feature. The state is that of
+    /// the requested range, as with [`Options::auto_align`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{BlkReader, BufPool, Options};
+    /// use std::path::Path;
+    ///
+    /// let pool = BufPool::new();
+    /// let (buf, _) = Path::new("/path/to/file")
+    ///     .blk_read_pooled(&pool, 0, 4096, &Options::new())
+    ///     .unwrap();
+    /// println!("{} bytes", buf.len());
+    /// ```
+    fn blk_read_pooled(
+        &self,
+        pool: &BufPool,
+        offset: u64,
+        length: usize,
+        options: &Options,
+    ) -> io::Result<(PooledBuf, State)> {
+        with_context(self.blk_source(), options, |ctx| {
+            ctx.read_pooled(pool, offset, length)
+        })
+    }
+
+    /// Alignment required of offsets and lengths of device reads.
+    ///
+    /// This is the logical sector size of the file's block device, queried
+    /// with `BLKSSZGET`: 512 bytes on most drives, 4096 on 4K-native ones.
+    /// Opening the device requires root privileges.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{AlignedBuf, BlkReader};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/path/to/file");
+    /// let alignment = path.blk_required_alignment().unwrap() as usize;
+    /// let mut buf = AlignedBuf::new(1 << 20, alignment);
+    /// let bytes = path.blk_read_at(&mut buf, 0).unwrap();
+    /// ```
+    fn blk_required_alignment(&self) -> io::Result<u64> {
+        with_context(self.blk_source(), &Options::new(), |ctx| {
+            ctx.required_alignment()
+        })
+    }
+
+    /// NUMA node local to the file's block device.
+    ///
+    /// This is the node of the PCIe root the device's controller is
+    /// attached to, read from sysfs without opening the device; for
+    /// device-mapper volumes, that of the first device below them with a
+    /// known node. Returns `None` if the kernel reports no node, as on
+    /// single-node hosts, and for device images. Pass it to
+    /// [`AlignedBuf::on_node`], or use [`NumaNode::Device`] to have the
+    /// library's own buffers placed there.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use blkreader::{AlignedBuf, BlkReader};
+    /// use std::path::Path;
+    ///
+    /// let path = Path::new("/path/to/file");
+    /// let buf = match path.blk_numa_node().unwrap() {
+    ///     Some(node) => AlignedBuf::on_node(1 << 20, 4096, node).unwrap(),
+    ///     None => AlignedBuf::new(1 << 20, 4096),
+    /// };
+    /// ```
+    fn blk_numa_node(&self) -> io::Result<Option<usize>> {
+        with_context(self.blk_source(), &Options::new(), |ctx| ctx.numa_node())
+    }
+}
+
+/// Read data into a possibly uninitialized buffer.
+///
+/// This behaves like [`blk_read_at_opt`](BlkReader::blk_read_at_opt) on
+/// `reader`, but accepts a buffer of [`MaybeUninit<u8>`], such as the spare
+/// capacity of a vector. Device reads, fills and fallback reads write into
+/// `buf` directly, and only the bytes past [`State::bytes_read`] are zeroed
+/// afterwards (all of `buf` if the read fails), so the whole of `buf` is
+/// initialized when this returns and its first `bytes_read` bytes hold the
+/// data read.
+///
+/// [`IoEngine`](crate::IoEngine)s set with
+/// [`Options::with_io_engine`] are handed the uninitialized memory and must
+/// only write to it. Readers without a [`blk_source`](BlkReader::blk_source),
+/// which may look at the buffer, and dry runs, which write nothing, get a
+/// zeroed buffer instead.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{blk_read_buf_at, Options};
+/// use std::path::Path;
+///
+/// let mut buf: Vec<u8> = Vec::with_capacity(1 << 30);
+/// let options = Options::new().with_allow_fallback(true);
+/// let state = blk_read_buf_at(Path::new("/path/to/file"), buf.spare_capacity_mut(), 0, &options)?;
+/// // SAFETY: blk_read_buf_at initializes the whole spare capacity.
+/// unsafe { buf.set_len(buf.capacity()) };
+/// buf.truncate(state.bytes_read);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn blk_read_buf_at<R: BlkReader + ?Sized>(
+    reader: &R,
+    buf: &mut [MaybeUninit<u8>],
+    offset: u64,
+    options: &Options,
+) -> io::Result<State> {
+    let result = read_uninit(reader, buf, offset, options);
+    let written = match &result {
+        Ok(state) => state.bytes_read.min(buf.len()),
+        Err(_) => 0,
+    };
+    buf[written..].fill(MaybeUninit::new(0));
+    result
+}
+
+/// Append `length` bytes of data read at `offset` to `buf`.
+///
+/// This reads like [`blk_read_at_opt`](BlkReader::blk_read_at_opt) on
+/// `reader` into the spare capacity of `buf`, reserving it as needed, and
+/// then extends `buf` by the [`State::bytes_read`] bytes read, leaving it
+/// unchanged on error. As with [`blk_read_buf_at`], the data is read into
+/// the spare capacity directly, without zeroing it first. The memory of a
+/// `BytesMut` is not aligned for Direct I/O, so unless `options` read
+/// buffered, the read is made with [`auto_align`](Options::auto_align).
+///
+/// Requires the `bytes` feature.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{blk_read_bytes, Options};
+/// use bytes::BytesMut;
+/// use std::path::Path;
+///
+/// let mut buf = BytesMut::new();
+/// blk_read_bytes(Path::new("/path/to/file"), &mut buf, 0, 4096, &Options::new())?;
+/// let frozen = buf.freeze();
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[cfg(feature = "bytes")]
+pub fn blk_read_bytes<R: BlkReader + ?Sized>(
+    reader: &R,
+    buf: &mut bytes::BytesMut,
+    offset: u64,
+    length: usize,
+    options: &Options,
+) -> io::Result<State> {
+    let aligned;
+    let options = if options.auto_align || !options.direct {
+        options
+    } else {
+        aligned = options.clone().with_auto_align(true);
+        &aligned
+    };
+    buf.reserve(length);
+    let len = buf.len();
+    let state = read_uninit(
+        reader,
+        &mut buf.spare_capacity_mut()[..length],
+        offset,
+        options,
+    )?;
+    // SAFETY: read_uninit initialized the first `bytes_read` spare bytes.
+    unsafe { buf.set_len(len + state.bytes_read.min(length)) };
+    Ok(state)
+}
+
+/// Read into `buf` without initializing it first. On success, the first
+/// [`State::bytes_read`] bytes of `buf` are initialized; the rest, and all
+/// of `buf` on error, may not be.
+fn read_uninit<R: BlkReader + ?Sized>(
+    reader: &R,
+    buf: &mut [MaybeUninit<u8>],
+    offset: u64,
+    options: &Options,
+) -> io::Result<State> {
+    let source = reader.blk_source().filter(|_| !options.dry_run);
+    let Some(source) = source else {
+        buf.fill(MaybeUninit::new(0));
+        // SAFETY: `MaybeUninit<u8>` has the same layout as `u8`, and every
+        // byte was just initialized.
+        let buf =
+            unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), buf.len()) };
+        return reader.blk_read_at_opt(buf, offset, options);
+    };
+    // SAFETY: `MaybeUninit<u8>` has the same layout as `u8`. The bytes are
+    // only handed to the read path below, whose device reads, fills and
+    // fallback reads write each byte of the returned `bytes_read` before
+    // anything reads it, and which never reads past `bytes_read`. Engines
+    // are documented to only write to the buffers they are handed.
+    let bytes = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), buf.len()) };
+    with_context(Some(source), options, |ctx| ctx.read_at(bytes, offset))
+}
+
+/// Chunk size used when reading whole files (1 MB).
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Maximum number of device reads merged into one vectored read.
+///
+/// Each merged read needs up to two iovecs, which must stay below `IOV_MAX`.
+const MAX_COALESCED_READS: usize = 256;
+
+/// Buffer and length alignment used when reading whole files.
+///
+/// 4096 bytes satisfies both 512-byte and 4K-native devices.
+const READ_ALIGNMENT: usize = 4096;
+
+/// Inode flag of files encrypted with fscrypt (`FS_ENCRYPT_FL`).
+pub(crate) const FS_ENCRYPT_FL: u32 = 0x00000800;
+
+/// Internal helper to perform the actual read operation.
+#[derive(Clone, Copy)]
+pub(crate) struct ReadContext<'a> {
+    file: &'a File,
+    path: Option<&'a Path>,
+    options: &'a Options,
+    /// Previously queried extent map to use instead of FIEMAP.
+    extent_map: Option<&'a [FiemapExtent]>,
+    /// Slot holding a device handle shared across reads.
+    device_slot: Option<&'a OnceLock<DeviceHandle>>,
+    /// Point in time after which no new device reads are started.
+    deadline: Option<Instant>,
+}
+
+impl<'a> ReadContext<'a> {
+    pub(crate) fn new(file: &'a File, options: &'a Options) -> Self {
+        Self {
+            file,
+            path: None,
+            options,
+            extent_map: None,
+            device_slot: None,
+            deadline: options.timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+
+    /// Use a previously queried extent map for the first read attempt.
+    fn with_extent_map(mut self, extents: &'a [FiemapExtent]) -> Self {
+        self.extent_map = Some(extents);
+        self
+    }
+
+    /// Reuse (or populate) a device handle shared across reads.
+    pub(crate) fn with_device_slot(mut self, slot: &'a OnceLock<DeviceHandle>) -> Self {
+        self.device_slot = Some(slot);
+        self
+    }
+
+    /// Attach the path the file was opened from, used for error reporting.
+    pub(crate) fn with_path(mut self, path: &'a Path) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    /// Fail with [`io::ErrorKind::TimedOut`] once the deadline has passed.
+    fn check_deadline(&self) -> io::Result<()> {
+        match (self.deadline, self.options.timeout) {
+            (Some(deadline), Some(timeout)) if Instant::now() >= deadline => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("read did not complete within {:?}", timeout),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Best-effort path of the file being read, for error reporting.
+    fn file_path(&self) -> Option<PathBuf> {
+        file_path(self.file, self.path)
+    }
+
+    /// Notify the [`Options::observer`], if any, and the observers of
+    /// enabled telemetry features.
+    fn observe(&self, f: impl Fn(&dyn ReadObserver)) {
+        #[cfg(any(feature = "tracing", feature = "metrics"))]
+        for observer in telemetry::builtin_observers() {
+            f(*observer);
+        }
+        if let Some(observer) = &self.options.observer {
+            f(observer.as_ref());
+        }
+    }
+
+    /// Whether any observer is notified of reads.
+    fn observed(&self) -> bool {
+        cfg!(any(feature = "tracing", feature = "metrics")) || self.options.observer.is_some()
+    }
+
+    /// Record stage durations into `state` if timing is enabled.
+    fn record_timing(&self, mut state: State, f: impl FnOnce(&mut Timing)) -> State {
+        if self.options.timing {
+            f(state.timing.get_or_insert_with(Timing::default));
+        }
+        state
+    }
+
+    /// Direct I/O alignment (memory, offset) required for this file's device.
+    ///
+    /// The offset alignment is at least the device's logical sector size,
+    /// if the device can be opened. Falls back to [`READ_ALIGNMENT`] if
+    /// neither the kernel nor the device report it. Buffered device reads
+    /// need no alignment.
+    fn dio_alignment(&self) -> (u64, u64) {
+        let device = self
+            .with_device(|device| Ok((device.is_direct(), device.info())))
+            .ok();
+        if device.is_some_and(|(direct, _)| !direct) {
+            return (1, 1);
+        }
+        let sector = device
+            .and_then(|(_, info)| info)
+            .map(|info| info.logical_block_size.max(1) as u64);
+        match (sys::statx_dio_align_fd(self.file.as_raw_fd()), sector) {
+            (Ok(Some((mem, offset))), sector) => {
+                (mem.max(1) as u64, (offset as u64).max(sector.unwrap_or(1)))
+            }
+            (_, Some(sector)) => (sector, sector),
+            _ => (READ_ALIGNMENT as u64, READ_ALIGNMENT as u64),
+        }
+    }
+
+    /// Alignment required of device reads: the device's logical sector size.
+    fn required_alignment(&self) -> io::Result<u64> {
+        self.with_device(|device| Ok(device.sector_size()))
+    }
+
+    /// NUMA node local to the file's device, found without opening it.
+    fn numa_node(&self) -> io::Result<Option<usize>> {
+        numa::device_node(&self.device_path()?)
+    }
+
+    /// NUMA node to allocate read buffers on, see [`Options::buffer_node`].
+    fn buffer_node(&self) -> Option<usize> {
+        match self.options.buffer_node? {
+            NumaNode::Node(node) => Some(node),
+            // Devices of unknown node get ordinary buffers
+            NumaNode::Device => self.numa_node().ok().flatten(),
+        }
+    }
+
+    /// Allocate a buffer for reads, on the node of [`Options::buffer_node`].
+    fn alloc_buf(&self, len: usize, align: usize) -> io::Result<AlignedBuf> {
+        match self.buffer_node() {
+            Some(node) => AlignedBuf::on_node(len, align, node),
+            None => Ok(AlignedBuf::new(len, align)),
+        }
+    }
+}
+
+/// The segment of a fallback read of `len` bytes at `logical`.
+fn fallback_segments(logical: u64, len: usize) -> Vec<Segment> {
+    if len == 0 {
+        return Vec::new();
+    }
+    vec![Segment {
+        range: 0..len,
+        logical,
+        source: SegmentSource::Fallback,
+    }]
+}
+
+/// Query the extents of `offset..offset + length` of `file`.
+///
+/// Passes `FIEMAP_FLAG_SYNC` if [`Options::fiemap_sync`] is set. Heavily
+/// fragmented files are mapped in several FIEMAP requests.
+pub(crate) fn fiemap_range(
+    file: &File,
+    offset: u64,
+    length: u64,
+    options: &Options,
+) -> io::Result<Vec<FiemapExtent>> {
+    let flags = if options.fiemap_sync {
+        sys::FIEMAP_FLAG_SYNC
+    } else {
+        0
+    };
+    stats::count(Counter::FiemapCalls, 1);
+    sys::fiemap(file.as_raw_fd(), offset, length, flags).inspect_err(stats::count_error)
+}
+
+/// Query the extents of the whole of `file`.
+pub(crate) fn fiemap_file(file: &File) -> io::Result<Vec<FiemapExtent>> {
+    stats::count(Counter::FiemapCalls, 1);
+    sys::fiemap(file.as_raw_fd(), 0, u64::MAX, 0).inspect_err(stats::count_error)
+}
+
+/// The file to read in place of `file`, if any: `file` reopened for
+/// reading if it is an `O_PATH` descriptor, or the overlayfs layer file
+/// holding its data (see [`Options::resolve_overlay`]).
+fn layer_file(file: &File, options: &Options) -> io::Result<Option<File>> {
+    let reopened = reopen_path_fd(file)?;
+    if !options.resolve_overlay {
+        return Ok(reopened);
+    }
+    let layer = overlay::real_file(reopened.as_ref().unwrap_or(file))?;
+    Ok(layer.or(reopened))
+}
+
+/// `file` opened for reading if it is an `O_PATH` descriptor.
+///
+/// Sandboxed callers, e.g. resolving paths with `openat2` under landlock,
+/// may only hold such descriptors. Opening the descriptor's entry in
+/// `/proc/self/fd` opens the file it refers to, checking read permission
+/// on it as opening by name would.
+fn reopen_path_fd(file: &File) -> io::Result<Option<File>> {
+    if !sys::is_path_fd(file.as_raw_fd())? {
+        return Ok(None);
+    }
+    File::open(format!("/proc/self/fd/{}", file.as_raw_fd())).map(Some)
+}
+
+/// `file`, or the file to read in place of it.
+pub(crate) fn data_file(file: File, options: &Options) -> io::Result<File> {
+    Ok(layer_file(&file, options)?.unwrap_or(file))
+}
+
+/// Path of `file`, or the path it was opened by if unknown.
+fn file_path(file: &File, path: Option<&Path>) -> Option<PathBuf> {
+    match path {
+        Some(path) => Some(path.to_path_buf()),
+        None => std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).ok(),
+    }
+}
+
+/// `statfs` magic numbers of filesystems without a local block device.
+const REMOTE_FILESYSTEMS: &[(u32, &str)] = &[
+    (0x6969, "nfs"),
+    (0xFF534D42, "cifs"),
+    (0xFE534D42, "smb2"),
+    (0x517B, "smb"),
+    (0x65735546, "fuse"),
+    (0x01021997, "9p"),
+    (0x00C36400, "ceph"),
+    (0x6B414653, "afs"),
+    (0x73757245, "coda"),
+];
+
+/// Name of the filesystem with magic `magic`, if it has no local block
+/// device.
+fn remote_filesystem(magic: i64) -> Option<&'static str> {
+    // `f_type` is signed on some architectures
+    REMOTE_FILESYSTEMS
+        .iter()
+        .find(|(remote, _)| *remote == magic as u32)
+        .map(|(_, name)| *name)
+}
+
+/// Fail with [`BlkReadError::UnsupportedFilesystem`] if `file` is on a
+/// network or FUSE filesystem.
+pub(crate) fn check_filesystem(file: &File, path: Option<&Path>) -> io::Result<()> {
+    let Some(fstype) = sys::fs_type(file.as_raw_fd())
+        .ok()
+        .and_then(remote_filesystem)
+    else {
+        return Ok(());
+    };
+    Err(BlkReadError::UnsupportedFilesystem {
+        file_path: file_path(file, path),
+        fstype: fstype.to_string(),
+    }
+    .into())
+}
+
+/// Attribute a failed FIEMAP query on `file` to that stage.
+pub(crate) fn fiemap_failed<'a>(
+    file: &'a File,
+    path: Option<&'a Path>,
+) -> impl FnOnce(io::Error) -> BlkReadError + 'a {
+    move |source| BlkReadError::FiemapFailed {
+        file_path: file_path(file, path),
+        source,
+    }
+}
+
+/// Whether a device read or open failed with `EINVAL`, as Direct I/O does
+/// for misaligned buffers or on devices that don't support it.
+fn is_einval(err: &io::Error) -> bool {
+    let source = match BlkReadError::from_io_error(err) {
+        Some(BlkReadError::DeviceOpen { source, .. }) => source,
+        Some(BlkReadError::DeviceRead(read)) => &read.source,
+        _ => err,
+    };
+    source.raw_os_error() == Some(libc::EINVAL)
+}
+
+/// Whether a device read failed because the device is gone, such as an
+/// unplugged disk or a detached loop device.
+fn is_device_gone(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENXIO | libc::ENODEV))
+}
+
+/// Whether a device read failed because the medium could not be read.
+fn is_media_error(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EIO | libc::ENODATA))
+}
+
+/// Read from a file until the buffer is full or EOF is reached.
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64, flags: ReadFlags) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        let fd = file.as_raw_fd();
+        match sys::pread(fd, &mut buf[total..], offset + total as u64, flags.bits())? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+/// Read `path` through `extents`, also if the file is gone.
+///
+/// A missing file is stood in for by a parent directory, which must be on
+/// the filesystem `device` the extents were captured on. Without `device`,
+/// this is only done when the options name the device explicitly, since
+/// the nearest existing directory may be left over from an unmounted
+/// filesystem.
+pub(crate) fn read_path_with_extents(
+    path: &Path,
+    buf: &mut [u8],
+    offset: u64,
+    extents: &[FiemapExtent],
+    device: Option<&DeviceIdentity>,
+    options: &Options,
+) -> io::Result<State> {
+    let file = match File::open(path) {
+        Ok(file) => {
+            if let Some(device) = device {
+                if !device.matches(&file)? {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{} is not on the device its extents were captured on ({})",
+                            path.display(),
+                            device.path.display()
+                        ),
+                    ));
+                }
+            }
+            file
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match device {
+            Some(device) => open_nearest_ancestor(path, Some(device.id))?,
+            None if options.device_backend.is_some() => open_nearest_ancestor(path, None)?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "{} no longer exists and its device cannot be told from its \
+                         parent directories; read it with ExtentMap::read_at or give \
+                         the device with Options::with_device_override",
+                        path.display()
+                    ),
+                ))
+            }
+        },
+        Err(e) => return Err(e),
+    };
+    let file = data_file(file, options)?;
+    let ctx = ReadContext::new(&file, options).with_path(path);
+    ctx.read_with_caller_extents(buf, offset, extents)
+}
+
+/// Sleep for the retry `backoff`, but not past `deadline`.
+fn sleep_backoff(backoff: Duration, deadline: Option<Instant>) {
+    let pause = match deadline {
+        Some(deadline) => backoff.min(deadline.saturating_duration_since(Instant::now())),
+        None => backoff,
+    };
+    thread::sleep(pause);
+}
+
+/// Open the nearest existing ancestor directory of a path.
+///
+/// With `device`, the directory must be on that filesystem: a directory on
+/// another one means a mount boundary lies in between, so that the
+/// filesystem `path` was on is no longer mounted there.
+fn open_nearest_ancestor(path: &Path, device: Option<u64>) -> io::Result<File> {
+    for ancestor in path.ancestors().skip(1) {
+        let dir = if ancestor.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            ancestor
+        };
+        if let Ok(file) = File::open(dir) {
+            match device {
+                Some(id) if file.metadata()?.dev() != id => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "the filesystem of {} is not mounted: its nearest existing \
+                             parent {} is on another device",
+                            path.display(),
+                            dir.display()
+                        ),
+                    ))
+                }
+                _ => return Ok(file),
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no existing parent directory for {}", path.display()),
+    ))
+}
+
+/// Select the extents of a map that overlap `[offset, offset + length)`.
+fn extents_in_range(map: &[FiemapExtent], offset: u64, length: u64) -> Vec<FiemapExtent> {
+    let end = offset.saturating_add(length);
+    map.iter()
+        .filter(|extent| extent.logical < end && extent.logical + extent.length > offset)
+        .copied()
+        .collect()
+}
+
+impl BlkReader for Path {
+    fn blk_read_at_opt(&self, buf: &mut [u8], offset: u64, options: &Options) -> io::Result<State> {
+        with_context(self.blk_source(), options, |ctx| ctx.read_at(buf, offset))
+    }
+
+    fn blk_source(&self) -> Option<BlkSource<'_>> {
+        Some(BlkSource::Path(self))
+    }
+}
+
+impl BlkReader for PathBuf {
+    fn blk_read_at_opt(&self, buf: &mut [u8], offset: u64, options: &Options) -> io::Result<State> {
+        with_context(self.blk_source(), options, |ctx| ctx.read_at(buf, offset))
+    }
+
+    fn blk_source(&self) -> Option<BlkSource<'_>> {
+        Some(BlkSource::Path(self))
+    }
+}
+
+impl BlkReader for File {
+    fn blk_read_at_opt(&self, buf: &mut [u8], offset: u64, options: &Options) -> io::Result<State> {
+        with_context(self.blk_source(), options, |ctx| ctx.read_at(buf, offset))
+    }
+
+    fn blk_source(&self) -> Option<BlkSource<'_>> {
+        Some(BlkSource::Fd(self.as_fd()))
+    }
+}
+
+impl BlkReader for BorrowedFd<'_> {
+    fn blk_read_at_opt(&self, buf: &mut [u8], offset: u64, options: &Options) -> io::Result<State> {
+        with_context(self.blk_source(), options, |ctx| ctx.read_at(buf, offset))
+    }
+
+    fn blk_source(&self) -> Option<BlkSource<'_>> {
+        Some(BlkSource::Fd(*self))
+    }
+}
+
+/// Run `f` with a read context for `source`.
+///
+/// Paths are opened for the duration of the call, and descriptors are read
+/// without taking ownership, through the upper layer of overlayfs files
+/// where [`Options`] ask for it. Readers without a source cannot be read
+/// this way.
+fn with_context<R>(
+    source: Option<BlkSource<'_>>,
+    options: &Options,
+    f: impl FnOnce(&ReadContext<'_>) -> io::Result<R>,
+) -> io::Result<R> {
+    let Some(source) = source else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reader has no path or file descriptor to read",
+        ));
+    };
+    match source {
+        BlkSource::Path(path) => {
+            let file = data_file(File::open(path)?, options)?;
+            f(&ReadContext::new(&file, options).with_path(path))
+        }
+        BlkSource::Fd(fd) => with_borrowed_file(fd, |file| {
+            let layer = layer_file(file, options)?;
+            f(&ReadContext::new(layer.as_ref().unwrap_or(file), options))
+        }),
+    }
+}
+
+/// Read a whole file through [`BlkReader::blk_read_at_opt`], in aligned
+/// chunks until one comes back short. Holes are filled, so that only the end
+/// of the file reads short.
+fn read_to_end_with<R: BlkReader + ?Sized>(reader: &R, options: &Options) -> io::Result<Vec<u8>> {
+    let options = options.clone().with_fill_holes(true);
+    let mut buf = AlignedBuf::new(READ_CHUNK_SIZE, READ_ALIGNMENT);
+    let mut data = Vec::new();
+    loop {
+        let state = reader.blk_read_at_opt(&mut buf, data.len() as u64, &options)?;
+        data.extend_from_slice(&buf[..state.bytes_read]);
+        if state.bytes_read < READ_CHUNK_SIZE {
+            return Ok(data);
+        }
+    }
+}
+
+/// Borrow a raw file descriptor for use with [`BlkReader`].
+///
+/// # Safety
+///
+/// `fd` must be an open file descriptor that remains open for the whole
+/// lifetime `'a`, as required by [`BorrowedFd::borrow_raw`].
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{borrow_raw_fd, BlkReader};
+/// # let raw_fd = 3;
+///
+/// let mut buf = vec![0u8; 4096];
+/// let fd = unsafe { borrow_raw_fd(raw_fd) };
+/// let bytes = fd.blk_read_at(&mut buf, 0).unwrap();
+/// ```
+pub unsafe fn borrow_raw_fd<'a>(fd: RawFd) -> BorrowedFd<'a> {
+    // SAFETY: the caller guarantees `fd` stays open for `'a`.
+    unsafe { BorrowedFd::borrow_raw(fd) }
+}
+
+/// Run `f` with a `File` view of a borrowed descriptor, without taking ownership.
+fn with_borrowed_file<R>(fd: BorrowedFd<'_>, f: impl FnOnce(&File) -> R) -> R {
+    // SAFETY: the descriptor is open for the duration of the borrow, and
+    // `ManuallyDrop` ensures the temporary `File` never closes it.
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd.as_raw_fd()) });
+    f(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::file_on_image;
+
+    #[test]
+    fn test_options_builder() {
+        let opts = Options::new()
+            .with_cache(false)
+            .with_fill_holes(true)
+            .with_zero_unwritten(true)
+            .with_allow_fallback(true)
+            .with_read_exact(false)
+            .with_dry_run(true);
+
+        assert!(!opts.enable_cache);
+        assert!(opts.fill_holes);
+        assert!(opts.zero_unwritten);
+        assert!(opts.allow_fallback);
+        assert!(!opts.read_exact);
+        assert!(opts.dry_run);
+    }
+
+    #[test]
+    fn test_read_buf_at_uninit() {
+        use std::io::Write;
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x3C; 8192]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let mut buf: Vec<u8> = Vec::with_capacity(4096);
+        let options = Options::new().with_allow_fallback(true);
+        let spare = &mut buf.spare_capacity_mut()[..4096];
+        let state = blk_read_buf_at(temp.path(), spare, 0, &options).unwrap();
+
+        assert_eq!(state.bytes_read, 4096);
+        // SAFETY: blk_read_buf_at initializes the whole buffer.
+        unsafe { buf.set_len(4096) };
+        assert!(buf.iter().all(|&b| b == 0x3C));
+
+        // Dry runs write nothing, leaving the buffer zeroed
+        let mut buf: Vec<u8> = Vec::with_capacity(4096);
+        let options = Options::new().with_dry_run(true);
+        let spare = &mut buf.spare_capacity_mut()[..4096];
+        let state = blk_read_buf_at(temp.path(), spare, 0, &options).unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        // SAFETY: blk_read_buf_at initializes the whole buffer.
+        unsafe { buf.set_len(4096) };
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_read_buf_at_contents() {
+        let data: Vec<u8> = (0..12288).map(|i| (i % 251) as u8).collect();
+        let (temp, options) = file_on_image(&data);
+        // A vector is not aligned for Direct I/O
+        let options = options.with_direct(false);
+
+        // Memory left over from earlier use, read past the end of the file
+        let mut buf = vec![MaybeUninit::new(0xFFu8); 16384];
+        let state = blk_read_buf_at(temp.path(), &mut buf, 4096, &options).unwrap();
+        assert_eq!(state.bytes_read, 8192);
+        // SAFETY: blk_read_buf_at initializes the whole buffer.
+        let buf: Vec<u8> = buf.iter().map(|b| unsafe { b.assume_init() }).collect();
+        assert!(buf[..8192] == data[4096..]);
+        assert!(buf[8192..].iter().all(|&b| b == 0));
+
+        // Failed reads leave it zeroed
+        let mut buf = vec![MaybeUninit::new(0xFFu8); 4096];
+        let missing = Path::new("/nonexistent");
+        assert!(blk_read_buf_at(missing, &mut buf, 0, &options).is_err());
+        // SAFETY: blk_read_buf_at initializes the whole buffer.
+        assert!(buf.iter().all(|b| unsafe { b.assume_init() } == 0));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_read_bytes() {
+        use std::os::unix::fs::FileExt;
+
+        let data: Vec<u8> = (0..16384).map(|i| (i % 251) as u8).collect();
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        temp.as_file().write_all_at(&data, 0).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        // Unaligned reads are appended after what the buffer holds
+        let options = Options::new().with_allow_fallback(true);
+        let mut buf = bytes::BytesMut::from(&b"head"[..]);
+        let state = blk_read_bytes(temp.path(), &mut buf, 100, 5000, &options).unwrap();
+        assert_eq!(state.bytes_read, 5000);
+        assert_eq!(&buf[..4], b"head");
+        assert_eq!(&buf[4..], &data[100..5100]);
+
+        let state = blk_read_bytes(temp.path(), &mut buf, 16000, 384, &options).unwrap();
+        assert_eq!(state.bytes_read, 384);
+        assert_eq!(&buf[5004..], &data[16000..]);
+
+        // Failed reads leave the buffer unchanged
+        let err = blk_read_bytes(Path::new("/nonexistent"), &mut buf, 0, 4096, &options);
+        assert!(err.is_err());
+        assert_eq!(buf.len(), 5388);
+    }
+
+    #[test]
+    fn test_remote_filesystem() {
+        assert_eq!(remote_filesystem(0x6969), Some("nfs"));
+        assert_eq!(remote_filesystem(0x65735546), Some("fuse"));
+        // CIFS as reported by a signed 32-bit `f_type`
+        assert_eq!(remote_filesystem(0xFF534D42u32 as i32 as i64), Some("cifs"));
+        assert_eq!(remote_filesystem(0xEF53), None);
+
+        // Local files are accepted
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        check_filesystem(temp.as_file(), Some(temp.path())).unwrap();
+    }
+
+    #[test]
+    fn test_path_fd() {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let (temp, options) = file_on_image(&[0x77; 8192]);
+
+        let path_fd = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(temp.path())
+            .unwrap();
+        assert!(sys::is_path_fd(path_fd.as_raw_fd()).unwrap());
+        assert!(!sys::is_path_fd(temp.as_file().as_raw_fd()).unwrap());
+
+        let mut buf = AlignedBuf::new(8192, READ_ALIGNMENT);
+        let state = path_fd.blk_read_at_opt(&mut buf, 0, &options).unwrap();
+        assert_eq!(state.bytes_read, 8192);
+        assert!(buf.iter().all(|&b| b == 0x77));
+
+        // Falling back to reading the file goes through the reopened file too
+        let options = Options::new().with_allow_fallback(true);
+        buf.fill(0);
+        let state = path_fd
+            .as_fd()
+            .blk_read_at_opt(&mut buf, 0, &options)
+            .unwrap();
+        assert!(state.used_fallback);
+        assert!(buf.iter().all(|&b| b == 0x77));
+
+        let file = BlkFile::from_file(path_fd).unwrap();
+        assert!(!file.extents().is_empty());
+        assert!(!sys::is_path_fd(file.file().as_raw_fd()).unwrap());
+    }
+
+    #[test]
+    fn test_borrowed_fd() {
+        use std::io::Write;
+        use std::os::fd::AsFd;
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x42; 4096]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let options = Options::new().with_allow_fallback(true);
+        let fd = temp.as_file().as_fd();
+        let mut buf = vec![0u8; 4096];
+        let state = fd.blk_read_at_opt(&mut buf, 0, &options).unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert!(buf.iter().all(|&b| b == 0x42));
+
+        // The descriptor must still be open after the borrowed reads
+        let raw = unsafe { borrow_raw_fd(temp.as_file().as_raw_fd()) };
+        assert_eq!(raw.blk_map(0, 4096).is_ok(), fd.blk_map(0, 4096).is_ok());
+        assert_eq!(temp.as_file().metadata().unwrap().len(), 4096);
+    }
+
+    #[test]
+    fn test_blk_source() {
+        use std::io::Write;
+
+        /// A reader implementing only the required method, over data in
+        /// memory.
+        struct Wrapped(Vec<u8>);
+
+        impl BlkReader for Wrapped {
+            fn blk_read_at_opt(
+                &self,
+                buf: &mut [u8],
+                offset: u64,
+                _options: &Options,
+            ) -> io::Result<State> {
+                let data = self.0.get(offset as usize..).unwrap_or_default();
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok(State::new(PathBuf::new(), Vec::new(), len, false))
+            }
+        }
+
+        /// A reader also returning its descriptor.
+        struct Sourced(tempfile::NamedTempFile);
+
+        impl BlkReader for Sourced {
+            fn blk_read_at_opt(
+                &self,
+                buf: &mut [u8],
+                offset: u64,
+                options: &Options,
+            ) -> io::Result<State> {
+                self.0.as_file().blk_read_at_opt(buf, offset, options)
+            }
+
+            fn blk_source(&self) -> Option<BlkSource<'_>> {
+                Some(BlkSource::Fd(self.0.as_file().as_fd()))
+            }
+        }
+
+        // Without a source, whole files are read through blk_read_at_opt, and
+        // the methods needing the file are unsupported
+        let data: Vec<u8> = (0..READ_CHUNK_SIZE + 8192).map(|i| i as u8).collect();
+        let wrapped = Wrapped(data.clone());
+        let mut buf = vec![0u8; 4096];
+        assert_eq!(wrapped.blk_read_at(&mut buf, 4096).unwrap(), 4096);
+        assert_eq!(buf, data[4096..8192]);
+        assert_eq!(wrapped.blk_read_to_end(&Options::new()).unwrap(), data);
+        assert!(wrapped.blk_source().is_none());
+        let err = wrapped.blk_map(0, 8192).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        // With one, they read through the descriptor
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&data).unwrap();
+        temp.as_file().sync_all().unwrap();
+        let path = temp.path().to_path_buf();
+        let sourced = Sourced(temp);
+        let options = Options::new().with_allow_fallback(true);
+        assert_eq!(sourced.blk_read_to_end(&options).unwrap(), data);
+        assert_eq!(
+            sourced.blk_map(0, 8192).unwrap(),
+            path.blk_map(0, 8192).unwrap()
+        );
+        assert!(matches!(sourced.blk_source(), Some(BlkSource::Fd(_))));
+        assert!(matches!(path.blk_source(), Some(BlkSource::Path(p)) if p == path));
+    }
+
+    #[test]
+    fn test_open_nearest_ancestor() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("a/b/deleted");
+        let file = open_nearest_ancestor(&missing, None).unwrap();
+        assert!(file.metadata().unwrap().is_dir());
+        let dev = file.metadata().unwrap().dev();
+        assert!(open_nearest_ancestor(&missing, Some(dev)).is_ok());
+
+        // A parent on another filesystem is a mount boundary
+        let err = open_nearest_ancestor(&missing, Some(dev ^ 1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("not mounted"));
+
+        assert!(open_nearest_ancestor(Path::new("relative-missing"), None).is_ok());
+    }
+}
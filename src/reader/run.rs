@@ -0,0 +1,1823 @@
+//! Execution of planned device reads: merging them into runs, issuing
+//! them through the I/O engine, and retrying, salvaging or accounting for
+//! their results.
+
+use super::walk::Step;
+use super::{
+    is_device_gone, is_einval, is_media_error, read_full_at, sleep_backoff, DeviceHandle,
+    ReadContext, READ_ALIGNMENT,
+};
+use crate::buffer::AlignedBuf;
+use crate::cache::InFlightGuard;
+use crate::engine::DeviceRead;
+use crate::error::{DeviceReadError, PartialReadError, ShortReadError};
+use crate::observer::{ExtentReadEvent, FillEvent};
+use crate::state::{
+    DeviceInfo, PlannedRead, ReadTiming, Segment, SegmentSource, State, UnreadableRange,
+};
+use crate::stats::{self, Counter};
+use crate::sys;
+
+use blkmap::FiemapExtent;
+
+use std::io::{self, IoSliceMut};
+use std::mem;
+use std::ops::Range;
+use std::os::unix::io::AsFd;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+impl<'a> ReadContext<'a> {
+    /// Execute runs of steps in order, writing into the runs' buffer slices.
+    ///
+    /// A run is either a single fill, a single file read, a single device
+    /// read, or several coalesced device reads serviced by one vectored
+    /// read, with the physical gaps between them read into a discard buffer. Consecutive
+    /// device runs are handed to the [`IoEngine`](crate::IoEngine) as one batch. Returns
+    /// the buffer positions where short reads ended.
+    fn execute_runs(
+        &self,
+        device: &DeviceHandle,
+        jobs: Vec<Vec<RunPart<'_>>>,
+        progress: &Mutex<ReadOutcome>,
+        planned: usize,
+    ) -> io::Result<Vec<usize>> {
+        let mut shorts = Vec::new();
+        let mut batch = Vec::new();
+        for mut job in jobs {
+            let (logical, len) = match job[0].step {
+                Step::Fill { logical, len, .. } | Step::File { logical, len } => (logical, len),
+                Step::Device { .. } => {
+                    batch.push(DeviceRun::new(job));
+                    continue;
+                }
+            };
+
+            self.read_runs(
+                device,
+                mem::take(&mut batch),
+                progress,
+                planned,
+                &mut shorts,
+                true,
+            )?;
+            let part = &mut job[0];
+            let (done, filled) = match part.step {
+                Step::File { .. } if self.options.dry_run => (len, 0),
+                Step::File { .. } => {
+                    let n = read_full_at(self.file, part.buf, logical, self.options.read_flags);
+                    stats::count_result(Counter::FallbackBytes, &n);
+                    let n = n?;
+                    if n < len {
+                        shorts.push(part.start + n);
+                    }
+                    (n, 0)
+                }
+                Step::Fill { source, .. } => {
+                    part.buf.fill(self.options.fill_byte);
+                    if !self.options.dry_run {
+                        stats::count(Counter::FilledBytes, len as u64);
+                    }
+                    self.observe(|observer| {
+                        observer.on_hole_fill(&FillEvent {
+                            logical,
+                            length: len as u64,
+                            source,
+                        })
+                    });
+                    (len, len)
+                }
+                Step::Device { .. } => unreachable!("device steps are batched"),
+            };
+            let mut progress = progress.lock().unwrap();
+            progress.bytes_read += done;
+            progress.bytes_filled += filled;
+            progress.done.push(part.start..part.start + done);
+            self.report_progress(&progress, planned, logical + done as u64);
+        }
+        self.read_runs(device, batch, progress, planned, &mut shorts, true)?;
+        Ok(shorts)
+    }
+
+    /// Read a batch of device runs through the configured [`IoEngine`](crate::IoEngine).
+    ///
+    /// With `reopen`, runs failing with `ENXIO` or `ENODEV` because the
+    /// device went away are read once more from a freshly opened device
+    /// (see [`reopen_device`](Self::reopen_device)).
+    fn read_runs(
+        &self,
+        device: &DeviceHandle,
+        mut runs: Vec<DeviceRun<'_>>,
+        progress: &Mutex<ReadOutcome>,
+        planned: usize,
+        shorts: &mut Vec<usize>,
+        reopen: bool,
+    ) -> io::Result<()> {
+        if runs.is_empty() {
+            return Ok(());
+        }
+        self.check_deadline()?;
+
+        if self.options.dry_run {
+            // Simulate the reads without actual I/O
+            for run in &runs {
+                let len = run.layout.total();
+                shorts.extend(self.settle(device, &run.layout, Ok(len), progress, planned)?);
+                self.check_deadline()?;
+            }
+            return Ok(());
+        }
+        let file = device.file().ok_or_else(|| device.not_opened())?;
+
+        let _priority = self.set_priority()?;
+        // Runs still to be read; those failing transiently are read again
+        let mut pending: Vec<usize> = (0..runs.len()).collect();
+        // Runs rejected by Direct I/O, read again through the page cache
+        let mut rejected = Vec::new();
+        // Runs whose device went away, read again from the reopened device
+        let mut gone = Vec::new();
+        for attempt in 1.. {
+            let mut failed = Vec::new();
+            let mut broken = Vec::new();
+            let (indices, layouts, mut reads): (Vec<usize>, Vec<&RunLayout>, Vec<DeviceRead>) =
+                runs.iter_mut()
+                    .enumerate()
+                    .filter(|(index, _)| pending.binary_search(index).is_ok())
+                    .map(|(index, run)| {
+                        let read = DeviceRead {
+                            offset: device.base_offset() + run.layout.physical(),
+                            bufs: run.layout.iovecs(&mut run.bufs, &mut run.discard),
+                            flags: self.options.read_flags,
+                        };
+                        (index, &run.layout, read)
+                    })
+                    .collect();
+            let events: Vec<ExtentReadEvent> = match self.observed() {
+                true => layouts
+                    .iter()
+                    .map(|layout| ExtentReadEvent {
+                        device: device.path(),
+                        logical: layout.parts[0].0.logical(),
+                        physical: layout.physical(),
+                        length: layout.total() as u64,
+                    })
+                    .collect(),
+                false => Vec::new(),
+            };
+            let mut last = Instant::now();
+            let submitted = last;
+            let mut complete = |index: usize, result: io::Result<usize>| {
+                #[cfg(feature = "fault-injection")]
+                let result = self.inject_fault(layouts[index], result);
+                stats::count_result(Counter::DeviceBytes, &result);
+                self.observe(|observer| {
+                    let outcome = result.as_ref().map(|&n| n);
+                    observer.on_extent_read_end(&events[index], outcome, submitted.elapsed())
+                });
+                if self.options.timing {
+                    let now = Instant::now();
+                    let first = layouts[index].parts[0].0;
+                    progress.lock().unwrap().reads.push(ReadTiming {
+                        logical: first.logical(),
+                        physical: first.physical(),
+                        length: layouts[index].total() as u64,
+                        duration: now - last,
+                    });
+                    last = now;
+                }
+                match result {
+                    Err(e) if reopen && is_device_gone(&e) => gone.push(indices[index]),
+                    Err(e) if self.options.retry.should_retry(&e, attempt) => {
+                        failed.push(indices[index])
+                    }
+                    Err(e) if self.options.best_effort && is_media_error(&e) => {
+                        broken.push(indices[index])
+                    }
+                    Err(e) if self.falls_back_to_buffered(device, &e) => {
+                        rejected.push(indices[index])
+                    }
+                    result => shorts.extend(self.settle(
+                        device,
+                        layouts[index],
+                        result,
+                        progress,
+                        planned,
+                    )?),
+                }
+                self.check_deadline()
+            };
+            self.throttle(&reads)?;
+            let permit = self.enter_device(device)?;
+            for event in &events {
+                self.observe(|observer| observer.on_extent_read_start(event));
+            }
+            self.options
+                .io_engine
+                .read_batch(file.as_fd(), &mut reads, &mut complete)?;
+            drop(permit);
+            for index in broken {
+                shorts.extend(self.salvage(device, &mut runs[index], progress, planned)?);
+            }
+
+            if failed.is_empty() {
+                break;
+            }
+            failed.sort_unstable();
+            pending = failed;
+            sleep_backoff(self.options.retry.backoff, self.deadline);
+            self.check_deadline()?;
+        }
+
+        if rejected.is_empty() && gone.is_empty() {
+            return Ok(());
+        }
+        rejected.sort_unstable();
+        gone.sort_unstable();
+        let (mut buffered_runs, mut reopened_runs) = (Vec::new(), Vec::new());
+        for (index, run) in runs.into_iter().enumerate() {
+            if rejected.binary_search(&index).is_ok() {
+                buffered_runs.push(run);
+            } else if gone.binary_search(&index).is_ok() {
+                reopened_runs.push(run);
+            }
+        }
+        if !buffered_runs.is_empty() {
+            let buffered = self.open_placed_device(device.path(), false)?;
+            self.read_runs(&buffered, buffered_runs, progress, planned, shorts, false)?;
+        }
+        if !reopened_runs.is_empty() {
+            let reopened = self.reopen_device(device)?;
+            self.read_runs(&reopened, reopened_runs, progress, planned, shorts, false)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for [`Options::max_throughput`], if set, to admit `reads`.
+    fn throttle(&self, reads: &[DeviceRead<'_>]) -> io::Result<()> {
+        let Some(throttle) = &self.options.max_throughput else {
+            return Ok(());
+        };
+        let bytes = reads.iter().map(|read| read.len() as u64).sum();
+        if !throttle.acquire(bytes, self.deadline) {
+            self.check_deadline()?;
+        }
+        Ok(())
+    }
+
+    /// Apply [`Options::fault_injection`], if set, to `result`, that of
+    /// reading `layout` from the device.
+    #[cfg(feature = "fault-injection")]
+    fn inject_fault(&self, layout: &RunLayout, result: io::Result<usize>) -> io::Result<usize> {
+        let Some(plan) = &self.options.fault_injection else {
+            return result;
+        };
+        let logical: Vec<Range<u64>> = layout
+            .parts
+            .iter()
+            .map(|(step, _)| step.logical()..step.logical() + step.len() as u64)
+            .collect();
+        plan.apply(&logical, result)
+    }
+
+    /// Switch the calling thread to [`Options::io_priority`], if set, until
+    /// the returned guard is dropped.
+    fn set_priority(&self) -> io::Result<Option<PriorityGuard>> {
+        let Some(priority) = self.options.io_priority else {
+            return Ok(None);
+        };
+        let previous = sys::ioprio_get()?;
+        if previous == priority.ioprio() {
+            return Ok(None);
+        }
+        sys::ioprio_set(priority.ioprio())?;
+        Ok(Some(PriorityGuard { previous }))
+    }
+
+    /// Wait until `device` has fewer than [`Options::max_in_flight`] reads in
+    /// flight, if set, and count a new one until the returned guard is
+    /// dropped.
+    fn enter_device<'d>(&self, device: &'d DeviceHandle) -> io::Result<Option<InFlightGuard<'d>>> {
+        let (Some(max), Some(in_flight)) = (self.options.max_in_flight, device.in_flight()) else {
+            return Ok(None);
+        };
+        match in_flight.enter(max, self.deadline) {
+            Some(guard) => Ok(Some(guard)),
+            None => Err(self.check_deadline().expect_err("the deadline has passed")),
+        }
+    }
+
+    /// Whether a read from `device` that failed with `err` is to be read
+    /// again through the page cache (see [`Options::buffered_fallback`]).
+    fn falls_back_to_buffered(&self, device: &DeviceHandle, err: &io::Error) -> bool {
+        self.options.buffered_fallback && device.is_direct() && is_einval(err)
+    }
+
+    /// Account for the result of a device run.
+    ///
+    /// Errors are attributed to the first read of the run. On a short read,
+    /// returns the buffer position where valid data ends.
+    fn settle(
+        &self,
+        device: &DeviceHandle,
+        run: &RunLayout,
+        result: io::Result<usize>,
+        progress: &Mutex<ReadOutcome>,
+        planned: usize,
+    ) -> io::Result<Option<usize>> {
+        let mut remaining = result.map_err(|source| {
+            device.read_failed(self.device_error(device, run.parts[0].0, run.total(), source))
+        })?;
+
+        // Attribute the bytes read to the parts of the run
+        let mut placed = 0;
+        let mut done = Vec::with_capacity(run.parts.len());
+        let mut short = None;
+        for (k, &(step, start)) in run.parts.iter().enumerate() {
+            if k > 0 {
+                remaining = remaining.saturating_sub(run.gaps[k - 1]);
+            }
+            let len = step.len();
+            let got = remaining.min(len);
+            placed += got;
+            remaining -= got;
+            done.push(start..start + got);
+            if got < len {
+                let logical = step.logical_end() - (len - got) as u64;
+                short = Some((start + got, logical));
+                break;
+            }
+        }
+
+        let mut progress = progress.lock().unwrap();
+        progress.bytes_read += placed;
+        progress.done.extend(done);
+        let last = run.parts[run.parts.len() - 1].0;
+        let logical_end = short.map_or(last.logical_end(), |(_, logical)| logical);
+        self.report_progress(&progress, planned, logical_end);
+        Ok(short.map(|(position, _)| position))
+    }
+
+    /// Read the parts of a failed run again, isolating the bad sectors.
+    ///
+    /// Used in best-effort mode. Failing reads are split in halves down to
+    /// the device's sector size, and only sectors that still cannot be read
+    /// are filled. On a short read, returns the buffer position where valid
+    /// data ends.
+    fn salvage(
+        &self,
+        device: &DeviceHandle,
+        run: &mut DeviceRun<'_>,
+        progress: &Mutex<ReadOutcome>,
+        planned: usize,
+    ) -> io::Result<Option<usize>> {
+        let sector = device.sector_size() as usize;
+
+        let mut placed = 0;
+        let mut filled = 0;
+        let mut unreadable: Vec<UnreadableRange> = Vec::new();
+        let mut done = Vec::new();
+        let mut short = None;
+        for (&(step, start), buf) in run.layout.parts.iter().zip(run.bufs.iter_mut()) {
+            let logical = step.logical_end() - step.len() as u64;
+            let physical = step.physical();
+            let mut bad = Vec::new();
+            let end = self.isolate(device, step, buf, 0, sector, &mut bad)?;
+
+            for range in bad {
+                filled += range.len();
+                unreadable.push(UnreadableRange {
+                    logical: logical + range.start as u64,
+                    physical: physical + range.start as u64,
+                    length: range.len() as u64,
+                });
+            }
+            if let Some(end) = end {
+                placed += end;
+                done.push(start..start + end);
+                short = Some((start + end, logical + end as u64));
+                break;
+            }
+            placed += step.len();
+            done.push(start..start + step.len());
+        }
+
+        let mut progress = progress.lock().unwrap();
+        progress.bytes_read += placed;
+        progress.bytes_filled += filled;
+        progress.done.extend(done);
+        progress.unreadable.extend(unreadable);
+        let last = run.layout.parts[run.layout.parts.len() - 1].0;
+        let logical_end = short.map_or(last.logical_end(), |(_, logical)| logical);
+        self.report_progress(&progress, planned, logical_end);
+        Ok(short.map(|(position, _)| position))
+    }
+
+    /// Read `buf`, which starts `base` bytes into the device `step`, halving
+    /// the read on media errors until single sectors remain.
+    ///
+    /// Sectors that cannot be read are filled and their ranges within the
+    /// step are appended to `bad`, merging adjacent ones. On a short read,
+    /// returns the position within the step where valid data ends.
+    fn isolate(
+        &self,
+        device: &DeviceHandle,
+        step: Step,
+        buf: &mut [u8],
+        base: usize,
+        sector: usize,
+        bad: &mut Vec<Range<usize>>,
+    ) -> io::Result<Option<usize>> {
+        self.check_deadline()?;
+        let len = buf.len();
+        match device.read_at(
+            buf,
+            step.physical() + base as u64,
+            self.options,
+            self.deadline,
+        ) {
+            Ok(n) if n < len => Ok(Some(base + n)),
+            Ok(_) => Ok(None),
+            Err(e) if is_media_error(&e) && len > sector => {
+                let half = (len / 2).next_multiple_of(sector);
+                let (first, second) = buf.split_at_mut(half);
+                if let Some(end) = self.isolate(device, step, first, base, sector, bad)? {
+                    return Ok(Some(end));
+                }
+                self.isolate(device, step, second, base + half, sector, bad)
+            }
+            Err(e) if is_media_error(&e) => {
+                buf.fill(self.options.fill_byte);
+                stats::count(Counter::FilledBytes, len as u64);
+                match bad.last_mut() {
+                    Some(last) if last.end == base => last.end = base + len,
+                    _ => bad.push(base..base + len),
+                }
+                Ok(None)
+            }
+            Err(source) => {
+                let mut err = self.device_error(device, step, len, source);
+                err.logical_offset += base as u64;
+                err.physical_offset += base as u64;
+                Err(device.read_failed(err))
+            }
+        }
+    }
+
+    /// Describe a failed read of `length` bytes starting at a device step.
+    fn device_error(
+        &self,
+        device: &DeviceHandle,
+        step: Step,
+        length: usize,
+        source: io::Error,
+    ) -> DeviceReadError {
+        let Step::Device {
+            extent_index,
+            logical,
+            physical,
+            ..
+        } = step
+        else {
+            unreachable!("device runs only hold device reads")
+        };
+        DeviceReadError {
+            file_path: self.file_path(),
+            device_path: device.path().clone(),
+            extent_index,
+            logical_offset: logical,
+            physical_offset: physical,
+            length,
+            source,
+        }
+    }
+
+    /// Read data from the block device based on extent information.
+    ///
+    /// The read is planned first, then device reads are issued in logical
+    /// order, or in physical order with
+    /// [`Options::sort_physical`](Options::sort_physical), optionally spread
+    /// across [`Options::parallelism`](Options::parallelism) threads, taken
+    /// from [`Options::read_pool`](Options::read_pool) if set.
+    pub(super) fn read_from_device(
+        &self,
+        device: &DeviceHandle,
+        buf: &mut [u8],
+        offset: u64,
+        extents: &[FiemapExtent],
+    ) -> io::Result<ReadOutcome> {
+        self.check_extents(extents)?;
+        let steps = self.plan_steps(offset, buf.len() as u64, extents);
+
+        device.check_steps(&steps)?;
+        self.verify_device(device, extents)?;
+
+        // Buffer position of each step
+        let mut positions = Vec::with_capacity(steps.len());
+        let mut planned_len = 0;
+        for step in &steps {
+            positions.push(planned_len);
+            planned_len += step.len();
+        }
+
+        let mut order: Vec<usize> = (0..steps.len()).collect();
+        if self.options.sort_physical {
+            order.sort_by_key(|&i| match steps[i] {
+                Step::Device { physical, .. } => (0, physical),
+                Step::Fill { .. } | Step::File { .. } => (1, 0),
+            });
+        }
+
+        // Hand every run its own disjoint slices of the buffer
+        let planned = buf.len();
+        let ranges: Vec<Range<usize>> = steps
+            .iter()
+            .zip(&positions)
+            .map(|(step, &start)| start..start + step.len())
+            .collect();
+        let mut slices: Vec<Option<&mut [u8]>> = disjoint_slices(buf, &ranges)
+            .into_iter()
+            .map(Some)
+            .collect();
+        let jobs: Vec<Vec<RunPart>> = self
+            .coalesce(&steps, order)
+            .into_iter()
+            .map(|run| {
+                run.into_iter()
+                    .map(|i| RunPart {
+                        step: steps[i],
+                        start: positions[i],
+                        buf: slices[i].take().expect("each step is in one run"),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // Execute the runs; a short device read truncates the result there
+        let progress = Mutex::new(ReadOutcome::default());
+        let workers = self.options.parallelism.clamp(1, jobs.len().max(1));
+        let result = if workers == 1 {
+            self.execute_runs(device, jobs, &progress, planned)
+        } else {
+            let mut buckets: Vec<Vec<Vec<RunPart>>> = (0..workers).map(|_| Vec::new()).collect();
+            for (k, job) in jobs.into_iter().enumerate() {
+                buckets[k % workers].push(job);
+            }
+            let results: Vec<io::Result<Vec<usize>>> = match &self.options.read_pool {
+                Some(pool) => {
+                    // The calling thread reads the first bucket itself
+                    let mut results: Vec<_> = (0..workers).map(|_| Ok(Vec::new())).collect();
+                    let (first, rest) = results.split_first_mut().expect("workers > 1");
+                    let mut buckets = buckets.into_iter();
+                    let local = buckets.next().expect("workers > 1");
+                    let progress = &progress;
+                    let tasks: Vec<Box<dyn FnOnce() + Send + '_>> = buckets
+                        .zip(rest)
+                        .map(|(bucket, result)| {
+                            Box::new(move || {
+                                *result = self.execute_runs(device, bucket, progress, planned)
+                            }) as Box<dyn FnOnce() + Send + '_>
+                        })
+                        .collect();
+                    pool.scope(
+                        || *first = self.execute_runs(device, local, progress, planned),
+                        tasks,
+                    );
+                    results
+                }
+                None => thread::scope(|scope| {
+                    let handles: Vec<_> = buckets
+                        .into_iter()
+                        .map(|bucket| {
+                            let progress = &progress;
+                            scope
+                                .spawn(move || self.execute_runs(device, bucket, progress, planned))
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|handle| handle.join().expect("extent read thread panicked"))
+                        .collect()
+                }),
+            };
+            results
+                .into_iter()
+                .try_fold(Vec::new(), |mut shorts, result| {
+                    shorts.extend(result?);
+                    Ok(shorts)
+                })
+        };
+        let progress = progress.into_inner().unwrap();
+
+        let shorts = match result {
+            Ok(shorts) => shorts,
+            Err(source) => {
+                // Report the prefix of the buffer that was completed
+                let mut done = progress.done;
+                done.sort_by_key(|range| range.start);
+                let mut prefix = 0;
+                for range in done {
+                    if range.start > prefix {
+                        break;
+                    }
+                    prefix = prefix.max(range.end);
+                }
+                let outcome = self.account(&steps, &positions, offset, prefix, progress.unreadable);
+                let mut state = device.state(extents.to_vec(), outcome.bytes_read);
+                state.synthesized = outcome.synthesized;
+                state.unreadable = outcome.unreadable;
+                state.set_segments(outcome.segments);
+                return Err(PartialReadError { state, source }.into());
+            }
+        };
+        let limit = shorts.into_iter().min().unwrap_or(planned_len);
+        let mut outcome = self.account(&steps, &positions, offset, limit, progress.unreadable);
+        outcome.reads = progress.reads;
+
+        // Check if we read the exact requested length
+        if self.options.read_exact && outcome.bytes_read < buf.len() {
+            return Err(ShortReadError {
+                expected: buf.len(),
+                bytes_read: outcome.bytes_read,
+            }
+            .into());
+        }
+
+        Ok(outcome)
+    }
+
+    /// Account for the steps whose data made it into the first `limit`
+    /// bytes of the buffer, in logical order.
+    fn account(
+        &self,
+        steps: &[Step],
+        positions: &[usize],
+        offset: u64,
+        limit: usize,
+        mut unreadable: Vec<UnreadableRange>,
+    ) -> ReadOutcome {
+        unreadable.sort_by_key(|range| range.logical);
+
+        let mut outcome = ReadOutcome::default();
+        for (step, &start) in steps.iter().zip(positions) {
+            if start >= limit {
+                break;
+            }
+            let len = step.len().min(limit - start);
+            match *step {
+                Step::Fill {
+                    logical, source, ..
+                } => outcome.record_fill(logical, len, source),
+                Step::File { logical, .. } => outcome.record_file(logical, len),
+                Step::Device {
+                    logical, physical, ..
+                } => {
+                    // Unreadable ranges were filled in best-effort mode
+                    let end = logical + len as u64;
+                    let physical_at = |at: u64| physical + (at - logical);
+                    let mut current = logical;
+                    for range in &unreadable {
+                        let range_end = range.logical + range.length;
+                        if range_end <= current || range.logical >= end {
+                            continue;
+                        }
+                        let fill_start = range.logical.max(current);
+                        let fill_end = range_end.min(end);
+                        outcome.record_read(
+                            current,
+                            (fill_start - current) as usize,
+                            physical_at(current),
+                        );
+                        outcome.record_fill(
+                            fill_start,
+                            (fill_end - fill_start) as usize,
+                            SegmentSource::Unreadable {
+                                physical: physical_at(fill_start),
+                            },
+                        );
+                        current = fill_end;
+                    }
+                    outcome.record_read(current, (end - current) as usize, physical_at(current));
+                }
+            }
+            if self.options.dry_run {
+                outcome.plan(step);
+            }
+        }
+
+        outcome.unreadable = unreadable
+            .into_iter()
+            .filter(|range| range.logical - offset < limit as u64)
+            .map(|range| UnreadableRange {
+                length: range.length.min(offset + limit as u64 - range.logical),
+                ..range
+            })
+            .collect();
+        outcome
+    }
+}
+
+/// Restores the I/O priority of the thread when dropped, see
+/// [`ReadContext::set_priority`].
+struct PriorityGuard {
+    previous: u16,
+}
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        let _ = sys::ioprio_set(self.previous);
+    }
+}
+
+/// Split `buf` into mutable slices for the given disjoint ranges, in the given order.
+fn disjoint_slices<'b>(buf: &'b mut [u8], ranges: &[Range<usize>]) -> Vec<&'b mut [u8]> {
+    let mut by_start: Vec<usize> = (0..ranges.len()).collect();
+    by_start.sort_by_key(|&k| ranges[k].start);
+
+    let mut slices: Vec<Option<&'b mut [u8]>> = ranges.iter().map(|_| None).collect();
+    let mut rest = buf;
+    let mut consumed = 0;
+    for k in by_start {
+        let range = &ranges[k];
+        let (_, tail) = rest.split_at_mut(range.start - consumed);
+        let (slice, tail) = tail.split_at_mut(range.len());
+        slices[k] = Some(slice);
+        rest = tail;
+        consumed = range.end;
+    }
+    slices
+        .into_iter()
+        .map(|slice| slice.expect("ranges are disjoint"))
+        .collect()
+}
+
+/// Progress of a device read, accumulated while walking the extents.
+#[derive(Debug, Default)]
+pub(super) struct ReadOutcome {
+    /// Number of bytes placed into the buffer so far.
+    pub(super) bytes_read: usize,
+    /// Number of bytes that were synthesized instead of read.
+    pub(super) bytes_filled: usize,
+    /// Buffer ranges that were synthesized instead of read from the device.
+    pub(super) synthesized: Vec<Range<usize>>,
+    /// Steps recorded for a dry run.
+    pub(super) planned: Vec<PlannedRead>,
+    /// Device ranges filled in best-effort mode.
+    pub(super) unreadable: Vec<UnreadableRange>,
+    /// Buffer ranges holding their final data, in completion order.
+    done: Vec<Range<usize>>,
+    /// Sources of the data in the buffer, in buffer order.
+    pub(super) segments: Vec<Segment>,
+    /// Durations of the device reads, in completion order.
+    pub(super) reads: Vec<ReadTiming>,
+}
+
+/// A step of a run, with the buffer slice it writes to.
+struct RunPart<'b> {
+    step: Step,
+    /// Position of the slice in the read buffer.
+    start: usize,
+    buf: &'b mut [u8],
+}
+
+/// A run of device reads serviced by one vectored read.
+struct DeviceRun<'b> {
+    layout: RunLayout,
+    bufs: Vec<&'b mut [u8]>,
+    /// Receives the data of the gaps, which is thrown away.
+    discard: AlignedBuf,
+}
+
+/// The reads making up a [`DeviceRun`].
+struct RunLayout {
+    /// The reads of the run with their buffer positions, in physical order.
+    parts: Vec<(Step, usize)>,
+    /// Physical gap between each pair of consecutive reads.
+    gaps: Vec<usize>,
+}
+
+impl<'b> DeviceRun<'b> {
+    fn new(run: Vec<RunPart<'b>>) -> Self {
+        let gaps: Vec<usize> = run
+            .windows(2)
+            .map(|pair| {
+                let prev_end = pair[0].step.physical() + pair[0].step.len() as u64;
+                (pair[1].step.physical() - prev_end) as usize
+            })
+            .collect();
+        let discard = AlignedBuf::new(gaps.iter().sum(), READ_ALIGNMENT);
+        let (parts, bufs) = run
+            .into_iter()
+            .map(|part| ((part.step, part.start), part.buf))
+            .unzip();
+        Self {
+            layout: RunLayout { parts, gaps },
+            bufs,
+            discard,
+        }
+    }
+}
+
+impl RunLayout {
+    /// Physical offset of the start of the run.
+    pub(super) fn physical(&self) -> u64 {
+        self.parts[0].0.physical()
+    }
+
+    /// Number of bytes covered on the device, including gaps.
+    fn total(&self) -> usize {
+        self.parts.iter().map(|(step, _)| step.len()).sum::<usize>()
+            + self.gaps.iter().sum::<usize>()
+    }
+
+    /// The buffers of the run interleaved with the gaps.
+    fn iovecs<'a>(&self, bufs: &'a mut [&mut [u8]], discard: &'a mut [u8]) -> Vec<IoSliceMut<'a>> {
+        let mut discard_rest = discard;
+        let mut iovs = Vec::with_capacity(bufs.len() * 2);
+        for (k, buf) in bufs.iter_mut().enumerate() {
+            if k > 0 && self.gaps[k - 1] > 0 {
+                let (gap_buf, rest) = discard_rest.split_at_mut(self.gaps[k - 1]);
+                discard_rest = rest;
+                iovs.push(IoSliceMut::new(gap_buf));
+            }
+            iovs.push(IoSliceMut::new(buf));
+        }
+        iovs
+    }
+}
+
+impl ReadOutcome {
+    /// Append the outcome of a read, possibly from another device, of the
+    /// part of the buffer following this one.
+    pub(super) fn append(&mut self, next: ReadOutcome) {
+        let shift = self.bytes_read;
+        let moved = |range: Range<usize>| range.start + shift..range.end + shift;
+        for range in next.synthesized.into_iter().map(moved) {
+            match self.synthesized.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => self.synthesized.push(range),
+            }
+        }
+        // Segments of different reads are never merged, since they may be
+        // on different devices
+        self.segments
+            .extend(next.segments.into_iter().map(|segment| Segment {
+                range: moved(segment.range.clone()),
+                ..segment
+            }));
+        self.done.extend(next.done.into_iter().map(moved));
+        self.planned.extend(next.planned);
+        self.unreadable.extend(next.unreadable);
+        self.reads.extend(next.reads);
+        self.bytes_read += next.bytes_read;
+        self.bytes_filled += next.bytes_filled;
+    }
+
+    /// State of a read from the device at `path` with this outcome.
+    pub(super) fn into_state(
+        self,
+        path: PathBuf,
+        info: Option<DeviceInfo>,
+        extents: Vec<FiemapExtent>,
+    ) -> State {
+        let mut state = State::new(path, extents, self.bytes_read, false);
+        state.device_info = info;
+        state.synthesized = self.synthesized;
+        state.planned = self.planned;
+        state.unreadable = self.unreadable;
+        state.set_segments(self.segments);
+        state
+    }
+
+    /// Record a step for a dry run, merging adjacent fills.
+    fn plan(&mut self, step: &Step) {
+        match *step {
+            Step::Fill { logical, len, .. } => match self.planned.last_mut() {
+                Some(PlannedRead::Fill {
+                    logical: start,
+                    length,
+                }) if *start + *length == logical => *length += len as u64,
+                _ => self.planned.push(PlannedRead::Fill {
+                    logical,
+                    length: len as u64,
+                }),
+            },
+            Step::Device {
+                logical,
+                physical,
+                len,
+                ..
+            } => self.planned.push(PlannedRead::Device {
+                logical,
+                physical,
+                length: len as u64,
+            }),
+            Step::File { logical, len } => self.planned.push(PlannedRead::File {
+                logical,
+                length: len as u64,
+            }),
+        }
+    }
+
+    /// Record the next `len` bytes, for `logical`, as read from the device
+    /// at `physical`.
+    pub(super) fn record_read(&mut self, logical: u64, len: usize, physical: u64) {
+        self.record_segment(logical, len, SegmentSource::Device { physical });
+        self.bytes_read += len;
+    }
+
+    /// Record the next `len` bytes, for `logical`, as read through the file.
+    pub(super) fn record_file(&mut self, logical: u64, len: usize) {
+        self.record_segment(logical, len, SegmentSource::Fallback);
+        self.bytes_read += len;
+    }
+
+    /// Record the next `len` bytes, for `logical`, as synthesized, without
+    /// touching a buffer.
+    pub(super) fn record_fill(&mut self, logical: u64, len: usize, source: SegmentSource) {
+        if len == 0 {
+            return;
+        }
+        self.record_segment(logical, len, source);
+
+        let start = self.bytes_read;
+        let end = start + len;
+        self.bytes_read = end;
+        self.bytes_filled += len;
+
+        // Merge with the previous range if they are adjacent
+        match self.synthesized.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => self.synthesized.push(start..end),
+        }
+    }
+
+    /// Append a segment for the next `len` bytes, merging it into the
+    /// previous one if it continues it.
+    fn record_segment(&mut self, logical: u64, len: usize, source: SegmentSource) {
+        if len == 0 {
+            return;
+        }
+        let segment = Segment {
+            range: self.bytes_read..self.bytes_read + len,
+            logical,
+            source,
+        };
+        if !self
+            .segments
+            .last_mut()
+            .is_some_and(|last| last.merge(&segment))
+        {
+            self.segments.push(segment);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CachedDevice;
+    use crate::options::Options;
+    use crate::test_support::{fake_device, fixture};
+
+    use std::fs::File;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sort_physical() {
+        use blkmap::ExtentFlags;
+        use std::sync::Mutex;
+
+        // Device blocks: 0x00, 0x11, 0x22, 0x33
+        let data: Vec<u8> = (0..4u8).flat_map(|b| [b * 0x11; 1024]).collect();
+        let (file, device) = fixture(&data);
+
+        // Logical blocks stored at physical blocks 3, 1, 2, with a hole in between
+        let extents: Vec<FiemapExtent> = [(0, 3), (1, 1), (3, 2)]
+            .iter()
+            .map(|&(logical, physical)| FiemapExtent {
+                logical: logical * 1024,
+                physical: physical * 1024,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            })
+            .collect();
+
+        for sort in [false, true] {
+            let offsets = Arc::new(Mutex::new(Vec::new()));
+            let recorded = Arc::clone(&offsets);
+            let options = Options::new()
+                .with_fill_holes(true)
+                .with_sort_physical(sort)
+                .with_progress(move |event| recorded.lock().unwrap().push(event.logical_offset));
+            let ctx = ReadContext::new(&file, &options);
+
+            let mut buf = vec![0xFFu8; 4096];
+            let outcome = ctx
+                .read_from_device(&device, &mut buf, 0, &extents)
+                .unwrap();
+
+            // The buffer is in logical order either way
+            assert_eq!(outcome.bytes_read, 4096);
+            assert_eq!(outcome.synthesized, vec![2048..3072]);
+            assert!(buf[..1024].iter().all(|&b| b == 0x33));
+            assert!(buf[1024..2048].iter().all(|&b| b == 0x11));
+            assert!(buf[2048..3072].iter().all(|&b| b == 0));
+            assert!(buf[3072..].iter().all(|&b| b == 0x22));
+
+            let expected = if sort {
+                // Physical order: blocks 1, 2, 3, then the fill
+                vec![2048, 4096, 1024, 3072]
+            } else {
+                vec![1024, 2048, 3072, 4096]
+            };
+            assert_eq!(*offsets.lock().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_coalesce_gap() {
+        use blkmap::ExtentFlags;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let data: Vec<u8> = (0..16384).map(|i| (i % 251) as u8).collect();
+        let (file, device) = fixture(&data);
+
+        // Physical layout: [0, 1024) and [2048, 3072) are 1 KiB apart,
+        // [8192, 9216) is 5 KiB further; logical order is reversed for the last
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 0,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 1024,
+                physical: 2048,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 2048,
+                physical: 8192,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+        ];
+
+        for (gap, expected_reads) in [(None, 3), (Some(1024), 2), (Some(8192), 1)] {
+            let reads = Arc::new(AtomicUsize::new(0));
+            let counter = Arc::clone(&reads);
+            let mut options = Options::new().with_progress(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+            options.coalesce_gap = gap;
+            let ctx = ReadContext::new(&file, &options);
+
+            let mut buf = vec![0u8; 3072];
+            let outcome = ctx
+                .read_from_device(&device, &mut buf, 0, &extents)
+                .unwrap();
+            assert_eq!(outcome.bytes_read, 3072);
+            assert_eq!(&buf[..1024], &data[..1024]);
+            assert_eq!(&buf[1024..2048], &data[2048..3072]);
+            assert_eq!(&buf[2048..], &data[8192..9216]);
+            assert_eq!(reads.load(Ordering::SeqCst), expected_reads);
+        }
+    }
+
+    #[test]
+    fn test_coalesce_short_read() {
+        use blkmap::ExtentFlags;
+
+        // The second extent runs past the end of the device
+        let (file, device) = fixture(&[0xAB; 3072]);
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 0,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 1024,
+                physical: 2048,
+                length: 2048,
+                flags: ExtentFlags::empty(),
+            },
+        ];
+        let options = Options::new().with_coalesce_gap(1024);
+        let ctx = ReadContext::new(&file, &options);
+
+        let mut buf = vec![0u8; 3072];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+        assert_eq!(outcome.bytes_read, 2048);
+    }
+
+    #[test]
+    fn test_parallelism() {
+        use crate::pool::ReadPool;
+        use blkmap::ExtentFlags;
+
+        let data: Vec<u8> = (0..65536).map(|i| (i % 251) as u8).collect();
+        let (file, device) = fixture(&data);
+
+        // Eight interleaved 4 KiB extents with a hole after each
+        let extents: Vec<FiemapExtent> = (0..8)
+            .map(|i| FiemapExtent {
+                logical: i * 8192,
+                physical: (i % 2) * 32768 + (i / 2) * 8192,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            })
+            .collect();
+
+        let serial_options = Options::new().with_fill_holes(true).with_fill_byte(0xEE);
+        let serial_ctx = ReadContext::new(&file, &serial_options);
+        let mut expected = vec![0u8; 65536];
+        let serial = serial_ctx
+            .read_from_device(&device, &mut expected, 0, &extents)
+            .unwrap();
+        assert_eq!(serial.bytes_read, 65536);
+        assert_eq!(serial.bytes_filled, 32768);
+
+        // Also with threads from a pool smaller than the parallelism
+        let pooled_options = serial_options
+            .clone()
+            .with_read_pool(ReadPool::new(2).unwrap());
+        for base in [&serial_options, &pooled_options] {
+            for threads in [0, 2, 3, 16] {
+                let options = base.clone().with_parallelism(threads);
+                let ctx = ReadContext::new(&file, &options);
+                let mut buf = vec![0u8; 65536];
+                let outcome = ctx
+                    .read_from_device(&device, &mut buf, 0, &extents)
+                    .unwrap();
+                assert_eq!(outcome.bytes_read, serial.bytes_read);
+                assert_eq!(outcome.bytes_filled, serial.bytes_filled);
+                assert_eq!(buf, expected);
+            }
+
+            // A short read in one thread truncates the result at that point
+            let short_device = fake_device(&data[..32768 + 1024]);
+            let options = base.clone().with_parallelism(4);
+            let ctx = ReadContext::new(&file, &options);
+            let mut buf = vec![0u8; 65536];
+            let outcome = ctx
+                .read_from_device(&short_device, &mut buf, 0, &extents)
+                .unwrap();
+            assert_eq!(outcome.bytes_read, 8192 + 1024);
+        }
+    }
+
+    #[test]
+    fn test_io_engines() {
+        use crate::engine::{IoEngine, LibaioEngine, PsyncEngine, UringEngine};
+        use blkmap::ExtentFlags;
+
+        let data: Vec<u8> = (0..65536).map(|i| (i % 251) as u8).collect();
+        let (file, device) = fixture(&data);
+        let extents: Vec<FiemapExtent> = (0..8)
+            .map(|i| FiemapExtent {
+                logical: i * 8192,
+                physical: (7 - i) * 8192,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            })
+            .collect();
+
+        for coalesce in [false, true] {
+            let mut options = Options::new()
+                .with_fill_holes(true)
+                .with_sort_physical(true);
+            if coalesce {
+                options = options.with_coalesce_gap(4096);
+            }
+            let ctx = ReadContext::new(&file, &options);
+            let mut expected = vec![0u8; 65536];
+            ctx.read_from_device(&device, &mut expected, 0, &extents)
+                .unwrap();
+
+            let engines: [Arc<dyn IoEngine>; 3] = [
+                Arc::new(PsyncEngine),
+                Arc::new(LibaioEngine),
+                Arc::new(UringEngine),
+            ];
+            for engine in engines {
+                let options = Options {
+                    io_engine: Arc::clone(&engine),
+                    ..options.clone()
+                };
+                let ctx = ReadContext::new(&file, &options);
+                let mut buf = vec![0u8; 65536];
+                match ctx.read_from_device(&device, &mut buf, 0, &extents) {
+                    Ok(outcome) => {
+                        assert_eq!(outcome.bytes_read, 65536);
+                        assert_eq!(outcome.bytes_filled, 32768);
+                        assert_eq!(buf, expected, "engine {}", engine.name());
+                    }
+                    Err(e) if engine.name() == "io_uring" && !cfg!(feature = "uring") => {
+                        assert_eq!(e.kind(), io::ErrorKind::Unsupported)
+                    }
+                    Err(_) if engine.name() == "io_uring" && !sys::io_uring_available() => {}
+                    Err(e) => panic!("engine {}: unexpected error: {:?}", engine.name(), e),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        use blkmap::ExtentFlags;
+        use std::time::Duration;
+
+        let (file, device) = fixture(&[0xAB; 8192]);
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 0,
+            length: 8192,
+            flags: ExtentFlags::empty(),
+        }];
+
+        let options = Options::new().with_timeout(Duration::from_secs(60));
+        let ctx = ReadContext::new(&file, &options);
+        let mut buf = vec![0u8; 8192];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+        assert_eq!(outcome.bytes_read, 8192);
+
+        // An expired deadline stops the read before the device is touched
+        let options = Options::new().with_timeout(Duration::ZERO);
+        let ctx = ReadContext::new(&file, &options);
+        let mut buf = vec![0u8; 8192];
+        let err = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        use crate::engine::{Completion, IoEngine, PreadvEngine};
+        use blkmap::ExtentFlags;
+        use std::os::unix::io::BorrowedFd;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Records the largest number of batches it serviced at once.
+        #[derive(Debug, Default)]
+        struct SlowEngine {
+            current: AtomicUsize,
+            peak: AtomicUsize,
+        }
+
+        impl IoEngine for SlowEngine {
+            fn name(&self) -> &str {
+                "slow"
+            }
+
+            fn read_batch(
+                &self,
+                fd: BorrowedFd<'_>,
+                reads: &mut [DeviceRead<'_>],
+                complete: &mut Completion<'_>,
+            ) -> io::Result<()> {
+                let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+                self.peak.fetch_max(current, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(10));
+                self.current.fetch_sub(1, Ordering::SeqCst);
+                PreadvEngine.read_batch(fd, reads, complete)
+            }
+        }
+
+        let (file, device) = fixture(&[0x5A; 4096]);
+        let extents = [FiemapExtent {
+            logical: 0,
+            physical: 0,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        let engine = Arc::new(SlowEngine::default());
+        let mut options = Options::new().with_max_in_flight(2);
+        options.io_engine = engine.clone();
+
+        thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|| {
+                    let ctx = ReadContext::new(&file, &options);
+                    let mut buf = vec![0u8; 4096];
+                    ctx.read_from_device(&device, &mut buf, 0, &extents)
+                        .unwrap();
+                    assert_eq!(buf, [0x5A; 4096]);
+                });
+            }
+        });
+        assert_eq!(engine.peak.load(Ordering::SeqCst), 2);
+
+        // Waiting for a slot is bounded by the timeout
+        let busy = device.in_flight().unwrap().enter(1, None).unwrap();
+        let options = Options::new()
+            .with_max_in_flight(1)
+            .with_timeout(Duration::from_millis(20));
+        let ctx = ReadContext::new(&file, &options);
+        let err = ctx
+            .read_from_device(&device, &mut [0u8; 4096], 0, &extents)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drop(busy);
+    }
+
+    #[test]
+    fn test_io_priority() {
+        use crate::engine::{Completion, IoEngine, PreadvEngine};
+        use crate::options::IoPriority;
+        use blkmap::ExtentFlags;
+        use std::os::unix::io::BorrowedFd;
+
+        /// Records the I/O priority of the thread issuing the reads.
+        #[derive(Debug, Default)]
+        struct RecordingEngine {
+            ioprio: Mutex<Option<u16>>,
+        }
+
+        impl IoEngine for RecordingEngine {
+            fn name(&self) -> &str {
+                "recording"
+            }
+
+            fn read_batch(
+                &self,
+                fd: BorrowedFd<'_>,
+                reads: &mut [DeviceRead<'_>],
+                complete: &mut Completion<'_>,
+            ) -> io::Result<()> {
+                *self.ioprio.lock().unwrap() = Some(sys::ioprio_get()?);
+                PreadvEngine.read_batch(fd, reads, complete)
+            }
+        }
+
+        let (file, device) = fixture(&[0x11; 4096]);
+        let extents = [FiemapExtent {
+            logical: 0,
+            physical: 0,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        let engine = Arc::new(RecordingEngine::default());
+        let mut options = Options::new().with_io_priority(IoPriority::Idle);
+        options.io_engine = engine.clone();
+
+        // Run on a thread of its own, whose priority no other test changes
+        thread::spawn(move || {
+            let before = sys::ioprio_get().unwrap();
+            let ctx = ReadContext::new(&file, &options);
+            ctx.read_from_device(&device, &mut [0u8; 4096], 0, &extents)
+                .unwrap();
+            assert_eq!(
+                *engine.ioprio.lock().unwrap(),
+                Some(IoPriority::Idle.ioprio())
+            );
+            assert_eq!(sys::ioprio_get().unwrap(), before);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_max_throughput() {
+        use blkmap::ExtentFlags;
+
+        let (file, device) = fixture(&[0x22; 16384]);
+        let extents: Vec<FiemapExtent> = (0..4)
+            .map(|i| FiemapExtent {
+                logical: i * 4096,
+                physical: i * 4096,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            })
+            .collect();
+
+        // One second's worth is read at once, the rest waits for the bucket
+        let options = Options::new().with_max_throughput(40960);
+        let ctx = ReadContext::new(&file, &options);
+        let started = Instant::now();
+        for _ in 0..3 {
+            let outcome = ctx
+                .read_from_device(&device, &mut [0u8; 16384], 0, &extents)
+                .unwrap();
+            assert_eq!(outcome.bytes_read, 16384);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        // Waiting is bounded by the timeout
+        let options = Options::new()
+            .with_max_throughput(4096)
+            .with_timeout(Duration::from_millis(20));
+        let ctx = ReadContext::new(&file, &options);
+        let err = ctx
+            .read_from_device(&device, &mut [0u8; 16384], 0, &extents)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_retry() {
+        use crate::engine::{Completion, IoEngine, PreadvEngine};
+        use crate::options::RetryPolicy;
+        use blkmap::ExtentFlags;
+        use std::os::unix::io::BorrowedFd;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        /// Fails the first `failures` reads with `EIO`.
+        #[derive(Debug)]
+        struct FlakyEngine {
+            failures: AtomicUsize,
+        }
+
+        impl IoEngine for FlakyEngine {
+            fn name(&self) -> &str {
+                "flaky"
+            }
+
+            fn read_batch(
+                &self,
+                fd: BorrowedFd<'_>,
+                reads: &mut [DeviceRead<'_>],
+                complete: &mut Completion<'_>,
+            ) -> io::Result<()> {
+                PreadvEngine.read_batch(fd, reads, &mut |index, result| {
+                    let fail = self
+                        .failures
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    if fail {
+                        complete(index, Err(io::Error::from_raw_os_error(libc::EIO)))
+                    } else {
+                        complete(index, result)
+                    }
+                })
+            }
+        }
+
+        let data: Vec<u8> = (0..16384).map(|i| (i % 251) as u8).collect();
+        let (file, device) = fixture(&data);
+        let extents: Vec<FiemapExtent> = (0..4)
+            .map(|i| FiemapExtent {
+                logical: i * 4096,
+                physical: (3 - i) * 4096,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            })
+            .collect();
+        let retry = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+        };
+
+        // Two failures are absorbed by three attempts
+        let options = Options::new()
+            .with_io_engine(FlakyEngine {
+                failures: AtomicUsize::new(2),
+            })
+            .with_retry(retry);
+        let ctx = ReadContext::new(&file, &options);
+        let mut buf = vec![0u8; 16384];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+        assert_eq!(outcome.bytes_read, 16384);
+        for i in 0..4 {
+            assert_eq!(
+                &buf[i * 4096..(i + 1) * 4096],
+                &data[(3 - i) * 4096..(4 - i) * 4096]
+            );
+        }
+
+        // Without retries the first failure is returned
+        let options = Options::new().with_io_engine(FlakyEngine {
+            failures: AtomicUsize::new(1),
+        });
+        let ctx = ReadContext::new(&file, &options);
+        let err = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap_err();
+        let context = DeviceReadError::from_io_error(&err).unwrap();
+        assert_eq!(context.source.raw_os_error(), Some(libc::EIO));
+
+        // A read failing on every attempt reports the last error
+        let options = Options::new()
+            .with_io_engine(FlakyEngine {
+                failures: AtomicUsize::new(usize::MAX),
+            })
+            .with_retry(retry);
+        let ctx = ReadContext::new(&file, &options);
+        let err = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap_err();
+        let context = DeviceReadError::from_io_error(&err).unwrap();
+        assert_eq!(context.source.raw_os_error(), Some(libc::EIO));
+
+        // The backoff does not outlast the deadline
+        let options = Options::new()
+            .with_io_engine(FlakyEngine {
+                failures: AtomicUsize::new(usize::MAX),
+            })
+            .with_retry(RetryPolicy {
+                attempts: 3,
+                backoff: Duration::from_secs(60),
+            })
+            .with_timeout(Duration::from_millis(50));
+        let ctx = ReadContext::new(&file, &options);
+        let start = Instant::now();
+        let err = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_best_effort() {
+        use crate::engine::{Completion, IoEngine, PreadvEngine};
+        use crate::state::UnreadableRange;
+        use blkmap::ExtentFlags;
+        use std::os::unix::io::BorrowedFd;
+
+        /// Fails every read touching the `bad` device range with `EIO`.
+        #[derive(Debug)]
+        struct BadBlockEngine {
+            bad: Range<u64>,
+        }
+
+        impl IoEngine for BadBlockEngine {
+            fn name(&self) -> &str {
+                "bad-block"
+            }
+
+            fn read_batch(
+                &self,
+                fd: BorrowedFd<'_>,
+                reads: &mut [DeviceRead<'_>],
+                complete: &mut Completion<'_>,
+            ) -> io::Result<()> {
+                let broken: Vec<bool> = reads
+                    .iter()
+                    .map(|read| {
+                        read.offset < self.bad.end
+                            && read.offset + read.len() as u64 > self.bad.start
+                    })
+                    .collect();
+                PreadvEngine.read_batch(fd, reads, &mut |index, result| {
+                    if broken[index] {
+                        complete(index, Err(io::Error::from_raw_os_error(libc::EIO)))
+                    } else {
+                        complete(index, result)
+                    }
+                })
+            }
+        }
+
+        let data: Vec<u8> = (0..393216).map(|i| (i % 251) as u8).collect();
+        let (file, device) = fixture(&data);
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 0,
+                length: 262144,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 262144,
+                physical: 327680,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            },
+        ];
+        let engine = || BadBlockEngine { bad: 70000..70001 };
+
+        // The whole coalesced run fails; only the bad sector is filled
+        let options = Options::new()
+            .with_io_engine(engine())
+            .with_coalesce_gap(65536)
+            .with_fill_byte(0xEE)
+            .with_best_effort(true);
+        let ctx = ReadContext::new(&file, &options);
+        let mut buf = vec![0u8; 266240];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+        let sector = UnreadableRange {
+            logical: 69632,
+            physical: 69632,
+            length: 512,
+        };
+        assert_eq!(outcome.bytes_read, 266240);
+        assert_eq!(outcome.bytes_filled, 512);
+        assert_eq!(outcome.synthesized, vec![69632..70144]);
+        assert_eq!(outcome.unreadable, vec![sector]);
+        assert_eq!(
+            outcome.segments[1],
+            Segment {
+                range: 69632..70144,
+                logical: 69632,
+                source: SegmentSource::Unreadable { physical: 69632 },
+            }
+        );
+        assert_eq!(&buf[..69632], &data[..69632]);
+        assert!(buf[69632..70144].iter().all(|&b| b == 0xEE));
+        assert_eq!(&buf[70144..262144], &data[70144..262144]);
+        assert_eq!(&buf[262144..], &data[327680..331776]);
+
+        // Reads starting inside the extent isolate the same sector
+        let mut buf = vec![0u8; 98304];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 32768, &extents)
+            .unwrap();
+        assert_eq!(outcome.bytes_read, 98304);
+        assert_eq!(outcome.synthesized, vec![36864..37376]);
+        assert_eq!(outcome.unreadable, vec![sector]);
+        assert_eq!(&buf[..36864], &data[32768..69632]);
+
+        // Without best-effort mode the read fails
+        let options = Options::new().with_io_engine(engine());
+        let ctx = ReadContext::new(&file, &options);
+        let mut buf = vec![0u8; 266240];
+        let err = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap_err();
+        let context = DeviceReadError::from_io_error(&err).unwrap();
+        assert_eq!(context.source.raw_os_error(), Some(libc::EIO));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault_injection() {
+        use crate::error::BlkReadError;
+        use crate::fault::{Fault, FaultPlan};
+        use crate::options::RetryPolicy;
+        use blkmap::ExtentFlags;
+
+        let (file, device) = fixture(&[0x66; 16384]);
+        let extents: Vec<FiemapExtent> = (0..4)
+            .map(|i| FiemapExtent {
+                logical: i * 4096,
+                physical: (3 - i) * 4096,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            })
+            .collect();
+        let read = |options: &Options| {
+            ReadContext::new(&file, options).read_from_device(
+                &device,
+                &mut [0u8; 16384],
+                0,
+                &extents,
+            )
+        };
+
+        // A transient error is retried away
+        let plan = FaultPlan::new().with_fault(Fault::error(4096..4097, libc::EIO).times(1));
+        let options = Options::new()
+            .with_fault_injection(plan.clone())
+            .with_retry(RetryPolicy {
+                attempts: 2,
+                backoff: Duration::ZERO,
+            });
+        assert_eq!(read(&options).unwrap().bytes_read, 16384);
+        // Once used up, the fault no longer fires
+        assert!(read(&Options::new().with_fault_injection(plan)).is_ok());
+
+        // A persistent one fails the read at the extent
+        let plan = FaultPlan::new().with_fault(Fault::error(8192..12288, libc::ENOSPC));
+        let err = read(&Options::new().with_fault_injection(plan)).unwrap_err();
+        match BlkReadError::from_io_error(&err) {
+            Some(BlkReadError::DeviceRead(read)) => {
+                assert_eq!(read.logical_offset, 8192);
+                assert_eq!(read.source.raw_os_error(), Some(libc::ENOSPC));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        // A short read ends the data early
+        let plan = FaultPlan::new().with_fault(Fault::short(4096..8192, 1000));
+        let outcome = read(&Options::new().with_fault_injection(plan)).unwrap();
+        assert_eq!(outcome.bytes_read, 4096 + 1000);
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault_raw_os_error() {
+        use crate::backend::MemDevice;
+        use crate::error::{BlkReadError, PartialReadError};
+        use crate::fault::{Fault, FaultPlan};
+        use crate::reader::BlkReader;
+        use crate::test_support::stand_in_file;
+
+        let device = MemDevice::new(16384).unwrap();
+        let extents = [
+            device.place(0, 0, &[0x5A; 4096]).unwrap(),
+            device.place(4096, 8192, &[0x5A; 4096]).unwrap(),
+        ];
+        let plan = FaultPlan::new().with_fault(Fault::error(4096..8192, libc::EIO));
+        let options = Options::new()
+            .with_device_backend(device)
+            .with_fault_injection(plan);
+
+        // The EIO of the second extent survives the conversion to io::Error,
+        // through each of the typed errors wrapping it
+        let mut buf = vec![0u8; 8192];
+        let err = stand_in_file()
+            .blk_read_with_extents(&mut buf, 0, &extents, &options)
+            .unwrap_err();
+        let eio = io::Error::from_raw_os_error(libc::EIO);
+        assert_eq!(err.kind(), eio.kind());
+        assert_eq!(err.raw_os_error(), None);
+        let partial = PartialReadError::from_io_error(&err).unwrap();
+        assert_eq!(partial.state.bytes_read, 4096);
+        assert_eq!(partial.raw_os_error(), Some(libc::EIO));
+        let read = DeviceReadError::from_io_error(&err).unwrap();
+        assert_eq!(read.logical_offset, 4096);
+        assert_eq!(read.raw_os_error(), Some(libc::EIO));
+        assert_eq!(
+            BlkReadError::from_io_error(&err).unwrap().raw_os_error(),
+            Some(libc::EIO)
+        );
+    }
+
+    #[test]
+    fn test_disjoint_slices() {
+        let mut buf: Vec<u8> = (0..10).collect();
+        let slices = disjoint_slices(&mut buf, &[6..8, 0..2, 3..4]);
+        assert_eq!(slices[0], &[6, 7]);
+        assert_eq!(slices[1], &[0, 1]);
+        assert_eq!(slices[2], &[3]);
+    }
+
+    #[test]
+    fn test_device_read_error_context() {
+        use blkmap::ExtentFlags;
+
+        // A write-only handle makes every device read fail with EBADF
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let device = DeviceHandle::Uncached(CachedDevice {
+            path: PathBuf::from("/dev/fake"),
+            file: std::fs::OpenOptions::new()
+                .write(true)
+                .open(temp.path())
+                .unwrap(),
+            info: None,
+            direct: true,
+            base_offset: 0,
+            in_flight: Default::default(),
+        });
+
+        let stand_in = tempfile::NamedTempFile::new().unwrap();
+        let path = stand_in.path();
+        let file = File::open(path).unwrap();
+        let options = Options::new().with_fill_holes(true);
+        let ctx = ReadContext::new(&file, &options).with_path(path);
+
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 0x1000,
+                length: 512,
+                flags: ExtentFlags::UNKNOWN,
+            },
+            FiemapExtent {
+                logical: 512,
+                physical: 0x8000,
+                length: 512,
+                flags: ExtentFlags::empty(),
+            },
+        ];
+
+        let mut buf = vec![0u8; 1024];
+        let err = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap_err();
+
+        let context = DeviceReadError::from_io_error(&err).unwrap();
+        assert_eq!(context.file_path.as_deref(), Some(path));
+        assert_eq!(context.device_path, PathBuf::from("/dev/fake"));
+        assert_eq!(context.extent_index, 1);
+        assert_eq!(context.logical_offset, 512);
+        assert_eq!(context.physical_offset, 0x8000);
+        assert_eq!(context.length, 512);
+        assert_eq!(err.raw_os_error(), None);
+        assert_eq!(
+            crate::BlkReadError::from_io_error(&err)
+                .unwrap()
+                .raw_os_error(),
+            Some(libc::EBADF)
+        );
+        assert_eq!(context.source.raw_os_error(), Some(libc::EBADF));
+    }
+}
@@ -0,0 +1,2628 @@
+//! The extent walk of reads: querying extents, planning the steps that
+//! read each range from a device or the file or fill it, and checking what
+//! was read.
+
+use super::run::ReadOutcome;
+use super::{
+    check_filesystem, extents_in_range, fallback_segments, fiemap_failed, fiemap_range,
+    read_full_at, ReadContext, FS_ENCRYPT_FL, MAX_COALESCED_READS, READ_ALIGNMENT, READ_CHUNK_SIZE,
+};
+use crate::buffer::{align_up, BufPool, PooledBuf};
+#[cfg(feature = "checksum")]
+use crate::checksum::{ChecksumUnit, Hasher, UnitChecksum};
+use crate::dm;
+use crate::error::{BlkReadError, DeviceReadError, Encryption, PartialReadError, ShortReadError};
+use crate::extent_cache::FileVersion;
+use crate::lvm;
+use crate::md;
+use crate::observer::{FallbackEvent, FiemapEvent};
+use crate::options::{EncodedPolicy, InlinePolicy, Options};
+use crate::progress::ProgressEvent;
+use crate::state::{is_encoded, NonzeroRange, PlannedRead, SegmentSource, State};
+use crate::stats::{self, Counter};
+use crate::sys;
+#[cfg(feature = "tracing")]
+use crate::telemetry;
+
+use blkmap::FiemapExtent;
+
+use std::io;
+use std::mem;
+#[cfg(feature = "checksum")]
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+impl<'a> ReadContext<'a> {
+    /// Query the extents of `offset..offset + length` with FIEMAP.
+    ///
+    /// The file's data is written out first if
+    /// [`Options::sync_first`] is set.
+    fn fiemap_range(&self, offset: u64, length: u64) -> io::Result<Vec<FiemapExtent>> {
+        check_filesystem(self.file, self.path)?;
+        if self.options.sync_first {
+            self.file.sync_data()?;
+        }
+        let started = Instant::now();
+        let extents = fiemap_range(self.file, offset, length, self.options)
+            .map_err(fiemap_failed(self.file, self.path))?;
+        self.observe(|observer| {
+            observer.on_fiemap(&FiemapEvent {
+                offset,
+                length,
+                extents: &extents,
+                duration: started.elapsed(),
+            })
+        });
+        Ok(extents)
+    }
+
+    /// Extents of a range, from the attached extent map, the extent maps
+    /// cached in the process ([`Options::cache_extents`]) or on disk
+    /// ([`Options::disk_extent_cache`]), or FIEMAP.
+    pub(super) fn extents(&self, offset: u64, length: u64) -> io::Result<Vec<FiemapExtent>> {
+        if let Some(map) = self.extent_map {
+            return Ok(extents_in_range(map, offset, length));
+        }
+        let disk = self.options.disk_extent_cache.as_ref();
+        if !self.options.cache_extents && disk.is_none() {
+            return self.fiemap_range(offset, length);
+        }
+
+        let version = FileVersion::of(self.file)?;
+        let memory = self.options.cache_extents.then(|| self.options.cache());
+        let cached = memory
+            .and_then(|cache| cache.get_extents(&version))
+            .or_else(|| {
+                let extents: Arc<[FiemapExtent]> = disk?.lookup(&version)?.into();
+                if let Some(cache) = memory {
+                    cache.insert_extents(version, extents.clone());
+                }
+                Some(extents)
+            });
+        let extents = match cached {
+            Some(extents) => {
+                if self.options.sync_first {
+                    self.file.sync_data()?;
+                }
+                extents
+            }
+            None => {
+                let extents: Arc<[FiemapExtent]> = self.fiemap_range(0, u64::MAX)?.into();
+                if let Some(cache) = disk {
+                    // The cache is an optimization, which never fails a read
+                    let _ = cache.store(&version, &extents);
+                }
+                if let Some(cache) = memory {
+                    cache.insert_extents(version, extents.clone());
+                }
+                extents
+            }
+        };
+        Ok(extents_in_range(&extents, offset, length))
+    }
+
+    /// Number of pages of a range whose data may not be on the device yet.
+    ///
+    /// Counts dirty and writeback pages with `cachestat`, or every cached
+    /// page with `mincore` on kernels without it.
+    fn dirty_pages(&self, offset: u64, length: u64) -> io::Result<u64> {
+        let fd = self.file.as_raw_fd();
+        match sys::cachestat(fd, offset, length) {
+            Ok(stat) => Ok(stat.nr_dirty + stat.nr_writeback),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => {
+                sys::resident_pages(fd, offset, length)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<State> {
+        #[cfg(feature = "tracing")]
+        let span = telemetry::read_span(self.path, offset, buf.len());
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        let started = Instant::now();
+        let result = if self.options.auto_align && !buf.is_empty() {
+            let (mem_align, offset_align) = self.dio_alignment();
+            let aligned = (buf.as_ptr() as u64).is_multiple_of(mem_align)
+                && offset.is_multiple_of(offset_align)
+                && (buf.len() as u64).is_multiple_of(offset_align);
+            if aligned {
+                self.read_at_aligned(buf, offset)
+            } else {
+                self.read_bounced(buf, offset, mem_align, offset_align)
+            }
+        } else {
+            self.read_at_aligned(buf, offset)
+        };
+        let result = result.map(|state| {
+            let state = self.record_unwritten(state, buf);
+            let state = self.record_checksum(state, buf, offset);
+            self.record_timing(state, |timing| timing.total = started.elapsed())
+        });
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(state) => {
+                span.record("bytes_read", state.bytes_read);
+            }
+            Err(error) => tracing::debug!(target: "blkreader", %error, "read failed"),
+        }
+        result
+    }
+
+    /// Read through an aligned bounce buffer covering the requested range.
+    fn read_bounced(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        mem_align: u64,
+        offset_align: u64,
+    ) -> io::Result<State> {
+        let start = offset - offset % offset_align;
+        let end = align_up(offset + buf.len() as u64, offset_align);
+        let mut bounce =
+            self.alloc_buf((end - start) as usize, mem_align.max(offset_align) as usize)?;
+
+        // The aligned range may extend past EOF, so only check the exact
+        // length once the requested slice has been copied back
+        let options = Options {
+            read_exact: false,
+            auto_align: false,
+            ..self.options.clone()
+        };
+        let ctx = ReadContext {
+            options: &options,
+            ..*self
+        };
+        let result = ctx.read_at_aligned(&mut bounce, start);
+
+        let skip = (offset - start) as usize;
+        let mut copy_back = |state: &mut State| {
+            let len = clip_state(state, skip, buf.len());
+            buf[..len].copy_from_slice(&bounce[skip..skip + len]);
+            len
+        };
+        let mut state = match result {
+            Ok(state) => state,
+            Err(err) if PartialReadError::from_io_error(&err).is_some() => {
+                let mut partial = *err
+                    .into_inner()
+                    .and_then(|inner| inner.downcast::<PartialReadError>().ok())
+                    .expect("error wraps a partial read");
+                copy_back(&mut partial.state);
+                return Err(partial.into());
+            }
+            Err(err) => return Err(err),
+        };
+        let len = copy_back(&mut state);
+
+        if self.options.read_exact && len < buf.len() {
+            return Err(ShortReadError {
+                expected: buf.len(),
+                bytes_read: len,
+            }
+            .into());
+        }
+        Ok(state)
+    }
+
+    /// Read a range into a buffer taken from `pool`, reading the aligned
+    /// range covering it in place rather than through a bounce buffer.
+    pub(super) fn read_pooled(
+        &self,
+        pool: &BufPool,
+        offset: u64,
+        length: usize,
+    ) -> io::Result<(PooledBuf, State)> {
+        let started = Instant::now();
+        let (mem_align, offset_align) = self.dio_alignment();
+        let start = offset - offset % offset_align;
+        let end = align_up(offset + length as u64, offset_align);
+        let mut buf = pool.take(
+            (end - start) as usize,
+            mem_align.max(offset_align) as usize,
+            self.buffer_node(),
+        )?;
+
+        let options = Options {
+            read_exact: false,
+            auto_align: false,
+            ..self.options.clone()
+        };
+        let ctx = ReadContext {
+            options: &options,
+            ..*self
+        };
+        let skip = (offset - start) as usize;
+        let result = ctx.read_at_aligned(&mut buf.full_mut()[..(end - start) as usize], start);
+        let mut state = match result {
+            Ok(state) => state,
+            Err(err) if PartialReadError::from_io_error(&err).is_some() => {
+                let mut partial = *err
+                    .into_inner()
+                    .and_then(|inner| inner.downcast::<PartialReadError>().ok())
+                    .expect("error wraps a partial read");
+                clip_state(&mut partial.state, skip, length);
+                return Err(partial.into());
+            }
+            Err(err) => return Err(err),
+        };
+        let len = clip_state(&mut state, skip, length);
+        buf.set_range(skip..skip + len);
+
+        if self.options.read_exact && len < length {
+            return Err(ShortReadError {
+                expected: length,
+                bytes_read: len,
+            }
+            .into());
+        }
+        let state = self.record_unwritten(state, &buf);
+        let state = self.record_checksum(state, &buf, offset);
+        let state = self.record_timing(state, |timing| timing.total = started.elapsed());
+        Ok((buf, state))
+    }
+
+    /// Read a range that is assumed to satisfy Direct I/O alignment.
+    fn read_at_aligned(&self, buf: &mut [u8], offset: u64) -> io::Result<State> {
+        if buf.is_empty() {
+            return Ok(State::fallback(Vec::new(), 0));
+        }
+
+        let length = buf.len() as u64;
+        let mut fiemap = Duration::ZERO;
+        let mut query = |fresh| {
+            let started = Instant::now();
+            let extents = self.query_extents(offset, length, fresh);
+            fiemap += started.elapsed();
+            extents
+        };
+        let extents = query(false)?;
+
+        // Probe after FIEMAP, which may have flushed the range, and before
+        // any device access
+        let dirty = if self.options.check_dirty || self.options.fail_on_dirty {
+            self.dirty_pages(offset, length)?
+        } else {
+            0
+        };
+        if dirty > 0 && self.options.fail_on_dirty {
+            return Err(BlkReadError::DirtyPages {
+                file_path: self.file_path(),
+                offset,
+                length,
+                pages: dirty,
+            }
+            .into());
+        }
+
+        let result = self.read_with_extents(buf, offset, extents);
+
+        // Refresh the extent map and retry once if the read looks stale
+        let stale = match &result {
+            Ok(state) => !self.is_valid(buf, state),
+            Err(err) => is_stale_error(err),
+        };
+        let mut state = if !stale {
+            result?
+        } else if !self.options.refresh_on_stale {
+            return result.and_then(|_| Err(validation_failed()));
+        } else {
+            let extents = query(true)?;
+            let mut state = self.read_with_extents(buf, offset, extents)?;
+            state.extents_refreshed = true;
+            if !self.is_valid(buf, &state) {
+                return Err(validation_failed());
+            }
+            state
+        };
+
+        // Data read through the page cache is consistent by itself
+        if self.options.verify_extents && !state.used_fallback && !self.options.dry_run {
+            let started = Instant::now();
+            let current = self.fiemap_range(offset, length)?;
+            fiemap += started.elapsed();
+            state.possibly_torn = !same_mapping(&state.extents, &current);
+        }
+        state.possibly_stale = dirty > 0 && !state.used_fallback;
+        Ok(self.record_timing(state, |timing| timing.fiemap = fiemap))
+    }
+
+    /// Read using a caller-supplied extent map instead of querying FIEMAP.
+    ///
+    /// Fallback is never used, since the file's current contents may no
+    /// longer correspond to the supplied extents.
+    pub(super) fn read_with_caller_extents(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        extents: &[FiemapExtent],
+    ) -> io::Result<State> {
+        if buf.is_empty() {
+            return Ok(self.record_checksum(State::fallback(Vec::new(), 0), buf, offset));
+        }
+
+        let started = Instant::now();
+        let extents = extents_in_range(extents, offset, buf.len() as u64);
+        if extents.is_empty() && !self.options.fill_holes {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no extents in requested range",
+            ));
+        }
+        let state = self.read_extents_from_device(buf, offset, extents)?;
+        if !self.is_valid(buf, &state) {
+            return Err(validation_failed());
+        }
+        let state = self.record_unwritten(state, buf);
+        let state = self.record_checksum(state, buf, offset);
+        Ok(self.record_timing(state, |timing| timing.total = started.elapsed()))
+    }
+
+    /// Read the entire file in aligned chunks.
+    ///
+    /// Holes are filled whether or not the options ask for it, since
+    /// skipping them would shift the data after them; a chunk coming back
+    /// short before the file size, as when the file shrinks meanwhile,
+    /// fails the read instead of returning part of the file.
+    pub(super) fn read_to_end(&self) -> io::Result<Vec<u8>> {
+        let filled;
+        let ctx = if self.options.fill_holes {
+            *self
+        } else {
+            filled = self.options.clone().with_fill_holes(true);
+            ReadContext {
+                options: &filled,
+                ..*self
+            }
+        };
+        let file_size = self.file.metadata()?.len();
+        let mut data = Vec::with_capacity(file_size as usize);
+        let mut buf = self.alloc_buf(READ_CHUNK_SIZE, READ_ALIGNMENT)?;
+        let mut offset = 0u64;
+
+        while offset < file_size {
+            let wanted = (file_size - offset).min(READ_CHUNK_SIZE as u64) as usize;
+            let aligned = align_up(wanted as u64, READ_ALIGNMENT as u64) as usize;
+
+            let state = ctx.read_at(&mut buf[..aligned], offset)?;
+            if state.bytes_read < wanted {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "read {} of {} bytes at offset {} of a {} byte file",
+                        state.bytes_read, wanted, offset, file_size
+                    ),
+                ));
+            }
+            data.extend_from_slice(&buf[..wanted]);
+            offset += wanted as u64;
+        }
+
+        Ok(data)
+    }
+
+    /// Fill the caches later reads of the file use: its extent map, if
+    /// extent maps are cached, and the handles of the devices holding it.
+    pub(crate) fn warm(&self) -> io::Result<()> {
+        self.warm_with(self.options.cache_extents || self.options.disk_extent_cache.is_some())
+    }
+
+    /// Like [`warm`](Self::warm), and with `query_extents` also open the
+    /// devices the file's extents are translated to, which only the
+    /// extents tell.
+    pub(crate) fn warm_with(&self, query_extents: bool) -> io::Result<()> {
+        let extents = if query_extents {
+            Some(self.extents(0, u64::MAX)?)
+        } else {
+            None
+        };
+        if !self.caches_devices() || self.options.dry_run {
+            // No handle would be kept, so only check there is a device
+            check_filesystem(self.file, self.path)?;
+            self.device_path()?;
+            return Ok(());
+        }
+
+        self.get_device_handle()?;
+        // Members of multi-device filesystems and devices below
+        // device-mapper targets are only known from the extents
+        let Some(extents) = extents else {
+            return Ok(());
+        };
+        if let Some(placement) = self.placement(&extents)? {
+            for path in &placement.devices {
+                self.open_with_fallback(|direct| self.open_placed_device(path, direct))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Query extent information for the requested range.
+    ///
+    /// Uses the attached extent map or the disk extent cache, if any,
+    /// unless `fresh` is set.
+    pub(super) fn query_extents(
+        &self,
+        offset: u64,
+        length: u64,
+        fresh: bool,
+    ) -> io::Result<Vec<FiemapExtent>> {
+        let extents = if fresh {
+            // The cached map may be the stale one, moved without the file's
+            // metadata changing
+            if self.options.cache_extents {
+                self.options.cache().invalidate_extents(self.file)?;
+            }
+            if let Some(cache) = &self.options.disk_extent_cache {
+                let _ = cache.remove(self.file);
+            }
+            self.fiemap_range(offset, length)?
+        } else {
+            self.extents(offset, length)?
+        };
+
+        // A range without extents is a hole, which is only readable when filling holes
+        if extents.is_empty() && !self.options.fill_holes {
+            if self.options.read_exact {
+                return Err(ShortReadError {
+                    expected: length as usize,
+                    bytes_read: 0,
+                }
+                .into());
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file has no extents",
+            ));
+        }
+
+        Ok(extents)
+    }
+
+    /// Read the requested range using the given extents.
+    fn read_with_extents(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        extents: Vec<FiemapExtent>,
+    ) -> io::Result<State> {
+        let length = buf.len() as u64;
+
+        // Check if fallback is allowed and safe
+        if self.options.allow_fallback && self.can_use_fallback(&extents, offset, length) {
+            return self.fallback_read(buf, offset, extents);
+        }
+
+        self.read_extents_from_device(buf, offset, extents)
+    }
+
+    /// Read the requested range from the block device using the given extents.
+    fn read_extents_from_device(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        extents: Vec<FiemapExtent>,
+    ) -> io::Result<State> {
+        self.check_extents(&extents)?;
+        if let Some(placement) = self.placement(&extents)? {
+            let ciphertext = self.check_ciphertext(&placement.devices)?;
+            let mut state = self.read_placed(buf, offset, extents, &placement)?;
+            // The devices read are below the file's device
+            self.describe_device(&mut state, &self.device_path()?, ciphertext);
+            return Ok(state);
+        }
+
+        self.with_timed_device(|device, resolve| {
+            let ciphertext = self.check_ciphertext(std::slice::from_ref(device.path()))?;
+            let outcome = self.read_from_device(device, buf, offset, &extents)?;
+
+            let mut state = device.state(extents, outcome.bytes_read);
+            state.synthesized = outcome.synthesized;
+            state.planned = outcome.planned;
+            state.unreadable = outcome.unreadable;
+            state.set_segments(outcome.segments);
+            self.describe_device(&mut state, device.path(), ciphertext);
+            Ok(self.record_timing(state, |timing| {
+                timing.device_resolve = resolve;
+                timing.reads = outcome.reads;
+            }))
+        })
+    }
+
+    /// Check whether the file's device blocks on `devices` are ciphertext.
+    ///
+    /// Fails with [`BlkReadError::Ciphertext`] if they are, unless
+    /// [`Options::allow_ciphertext`] is set.
+    pub(super) fn check_ciphertext(&self, devices: &[PathBuf]) -> io::Result<bool> {
+        // Filesystems without inode flags don't support fscrypt either
+        let fscrypt =
+            sys::inode_flags(self.file.as_raw_fd()).is_ok_and(|flags| flags & FS_ENCRYPT_FL != 0);
+        let encrypted = devices.iter().find_map(|device| {
+            let encryption = if fscrypt {
+                Encryption::Fscrypt
+            } else {
+                Encryption::DmCrypt {
+                    mapping: dm::crypt_mapping(device)?,
+                }
+            };
+            Some((device, encryption))
+        });
+        match encrypted {
+            None => Ok(false),
+            Some(_) if self.options.allow_ciphertext => Ok(true),
+            Some((device, encryption)) => Err(BlkReadError::Ciphertext {
+                file_path: self.file_path(),
+                device_path: device.clone(),
+                encryption,
+            }
+            .into()),
+        }
+    }
+
+    /// Record in `state` what the file's device `device` is.
+    pub(super) fn describe_device(&self, state: &mut State, device: &Path, ciphertext: bool) {
+        state.lvm = lvm::volume(device, self.options.enable_cache);
+        state.md = md::array(device);
+        state.ciphertext = ciphertext;
+    }
+
+    /// Run the caller-provided validator, if any, over the returned data.
+    fn is_valid(&self, buf: &[u8], state: &State) -> bool {
+        match &self.options.validator {
+            Some(validator) => validator.validate(&buf[..state.bytes_read]),
+            None => true,
+        }
+    }
+
+    /// Compute the digests of the returned data, read at `offset`, if
+    /// [`Options::checksum`] is set.
+    #[cfg(feature = "checksum")]
+    fn record_checksum(&self, mut state: State, buf: &[u8], offset: u64) -> State {
+        // Dry runs leave the buffer alone
+        let Some(checksum) = self.options.checksum.filter(|_| !self.options.dry_run) else {
+            return state;
+        };
+        let data = &buf[..state.bytes_read.min(buf.len())];
+
+        let end = offset + data.len() as u64;
+        let mut units: Vec<Range<u64>> = match self.options.checksum_unit {
+            None | Some(ChecksumUnit::Block(0)) => Vec::new(),
+            Some(ChecksumUnit::Extent) => state
+                .extents
+                .iter()
+                .filter_map(|extent| {
+                    let start = extent.logical.max(offset);
+                    let stop = (extent.logical + extent.length).min(end);
+                    (start < stop).then_some(start..stop)
+                })
+                .collect(),
+            Some(ChecksumUnit::Block(size)) => {
+                let mut blocks = Vec::new();
+                let mut start = offset;
+                while start < end {
+                    let stop = (start - start % size).saturating_add(size).min(end);
+                    blocks.push(start..stop);
+                    start = stop;
+                }
+                blocks
+            }
+        };
+        // Caller-supplied extents may come in any order
+        units.sort_by_key(|unit| unit.start);
+
+        // One pass over the data: each unit is added to the digest of the
+        // whole read as it is digested on its own
+        let mut whole = Hasher::new(checksum);
+        let mut digested = 0;
+        state.unit_checksums = units
+            .into_iter()
+            .map(|unit| {
+                let range = (unit.start - offset) as usize..(unit.end - offset) as usize;
+                let stop = range.end.max(digested);
+                whole.update(&data[digested..stop]);
+                digested = stop;
+                let mut hasher = Hasher::new(checksum);
+                hasher.update(&data[range]);
+                UnitChecksum {
+                    logical: unit.start,
+                    length: unit.end - unit.start,
+                    digest: hasher.finish(),
+                }
+            })
+            .collect();
+        whole.update(&data[digested..]);
+        state.checksum = Some(whole.finish());
+        state
+    }
+
+    /// Without the `checksum` feature, reads compute no digests.
+    #[cfg(not(feature = "checksum"))]
+    fn record_checksum(&self, state: State, _buf: &[u8], _offset: u64) -> State {
+        state
+    }
+
+    /// List the sectors of unwritten extents read raw from the device that
+    /// hold nonzero bytes, if [`Options::check_unwritten`] is set.
+    fn record_unwritten(&self, mut state: State, buf: &[u8]) -> State {
+        const SECTOR: u64 = 512;
+
+        if !self.options.check_unwritten || self.options.dry_run {
+            return state;
+        }
+        let mut nonzero: Vec<NonzeroRange> = Vec::new();
+        for segment in &state.segments {
+            let SegmentSource::Device { physical } = segment.source else {
+                continue;
+            };
+            let segment_end = segment.logical + segment.range.len() as u64;
+            for extent in state.extents.iter().filter(|e| e.flags.is_unwritten()) {
+                let mut pos = extent.logical.max(segment.logical);
+                let end = (extent.logical + extent.length).min(segment_end);
+                while pos < end {
+                    let sector = physical + (pos - segment.logical);
+                    let stop = (pos + SECTOR - sector % SECTOR).min(end);
+                    let start_index = segment.range.start + (pos - segment.logical) as usize;
+                    let stop_index = segment.range.start + (stop - segment.logical) as usize;
+                    if buf[start_index..stop_index].iter().any(|&byte| byte != 0) {
+                        match nonzero.last_mut() {
+                            Some(last)
+                                if last.logical + last.length == pos
+                                    && last.physical + last.length == sector =>
+                            {
+                                last.length += stop - pos
+                            }
+                            _ => nonzero.push(NonzeroRange {
+                                logical: pos,
+                                physical: sector,
+                                length: stop - pos,
+                            }),
+                        }
+                    }
+                    pos = stop;
+                }
+            }
+        }
+        state.unwritten_nonzero = nonzero;
+        state
+    }
+
+    /// Reject inline and encoded extents whose policy is to fail.
+    ///
+    /// Inline extents read through the file are never rejected as encoded.
+    pub(super) fn check_extents(&self, extents: &[FiemapExtent]) -> io::Result<()> {
+        for (extent_index, extent) in extents.iter().enumerate() {
+            let flags = &extent.flags;
+            let (file_path, logical_offset, length) =
+                (self.file_path(), extent.logical, extent.length);
+            if flags.is_inline() {
+                if self.options.inline_policy == InlinePolicy::Error {
+                    return Err(BlkReadError::InlineExtent {
+                        file_path,
+                        extent_index,
+                        logical_offset,
+                        length,
+                    }
+                    .into());
+                }
+            } else if is_encoded(flags) && self.options.encoded_policy == EncodedPolicy::Error {
+                return Err(BlkReadError::EncodedExtent {
+                    file_path,
+                    extent_index,
+                    logical_offset,
+                    length,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if we can safely use fallback (regular file I/O).
+    ///
+    /// Fallback is safe if:
+    /// 1. All extents fully cover the requested range
+    /// 2. No extents are unwritten
+    /// 3. No holes in the range
+    pub(super) fn can_use_fallback(
+        &self,
+        extents: &[FiemapExtent],
+        offset: u64,
+        length: u64,
+    ) -> bool {
+        if extents.is_empty() {
+            return false;
+        }
+
+        let end = offset + length;
+        let mut current = offset;
+
+        for extent in extents {
+            // Check for hole before this extent
+            if extent.logical > current {
+                return false;
+            }
+
+            // Check for unwritten extent
+            if extent.flags.is_unwritten() {
+                return false;
+            }
+
+            // Check for unknown/delalloc (hole-like)
+            if extent.flags.is_unknown() || extent.flags.is_delalloc() {
+                return false;
+            }
+
+            // Update current position
+            let extent_end = extent.logical + extent.length;
+            if extent_end >= end {
+                return true;
+            }
+            current = extent_end;
+        }
+
+        false
+    }
+
+    /// Perform a fallback read using regular file I/O.
+    fn fallback_read(
+        &self,
+        buf: &mut [u8],
+        offset: u64,
+        extents: Vec<FiemapExtent>,
+    ) -> io::Result<State> {
+        // Check if we read the exact requested length
+        let bytes_read = if self.options.dry_run {
+            // In dry run mode, simulate read without actual I/O
+            buf.len()
+        } else if self.options.read_exact {
+            let result = read_full_at(self.file, buf, offset, self.options.read_flags);
+            stats::count_result(Counter::FallbackBytes, &result);
+            let bytes_read = result?;
+            if bytes_read < buf.len() {
+                return Err(ShortReadError {
+                    expected: buf.len(),
+                    bytes_read,
+                }
+                .into());
+            }
+            bytes_read
+        } else {
+            let flags = self.options.read_flags.bits();
+            let result = sys::pread(self.file.as_raw_fd(), buf, offset, flags);
+            stats::count_result(Counter::FallbackBytes, &result);
+            result?
+        };
+
+        if let Some(progress) = &self.options.progress {
+            progress.report(ProgressEvent {
+                bytes_planned: buf.len() as u64,
+                bytes_read: bytes_read as u64,
+                bytes_filled: 0,
+                logical_offset: offset + bytes_read as u64,
+            });
+        }
+
+        self.observe(|observer| {
+            observer.on_fallback(&FallbackEvent {
+                offset,
+                length: buf.len() as u64,
+                bytes_read: bytes_read as u64,
+            })
+        });
+
+        let mut state = State::fallback(extents, bytes_read);
+        state.set_segments(fallback_segments(offset, bytes_read));
+        if self.options.dry_run {
+            state.planned.push(PlannedRead::File {
+                logical: offset,
+                length: bytes_read as u64,
+            });
+        }
+        Ok(state)
+    }
+
+    /// Notify the progress callback, if any, of the current read position.
+    pub(super) fn report_progress(
+        &self,
+        outcome: &ReadOutcome,
+        planned: usize,
+        logical_offset: u64,
+    ) {
+        if let Some(progress) = &self.options.progress {
+            progress.report(ProgressEvent {
+                bytes_planned: planned as u64,
+                bytes_read: (outcome.bytes_read - outcome.bytes_filled) as u64,
+                bytes_filled: outcome.bytes_filled as u64,
+                logical_offset,
+            });
+        }
+    }
+
+    /// Plan the steps that produce `offset..offset + length`, in logical order.
+    ///
+    /// Planning stops at the first hole (or hole-like extent) unless holes
+    /// are filled.
+    pub(super) fn plan_steps(
+        &self,
+        offset: u64,
+        length: u64,
+        extents: &[FiemapExtent],
+    ) -> Vec<Step> {
+        let end = offset + length;
+        let mut steps = Vec::new();
+        let mut current = offset;
+
+        for (index, extent) in extents.iter().enumerate() {
+            if current >= end {
+                break;
+            }
+
+            // Hole before this extent
+            if extent.logical > current {
+                if !self.options.fill_holes {
+                    return steps;
+                }
+                let hole_end = extent.logical.min(end);
+                steps.push(Step::fill(current, hole_end - current, SegmentSource::Hole));
+                current = hole_end;
+                if current >= end {
+                    break;
+                }
+            }
+
+            let stop = (extent.logical + extent.length).min(end);
+            let flags = extent.flags;
+            if flags.is_unknown() || flags.is_delalloc() {
+                // Hole-like extents have no data on the device
+                if !self.options.fill_holes {
+                    return steps;
+                }
+                steps.push(Step::fill(current, stop - current, SegmentSource::Hole));
+            } else if flags.is_unwritten() && self.options.zero_unwritten {
+                steps.push(Step::fill(
+                    current,
+                    stop - current,
+                    SegmentSource::Unwritten,
+                ));
+            } else if flags.is_inline() {
+                // The physical offset points into metadata, not at the data
+                steps.push(Step::File {
+                    logical: current,
+                    len: (stop - current) as usize,
+                });
+            } else if is_encoded(&flags) && self.options.encoded_policy == EncodedPolicy::Fill {
+                steps.push(Step::fill(current, stop - current, SegmentSource::Encoded));
+            } else {
+                // Normal extent (or unwritten with zero_unwritten=false)
+                steps.push(Step::Device {
+                    extent_index: index,
+                    logical: current,
+                    physical: extent.physical + (current - extent.logical),
+                    len: (stop - current) as usize,
+                });
+            }
+            current = stop;
+        }
+
+        // Trailing hole
+        if current < end && self.options.fill_holes {
+            steps.push(Step::fill(current, end - current, SegmentSource::Hole));
+        }
+        steps
+    }
+
+    /// Group steps, in execution order, into runs serviced by one device read.
+    ///
+    /// With [`Options::coalesce_gap`](Options::coalesce_gap), consecutive
+    /// device reads whose physical ranges are separated by at most that many
+    /// bytes are merged; everything else is a run of its own.
+    pub(super) fn coalesce(&self, steps: &[Step], order: Vec<usize>) -> Vec<Vec<usize>> {
+        let mut runs: Vec<Vec<usize>> = Vec::new();
+        for i in order {
+            if let (Some(max_gap), Step::Device { physical, .. }) =
+                (self.options.coalesce_gap, steps[i])
+            {
+                if let Some(run) = runs.last_mut() {
+                    if let Step::Device {
+                        physical: prev_physical,
+                        len: prev_len,
+                        ..
+                    } = steps[*run.last().expect("run is non-empty")]
+                    {
+                        let prev_end = prev_physical + prev_len as u64;
+                        if physical >= prev_end
+                            && physical - prev_end <= max_gap
+                            && run.len() < MAX_COALESCED_READS
+                        {
+                            run.push(i);
+                            continue;
+                        }
+                    }
+                }
+            }
+            runs.push(vec![i]);
+        }
+        runs
+    }
+}
+
+/// Narrow the state of a read into an aligned buffer to the `len` bytes
+/// starting `skip` bytes into it, returning the number of those bytes read.
+fn clip_state(state: &mut State, skip: usize, len: usize) -> usize {
+    let len = state.bytes_read.saturating_sub(skip).min(len);
+    state.bytes_read = len;
+    state.synthesized = state
+        .synthesized
+        .iter()
+        .map(|range| range.start.max(skip) - skip..range.end.min(skip + len).max(skip) - skip)
+        .filter(|range| !range.is_empty())
+        .collect();
+    let segments = mem::take(&mut state.segments)
+        .into_iter()
+        .filter_map(|mut segment| {
+            let start = segment.range.start.max(skip);
+            let end = segment.range.end.min(skip + len);
+            if start >= end {
+                return None;
+            }
+            segment.advance(start - segment.range.start);
+            segment.range = start - skip..end - skip;
+            Some(segment)
+        })
+        .collect();
+    state.set_segments(segments);
+    len
+}
+
+/// Whether two extent maps place the same data at the same physical locations.
+///
+/// Flags are ignored, since e.g. writing an unwritten extent changes its
+/// flags but not its location.
+fn same_mapping(before: &[FiemapExtent], after: &[FiemapExtent]) -> bool {
+    before.len() == after.len()
+        && before.iter().zip(after).all(|(a, b)| {
+            a.logical == b.logical && a.physical == b.physical && a.length == b.length
+        })
+}
+
+/// Whether a read error may be caused by a stale extent map.
+fn is_stale_error(err: &io::Error) -> bool {
+    DeviceReadError::from_io_error(err)
+        .is_some_and(|context| context.source.raw_os_error() == Some(libc::EIO))
+}
+
+/// Error returned when the read data fails the caller-provided validator.
+fn validation_failed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "read data failed the validity check",
+    )
+}
+
+/// A planned step of a device read.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Step {
+    /// Fill `len` bytes for a hole or unwritten extent, as told by `source`.
+    Fill {
+        logical: u64,
+        len: usize,
+        source: SegmentSource,
+    },
+    /// Read `len` bytes from the device.
+    Device {
+        extent_index: usize,
+        logical: u64,
+        physical: u64,
+        len: usize,
+    },
+    /// Read `len` bytes through the file, for inline data.
+    File { logical: u64, len: usize },
+}
+
+impl Step {
+    fn fill(logical: u64, len: u64, source: SegmentSource) -> Self {
+        Step::Fill {
+            logical,
+            len: len as usize,
+            source,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        match *self {
+            Step::Fill { len, .. } | Step::Device { len, .. } | Step::File { len, .. } => len,
+        }
+    }
+
+    pub(super) fn logical(&self) -> u64 {
+        match *self {
+            Step::Fill { logical, .. }
+            | Step::Device { logical, .. }
+            | Step::File { logical, .. } => logical,
+        }
+    }
+
+    pub(super) fn logical_end(&self) -> u64 {
+        match *self {
+            Step::Fill { logical, len, .. }
+            | Step::Device { logical, len, .. }
+            | Step::File { logical, len } => logical + len as u64,
+        }
+    }
+
+    pub(super) fn physical(&self) -> u64 {
+        match *self {
+            Step::Device { physical, .. } => physical,
+            Step::Fill { .. } | Step::File { .. } => {
+                unreachable!("only device reads have a physical location")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BlockDeviceBackend;
+    use crate::buffer::AlignedBuf;
+    use crate::cache::open_device_uncached_at;
+    use crate::engine::{DeviceRead, ReadFlags};
+    use crate::observer::{ExtentReadEvent, FillEvent};
+    use crate::options::NumaNode;
+    use crate::reader::{is_einval, BlkFile, BlkReader, DeviceHandle};
+    use crate::state::Segment;
+    use crate::test_support::{fake_device, fixture, image_of, stand_in_file};
+
+    use std::fs::File;
+    use std::os::unix::io::RawFd;
+    use std::sync::{Mutex, OnceLock};
+
+    #[test]
+    fn test_can_use_fallback() {
+        use blkmap::ExtentFlags;
+
+        let file = stand_in_file();
+        let options = Options::new().with_allow_fallback(true);
+        let ctx = ReadContext::new(&file, &options);
+
+        // Empty extents - cannot fallback
+        assert!(!ctx.can_use_fallback(&[], 0, 100));
+
+        // Normal extent covering range - can fallback
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 1000,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        assert!(ctx.can_use_fallback(&extents, 0, 100));
+
+        // Unwritten extent - cannot fallback
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 1000,
+            length: 4096,
+            flags: ExtentFlags::UNWRITTEN,
+        }];
+        assert!(!ctx.can_use_fallback(&extents, 0, 100));
+
+        // Hole at start - cannot fallback
+        let extents = vec![FiemapExtent {
+            logical: 100,
+            physical: 1000,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        assert!(!ctx.can_use_fallback(&extents, 0, 200));
+    }
+
+    #[test]
+    fn test_synthesized_ranges() {
+        use blkmap::ExtentFlags;
+
+        let (file, device) = fixture(&[0xAB; 4096]);
+        let options = Options::new().with_fill_holes(true);
+        let ctx = ReadContext::new(&file, &options);
+
+        // Hole, data, hole-like (delalloc), trailing hole
+        let extents = vec![
+            FiemapExtent {
+                logical: 1024,
+                physical: 0,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 2048,
+                physical: 0,
+                length: 1024,
+                flags: ExtentFlags::DELALLOC,
+            },
+        ];
+
+        let mut buf = vec![0xFFu8; 4096];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+
+        assert_eq!(outcome.bytes_read, 4096);
+        assert_eq!(outcome.synthesized, vec![0..1024, 2048..4096]);
+        assert!(buf[..1024].iter().all(|&b| b == 0));
+        assert!(buf[1024..2048].iter().all(|&b| b == 0xAB));
+        assert!(buf[2048..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_fill_byte() {
+        use blkmap::ExtentFlags;
+
+        let (file, device) = fixture(&[0xAB; 4096]);
+        let options = Options::new()
+            .with_fill_holes(true)
+            .with_zero_unwritten(true)
+            .with_fill_byte(0xDE);
+        let ctx = ReadContext::new(&file, &options);
+
+        // Hole, unwritten extent, data
+        let extents = vec![
+            FiemapExtent {
+                logical: 512,
+                physical: 0,
+                length: 512,
+                flags: ExtentFlags::UNWRITTEN,
+            },
+            FiemapExtent {
+                logical: 1024,
+                physical: 0,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+        ];
+
+        let mut buf = vec![0u8; 2048];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+
+        assert_eq!(outcome.bytes_read, 2048);
+        assert_eq!(outcome.synthesized, vec![0..1024]);
+        assert!(buf[..1024].iter().all(|&b| b == 0xDE));
+        assert!(buf[1024..].iter().all(|&b| b == 0xAB));
+    }
+
+    #[test]
+    fn test_fill_byte_delalloc_and_trailing_hole() {
+        use blkmap::ExtentFlags;
+
+        let (file, device) = fixture(&[0xAB; 4096]);
+        let options = Options::new().with_fill_holes(true).with_fill_byte(0xFF);
+        let ctx = ReadContext::new(&file, &options);
+
+        // Data, delayed allocation, then a trailing hole
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 0,
+                length: 512,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 512,
+                physical: 0,
+                length: 512,
+                flags: ExtentFlags::DELALLOC,
+            },
+        ];
+
+        let mut buf = vec![0u8; 2048];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+
+        assert_eq!(outcome.bytes_read, 2048);
+        assert_eq!(outcome.synthesized, vec![512..2048]);
+        assert!(buf[..512].iter().all(|&b| b == 0xAB));
+        assert!(buf[512..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_inline_extents() {
+        use blkmap::ExtentFlags;
+        use std::io::Write;
+
+        let slot = OnceLock::new();
+        let _ = slot.set(fake_device(&[0xAB; 8192]));
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x11; 4096]).unwrap();
+        temp.write_all(&[0x22; 4096]).unwrap();
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 4096,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 4096,
+                physical: 0,
+                length: 4096,
+                flags: ExtentFlags::DATA_INLINE | ExtentFlags::LAST,
+            },
+        ];
+
+        // Inline data is read through the file
+        let options = Options::new();
+        let ctx = ReadContext::new(temp.as_file(), &options).with_device_slot(&slot);
+        let mut buf = vec![0u8; 8192];
+        let state = ctx.read_with_caller_extents(&mut buf, 0, &extents).unwrap();
+        assert_eq!(state.bytes_read, 8192);
+        assert!(buf[..4096].iter().all(|&b| b == 0xAB));
+        assert!(buf[4096..].iter().all(|&b| b == 0x22));
+        assert_eq!(state.segments.len(), 2);
+        assert_eq!(state.segments[1].source, SegmentSource::Fallback);
+        assert_eq!(state.device_bytes_read, 4096);
+
+        let options = Options::new().with_inline_policy(InlinePolicy::Error);
+        let ctx = ReadContext::new(temp.as_file(), &options).with_device_slot(&slot);
+        let err = ctx
+            .read_with_caller_extents(&mut buf, 0, &extents)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::InlineExtent {
+                extent_index: 1,
+                logical_offset: 4096,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_encoded_extents() {
+        use blkmap::ExtentFlags;
+
+        let slot = OnceLock::new();
+        let _ = slot.set(fake_device(&[0xAB; 8192]));
+        let file = stand_in_file();
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 0,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 4096,
+                physical: 4096,
+                length: 4096,
+                flags: ExtentFlags::ENCODED | ExtentFlags::LAST,
+            },
+        ];
+        let mut buf = vec![0u8; 8192];
+
+        // Rejected by default
+        let options = Options::new();
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let err = ctx
+            .read_with_caller_extents(&mut buf, 0, &extents)
+            .unwrap_err();
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::EncodedExtent {
+                extent_index: 1,
+                ..
+            })
+        ));
+
+        let options = Options::new().with_encoded_policy(EncodedPolicy::Raw);
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let state = ctx.read_with_caller_extents(&mut buf, 0, &extents).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xAB));
+        assert_eq!(state.encoded_bytes, 4096);
+        assert_eq!(state.device_bytes_read, 8192);
+
+        let options = Options::new()
+            .with_encoded_policy(EncodedPolicy::Fill)
+            .with_fill_byte(0xEE);
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let state = ctx.read_with_caller_extents(&mut buf, 0, &extents).unwrap();
+        assert_eq!(state.bytes_read, 8192);
+        assert!(buf[..4096].iter().all(|&b| b == 0xAB));
+        assert!(buf[4096..].iter().all(|&b| b == 0xEE));
+        assert_eq!(state.segments[1].source, SegmentSource::Encoded);
+        assert_eq!(state.encoded_bytes, 4096);
+        assert_eq!(state.zero_filled_bytes, 4096);
+        assert_eq!(state.synthesized, vec![4096..8192]);
+    }
+
+    #[test]
+    fn test_buffered_fallback() {
+        use blkmap::ExtentFlags;
+        use std::io::Write;
+
+        let mut data = tempfile::NamedTempFile::new().unwrap();
+        data.write_all(&[0x5A; 8192]).unwrap();
+        let device = open_device_uncached_at(&BlockDeviceBackend, data.path(), true).unwrap();
+        let slot = OnceLock::new();
+        let _ = slot.set(DeviceHandle::Uncached(device));
+        let file = stand_in_file();
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 4096,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        // A buffer O_DIRECT rejects
+        let mut buf = AlignedBuf::new(4096 + 1, 4096);
+        let misaligned = &mut buf[1..];
+
+        let options = Options::new().with_cache(false);
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let err = ctx
+            .read_with_caller_extents(misaligned, 0, &extents)
+            .unwrap_err();
+        assert!(is_einval(&err));
+
+        let options = options.with_buffered_fallback(true);
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let state = ctx
+            .read_with_caller_extents(misaligned, 0, &extents)
+            .unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert!(misaligned.iter().all(|&b| b == 0x5A));
+    }
+
+    #[test]
+    fn test_timing() {
+        use blkmap::ExtentFlags;
+
+        let file = stand_in_file();
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 8192,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 4096,
+                physical: 0,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            },
+        ];
+
+        // Nothing is measured unless asked for
+        let slot = OnceLock::new();
+        let _ = slot.set(fake_device(&[0xAB; 16384]));
+        let options = Options::new();
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let mut buf = vec![0u8; 8192];
+        let state = ctx.read_with_caller_extents(&mut buf, 0, &extents).unwrap();
+        assert!(state.timing.is_none());
+
+        let options = Options::new().with_timing(true);
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let state = ctx.read_with_caller_extents(&mut buf, 0, &extents).unwrap();
+        let timing = state.timing.unwrap();
+        assert_eq!(timing.fiemap, Duration::ZERO);
+        let reads: Vec<(u64, u64, u64)> = timing
+            .reads
+            .iter()
+            .map(|read| (read.logical, read.physical, read.length))
+            .collect();
+        assert_eq!(reads, vec![(0, 8192, 4096), (4096, 0, 4096)]);
+        let device_time: Duration = timing.reads.iter().map(|read| read.duration).sum();
+        assert!(timing.total >= timing.device_resolve + device_time);
+    }
+
+    #[test]
+    fn test_read_pooled() {
+        use crate::backend::MemDevice;
+
+        let device = MemDevice::new(16384).unwrap();
+        let data: Vec<u8> = (0..8192u32).map(|i| i as u8).collect();
+        let extents = [device.place(0, 4096, &data).unwrap()];
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(9000).unwrap();
+        let options = Options::new()
+            .with_device_backend(device)
+            .with_fill_holes(true);
+        let ctx = ReadContext::new(&file, &options).with_extent_map(&extents);
+        let pool = BufPool::new();
+
+        // Unaligned, and read in place
+        let (buf, state) = ctx.read_pooled(&pool, 100, 5000).unwrap();
+        assert_eq!(&buf[..], &data[100..5100]);
+        assert_eq!(state.bytes_read, 5000);
+        assert_eq!(state.segments[0].range, 0..5000);
+        assert_eq!(state.segments[0].logical, 100);
+        drop(buf);
+        assert_eq!(pool.idle(), 1);
+
+        // Past the data, the hole is filled
+        let (buf, state) = ctx.read_pooled(&pool, 8000, 1000).unwrap();
+        assert_eq!(&buf[..192], &data[8000..]);
+        assert!(buf[192..].iter().all(|&b| b == 0));
+        assert_eq!(state.synthesized, vec![192..1000]);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_check_unwritten() {
+        use crate::backend::MemDevice;
+        use blkmap::ExtentFlags;
+
+        let device = MemDevice::new(16384).unwrap();
+        let mut data = [0u8; 4096];
+        data[1000..1030].fill(0x5a);
+        data[3000] = 1;
+        let written = device.place(0, 4096, &[0x33; 4096]).unwrap();
+        let unwritten = FiemapExtent {
+            flags: ExtentFlags::UNWRITTEN,
+            ..device.place(4096, 8192, &data).unwrap()
+        };
+        let extents = [written, unwritten];
+        let file = tempfile::tempfile().unwrap();
+        let options = Options::new()
+            .with_device_backend(device)
+            .with_check_unwritten(true);
+        let mut buf = vec![0u8; 8192];
+        let state = file
+            .blk_read_with_extents(&mut buf, 0, &extents, &options)
+            .unwrap();
+        assert_eq!(
+            state.unwritten_nonzero,
+            [
+                NonzeroRange {
+                    logical: 4096 + 512,
+                    physical: 8192 + 512,
+                    length: 1024,
+                },
+                NonzeroRange {
+                    logical: 4096 + 2560,
+                    physical: 8192 + 2560,
+                    length: 512,
+                },
+            ]
+        );
+
+        // Within the returned data only
+        let state = file
+            .blk_read_with_extents(&mut buf[..4096], 4096 + 1024, &extents, &options)
+            .unwrap();
+        assert_eq!(state.unwritten_nonzero[0].logical, 4096 + 1024);
+        assert_eq!(state.unwritten_nonzero[0].length, 512);
+
+        // Filled extents are not read, and nothing is checked by default
+        let filled = options.clone().with_zero_unwritten(true);
+        let state = file
+            .blk_read_with_extents(&mut buf, 0, &extents, &filled)
+            .unwrap();
+        assert!(state.unwritten_nonzero.is_empty());
+        let unchecked = options.with_check_unwritten(false);
+        let state = file
+            .blk_read_with_extents(&mut buf, 0, &extents, &unchecked)
+            .unwrap();
+        assert!(state.unwritten_nonzero.is_empty());
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn test_checksum() {
+        use crate::backend::MemDevice;
+        use crate::checksum::{Checksum, ChecksumUnit};
+        use std::io::Write;
+
+        // Through the device, with a hole filled
+        let device = MemDevice::new(8192).unwrap();
+        let extents = [device.place(0, 4096, &[0x71; 4096]).unwrap()];
+        let file = tempfile::tempfile().unwrap();
+        let on_device = Options::new()
+            .with_device_backend(device)
+            .with_fill_holes(true)
+            .with_checksum(Checksum::Sha256);
+        let mut buf = vec![0u8; 8192];
+        let state = file
+            .blk_read_with_extents(&mut buf, 0, &extents, &on_device)
+            .unwrap();
+        assert_eq!(state.bytes_read, 8192);
+        assert_eq!(state.checksum, Some(Checksum::Sha256.digest(&buf)));
+        assert!(state.unit_checksums.is_empty());
+
+        // Through the file, where a short read covers only the data
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x72; 6000]).unwrap();
+        temp.as_file().sync_all().unwrap();
+        let options = Options::new()
+            .with_allow_fallback(true)
+            .with_checksum(Checksum::XxHash64);
+        let state = temp.path().blk_read_at_opt(&mut buf, 0, &options).unwrap();
+        assert_eq!(state.bytes_read, 6000);
+        assert_eq!(
+            state.checksum,
+            Some(Checksum::XxHash64.digest(&[0x72; 6000]))
+        );
+
+        // Per extent, only the extents' data is digested
+        let two = [
+            extents[0],
+            FiemapExtent {
+                logical: 6144,
+                length: 1024,
+                ..extents[0]
+            },
+        ];
+        let digest = |data: &[u8]| Checksum::Crc32.digest(data);
+        let on_device = on_device.with_checksum(Checksum::Crc32);
+        let state = file
+            .blk_read_with_extents(
+                &mut buf[..6144],
+                1024,
+                &two,
+                &on_device.clone().with_checksum_unit(ChecksumUnit::Extent),
+            )
+            .unwrap();
+        let units: Vec<_> = state
+            .unit_checksums
+            .iter()
+            .map(|unit| (unit.logical, unit.length, unit.digest.clone()))
+            .collect();
+        assert_eq!(
+            units,
+            [
+                (1024, 3072, digest(&[0x71; 3072])),
+                (6144, 1024, digest(&[0x71; 1024])),
+            ]
+        );
+        // The whole read is digested in the same pass, gaps included
+        assert_eq!(state.checksum, Some(digest(&buf[..6144])));
+
+        // Caller-supplied extents out of order give the same digests
+        let reversed = [two[1], two[0]];
+        let state = file
+            .blk_read_with_extents(
+                &mut buf[..6144],
+                1024,
+                &reversed,
+                &on_device.clone().with_checksum_unit(ChecksumUnit::Extent),
+            )
+            .unwrap();
+        let logical: Vec<_> = state.unit_checksums.iter().map(|u| u.logical).collect();
+        assert_eq!(logical, [1024, 6144]);
+        assert_eq!(state.checksum, Some(digest(&buf[..6144])));
+
+        // Blocks are aligned in the file, not to the start of the read
+        let on_device = on_device.with_checksum_unit(ChecksumUnit::Block(4096));
+        let state = file
+            .blk_read_with_extents(&mut buf[..6144], 1024, &two, &on_device)
+            .unwrap();
+        let units: Vec<_> = state
+            .unit_checksums
+            .iter()
+            .map(|unit| (unit.logical, unit.length, unit.digest.clone()))
+            .collect();
+        assert_eq!(
+            units,
+            [
+                (1024, 3072, digest(&buf[..3072])),
+                (4096, 3072, digest(&buf[3072..6144])),
+            ]
+        );
+        assert_eq!(state.checksum, Some(digest(&buf[..6144])));
+
+        // Not computed unless asked for, nor by dry runs
+        let state = temp
+            .path()
+            .blk_read_at_opt(&mut buf, 0, &Options::new().with_allow_fallback(true))
+            .unwrap();
+        assert_eq!(state.checksum, None);
+        let state = temp
+            .path()
+            .blk_read_at_opt(&mut buf, 0, &options.clone().with_dry_run(true))
+            .unwrap();
+        assert_eq!(state.checksum, None);
+    }
+
+    #[test]
+    fn test_cache_extents() {
+        use crate::cache::BlkCache;
+        use crate::observer::ReadObserver;
+        use std::io::Write;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Counts FIEMAP queries.
+        #[derive(Debug, Default)]
+        struct Queries(AtomicUsize);
+
+        impl ReadObserver for Queries {
+            fn on_fiemap(&self, _: &FiemapEvent<'_>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x44; 8192]).unwrap();
+        temp.as_file().sync_all().unwrap();
+        let path = temp.path().to_path_buf();
+
+        let cache = BlkCache::new();
+        let queries = Arc::new(Queries::default());
+        let options = Options::new()
+            .with_allow_fallback(true)
+            .with_cache_handle(&cache)
+            .with_cache_extents(true)
+            .with_observer(queries.clone());
+        let read = || {
+            let mut buf = vec![0u8; 4096];
+            let state = path.blk_read_at_opt(&mut buf, 0, &options).unwrap();
+            assert_eq!(state.bytes_read, 4096);
+            queries.0.load(Ordering::Relaxed)
+        };
+
+        assert_eq!(read(), 1);
+        assert_eq!(read(), 1);
+        // Changes to the file and explicit invalidation query again
+        temp.write_all(&[0x44; 4096]).unwrap();
+        temp.as_file().sync_all().unwrap();
+        assert_eq!(read(), 2);
+        assert_eq!(read(), 2);
+        cache.invalidate_extents(temp.as_file()).unwrap();
+        assert_eq!(read(), 3);
+        // Other caches keep their own maps
+        let other = Options {
+            cache_handle: Some(BlkCache::new()),
+            ..options.clone()
+        };
+        let mut buf = vec![0u8; 4096];
+        path.blk_read_at_opt(&mut buf, 0, &other).unwrap();
+        assert_eq!(queries.0.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_disk_extent_cache() {
+        use crate::extent_cache::DiskExtentCache;
+        use crate::observer::ReadObserver;
+        use std::io::Write;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Counts FIEMAP queries.
+        #[derive(Debug, Default)]
+        struct Queries(AtomicUsize);
+
+        impl ReadObserver for Queries {
+            fn on_fiemap(&self, _: &FiemapEvent<'_>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut temp = tempfile::NamedTempFile::new_in(dir.path()).unwrap();
+        temp.write_all(&[0x33; 8192]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let path = temp.path().to_path_buf();
+        let queries = Arc::new(Queries::default());
+        let read = |cache: DiskExtentCache| {
+            let options = Options::new()
+                .with_allow_fallback(true)
+                .with_disk_extent_cache(cache)
+                .with_observer(queries.clone());
+            let mut buf = vec![0u8; 4096];
+            let state = path.blk_read_at_opt(&mut buf, 4096, &options).unwrap();
+            assert_eq!(state.bytes_read, 4096);
+            assert!(buf.iter().all(|&b| b == 0x33));
+            queries.0.load(Ordering::Relaxed)
+        };
+
+        // The first read queries the whole file, later ones use the cache,
+        // also through a new cache on the directory as after a restart
+        let cache_dir = dir.path().join("extents");
+        assert_eq!(read(DiskExtentCache::new(&cache_dir).unwrap()), 1);
+        assert_eq!(read(DiskExtentCache::new(&cache_dir).unwrap()), 1);
+        let cache = DiskExtentCache::new(&cache_dir).unwrap();
+        assert!(cache.get(temp.as_file()).unwrap().is_some());
+
+        // Changing the file invalidates the cached map
+        temp.write_all(&[0x33; 4096]).unwrap();
+        temp.as_file().sync_all().unwrap();
+        assert_eq!(read(cache.clone()), 2);
+        assert_eq!(read(cache), 2);
+    }
+
+    #[test]
+    fn test_observer() {
+        use crate::observer::ReadObserver;
+        use blkmap::ExtentFlags;
+        use std::io::Write;
+
+        /// Records the events it observes.
+        #[derive(Debug, Default)]
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl ReadObserver for Recorder {
+            fn on_fiemap(&self, event: &FiemapEvent<'_>) {
+                let extents = event.extents.len();
+                self.0.lock().unwrap().push(format!("fiemap {}", extents));
+            }
+
+            fn on_extent_read_start(&self, event: &ExtentReadEvent<'_>) {
+                let line = format!("start {} {}", event.physical, event.length);
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn on_extent_read_end(
+                &self,
+                event: &ExtentReadEvent<'_>,
+                result: Result<usize, &io::Error>,
+                _: Duration,
+            ) {
+                let line = format!("end {} {:?}", event.logical, result.ok());
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn on_hole_fill(&self, event: &FillEvent) {
+                let line = format!("fill {} {:?}", event.logical, event.source);
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn on_fallback(&self, event: &FallbackEvent) {
+                let line = format!("fallback {} {}", event.offset, event.bytes_read);
+                self.0.lock().unwrap().push(line);
+            }
+        }
+
+        let (file, device) = fixture(&[0x33; 16384]);
+        let extents = [FiemapExtent {
+            logical: 0,
+            physical: 8192,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        let recorder = Arc::new(Recorder::default());
+        let options = Options::new()
+            .with_fill_holes(true)
+            .with_observer(recorder.clone());
+        let ctx = ReadContext::new(&file, &options);
+        ctx.read_from_device(&device, &mut [0u8; 8192], 0, &extents)
+            .unwrap();
+        assert_eq!(
+            mem::take(&mut *recorder.0.lock().unwrap()),
+            ["start 8192 4096", "end 0 Some(4096)", "fill 4096 Hole"]
+        );
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x44; 4096]).unwrap();
+        temp.as_file().sync_all().unwrap();
+        let options = options.with_allow_fallback(true);
+        temp.path()
+            .blk_read_at_opt(&mut [0u8; 4096], 0, &options)
+            .unwrap();
+        assert_eq!(
+            mem::take(&mut *recorder.0.lock().unwrap()),
+            ["fiemap 1", "fallback 0 4096"]
+        );
+    }
+
+    #[test]
+    fn test_error_stages() {
+        use crate::engine::{Completion, IoEngine};
+        use blkmap::ExtentFlags;
+        use std::os::unix::io::BorrowedFd;
+
+        // procfs does not implement FIEMAP
+        let file = File::open("/proc/self/status").unwrap();
+        let err = BlkFile::from_file(file).unwrap_err();
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::FiemapFailed {
+                file_path: Some(_),
+                ..
+            })
+        ));
+
+        /// Rejects every read like Direct I/O does for unaligned requests.
+        #[derive(Debug)]
+        struct RejectingEngine;
+
+        impl IoEngine for RejectingEngine {
+            fn name(&self) -> &str {
+                "rejecting"
+            }
+
+            fn read_batch(
+                &self,
+                _fd: BorrowedFd<'_>,
+                reads: &mut [DeviceRead<'_>],
+                complete: &mut Completion<'_>,
+            ) -> io::Result<()> {
+                for index in 0..reads.len() {
+                    complete(index, Err(io::Error::from_raw_os_error(libc::EINVAL)))?;
+                }
+                Ok(())
+            }
+        }
+
+        let (file, device) = fixture(&[0u8; 16384]);
+        let options = Options::new().with_io_engine(RejectingEngine);
+        let ctx = ReadContext::new(&file, &options);
+        let read = |physical| {
+            let extents = [FiemapExtent {
+                logical: 0,
+                physical,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            }];
+            ctx.read_from_device(&device, &mut [0u8; 4096], 0, &extents)
+                .unwrap_err()
+        };
+
+        let err = read(100);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(
+            BlkReadError::from_io_error(&err),
+            Some(BlkReadError::Unaligned {
+                physical_offset: 100,
+                length: 4096,
+                alignment: 512,
+                ..
+            })
+        ));
+
+        // Aligned reads failing with EINVAL are plain device read errors
+        let err = read(4096);
+        let context = DeviceReadError::from_io_error(&err).unwrap();
+        assert_eq!(context.source.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_partial_state_on_error() {
+        use crate::engine::{Completion, IoEngine, PreadvEngine};
+        use blkmap::ExtentFlags;
+        use std::os::unix::io::BorrowedFd;
+
+        /// Fails reads starting at `bad` with `EIO`.
+        #[derive(Debug)]
+        struct FailingEngine {
+            bad: u64,
+        }
+
+        impl IoEngine for FailingEngine {
+            fn name(&self) -> &str {
+                "failing"
+            }
+
+            fn read_batch(
+                &self,
+                fd: BorrowedFd<'_>,
+                reads: &mut [DeviceRead<'_>],
+                complete: &mut Completion<'_>,
+            ) -> io::Result<()> {
+                let offsets: Vec<u64> = reads.iter().map(|read| read.offset).collect();
+                PreadvEngine.read_batch(fd, reads, &mut |index, result| {
+                    if offsets[index] == self.bad {
+                        complete(index, Err(io::Error::from_raw_os_error(libc::EIO)))
+                    } else {
+                        complete(index, result)
+                    }
+                })
+            }
+        }
+
+        let data: Vec<u8> = (0..16384).map(|i| (i % 251) as u8).collect();
+        let (file, device) = fixture(&data);
+        // Data, hole, bad data, data; the last extent comes first physically
+        let extents: Vec<FiemapExtent> = [(0, 4096), (8192, 8192), (12288, 0)]
+            .into_iter()
+            .map(|(logical, physical)| FiemapExtent {
+                logical,
+                physical,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            })
+            .collect();
+
+        for sort_physical in [false, true] {
+            let options = Options::new()
+                .with_io_engine(FailingEngine { bad: 8192 })
+                .with_fill_holes(true)
+                .with_sort_physical(sort_physical);
+            let ctx = ReadContext::new(&file, &options);
+            let mut buf = vec![0u8; 16384];
+            let err = ctx
+                .read_from_device(&device, &mut buf, 0, &extents)
+                .unwrap_err();
+
+            // In physical order, fills are only done after the device reads
+            let partial = PartialReadError::from_io_error(&err).unwrap();
+            if sort_physical {
+                assert_eq!(partial.state.bytes_read, 4096);
+                assert!(partial.state.synthesized.is_empty());
+            } else {
+                assert_eq!(partial.state.bytes_read, 8192);
+                assert_eq!(partial.state.synthesized, vec![4096..8192]);
+            }
+            assert_eq!(&buf[..4096], &data[4096..8192]);
+            let context = DeviceReadError::from_io_error(&err).unwrap();
+            assert_eq!(context.logical_offset, 8192);
+        }
+    }
+
+    #[test]
+    fn test_segments() {
+        use blkmap::ExtentFlags;
+
+        let (file, device) = fixture(&[0xAB; 16384]);
+        let options = Options::new()
+            .with_fill_holes(true)
+            .with_zero_unwritten(true);
+        let ctx = ReadContext::new(&file, &options);
+
+        // Unwritten, data, data continuing it on the device, hole, data
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 0,
+                length: 1024,
+                flags: ExtentFlags::UNWRITTEN,
+            },
+            FiemapExtent {
+                logical: 1024,
+                physical: 8192,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 2048,
+                physical: 9216,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 4096,
+                physical: 0,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+        ];
+
+        let mut buf = vec![0u8; 4608];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 512, &extents)
+            .unwrap();
+        assert_eq!(outcome.bytes_read, 4608);
+        assert_eq!(
+            outcome.segments,
+            vec![
+                Segment {
+                    range: 0..512,
+                    logical: 512,
+                    source: SegmentSource::Unwritten,
+                },
+                Segment {
+                    range: 512..2560,
+                    logical: 1024,
+                    source: SegmentSource::Device { physical: 8192 },
+                },
+                Segment {
+                    range: 2560..3584,
+                    logical: 3072,
+                    source: SegmentSource::Hole,
+                },
+                Segment {
+                    range: 3584..4608,
+                    logical: 4096,
+                    source: SegmentSource::Device { physical: 0 },
+                },
+            ]
+        );
+        let synthesized: Vec<std::ops::Range<usize>> = outcome
+            .segments
+            .iter()
+            .filter(|segment| segment.is_synthesized())
+            .map(|segment| segment.range.clone())
+            .collect();
+        assert_eq!(synthesized, outcome.synthesized);
+    }
+
+    #[test]
+    fn test_progress_events() {
+        use blkmap::ExtentFlags;
+        use std::sync::Mutex;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+
+        let (file, device) = fixture(&[0xAB; 4096]);
+        let options = Options::new()
+            .with_fill_holes(true)
+            .with_progress(move |event| sink.lock().unwrap().push(event));
+        let ctx = ReadContext::new(&file, &options);
+
+        let extents = vec![FiemapExtent {
+            logical: 1024,
+            physical: 0,
+            length: 1024,
+            flags: ExtentFlags::empty(),
+        }];
+
+        let mut buf = vec![0u8; 4096];
+        ctx.read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+
+        let events = events.lock().unwrap();
+        let progress: Vec<_> = events
+            .iter()
+            .map(|e| (e.bytes_read, e.bytes_filled, e.logical_offset))
+            .collect();
+        assert_eq!(
+            progress,
+            vec![(0, 1024, 1024), (1024, 1024, 2048), (1024, 3072, 4096)]
+        );
+        assert!(events.iter().all(|e| e.bytes_planned == 4096));
+    }
+
+    #[test]
+    fn test_stale_error_detection() {
+        let eio: io::Error = DeviceReadError {
+            file_path: None,
+            device_path: PathBuf::from("/dev/fake"),
+            extent_index: 0,
+            logical_offset: 0,
+            physical_offset: 0,
+            length: 512,
+            source: io::Error::from_raw_os_error(libc::EIO),
+        }
+        .into();
+        assert!(is_stale_error(&eio));
+
+        // Plain EIO without device read context does not trigger a refresh
+        assert!(!is_stale_error(&io::Error::from_raw_os_error(libc::EIO)));
+    }
+
+    #[test]
+    fn test_refresh_on_stale() {
+        use std::io::Write;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x5A; 4096]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        // Reject the first read only
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let options = Options::new()
+            .with_allow_fallback(true)
+            .with_refresh_on_stale(true)
+            .with_validator(move |data| {
+                counter.fetch_add(1, Ordering::SeqCst) > 0 && data.iter().all(|&b| b == 0x5A)
+            });
+
+        let mut buf = vec![0u8; 4096];
+        let state = temp.path().blk_read_at_opt(&mut buf, 0, &options).unwrap();
+        assert!(state.extents_refreshed);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Without refresh, a failed validation is reported as invalid data
+        let options = Options::new()
+            .with_allow_fallback(true)
+            .with_validator(|_| false);
+        let err = temp
+            .path()
+            .blk_read_at_opt(&mut buf, 0, &options)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_extents_in_range() {
+        use blkmap::ExtentFlags;
+
+        let map: Vec<_> = (0..4)
+            .map(|i| FiemapExtent {
+                logical: i * 4096,
+                physical: 1 << 20,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            })
+            .collect();
+
+        let selected = extents_in_range(&map, 4096, 4096);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].logical, 4096);
+
+        let selected = extents_in_range(&map, 4000, 200);
+        assert_eq!(selected.len(), 2);
+
+        assert!(extents_in_range(&map, 16384, 4096).is_empty());
+    }
+
+    #[test]
+    fn test_dirty_pages() {
+        use std::io::Write;
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x5A; 8192]).unwrap();
+        let file = temp.as_file();
+        let options = Options::new().with_fail_on_dirty(true);
+        let ctx = ReadContext::new(file, &options);
+        assert!(ctx.dirty_pages(0, 8192).unwrap() > 0);
+
+        let mut buf = vec![0u8; 8192];
+        match ctx.read_at(&mut buf, 0) {
+            Err(e) => {
+                if let Some(BlkReadError::DirtyPages { offset, pages, .. }) =
+                    BlkReadError::from_io_error(&e)
+                {
+                    assert_eq!(*offset, 0);
+                    assert!(*pages > 0);
+                    assert_eq!(e.kind(), io::ErrorKind::ResourceBusy);
+                }
+            }
+            Ok(state) => panic!("read of dirty range succeeded: {:?}", state),
+        }
+
+        // Written back pages are clean, which only cachestat can tell
+        file.sync_all().unwrap();
+        if sys::cachestat(file.as_raw_fd(), 0, 8192).is_ok() {
+            assert_eq!(ctx.dirty_pages(0, 8192).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_sync_first() {
+        use std::io::Write;
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x5A; 8192]).unwrap();
+        let file = temp.as_file();
+        let options = Options::new().with_sync_first(true);
+        let ctx = ReadContext::new(file, &options);
+
+        match ctx.fiemap_range(0, 8192) {
+            Ok(extents) => assert!(extents.iter().all(|extent| !extent.flags.is_delalloc())),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+        }
+        if sys::cachestat(file.as_raw_fd(), 0, 8192).is_ok() {
+            assert_eq!(ctx.dirty_pages(0, 8192).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_same_mapping() {
+        use blkmap::ExtentFlags;
+
+        let extent = FiemapExtent {
+            logical: 0,
+            physical: 1 << 20,
+            length: 8192,
+            flags: ExtentFlags::UNWRITTEN,
+        };
+        let written = FiemapExtent {
+            flags: ExtentFlags::empty(),
+            ..extent
+        };
+        let moved = FiemapExtent {
+            physical: 2 << 20,
+            ..extent
+        };
+
+        assert!(same_mapping(&[extent], &[extent]));
+        assert!(same_mapping(&[extent], &[written]));
+        assert!(!same_mapping(&[extent], &[moved]));
+        assert!(!same_mapping(&[extent], &[extent, moved]));
+        assert!(!same_mapping(&[extent], &[]));
+    }
+
+    #[test]
+    fn test_deleted_open_file() {
+        use std::io::Write;
+        use std::os::unix::io::{AsFd, FromRawFd, OwnedFd};
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x66; 8192]).unwrap();
+        temp.as_file().sync_all().unwrap();
+        let (_image, options) = image_of(temp.as_file());
+
+        // Only the open descriptor is left
+        let (open, path) = temp.keep().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let fd = open.as_raw_fd();
+
+        let mut buf = AlignedBuf::new(8192, READ_ALIGNMENT);
+        let file = BlkFile::open_proc_fd(std::process::id(), fd).unwrap();
+        assert!(!file.extents().is_empty());
+        let state = file.read_at_opt(&mut buf, 0, &options).unwrap();
+        assert_eq!(state.bytes_read, 8192);
+        assert!(!state.used_fallback);
+        assert!(buf.iter().all(|&b| b == 0x66));
+
+        // SAFETY: plain syscall without pointer arguments.
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, std::process::id(), 0) };
+        if pidfd >= 0 {
+            // SAFETY: the pidfd was just opened and is owned by nothing else.
+            let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) };
+            let file = BlkFile::from_pidfd(pidfd.as_fd(), fd).unwrap();
+            buf.fill(0);
+            assert_eq!(
+                file.read_at_opt(&mut buf, 0, &options).unwrap().bytes_read,
+                8192
+            );
+            assert!(buf.iter().all(|&b| b == 0x66));
+        }
+    }
+
+    #[test]
+    fn test_fallback_read_flags() {
+        use std::io::Write;
+
+        // Freshly written data is in the page cache, so NOWAIT reads succeed
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x5A; 8192]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let options = Options::new()
+            .with_allow_fallback(true)
+            .with_read_flags(ReadFlags::NOWAIT);
+        let mut buf = vec![0u8; 4096];
+        let state = temp
+            .path()
+            .blk_read_at_opt(&mut buf, 4096, &options)
+            .unwrap();
+        assert_eq!(state.bytes_read, 4096);
+        assert!(buf.iter().all(|&b| b == 0x5A));
+    }
+
+    #[test]
+    fn test_read_to_end() {
+        use std::io::Write;
+
+        // Not a multiple of the chunk size or alignment
+        let data: Vec<u8> = (0..READ_CHUNK_SIZE + 5000).map(|i| i as u8).collect();
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&data).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let options = Options::new().with_allow_fallback(true);
+        assert!(temp.path().blk_read_to_end(&options).unwrap() == data);
+    }
+
+    #[test]
+    fn test_read_to_end_holes() {
+        use std::os::unix::fs::FileExt;
+
+        // A hole in the middle of the first chunk, one the second chunk
+        // starts in, and one at the end of the file
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let file = temp.as_file();
+        let chunk = READ_CHUNK_SIZE as u64;
+        file.write_all_at(&[0x11; 4096], 0).unwrap();
+        file.write_all_at(&[0x22; 4096], 8192).unwrap();
+        file.write_all_at(&[0x33; 4096], chunk + 4096).unwrap();
+        file.set_len(chunk + 16384).unwrap();
+        file.sync_all().unwrap();
+
+        let (_image, options) = image_of(file);
+        let options = options.with_fill_byte(0xDE);
+
+        // Holes are filled with the fill byte even without fill_holes, so
+        // the data after them stays in place
+        let mut expected = vec![0xDE; (chunk + 16384) as usize];
+        expected[..4096].fill(0x11);
+        expected[8192..12288].fill(0x22);
+        expected[READ_CHUNK_SIZE + 4096..READ_CHUNK_SIZE + 8192].fill(0x33);
+        let data = temp.path().blk_read_to_end(&options).unwrap();
+        assert_eq!(data.len(), expected.len());
+        assert!(data == expected);
+        let filled = options.with_fill_holes(true);
+        assert!(temp.path().blk_read_to_end(&filled).unwrap() == expected);
+    }
+
+    #[test]
+    fn test_read_with_caller_extents() {
+        use blkmap::ExtentFlags;
+
+        let mut data = vec![0x11u8; 4096];
+        data.extend_from_slice(&[0x22; 4096]);
+        let slot = OnceLock::new();
+        slot.set(fake_device(&data)).unwrap();
+
+        // The file itself is empty, as if truncated after the map was saved
+        let file = tempfile::tempfile().unwrap();
+        let options = Options::new().with_allow_fallback(true);
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 4096,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        let mut buf = vec![0u8; 4096];
+        let state = ctx.read_with_caller_extents(&mut buf, 0, &extents).unwrap();
+        assert!(!state.used_fallback);
+        assert_eq!(state.bytes_read, 4096);
+        assert_eq!(state.block_device_path, PathBuf::from("/dev/fake"));
+        assert!(buf.iter().all(|&b| b == 0x22));
+
+        // Ranges outside the supplied map have no extents
+        let err = ctx
+            .read_with_caller_extents(&mut buf, 8192, &extents)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_synthesized_ranges_stop_at_hole() {
+        use blkmap::ExtentFlags;
+
+        let (file, device) = fixture(&[0xAB; 4096]);
+        let options = Options::new();
+        let ctx = ReadContext::new(&file, &options);
+
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 0,
+            length: 1024,
+            flags: ExtentFlags::empty(),
+        }];
+
+        let mut buf = vec![0u8; 4096];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+
+        assert_eq!(outcome.bytes_read, 1024);
+        assert!(outcome.synthesized.is_empty());
+    }
+
+    #[test]
+    fn test_read_exact_holes_and_unwritten() {
+        use blkmap::ExtentFlags;
+
+        let (file, device) = fixture(&[0xAB; 8192]);
+
+        // [0, 1024) data, [1024, 2048) hole, [2048, 3072) unwritten, then trailing hole
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 0,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 2048,
+                physical: 2048,
+                length: 1024,
+                flags: ExtentFlags::UNWRITTEN,
+            },
+        ];
+        let read = |options: &Options| {
+            let ctx = ReadContext::new(&file, options);
+            let mut buf = vec![0u8; 4096];
+            ctx.read_from_device(&device, &mut buf, 0, &extents)
+                .map(|outcome| outcome.bytes_read)
+        };
+
+        // Stopping at a hole is a short read
+        let err = read(&Options::new().with_read_exact(true)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let short = ShortReadError::from_io_error(&err).unwrap();
+        assert_eq!(short.expected, 4096);
+        assert_eq!(short.bytes_read, 1024);
+
+        // Filling holes (including the trailing one) satisfies the full length,
+        // whether unwritten data is read raw or zeroed
+        let options = Options::new().with_read_exact(true).with_fill_holes(true);
+        assert_eq!(read(&options).unwrap(), 4096);
+        assert_eq!(
+            read(&options.clone().with_zero_unwritten(true)).unwrap(),
+            4096
+        );
+
+        // Without read_exact, the same reads are simply short
+        assert_eq!(read(&Options::new()).unwrap(), 1024);
+    }
+
+    #[test]
+    fn test_read_exact_no_extents() {
+        let file = tempfile::tempfile().unwrap();
+        let options = Options::new().with_read_exact(true);
+        let ctx = ReadContext::new(&file, &options);
+
+        let err = ctx.query_extents(0, 4096, false).unwrap_err();
+        let short = ShortReadError::from_io_error(&err).unwrap();
+        assert_eq!(short.bytes_read, 0);
+    }
+
+    #[test]
+    fn test_dry_run_plan() {
+        use blkmap::ExtentFlags;
+
+        let (file, device) = fixture(&[]);
+        let options = Options::new()
+            .with_dry_run(true)
+            .with_fill_holes(true)
+            .with_zero_unwritten(true);
+        let ctx = ReadContext::new(&file, &options);
+
+        // data, hole, unwritten, trailing hole
+        let extents = vec![
+            FiemapExtent {
+                logical: 0,
+                physical: 1 << 20,
+                length: 1024,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 2048,
+                physical: 2 << 20,
+                length: 1024,
+                flags: ExtentFlags::UNWRITTEN,
+            },
+        ];
+
+        let mut buf = vec![0u8; 4096];
+        let outcome = ctx
+            .read_from_device(&device, &mut buf, 0, &extents)
+            .unwrap();
+        assert_eq!(outcome.bytes_read, 4096);
+        assert_eq!(
+            outcome.planned,
+            vec![
+                PlannedRead::Device {
+                    logical: 0,
+                    physical: 1 << 20,
+                    length: 1024,
+                },
+                PlannedRead::Fill {
+                    logical: 1024,
+                    length: 3072,
+                },
+            ]
+        );
+
+        // Nothing is planned outside dry runs
+        let options = Options::new().with_fill_holes(true);
+        let ctx = ReadContext::new(&file, &options);
+        let outcome = ctx
+            .read_from_device(&fake_device(&[0; 4096]), &mut buf, 0, &extents[..1])
+            .unwrap();
+        assert!(outcome.planned.is_empty());
+    }
+
+    #[test]
+    fn test_dry_run_does_not_open_device() {
+        use std::io::Write;
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x42; 8192]).unwrap();
+        temp.as_file().sync_all().unwrap();
+
+        let options = Options::new().with_dry_run(true);
+        let slot = OnceLock::new();
+        let ctx = ReadContext::new(temp.as_file(), &options).with_device_slot(&slot);
+        let mut buf = vec![0u8; 8192];
+        let state = ctx.read_at(&mut buf, 0).unwrap();
+
+        assert_eq!(state.bytes_read, 8192);
+        assert!(!state.block_device_path.as_os_str().is_empty());
+        let planned: u64 = state
+            .planned
+            .iter()
+            .map(|step| match step {
+                PlannedRead::Device { length, .. } => *length,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(planned, 8192);
+        // The resolved, unopened handle is not shared with later reads
+        assert!(slot.get().is_none());
+    }
+
+    #[test]
+    fn test_auto_align() {
+        use blkmap::ExtentFlags;
+
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        let slot = OnceLock::new();
+        slot.set(fake_device(&data)).unwrap();
+
+        // Only the first 4 KiB is mapped; the rest is a hole
+        let file = tempfile::tempfile().unwrap();
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 4096,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+        let options = Options::new()
+            .with_auto_align(true)
+            .with_fill_holes(true)
+            .with_fill_byte(0xEE);
+        let ctx = ReadContext::new(&file, &options)
+            .with_extent_map(&extents)
+            .with_device_slot(&slot);
+
+        // Unaligned offset and length, spanning into the hole
+        let mut buf = vec![0u8; 4000];
+        let state = ctx.read_at(&mut buf, 100).unwrap();
+        assert_eq!(state.bytes_read, 4000);
+        assert_eq!(&buf[..3996], &data[4196..]);
+        assert!(buf[3996..].iter().all(|&b| b == 0xEE));
+        assert_eq!(state.synthesized, vec![3996..4000]);
+        assert_eq!(
+            state.segments,
+            vec![
+                Segment {
+                    range: 0..3996,
+                    logical: 100,
+                    source: SegmentSource::Device { physical: 4196 },
+                },
+                Segment {
+                    range: 3996..4000,
+                    logical: 4096,
+                    source: SegmentSource::Hole,
+                },
+            ]
+        );
+
+        // read_exact applies to the requested slice, not the aligned range
+        let options = Options::new().with_auto_align(true).with_read_exact(true);
+        let ctx = ReadContext::new(&file, &options)
+            .with_extent_map(&extents)
+            .with_device_slot(&slot);
+        let mut buf = vec![0u8; 1000];
+        assert_eq!(ctx.read_at(&mut buf, 3000).unwrap().bytes_read, 1000);
+        let err = ctx.read_at(&mut buf, 3500).unwrap_err();
+        assert_eq!(ShortReadError::from_io_error(&err).unwrap().bytes_read, 596);
+    }
+
+    #[test]
+    fn test_buffer_node() {
+        use blkmap::ExtentFlags;
+
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        let slot = OnceLock::new();
+        slot.set(fake_device(&data)).unwrap();
+        let file = tempfile::tempfile().unwrap();
+        let extents = vec![FiemapExtent {
+            logical: 0,
+            physical: 4096,
+            length: 4096,
+            flags: ExtentFlags::empty(),
+        }];
+
+        // Bounce buffers on a given node, or ordinary ones if the device's
+        // node is unknown
+        for node in [NumaNode::Node(0), NumaNode::Device] {
+            let options = Options::new().with_auto_align(true).with_buffer_node(node);
+            let ctx = ReadContext::new(&file, &options)
+                .with_extent_map(&extents)
+                .with_device_slot(&slot);
+            let mut buf = vec![0u8; 1000];
+            assert_eq!(ctx.read_at(&mut buf, 100).unwrap().bytes_read, 1000);
+            assert_eq!(buf, data[4196..5196]);
+        }
+
+        let options = Options::new().with_buffer_node(NumaNode::Device);
+        let ctx = ReadContext::new(&file, &options).with_device_slot(&slot);
+        assert!(ctx.numa_node().is_err());
+        assert_eq!(ctx.buffer_node(), None);
+    }
+
+    #[test]
+    fn test_read_exact_builder() {
+        let opts = Options::new().with_read_exact(false);
+        assert!(!opts.read_exact);
+
+        let opts = opts.with_read_exact(true);
+        assert!(opts.read_exact);
+    }
+}
@@ -0,0 +1,166 @@
+//! Pattern search over a file's device data.
+//!
+//! [`BlkReader::blk_scan`](crate::BlkReader::blk_scan) searches the physical
+//! ranges backing a file, unwritten extents included, for a byte pattern,
+//! e.g. to locate known record headers while recovering after a crash.
+
+use blkmap::{ExtentFlags, FiemapExtent};
+
+/// An occurrence of a pattern in a file's device data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match {
+    /// Logical file offset of the first byte of the occurrence.
+    pub logical: u64,
+
+    /// Physical byte offset on the device of the first byte.
+    pub physical: u64,
+
+    /// Flags of the extent holding the first byte.
+    pub flags: ExtentFlags,
+}
+
+/// Finds a pattern in data delivered piece by piece in logical order,
+/// including occurrences spanning pieces that are logically contiguous.
+pub(crate) struct Scanner<'a> {
+    pattern: &'a [u8],
+    /// Last bytes of the previous pieces, shorter than the pattern.
+    carry: Vec<u8>,
+    /// Where the bytes of `carry` came from, as pieces clipped to them.
+    carry_pieces: Vec<FiemapExtent>,
+    matches: Vec<Match>,
+}
+
+impl<'a> Scanner<'a> {
+    /// Search for `pattern`, which must not be empty.
+    pub(crate) fn new(pattern: &'a [u8]) -> Self {
+        debug_assert!(!pattern.is_empty());
+        Self {
+            pattern,
+            carry: Vec::new(),
+            carry_pieces: Vec::new(),
+            matches: Vec::new(),
+        }
+    }
+
+    /// Search the data of `piece`.
+    pub(crate) fn feed(&mut self, piece: &FiemapExtent, data: &[u8]) {
+        let len = self.pattern.len();
+        let continues = self
+            .carry_pieces
+            .last()
+            .is_some_and(|last| last.logical + last.length == piece.logical);
+        if !continues {
+            self.carry.clear();
+            self.carry_pieces.clear();
+        }
+
+        // Occurrences starting in the carried bytes
+        let head = &data[..data.len().min(len - 1)];
+        let mut joined = self.carry.clone();
+        joined.extend_from_slice(head);
+        for start in 0..self.carry.len() {
+            if !joined[start..].starts_with(self.pattern) {
+                continue;
+            }
+            let mut rest = start as u64;
+            for carried in &self.carry_pieces {
+                if rest < carried.length {
+                    self.matches.push(at(carried, rest));
+                    break;
+                }
+                rest -= carried.length;
+            }
+        }
+
+        // Occurrences starting in the piece
+        for (start, window) in data.windows(len).enumerate() {
+            if window == self.pattern {
+                self.matches.push(at(piece, start as u64));
+            }
+        }
+
+        // Keep the bytes an occurrence spanning into the next piece may
+        // start in
+        self.carry.extend_from_slice(data);
+        self.carry_pieces.push(FiemapExtent {
+            length: data.len() as u64,
+            ..*piece
+        });
+        let excess = self.carry.len().saturating_sub(len - 1);
+        self.carry.drain(..excess);
+        let mut dropped = excess as u64;
+        while let Some(first) = self.carry_pieces.first_mut() {
+            if dropped < first.length {
+                first.logical += dropped;
+                first.physical += dropped;
+                first.length -= dropped;
+                break;
+            }
+            dropped -= first.length;
+            self.carry_pieces.remove(0);
+        }
+    }
+
+    /// The occurrences found, in logical order.
+    pub(crate) fn finish(self) -> Vec<Match> {
+        self.matches
+    }
+}
+
+/// The match `offset` bytes into `piece`.
+fn at(piece: &FiemapExtent, offset: u64) -> Match {
+    Match {
+        logical: piece.logical + offset,
+        physical: piece.physical + offset,
+        flags: piece.flags,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn piece(logical: u64, physical: u64, length: u64) -> FiemapExtent {
+        FiemapExtent {
+            logical,
+            physical,
+            length,
+            flags: ExtentFlags::empty(),
+        }
+    }
+
+    #[test]
+    fn test_scanner() {
+        let mut scanner = Scanner::new(b"HDR");
+        scanner.feed(&piece(0, 1000, 8), b"xHDRxxHD");
+        // Continues the first piece, elsewhere on the device
+        scanner.feed(&piece(8, 5000, 4), b"RxHD");
+        // After a hole, so the carried "HD" is not joined with "R"
+        scanner.feed(&piece(16, 9000, 3), b"RxH");
+        scanner.feed(&piece(19, 9003, 2), b"DR");
+
+        let found: Vec<_> = scanner
+            .finish()
+            .iter()
+            .map(|m| (m.logical, m.physical))
+            .collect();
+        assert_eq!(found, [(1, 1001), (6, 1006), (18, 9002)]);
+    }
+
+    #[test]
+    fn test_scanner_short_pieces() {
+        // Pieces shorter than the pattern, carried across several feeds
+        let mut scanner = Scanner::new(b"abcd");
+        scanner.feed(&piece(0, 100, 1), b"a");
+        scanner.feed(&piece(1, 200, 1), b"b");
+        scanner.feed(&piece(2, 300, 1), b"c");
+        scanner.feed(&piece(3, 400, 2), b"da");
+        scanner.feed(&piece(5, 500, 3), b"bcd");
+        let found: Vec<_> = scanner
+            .finish()
+            .iter()
+            .map(|m| (m.logical, m.physical))
+            .collect();
+        assert_eq!(found, [(0, 100), (4, 401)]);
+    }
+}
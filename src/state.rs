@@ -1,15 +1,282 @@
 //! State returned from read operations.
 
-use blkmap::FiemapExtent;
+#[cfg(feature = "checksum")]
+use crate::checksum::{Digest, UnitChecksum};
+
+use blkmap::{ExtentFlags, FiemapExtent};
+use std::ops::Range;
 use std::path::PathBuf;
+use std::time::Duration;
+
+/// A step of a read that a dry run would perform.
+///
+/// See [`Options::dry_run`](crate::Options::dry_run).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PlannedRead {
+    /// Read `length` bytes from the block device at `physical`.
+    Device {
+        /// Logical file offset the data belongs to.
+        logical: u64,
+        /// Physical byte offset on the device.
+        physical: u64,
+        /// Number of bytes to read.
+        length: u64,
+    },
+
+    /// Fill `length` bytes with the fill byte, for a hole or unwritten extent.
+    Fill {
+        /// Logical file offset of the filled region.
+        logical: u64,
+        /// Number of bytes to fill.
+        length: u64,
+    },
+
+    /// Read `length` bytes through regular file I/O (fallback mode, or
+    /// inline data).
+    File {
+        /// Logical file offset to read from.
+        logical: u64,
+        /// Number of bytes to read.
+        length: u64,
+    },
+}
+
+/// Where the data of a [`Segment`] came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SegmentSource {
+    /// Read from the block device, starting at byte offset `physical`.
+    Device {
+        /// Physical byte offset on the device.
+        physical: u64,
+    },
+
+    /// Filled for a hole, or an extent without data on the device.
+    Hole,
+
+    /// Filled for an unwritten extent.
+    Unwritten,
+
+    /// Filled because the device range starting at `physical` could not be
+    /// read; see [`Options::best_effort`](crate::Options::best_effort).
+    Unreadable {
+        /// Physical byte offset on the device.
+        physical: u64,
+    },
+
+    /// Read through regular file I/O (fallback mode, or inline data).
+    Fallback,
+
+    /// Filled for an encoded extent; see
+    /// [`EncodedPolicy::Fill`](crate::EncodedPolicy::Fill).
+    Encoded,
+}
+
+/// A contiguous region of the buffer with a single source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Segment {
+    /// Range of the buffer, relative to its start.
+    pub range: Range<usize>,
+
+    /// Logical file offset of the start of the range.
+    pub logical: u64,
+
+    /// Where the data of the range came from.
+    pub source: SegmentSource,
+}
+
+impl Segment {
+    /// Whether the data was filled in rather than read.
+    pub fn is_synthesized(&self) -> bool {
+        matches!(
+            self.source,
+            SegmentSource::Hole
+                | SegmentSource::Unwritten
+                | SegmentSource::Unreadable { .. }
+                | SegmentSource::Encoded
+        )
+    }
+
+    /// Remove the first `len` bytes of the segment.
+    pub(crate) fn advance(&mut self, len: usize) {
+        self.range.start += len;
+        self.logical += len as u64;
+        match &mut self.source {
+            SegmentSource::Device { physical } | SegmentSource::Unreadable { physical } => {
+                *physical += len as u64
+            }
+            _ => {}
+        }
+    }
+
+    /// Try to extend the segment by `next`, if it directly continues it.
+    pub(crate) fn merge(&mut self, next: &Segment) -> bool {
+        let len = self.range.len() as u64;
+        let continues = match (self.source, next.source) {
+            (SegmentSource::Device { physical: a }, SegmentSource::Device { physical: b })
+            | (
+                SegmentSource::Unreadable { physical: a },
+                SegmentSource::Unreadable { physical: b },
+            ) => a + len == b,
+            (a, b) => a == b,
+        };
+        if !continues || self.range.end != next.range.start || self.logical + len != next.logical {
+            return false;
+        }
+        self.range.end = next.range.end;
+        true
+    }
+}
+
+/// A device range that could not be read and was filled instead.
+///
+/// See [`Options::best_effort`](crate::Options::best_effort).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnreadableRange {
+    /// Logical file offset of the range.
+    pub logical: u64,
+    /// Physical byte offset on the device.
+    pub physical: u64,
+    /// Length of the range in bytes.
+    pub length: u64,
+}
+
+/// Sectors of an unwritten extent whose device data is not all zeros.
+///
+/// See [`Options::check_unwritten`](crate::Options::check_unwritten).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NonzeroRange {
+    /// Logical file offset of the range.
+    pub logical: u64,
+    /// Physical byte offset on the device.
+    pub physical: u64,
+    /// Length of the range in bytes.
+    pub length: u64,
+}
+
+/// Time spent in each stage of a read.
+///
+/// Only measured with [`Options::timing`](crate::Options::timing).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Timing {
+    /// Time spent querying the extent map with FIEMAP.
+    ///
+    /// Includes the second query when the extent map was refreshed, and is
+    /// zero for reads using a previously queried extent map.
+    pub fiemap: Duration,
+
+    /// Time spent resolving and opening the block device.
+    ///
+    /// Close to zero when the device handle is cached.
+    pub device_resolve: Duration,
+
+    /// Duration of each device read, in completion order.
+    pub reads: Vec<ReadTiming>,
+
+    /// Wall time of the whole call.
+    pub total: Duration,
+}
+
+/// Duration of a single device read.
+///
+/// Reads of adjacent extents may be coalesced into one device read, which
+/// is then reported once, at the extent it starts in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReadTiming {
+    /// Logical file offset of the first byte read.
+    pub logical: u64,
+    /// Physical byte offset on the device.
+    pub physical: u64,
+    /// Number of bytes requested from the device.
+    pub length: u64,
+    /// Time from the start of the read's batch, or from the completion of
+    /// the batch's previous read, until the read completed.
+    pub duration: Duration,
+}
+
+/// Geometry of the block device a read went to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceInfo {
+    /// Logical sector size in bytes (`BLKSSZGET`), the smallest unit the
+    /// device can address, and thus the Direct I/O alignment.
+    pub logical_block_size: u32,
+    /// Physical sector size in bytes (`BLKPBSZGET`), the unit the device
+    /// writes internally.
+    pub physical_block_size: u32,
+    /// Size of the device in bytes (`BLKGETSIZE64`).
+    pub size: u64,
+}
+
+/// The LVM logical volume a file's block device is.
+///
+/// See [`State::lvm`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LvmVolume {
+    /// Name of the volume group.
+    pub vg_name: String,
+    /// Name of the logical volume.
+    pub lv_name: String,
+    /// Path of the device-mapper device of the volume, such as `/dev/dm-0`.
+    pub device_path: PathBuf,
+    /// Target types of the volume's table, such as `linear` or `striped`.
+    ///
+    /// Empty if the table could not be read, which requires
+    /// `CAP_SYS_ADMIN`.
+    pub targets: Vec<String>,
+    /// Physical volumes holding the volume's data, found through the
+    /// devices below it and below intermediate volumes such as RAID images.
+    pub physical_volumes: Vec<PathBuf>,
+}
+
+/// The md (software RAID) array a file's block device is.
+///
+/// See [`State::md`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MdArray {
+    /// Path of the array device, such as `/dev/md0`.
+    pub device_path: PathBuf,
+    /// RAID level, such as `raid1` or `raid5`.
+    pub level: String,
+    /// Member devices of the array, including failed and spare ones.
+    pub members: Vec<PathBuf>,
+    /// Whether the array is missing redundancy, e.g. after a member failed.
+    pub degraded: bool,
+}
 
 /// Result state from a read operation.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct State {
     /// Path to the block device used for reading.
     pub block_device_path: PathBuf,
 
+    /// Sector sizes and size of the block device.
+    ///
+    /// `None` for fallback reads and dry runs, which never open the
+    /// device, or if the device could not be queried.
+    pub device_info: Option<DeviceInfo>,
+
+    /// The LVM logical volume the file's block device is, if any.
+    ///
+    /// `None` for fallback reads, or if the device is not a logical volume.
+    pub lvm: Option<LvmVolume>,
+
+    /// The md array the file's block device is, if any.
+    ///
+    /// `None` for fallback reads, or if the device is not an md array.
+    pub md: Option<MdArray>,
+
     /// List of extents that were involved in the read operation.
+    #[cfg_attr(feature = "serde", serde(with = "crate::persist::extents"))]
     pub extents: Vec<FiemapExtent>,
 
     /// Number of bytes successfully read.
@@ -17,6 +284,117 @@ pub struct State {
 
     /// Whether the read used fallback (regular file I/O instead of block device).
     pub used_fallback: bool,
+
+    /// Ranges of the buffer that were synthesized rather than read.
+    ///
+    /// Each range is relative to the start of the buffer passed to the read
+    /// and covers bytes that were filled in for holes or unwritten extents
+    /// instead of being read from the device. Adjacent ranges are merged,
+    /// and all ranges lie within `0..bytes_read`.
+    pub synthesized: Vec<Range<usize>>,
+
+    /// Whether the extent map was re-queried because the first read looked stale.
+    ///
+    /// See [`Options::refresh_on_stale`](crate::Options::refresh_on_stale).
+    pub extents_refreshed: bool,
+
+    /// Whether the extent map changed while the device was being read.
+    ///
+    /// The data may then mix old and new contents of relocated extents.
+    /// Only checked with
+    /// [`Options::verify_extents`](crate::Options::verify_extents).
+    pub possibly_torn: bool,
+
+    /// Whether the range had dirty pages in the page cache when it was read.
+    ///
+    /// Device reads then return data older than the file's contents. Only
+    /// checked with [`Options::check_dirty`](crate::Options::check_dirty).
+    pub possibly_stale: bool,
+
+    /// Steps the read would perform, in order; only populated by dry runs.
+    ///
+    /// See [`Options::dry_run`](crate::Options::dry_run).
+    pub planned: Vec<PlannedRead>,
+
+    /// Device ranges that could not be read, in logical order.
+    ///
+    /// Only populated in best-effort mode, where these ranges are filled
+    /// and also listed in `synthesized`. See
+    /// [`Options::best_effort`](crate::Options::best_effort).
+    pub unreadable: Vec<UnreadableRange>,
+
+    /// Where the data of each region of the buffer came from, in order.
+    ///
+    /// The segments are contiguous and cover `0..bytes_read`, telling
+    /// real disk data apart from synthesized bytes.
+    pub segments: Vec<Segment>,
+
+    /// Number of holes within the returned data.
+    pub holes_encountered: usize,
+
+    /// Number of returned bytes belonging to unwritten extents, whether
+    /// they were filled or read raw from the device.
+    pub unwritten_bytes: usize,
+
+    /// Ranges of unwritten extents read raw from the device that hold
+    /// nonzero bytes, in logical order and rounded out to 512-byte sectors
+    /// of the device within the returned data.
+    ///
+    /// Only checked with
+    /// [`Options::check_unwritten`](crate::Options::check_unwritten); such
+    /// data was written to the device but never committed by the
+    /// filesystem, e.g. before a crash.
+    pub unwritten_nonzero: Vec<NonzeroRange>,
+
+    /// Number of returned bytes belonging to encoded (compressed or
+    /// encrypted) extents, whether they were filled or read raw.
+    ///
+    /// See [`Options::encoded_policy`](crate::Options::encoded_policy).
+    pub encoded_bytes: usize,
+
+    /// Whether the data read from the device is ciphertext, of a file
+    /// encrypted with fscrypt or of a device below a dm-crypt mapping.
+    ///
+    /// Such reads only succeed with
+    /// [`Options::allow_ciphertext`](crate::Options::allow_ciphertext).
+    pub ciphertext: bool,
+
+    /// Number of returned bytes read from the device out of extents shared
+    /// with other files (`FIEMAP_EXTENT_SHARED`), e.g. reflinked or
+    /// deduplicated data.
+    ///
+    /// Copy-on-write writes to any of the sharing files relocate data
+    /// rather than overwrite these blocks, but the blocks may be reused
+    /// once no file references them any more.
+    pub shared_bytes: usize,
+
+    /// Number of returned bytes filled with the fill byte (zero by default)
+    /// instead of being read, for holes, unwritten and encoded extents and
+    /// unreadable device ranges.
+    pub zero_filled_bytes: usize,
+
+    /// Number of returned bytes read from the block device.
+    pub device_bytes_read: usize,
+
+    /// Duration of each stage of the read.
+    ///
+    /// Only populated with [`Options::timing`](crate::Options::timing).
+    pub timing: Option<Timing>,
+
+    /// Digest of the returned data, `0..bytes_read` of the buffer.
+    ///
+    /// Only computed with [`Options::checksum`](crate::Options::checksum).
+    /// Requires the `checksum` feature.
+    #[cfg(feature = "checksum")]
+    pub checksum: Option<Digest>,
+
+    /// Digests of the parts of the returned data, in logical order.
+    ///
+    /// Only computed with [`Options::checksum`](crate::Options::checksum)
+    /// and [`Options::checksum_unit`](crate::Options::checksum_unit).
+    /// Requires the `checksum` feature.
+    #[cfg(feature = "checksum")]
+    pub unit_checksums: Vec<UnitChecksum>,
 }
 
 impl State {
@@ -29,9 +407,32 @@ impl State {
     ) -> Self {
         Self {
             block_device_path,
+            device_info: None,
+            lvm: None,
+            md: None,
             extents,
             bytes_read,
             used_fallback,
+            synthesized: Vec::new(),
+            extents_refreshed: false,
+            possibly_torn: false,
+            possibly_stale: false,
+            planned: Vec::new(),
+            unreadable: Vec::new(),
+            segments: Vec::new(),
+            holes_encountered: 0,
+            unwritten_bytes: 0,
+            unwritten_nonzero: Vec::new(),
+            encoded_bytes: 0,
+            ciphertext: false,
+            shared_bytes: 0,
+            zero_filled_bytes: 0,
+            device_bytes_read: 0,
+            timing: None,
+            #[cfg(feature = "checksum")]
+            checksum: None,
+            #[cfg(feature = "checksum")]
+            unit_checksums: Vec::new(),
         }
     }
 
@@ -41,17 +442,103 @@ impl State {
     pub fn fallback(extents: Vec<FiemapExtent>, bytes_read: usize) -> Self {
         Self {
             block_device_path: PathBuf::new(),
+            device_info: None,
+            lvm: None,
+            md: None,
             extents,
             bytes_read,
             used_fallback: true,
+            synthesized: Vec::new(),
+            extents_refreshed: false,
+            possibly_torn: false,
+            possibly_stale: false,
+            planned: Vec::new(),
+            unreadable: Vec::new(),
+            segments: Vec::new(),
+            holes_encountered: 0,
+            unwritten_bytes: 0,
+            unwritten_nonzero: Vec::new(),
+            encoded_bytes: 0,
+            ciphertext: false,
+            shared_bytes: 0,
+            zero_filled_bytes: 0,
+            device_bytes_read: 0,
+            timing: None,
+            #[cfg(feature = "checksum")]
+            checksum: None,
+            #[cfg(feature = "checksum")]
+            unit_checksums: Vec::new(),
+        }
+    }
+
+    /// Extents of the read shared with other files.
+    ///
+    /// See [`shared_bytes`](State::shared_bytes).
+    pub fn shared_extents(&self) -> impl Iterator<Item = &FiemapExtent> {
+        self.extents
+            .iter()
+            .filter(|extent| extent.flags.is_shared())
+    }
+
+    /// Number of bytes in the buffer that were synthesized rather than read.
+    pub fn synthesized_bytes(&self) -> usize {
+        self.synthesized.iter().map(|range| range.len()).sum()
+    }
+
+    /// Set the segments of the buffer and the counters summarizing them.
+    pub(crate) fn set_segments(&mut self, segments: Vec<Segment>) {
+        self.holes_encountered = 0;
+        self.unwritten_bytes = 0;
+        self.encoded_bytes = 0;
+        self.shared_bytes = 0;
+        self.zero_filled_bytes = 0;
+        self.device_bytes_read = 0;
+        for segment in &segments {
+            let len = segment.range.len();
+            match segment.source {
+                SegmentSource::Device { .. } => {
+                    let logical = segment.logical;
+                    self.device_bytes_read += len;
+                    self.unwritten_bytes +=
+                        self.overlap(ExtentFlags::is_unwritten, logical, len as u64);
+                    self.encoded_bytes += self.overlap(is_encoded, logical, len as u64);
+                    self.shared_bytes += self.overlap(ExtentFlags::is_shared, logical, len as u64);
+                }
+                SegmentSource::Hole => self.holes_encountered += 1,
+                SegmentSource::Unwritten => self.unwritten_bytes += len,
+                SegmentSource::Encoded => self.encoded_bytes += len,
+                SegmentSource::Unreadable { .. } | SegmentSource::Fallback => {}
+            }
+            if segment.is_synthesized() {
+                self.zero_filled_bytes += len;
+            }
         }
+        self.segments = segments;
     }
+
+    /// Number of bytes of `logical..logical + len` in extents matching `kind`.
+    fn overlap(&self, kind: fn(&ExtentFlags) -> bool, logical: u64, len: u64) -> usize {
+        self.extents
+            .iter()
+            .filter(|extent| kind(&extent.flags))
+            .map(|extent| {
+                let start = extent.logical.max(logical);
+                let end = (extent.logical + extent.length).min(logical + len);
+                end.saturating_sub(start) as usize
+            })
+            .sum()
+    }
+}
+
+/// Whether an extent's device blocks hold its data encoded, i.e.
+/// compressed or encrypted.
+pub(crate) fn is_encoded(flags: &ExtentFlags) -> bool {
+    flags.is_encoded() || flags.is_encrypted()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use blkmap::ExtentFlags;
 
     #[test]
     fn test_state_new() {
@@ -68,9 +555,18 @@ mod tests {
         );
 
         assert_eq!(state.block_device_path, PathBuf::from("/dev/sda"));
+        assert!(state.device_info.is_none());
         assert_eq!(state.extents.len(), 1);
         assert_eq!(state.bytes_read, 4096);
         assert!(!state.used_fallback);
+        assert!(state.synthesized.is_empty());
+        assert!(!state.possibly_torn);
+        assert!(!state.possibly_stale);
+        assert!(state.planned.is_empty());
+        assert!(state.unreadable.is_empty());
+        assert!(state.segments.is_empty());
+        assert_eq!(state.device_bytes_read, 0);
+        assert!(state.timing.is_none());
     }
 
     #[test]
@@ -87,5 +583,125 @@ mod tests {
         assert_eq!(state.extents.len(), 1);
         assert_eq!(state.bytes_read, 1024);
         assert!(state.used_fallback);
+        assert_eq!(state.synthesized_bytes(), 0);
+    }
+
+    #[test]
+    fn test_segment_merge() {
+        let mut segment = Segment {
+            range: 0..4096,
+            logical: 8192,
+            source: SegmentSource::Device { physical: 65536 },
+        };
+        assert!(!segment.is_synthesized());
+        assert!(segment.merge(&Segment {
+            range: 4096..8192,
+            logical: 12288,
+            source: SegmentSource::Device { physical: 69632 },
+        }));
+        assert_eq!(segment.range, 0..8192);
+
+        // Physically discontiguous data stays separate
+        assert!(!segment.merge(&Segment {
+            range: 8192..12288,
+            logical: 16384,
+            source: SegmentSource::Device { physical: 0 },
+        }));
+        assert!(!segment.merge(&Segment {
+            range: 8192..12288,
+            logical: 16384,
+            source: SegmentSource::Hole,
+        }));
+
+        segment.advance(1024);
+        assert_eq!(segment.range, 1024..8192);
+        assert_eq!(segment.logical, 9216);
+        assert_eq!(segment.source, SegmentSource::Device { physical: 66560 });
+    }
+
+    #[test]
+    fn test_set_segments() {
+        let mut state = State::new(
+            PathBuf::from("/dev/sda"),
+            vec![
+                FiemapExtent {
+                    logical: 0,
+                    physical: 65536,
+                    length: 4096,
+                    flags: ExtentFlags::UNWRITTEN,
+                },
+                FiemapExtent {
+                    logical: 8192,
+                    physical: 0,
+                    length: 4096,
+                    flags: ExtentFlags::UNWRITTEN,
+                },
+            ],
+            12288,
+            false,
+        );
+        state.set_segments(vec![
+            // Unwritten data read raw
+            Segment {
+                range: 0..4096,
+                logical: 0,
+                source: SegmentSource::Device { physical: 65536 },
+            },
+            Segment {
+                range: 4096..6144,
+                logical: 4096,
+                source: SegmentSource::Hole,
+            },
+            Segment {
+                range: 6144..8192,
+                logical: 6144,
+                source: SegmentSource::Unreadable { physical: 4096 },
+            },
+            Segment {
+                range: 8192..12288,
+                logical: 8192,
+                source: SegmentSource::Unwritten,
+            },
+        ]);
+
+        assert_eq!(state.segments.len(), 4);
+        assert_eq!(state.holes_encountered, 1);
+        assert_eq!(state.unwritten_bytes, 8192);
+        assert_eq!(state.encoded_bytes, 0);
+        assert_eq!(state.shared_bytes, 0);
+        assert_eq!(state.zero_filled_bytes, 8192);
+        assert_eq!(state.device_bytes_read, 4096);
+    }
+
+    #[test]
+    fn test_shared_bytes() {
+        let mut state = State::new(
+            PathBuf::from("/dev/sda"),
+            vec![
+                FiemapExtent {
+                    logical: 0,
+                    physical: 65536,
+                    length: 4096,
+                    flags: ExtentFlags::empty(),
+                },
+                FiemapExtent {
+                    logical: 4096,
+                    physical: 1 << 20,
+                    length: 8192,
+                    flags: ExtentFlags::SHARED | ExtentFlags::LAST,
+                },
+            ],
+            8192,
+            false,
+        );
+        state.set_segments(vec![Segment {
+            range: 0..8192,
+            logical: 0,
+            source: SegmentSource::Device { physical: 65536 },
+        }]);
+
+        assert_eq!(state.shared_bytes, 4096);
+        assert_eq!(state.shared_extents().count(), 1);
+        assert_eq!(state.shared_extents().next().unwrap().logical, 4096);
     }
 }
@@ -0,0 +1,132 @@
+//! Process-wide read statistics.
+//!
+//! Services embedding this crate can feed [`stats`] into their capacity
+//! planning dashboards. The counters only ever grow; rates follow from the
+//! difference of two snapshots. Dry runs do no I/O and count no bytes.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+/// Cumulative counts of the work done by all reads of the process.
+///
+/// # Example
+///
+/// ```
+/// let stats = blkreader::stats();
+/// println!(
+///     "{} bytes from devices, {} errors",
+///     stats.device_bytes,
+///     stats.error_count()
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes read from block devices, including the gaps read along when
+    /// coalescing nearby extents.
+    pub device_bytes: u64,
+    /// Bytes read through regular file I/O, in fallback mode or for inline
+    /// data.
+    pub fallback_bytes: u64,
+    /// Bytes filled in for holes, unwritten extents and unreadable ranges.
+    pub filled_bytes: u64,
+    /// FIEMAP queries issued.
+    pub fiemap_calls: u64,
+    /// Failed FIEMAP queries, device reads and fallback reads, by kind.
+    /// Attempts that were retried count too.
+    pub errors: HashMap<io::ErrorKind, u64>,
+}
+
+impl Stats {
+    /// Total number of errors, of any kind.
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+}
+
+/// A snapshot of the process-wide read statistics.
+pub fn stats() -> Stats {
+    let counters = &*COUNTERS;
+    Stats {
+        device_bytes: counters.device_bytes.load(Ordering::Relaxed),
+        fallback_bytes: counters.fallback_bytes.load(Ordering::Relaxed),
+        filled_bytes: counters.filled_bytes.load(Ordering::Relaxed),
+        fiemap_calls: counters.fiemap_calls.load(Ordering::Relaxed),
+        errors: counters.errors.lock().unwrap().clone(),
+    }
+}
+
+/// A counter of [`Stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Counter {
+    DeviceBytes,
+    FallbackBytes,
+    FilledBytes,
+    FiemapCalls,
+}
+
+/// Live counters behind [`Stats`].
+#[derive(Debug, Default)]
+struct Counters {
+    device_bytes: AtomicU64,
+    fallback_bytes: AtomicU64,
+    filled_bytes: AtomicU64,
+    fiemap_calls: AtomicU64,
+    errors: Mutex<HashMap<io::ErrorKind, u64>>,
+}
+
+static COUNTERS: LazyLock<Counters> = LazyLock::new(Counters::default);
+
+/// Add `n` to `counter`.
+pub(crate) fn count(counter: Counter, n: u64) {
+    let counters = &*COUNTERS;
+    let counter = match counter {
+        Counter::DeviceBytes => &counters.device_bytes,
+        Counter::FallbackBytes => &counters.fallback_bytes,
+        Counter::FilledBytes => &counters.filled_bytes,
+        Counter::FiemapCalls => &counters.fiemap_calls,
+    };
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// Count `err` by its kind.
+pub(crate) fn count_error(err: &io::Error) {
+    *COUNTERS
+        .errors
+        .lock()
+        .unwrap()
+        .entry(err.kind())
+        .or_default() += 1;
+}
+
+/// Count the bytes of a successful read under `counter`, or its error.
+pub(crate) fn count_result(counter: Counter, result: &io::Result<usize>) {
+    match result {
+        Ok(n) => count(counter, *n as u64),
+        Err(e) => count_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        // Other tests read concurrently, so only growth is checked
+        let before = stats();
+        count(Counter::DeviceBytes, 4096);
+        count(Counter::FiemapCalls, 1);
+        count_result(Counter::FallbackBytes, &Ok(512));
+        count_result(Counter::FilledBytes, &Err(io::ErrorKind::TimedOut.into()));
+        let after = stats();
+
+        assert!(after.device_bytes >= before.device_bytes + 4096);
+        assert!(after.fallback_bytes >= before.fallback_bytes + 512);
+        assert!(after.fiemap_calls > before.fiemap_calls);
+        let timed_out = |stats: &Stats| stats.errors.get(&io::ErrorKind::TimedOut).copied();
+        assert!(timed_out(&after) > timed_out(&before));
+        assert!(after.error_count() > before.error_count());
+    }
+}
@@ -0,0 +1,1455 @@
+//! Thin wrappers around Linux system interfaces not covered by dependencies.
+
+use blkmap::{ExtentFlags, FiemapExtent};
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::File;
+use std::io::{self, IoSliceMut};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+
+/// FIEMAP ioctl request code (`_IOWR('f', 11, struct fiemap)`).
+pub const FS_IOC_FIEMAP: libc::c_ulong = 0xC020660B;
+
+/// Block device size ioctl request code (`_IOR(0x12, 114, size_t)`).
+const BLKGETSIZE64: libc::c_ulong =
+    0x80001272 | ((std::mem::size_of::<libc::size_t>() as libc::c_ulong) << 16);
+
+/// Flush dirty data before mapping (`FIEMAP_FLAG_SYNC`).
+pub const FIEMAP_FLAG_SYNC: u32 = 0x00000001;
+
+/// Header of `struct fiemap`, without the trailing extent array.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct FiemapHeader {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+/// One extent of `struct fiemap` (`struct fiemap_extent`).
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct FiemapExtentRaw {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+/// Number of extents fetched by each FIEMAP request.
+const FIEMAP_BATCH: usize = 256;
+
+/// `struct fiemap` with room for [`FIEMAP_BATCH`] extents.
+#[repr(C)]
+struct FiemapRequest {
+    header: FiemapHeader,
+    extents: [FiemapExtentRaw; FIEMAP_BATCH],
+}
+
+/// Query the extents of `start..start + length` with FIEMAP request `flags`.
+///
+/// Like [`Fiemap::fiemap_range`](blkmap::Fiemap::fiemap_range), which
+/// always passes no flags, but allows e.g. [`FIEMAP_FLAG_SYNC`]. Extents
+/// are fetched in batches of [`FIEMAP_BATCH`], each request starting where
+/// the previous one ended, until an extent flagged
+/// `FIEMAP_EXTENT_LAST` or the end of the range is reached, so files with
+/// any number of extents are mapped completely.
+pub fn fiemap(fd: RawFd, start: u64, length: u64, flags: u32) -> io::Result<Vec<FiemapExtent>> {
+    let end = start.checked_add(length).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid range: offset={}, length={}", start, length),
+        )
+    })?;
+
+    let mut request = Box::new(FiemapRequest {
+        header: FiemapHeader::default(),
+        extents: [FiemapExtentRaw::default(); FIEMAP_BATCH],
+    });
+    let mut extents = Vec::new();
+    let mut next = start;
+    while next < end {
+        request.header = FiemapHeader {
+            fm_start: next,
+            fm_length: end - next,
+            fm_flags: flags,
+            fm_extent_count: FIEMAP_BATCH as u32,
+            ..Default::default()
+        };
+        // SAFETY: `request` is a valid `struct fiemap` with room for
+        // `fm_extent_count` extents.
+        let ret = unsafe { libc::ioctl(fd, FS_IOC_FIEMAP, &mut *request as *mut FiemapRequest) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if matches!(
+                err.raw_os_error(),
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY)
+            ) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "FIEMAP not supported on this filesystem",
+                ));
+            }
+            return Err(err);
+        }
+
+        let mapped = request.header.fm_mapped_extents as usize;
+        let mut last = mapped == 0;
+        for raw in &request.extents[..mapped.min(FIEMAP_BATCH)] {
+            let flags = ExtentFlags::from_bits_truncate(raw.fe_flags);
+            last |= flags.contains(ExtentFlags::LAST);
+            extents.push(FiemapExtent {
+                logical: raw.fe_logical,
+                physical: raw.fe_physical,
+                length: raw.fe_length,
+                flags,
+            });
+        }
+        if last {
+            break;
+        }
+
+        // Never stop short or loop forever on a batch that does not advance
+        let end_of_batch = extents
+            .last()
+            .map_or(next, |extent| extent.logical.saturating_add(extent.length));
+        if end_of_batch <= next {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("FIEMAP made no progress at offset {}", next),
+            ));
+        }
+        next = end_of_batch;
+    }
+    Ok(extents)
+}
+
+/// Issue a FIEMAP request that only counts extents, returning the count.
+///
+/// With `fm_extent_count` set to zero the kernel does not copy any extents
+/// and only reports how many exist, which makes this a cheap probe for
+/// whether FIEMAP (and the given flags) are supported.
+pub fn fiemap_count(fd: RawFd, flags: u32) -> io::Result<u32> {
+    let mut header = FiemapHeader {
+        fm_length: u64::MAX,
+        fm_flags: flags,
+        ..Default::default()
+    };
+
+    // SAFETY: `header` is a valid `struct fiemap` with room for zero extents.
+    let ret = unsafe { libc::ioctl(fd, FS_IOC_FIEMAP, &mut header as *mut FiemapHeader) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(header.fm_mapped_extents)
+}
+
+/// Query the Direct I/O alignment (memory, offset) of a path via `statx`.
+///
+/// Returns `None` if the kernel or filesystem does not report it.
+pub fn statx_dio_align(path: &Path) -> io::Result<Option<(u32, u32)>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    statx_dio_align_at(libc::AT_FDCWD, &c_path, 0)
+}
+
+/// Query the Direct I/O alignment (memory, offset) of an open file via `statx`.
+///
+/// Returns `None` if the kernel or filesystem does not report it.
+pub fn statx_dio_align_fd(fd: RawFd) -> io::Result<Option<(u32, u32)>> {
+    statx_dio_align_at(fd, c"", libc::AT_EMPTY_PATH)
+}
+
+fn statx_dio_align_at(dirfd: RawFd, path: &CStr, flags: i32) -> io::Result<Option<(u32, u32)>> {
+    // SAFETY: `statx` is plain old data and a zeroed value is valid.
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stx` is a valid output buffer.
+    let ret = unsafe { libc::statx(dirfd, path.as_ptr(), flags, libc::STATX_DIOALIGN, &mut stx) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if stx.stx_mask & libc::STATX_DIOALIGN == 0 || stx.stx_dio_offset_align == 0 {
+        return Ok(None);
+    }
+    Ok(Some((stx.stx_dio_mem_align, stx.stx_dio_offset_align)))
+}
+
+/// Get the filesystem magic (`f_type`) of the filesystem holding `fd`.
+pub fn fs_type(fd: RawFd) -> io::Result<i64> {
+    // SAFETY: `statfs` is plain old data and a zeroed value is valid.
+    let mut stfs: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `stfs` is a valid output buffer.
+    if unsafe { libc::fstatfs(fd, &mut stfs) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stfs.f_type as i64)
+}
+
+/// Get the extended attribute `name` of `path`, or `None` if it is not set
+/// (or not visible to the caller, as for `trusted.*` without
+/// `CAP_SYS_ADMIN`).
+pub fn getxattr(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains NUL"))?;
+    let c_name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains NUL"))?;
+    let mut value = vec![0u8; 256];
+    loop {
+        // SAFETY: the strings are NUL-terminated and `value` is a valid
+        // output buffer of the given length.
+        let ret = unsafe {
+            libc::getxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if ret >= 0 {
+            value.truncate(ret as usize);
+            return Ok(Some(value));
+        }
+        match io::Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ENODATA) => return Ok(None),
+            e if e.raw_os_error() == Some(libc::ERANGE) => value.resize(value.len() * 2, 0),
+            e => return Err(e),
+        }
+    }
+}
+
+/// Read a sysfs attribute, without its trailing newline.
+pub fn read_attr(path: &Path) -> io::Result<String> {
+    let mut value = std::fs::read_to_string(path)?;
+    value.truncate(value.trim_end().len());
+    Ok(value)
+}
+
+/// Write out the dirty pages of `offset..offset + len` of `fd` and wait for
+/// them (`sync_file_range`), without syncing metadata.
+pub fn sync_range(fd: RawFd, offset: u64, len: u64) -> io::Result<()> {
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+        | libc::SYNC_FILE_RANGE_WRITE
+        | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    // SAFETY: plain syscall on a caller-provided fd.
+    if unsafe { libc::sync_file_range(fd, offset as i64, len as i64, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Start reading `offset..offset + len` of `fd` into the page cache
+/// (`posix_fadvise` with `POSIX_FADV_WILLNEED`), without waiting for it.
+pub fn fadvise_willneed(fd: RawFd, offset: u64, len: u64) -> io::Result<()> {
+    // SAFETY: plain syscall on a caller-provided fd.
+    let err = unsafe {
+        libc::posix_fadvise(
+            fd,
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        )
+    };
+    // Returns the error number rather than setting errno
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+    Ok(())
+}
+
+/// Deallocate `offset..offset + len` of `fd`, leaving a hole that reads as
+/// zeros (`fallocate` with `FALLOC_FL_PUNCH_HOLE`), without changing its size.
+pub fn punch_hole(fd: RawFd, offset: u64, len: u64) -> io::Result<()> {
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // SAFETY: plain syscall on a caller-provided fd.
+    if unsafe { libc::fallocate(fd, mode, offset as libc::off_t, len as libc::off_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Size of the huge pages [`map_huge`] maps.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Map `len` bytes, a multiple of [`HUGE_PAGE_SIZE`], of zeroed anonymous
+/// memory backed by 2 MiB huge pages from the hugetlbfs pool
+/// (`MAP_HUGETLB`). Fails with `ENOMEM` if the pool has too few free pages.
+pub fn map_huge(len: usize) -> io::Result<NonNull<u8>> {
+    // MAP_HUGE_2MB: log2 of the page size in the bits above MAP_HUGE_SHIFT
+    const MAP_HUGE_2MB: libc::c_int = 21 << 26;
+    // SAFETY: an anonymous mapping at an address of the kernel's choosing
+    // does not alias any memory.
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | MAP_HUGE_2MB,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(NonNull::new(ptr.cast()).expect("mmap does not return null on success"))
+}
+
+/// Map `len` bytes, a multiple of the page size, of zeroed anonymous memory
+/// aligned to `align`, a power of two.
+pub fn map_anonymous(len: usize, align: usize) -> io::Result<NonNull<u8>> {
+    // Map enough to find an aligned start in, and unmap the rest
+    let extra = align.saturating_sub(page_size());
+    // SAFETY: an anonymous mapping at an address of the kernel's choosing
+    // does not alias any memory.
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len + extra,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let base = ptr as usize;
+    let start = (base + align - 1) & !(align - 1);
+    // SAFETY: the head and tail are parts of the mapping no one references.
+    unsafe {
+        if start > base {
+            libc::munmap(ptr, start - base);
+        }
+        if base + extra > start {
+            libc::munmap((start + len) as *mut libc::c_void, base + extra - start);
+        }
+    }
+    Ok(NonNull::new(start as *mut u8).expect("mmap does not return null on success"))
+}
+
+/// Size of a page of memory.
+pub fn page_size() -> usize {
+    // SAFETY: sysconf has no memory-safety preconditions.
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+/// Unmap memory mapped with [`map_huge`] or [`map_anonymous`].
+///
+/// # Safety
+///
+/// `ptr` and `len` must be those of such a mapping that is no longer
+/// referenced.
+pub unsafe fn unmap(ptr: NonNull<u8>, len: usize) {
+    // SAFETY: guaranteed by the caller.
+    unsafe { libc::munmap(ptr.as_ptr().cast(), len) };
+}
+
+/// Allocate the pages of `ptr..ptr + len` on NUMA node `node` as long as it
+/// has free memory (`mbind` with `MPOL_PREFERRED`), moving those already
+/// allocated. `ptr` must be page-aligned.
+pub fn bind_preferred(ptr: NonNull<u8>, len: usize, node: usize) -> io::Result<()> {
+    const MPOL_PREFERRED: libc::c_long = 1;
+    const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+    const BITS: usize = libc::c_ulong::BITS as usize;
+    let mut mask: Vec<libc::c_ulong> = vec![0; node / BITS + 1];
+    mask[node / BITS] |= 1 << (node % BITS);
+    // SAFETY: the mask holds `mask.len() * BITS` bits; the kernel reads one
+    // less than the count passed.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr.as_ptr(),
+            len,
+            MPOL_PREFERRED,
+            mask.as_ptr(),
+            mask.len() * BITS + 1,
+            MPOL_MF_MOVE,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Ask for `ptr..ptr + len` to be backed by transparent huge pages
+/// (`madvise` with `MADV_HUGEPAGE`). `ptr` must be page-aligned.
+pub fn madvise_hugepage(ptr: NonNull<u8>, len: usize) -> io::Result<()> {
+    // SAFETY: advice does not change the contents or validity of memory.
+    if unsafe { libc::madvise(ptr.as_ptr().cast(), len, libc::MADV_HUGEPAGE) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Create an anonymous file in memory (`memfd_create`), named `name` in
+/// `/proc/self/fd`.
+pub fn memfd(name: &str) -> io::Result<File> {
+    let c_name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains NUL"))?;
+    // SAFETY: the name is NUL-terminated.
+    let fd = unsafe { libc::memfd_create(c_name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just created and is owned by nothing else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Whether `fd` is an `O_PATH` descriptor, which only locates a file: its
+/// data cannot be read and ioctls such as FIEMAP fail with `EBADF`.
+pub fn is_path_fd(fd: RawFd) -> io::Result<bool> {
+    // SAFETY: F_GETFL takes no pointer arguments.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & libc::O_PATH != 0)
+}
+
+/// Whether `fd` was opened for writing (`O_WRONLY` or `O_RDWR`).
+pub fn is_writable_fd(fd: RawFd) -> io::Result<bool> {
+    // SAFETY: F_GETFL takes no pointer arguments.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(matches!(
+        flags & libc::O_ACCMODE,
+        libc::O_WRONLY | libc::O_RDWR
+    ))
+}
+
+/// Switch to user `uid` and group `gid` for good, with no supplementary
+/// groups (`setgroups`, `setresgid`, `setresuid`).
+///
+/// Requires `CAP_SETUID` and `CAP_SETGID`. If a step fails, the steps
+/// before it are undone, so that the process keeps all of its previous
+/// credentials; should that fail too, the process aborts rather than run
+/// on with some of them. Fails unless, afterwards, neither user nor group
+/// root can be regained and no supplementary groups are left.
+pub fn drop_privileges(uid: u32, gid: u32) -> io::Result<()> {
+    let groups = supplementary_groups()?;
+    let (rgid, egid, sgid) = real_effective_saved_gids()?;
+    let restore_groups = || {
+        // SAFETY: `groups` holds `groups.len()` IDs.
+        if unsafe { libc::setgroups(groups.len(), groups.as_ptr()) } < 0 {
+            std::process::abort();
+        }
+    };
+    // SAFETY: an empty group list is not read; the rest take integers.
+    unsafe {
+        if libc::setgroups(0, std::ptr::null()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::setresgid(gid, gid, gid) < 0 {
+            let err = io::Error::last_os_error();
+            restore_groups();
+            return Err(err);
+        }
+        if libc::setresuid(uid, uid, uid) < 0 {
+            let err = io::Error::last_os_error();
+            if libc::setresgid(rgid, egid, sgid) < 0 {
+                std::process::abort();
+            }
+            restore_groups();
+            return Err(err);
+        }
+        if uid != 0 && libc::setuid(0) == 0 {
+            return Err(io::Error::other("root privileges could be regained"));
+        }
+        if uid != 0 && gid != 0 && libc::setgid(0) == 0 {
+            return Err(io::Error::other("the root group could be regained"));
+        }
+    }
+    if !supplementary_groups()?.is_empty() {
+        return Err(io::Error::other("supplementary groups were kept"));
+    }
+    Ok(())
+}
+
+/// Real, effective and saved group IDs of the process.
+fn real_effective_saved_gids() -> io::Result<(libc::gid_t, libc::gid_t, libc::gid_t)> {
+    let (mut rgid, mut egid, mut sgid) = (0, 0, 0);
+    // SAFETY: the three pointers are valid for writes of a gid_t.
+    if unsafe { libc::getresgid(&mut rgid, &mut egid, &mut sgid) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((rgid, egid, sgid))
+}
+
+/// User ID of the process at the other end of Unix socket `sock`, as of
+/// when it connected or created the socket pair (`SO_PEERCRED`).
+pub fn peer_uid(sock: RawFd) -> io::Result<u32> {
+    // SAFETY: `ucred` is plain data, for which all zeros is valid.
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes of the sizes given.
+    let ret = unsafe {
+        libc::getsockopt(
+            sock,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Make `fd` blocking again after opening it with `O_NONBLOCK`.
+pub fn clear_nonblock(fd: RawFd) -> io::Result<()> {
+    // SAFETY: F_GETFL and F_SETFL take no pointer arguments.
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Send `data` over the Unix socket `sock` with descriptor `fd` attached
+/// (`SCM_RIGHTS`), if given, returning the number of bytes sent.
+pub fn send_with_fd(sock: RawFd, data: &[u8], fd: Option<RawFd>) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    // u64 elements keep the control message aligned
+    let mut control = [0u64; 4];
+    // SAFETY: an all-zero msghdr is a valid message without control data.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        const FD_LEN: libc::c_uint = std::mem::size_of::<RawFd>() as libc::c_uint;
+        msg.msg_control = control.as_mut_ptr().cast();
+        // SAFETY: CMSG_SPACE only computes a size.
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(FD_LEN) } as _;
+        // SAFETY: the control buffer is large enough and aligned for one
+        // message holding a descriptor.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(FD_LEN) as _;
+            libc::CMSG_DATA(cmsg).cast::<RawFd>().write_unaligned(fd);
+        }
+    }
+    // SAFETY: `msg` points to `data` and the control buffer, both alive.
+    let sent = unsafe { libc::sendmsg(sock, &msg, libc::MSG_NOSIGNAL) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Receive up to `buf.len()` bytes from the Unix socket `sock`, with the
+/// descriptor attached to them, if any.
+pub fn recv_with_fd(sock: RawFd, buf: &mut [u8]) -> io::Result<(usize, Option<File>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 4];
+    // SAFETY: an all-zero msghdr is a valid message without control data.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    // SAFETY: `msg` points to `buf` and the control buffer, both alive.
+    let received = unsafe { libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut file = None;
+    // SAFETY: the kernel filled in the control messages it reports.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let fd = libc::CMSG_DATA(cmsg).cast::<RawFd>().read_unaligned();
+                file = Some(File::from_raw_fd(fd));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "received more descriptors than expected",
+        ));
+    }
+    Ok((received as usize, file))
+}
+
+/// Duplicate file descriptor `fd` of the process `pidfd` refers to
+/// (`pidfd_getfd`), which requires permission to ptrace the process.
+pub fn pidfd_getfd(pidfd: RawFd, fd: RawFd) -> io::Result<File> {
+    // SAFETY: plain syscall without pointer arguments.
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd, fd, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the new descriptor is owned by nothing else.
+    Ok(unsafe { File::from_raw_fd(ret as RawFd) })
+}
+
+/// `ioprio_get`/`ioprio_set` target selecting a thread by id, 0 for the
+/// calling thread.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// Bit position of the scheduling class in an I/O priority.
+pub const IOPRIO_CLASS_SHIFT: u16 = 13;
+
+/// Best-effort I/O scheduling class, with levels 0 (highest) to 7.
+pub const IOPRIO_CLASS_BE: u16 = 2;
+
+/// Idle I/O scheduling class, serviced when the disk is otherwise unused.
+pub const IOPRIO_CLASS_IDLE: u16 = 3;
+
+/// Get the I/O priority of the calling thread (`ioprio_get`).
+pub fn ioprio_get() -> io::Result<u16> {
+    // SAFETY: plain syscall without pointer arguments.
+    let ret = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as u16)
+}
+
+/// Set the I/O priority of the calling thread (`ioprio_set`).
+pub fn ioprio_set(ioprio: u16) -> io::Result<()> {
+    // SAFETY: plain syscall without pointer arguments.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            libc::c_int::from(ioprio),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Get the inode flags (`FS_IOC_GETFLAGS`) of `fd`.
+pub fn inode_flags(fd: RawFd) -> io::Result<u32> {
+    // The kernel reads and writes an `int`, despite the ioctl's declared type.
+    let mut flags: libc::c_int = 0;
+    // SAFETY: `flags` is a valid output buffer for FS_IOC_GETFLAGS.
+    if unsafe { libc::ioctl(fd, libc::FS_IOC_GETFLAGS, &mut flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags as u32)
+}
+
+/// Get the inode generation (`FS_IOC_GETVERSION`) of `fd`, which changes
+/// when the inode number is reused for another file.
+pub fn inode_generation(fd: RawFd) -> io::Result<u32> {
+    // As for FS_IOC_GETFLAGS, the kernel reads and writes an `int`.
+    let mut generation: libc::c_int = 0;
+    // SAFETY: `generation` is a valid output buffer for FS_IOC_GETVERSION.
+    if unsafe { libc::ioctl(fd, libc::FS_IOC_GETVERSION, &mut generation) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(generation as u32)
+}
+
+/// Get the logical sector size (`BLKSSZGET`) of the block device `fd`.
+pub fn logical_block_size(fd: RawFd) -> io::Result<u32> {
+    let mut size: libc::c_int = 0;
+    // SAFETY: `size` is a valid output buffer for BLKSSZGET.
+    if unsafe { libc::ioctl(fd, libc::BLKSSZGET, &mut size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size as u32)
+}
+
+/// Get the physical sector size (`BLKPBSZGET`) of the block device `fd`.
+pub fn physical_block_size(fd: RawFd) -> io::Result<u32> {
+    let mut size: libc::c_uint = 0;
+    // SAFETY: `size` is a valid output buffer for BLKPBSZGET.
+    if unsafe { libc::ioctl(fd, libc::BLKPBSZGET, &mut size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size)
+}
+
+/// Get the size in bytes (`BLKGETSIZE64`) of the block device `fd`.
+pub fn device_size(fd: RawFd) -> io::Result<u64> {
+    let mut size: u64 = 0;
+    // SAFETY: `size` is a valid output buffer for BLKGETSIZE64.
+    if unsafe { libc::ioctl(fd, BLKGETSIZE64 as _, &mut size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size)
+}
+
+/// btrfs tree search ioctl request code (`_IOWR(0x94, 17, struct btrfs_ioctl_search_args)`).
+const BTRFS_IOC_TREE_SEARCH: libc::c_ulong = 0xD0009411;
+
+/// btrfs member device query ioctl request code
+/// (`_IOWR(0x94, 30, struct btrfs_ioctl_dev_info_args)`).
+const BTRFS_IOC_DEV_INFO: libc::c_ulong = 0xD000941E;
+
+/// Size of the result buffer of a btrfs tree search.
+const BTRFS_SEARCH_BUF_SIZE: usize = 4096 - std::mem::size_of::<BtrfsSearchKey>();
+
+/// Key range of a btrfs tree search (`struct btrfs_ioctl_search_key`).
+#[repr(C)]
+#[derive(Debug, Default)]
+struct BtrfsSearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    nr_items: u32,
+    unused: u32,
+    unused1: u64,
+    unused2: u64,
+    unused3: u64,
+    unused4: u64,
+}
+
+/// Argument of `BTRFS_IOC_TREE_SEARCH` (`struct btrfs_ioctl_search_args`).
+#[repr(C)]
+struct BtrfsSearchArgs {
+    key: BtrfsSearchKey,
+    buf: [u8; BTRFS_SEARCH_BUF_SIZE],
+}
+
+/// Argument of `BTRFS_IOC_DEV_INFO` (`struct btrfs_ioctl_dev_info_args`).
+#[repr(C)]
+struct BtrfsDevInfoArgs {
+    devid: u64,
+    uuid: [u8; 16],
+    bytes_used: u64,
+    total_bytes: u64,
+    unused: [u64; 379],
+    path: [u8; 1024],
+}
+
+/// An item found by a btrfs tree search.
+#[derive(Debug, Clone)]
+pub struct BtrfsItem {
+    /// Offset field of the item's key.
+    pub offset: u64,
+    /// Raw item data, in on-disk (little-endian) layout.
+    pub data: Vec<u8>,
+}
+
+/// Get all items with `objectid` and `item_type` in tree `tree_id` of the
+/// btrfs filesystem holding `fd`, in key order. Requires `CAP_SYS_ADMIN`.
+///
+/// Large results are fetched in several searches.
+pub fn btrfs_tree_search(
+    fd: RawFd,
+    tree_id: u64,
+    objectid: u64,
+    item_type: u32,
+) -> io::Result<Vec<BtrfsItem>> {
+    // Header of each result (`struct btrfs_ioctl_search_header`)
+    const HEADER_SIZE: usize = 32;
+
+    let mut items = Vec::new();
+    let mut min_offset = 0;
+    loop {
+        let mut args = Box::new(BtrfsSearchArgs {
+            key: BtrfsSearchKey {
+                tree_id,
+                min_objectid: objectid,
+                max_objectid: objectid,
+                min_offset,
+                max_offset: u64::MAX,
+                max_transid: u64::MAX,
+                min_type: item_type,
+                max_type: item_type,
+                nr_items: u32::MAX,
+                ..Default::default()
+            },
+            buf: [0; BTRFS_SEARCH_BUF_SIZE],
+        });
+        // SAFETY: `args` has the layout the ioctl expects.
+        let ret = unsafe {
+            libc::ioctl(
+                fd,
+                BTRFS_IOC_TREE_SEARCH,
+                &mut *args as *mut BtrfsSearchArgs,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if args.key.nr_items == 0 {
+            return Ok(items);
+        }
+
+        let mut pos = 0;
+        let mut last_offset = min_offset;
+        for _ in 0..args.key.nr_items {
+            let header = args.buf.get(pos..pos + HEADER_SIZE).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "truncated search result")
+            })?;
+            let field = |at: usize| u64::from_ne_bytes(header[at..at + 8].try_into().unwrap());
+            let (found_objectid, offset) = (field(8), field(16));
+            let found_type = u32::from_ne_bytes(header[24..28].try_into().unwrap());
+            let len = u32::from_ne_bytes(header[28..32].try_into().unwrap()) as usize;
+            let data = args
+                .buf
+                .get(pos + HEADER_SIZE..pos + HEADER_SIZE + len)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "truncated search result")
+                })?;
+            if found_objectid == objectid && found_type == item_type {
+                items.push(BtrfsItem {
+                    offset,
+                    data: data.to_vec(),
+                });
+            }
+            last_offset = offset;
+            pos += HEADER_SIZE + len;
+        }
+        if last_offset == u64::MAX {
+            return Ok(items);
+        }
+        min_offset = last_offset + 1;
+    }
+}
+
+/// Get the path of member device `devid` of the btrfs filesystem holding
+/// `fd`.
+pub fn btrfs_device_path(fd: RawFd, devid: u64) -> io::Result<PathBuf> {
+    // SAFETY: `BtrfsDevInfoArgs` is plain old data and a zeroed value is valid.
+    let mut args: Box<BtrfsDevInfoArgs> = Box::new(unsafe { std::mem::zeroed() });
+    args.devid = devid;
+    // SAFETY: `args` has the layout the ioctl expects.
+    if unsafe { libc::ioctl(fd, BTRFS_IOC_DEV_INFO, &mut *args as *mut BtrfsDevInfoArgs) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let path = CStr::from_bytes_until_nul(&args.path)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "device path is not terminated"))?;
+    Ok(PathBuf::from(OsStr::from_bytes(path.to_bytes())))
+}
+
+/// device-mapper table query ioctl request code (`_IOWR(0xfd, 12, struct dm_ioctl)`).
+const DM_TABLE_STATUS: libc::c_ulong = 0xC138FD0C;
+
+/// Request the table instead of the target status (`DM_STATUS_TABLE_FLAG`).
+const DM_STATUS_TABLE_FLAG: u32 = 1 << 4;
+
+/// Set by the kernel if the result did not fit (`DM_BUFFER_FULL_FLAG`).
+const DM_BUFFER_FULL_FLAG: u32 = 1 << 8;
+
+/// Header of the device-mapper ioctl argument (`struct dm_ioctl`).
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DmIoctl {
+    version: [u32; 3],
+    data_size: u32,
+    data_start: u32,
+    target_count: u32,
+    open_count: i32,
+    flags: u32,
+    event_nr: u32,
+    padding: u32,
+    dev: u64,
+    name: [u8; 128],
+    uuid: [u8; 129],
+    data: [u8; 7],
+}
+
+const _: () = assert!(std::mem::size_of::<DmIoctl>() == 312);
+
+/// Get the active table of the device-mapper device `name`.
+///
+/// Returns the number of targets and the result area, holding a
+/// `struct dm_target_spec` and parameter string per target. Requires
+/// `CAP_SYS_ADMIN`.
+pub fn dm_table(name: &str) -> io::Result<(u32, Vec<u8>)> {
+    let control = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/mapper/control")?;
+    if name.len() >= 128 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "device-mapper name is too long",
+        ));
+    }
+
+    let header_size = std::mem::size_of::<DmIoctl>();
+    let mut size = 16384;
+    loop {
+        // u64 words keep the buffer aligned for the header
+        let mut buf = vec![0u64; size / 8];
+        // SAFETY: `DmIoctl` is plain old data and a zeroed value is valid.
+        let mut header: DmIoctl = unsafe { std::mem::zeroed() };
+        header.version = [4, 0, 0];
+        header.data_size = size as u32;
+        header.data_start = header_size as u32;
+        header.flags = DM_STATUS_TABLE_FLAG;
+        header.name[..name.len()].copy_from_slice(name.as_bytes());
+        // SAFETY: the buffer is larger than and aligned for the header.
+        unsafe { std::ptr::write(buf.as_mut_ptr().cast::<DmIoctl>(), header) };
+
+        // SAFETY: the buffer is `data_size` bytes long.
+        let ret = unsafe {
+            libc::ioctl(
+                control.as_raw_fd(),
+                DM_TABLE_STATUS,
+                buf.as_mut_ptr().cast::<DmIoctl>(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the kernel wrote the header back in place.
+        let header = unsafe { std::ptr::read(buf.as_ptr().cast::<DmIoctl>()) };
+        if header.flags & DM_BUFFER_FULL_FLAG != 0 {
+            size *= 4;
+            continue;
+        }
+
+        // SAFETY: the buffer is `size` bytes of initialized memory.
+        let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr().cast::<u8>(), size) };
+        let start = (header.data_start as usize).min(size);
+        let end = (header.data_size as usize).clamp(start, size);
+        return Ok((header.target_count, bytes[start..end].to_vec()));
+    }
+}
+
+/// `cachestat` system call number, the same on all architectures.
+const SYS_CACHESTAT: libc::c_long = 451;
+
+/// Byte range argument of `cachestat` (`struct cachestat_range`).
+#[repr(C)]
+struct CachestatRange {
+    off: u64,
+    len: u64,
+}
+
+/// Page cache state of a file range (`struct cachestat`).
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Cachestat {
+    /// Pages in the page cache.
+    pub nr_cache: u64,
+    /// Dirty pages, not yet written back.
+    pub nr_dirty: u64,
+    /// Pages being written back.
+    pub nr_writeback: u64,
+    /// Pages evicted from the page cache.
+    pub nr_evicted: u64,
+    /// Pages evicted recently.
+    pub nr_recently_evicted: u64,
+}
+
+/// Get the page cache state of `offset..offset + len` of `fd` (Linux 6.5+).
+///
+/// A `len` of zero extends the range to the end of the file. Fails with
+/// `ENOSYS` on older kernels.
+pub fn cachestat(fd: RawFd, offset: u64, len: u64) -> io::Result<Cachestat> {
+    let range = CachestatRange { off: offset, len };
+    let mut stat = Cachestat::default();
+    // SAFETY: `range` and `stat` are valid for the duration of the call.
+    let ret = unsafe {
+        libc::syscall(
+            SYS_CACHESTAT,
+            fd,
+            &range as *const CachestatRange,
+            &mut stat as *mut Cachestat,
+            0u32,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+/// Count the pages of `offset..offset + len` of `fd` in the page cache.
+///
+/// Uses `mincore` on a temporary mapping of the range, which reports
+/// residency only, not whether the pages are dirty.
+pub fn resident_pages(fd: RawFd, offset: u64, len: u64) -> io::Result<u64> {
+    if len == 0 {
+        return Ok(0);
+    }
+    // SAFETY: `sysconf` has no preconditions.
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+    let start = offset - offset % page;
+    let map_len = (offset + len - start).div_ceil(page) * page;
+
+    // SAFETY: a fresh shared read-only mapping; its pages are never accessed.
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            map_len as usize,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            fd,
+            start as libc::off_t,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut vec = vec![0u8; (map_len / page) as usize];
+    // SAFETY: `addr` maps `map_len` bytes and `vec` has one byte per page.
+    let ret = unsafe { libc::mincore(addr, map_len as usize, vec.as_mut_ptr()) };
+    let result = if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(vec.iter().filter(|&&v| v & 1 != 0).count() as u64)
+    };
+    // SAFETY: `addr` was mapped above and is not used afterwards.
+    unsafe { libc::munmap(addr, map_len as usize) };
+    result
+}
+
+/// Copy up to `len` bytes from `in_fd` at `offset` to `out_fd` with `sendfile`.
+///
+/// The data never passes through user space. Returns the number of bytes
+/// copied, which may be short; zero means end of input.
+pub fn sendfile(out_fd: RawFd, in_fd: RawFd, offset: u64, len: usize) -> io::Result<usize> {
+    let mut off = offset as libc::off_t;
+    loop {
+        // SAFETY: `off` is a valid offset buffer; the kernel validates the fds.
+        let ret = unsafe { libc::sendfile(out_fd, in_fd, &mut off, len) };
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Read into several buffers from consecutive locations starting at `offset`.
+///
+/// Non-zero `flags` (`RWF_*`) are passed on with `preadv2`. Returns the
+/// total number of bytes read, which may be short.
+pub fn preadv(
+    fd: RawFd,
+    bufs: &mut [IoSliceMut<'_>],
+    offset: u64,
+    flags: i32,
+) -> io::Result<usize> {
+    loop {
+        // SAFETY: `IoSliceMut` is ABI compatible with `struct iovec`, and
+        // each slice is valid for writes of its length.
+        let ret = unsafe {
+            let iov = bufs.as_mut_ptr() as *const libc::iovec;
+            let count = bufs.len() as libc::c_int;
+            if flags == 0 {
+                libc::preadv(fd, iov, count, offset as libc::off_t)
+            } else {
+                libc::preadv2(fd, iov, count, offset as libc::off_t, flags)
+            }
+        };
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Read into `buf` from `offset`, retrying on `EINTR`.
+///
+/// Non-zero `flags` (`RWF_*`) are passed on with `preadv2`. Returns the
+/// number of bytes read, which may be short.
+pub fn pread(fd: RawFd, buf: &mut [u8], offset: u64, flags: i32) -> io::Result<usize> {
+    if flags != 0 {
+        return preadv(fd, &mut [IoSliceMut::new(buf)], offset, flags);
+    }
+    loop {
+        // SAFETY: `buf` is valid for writes of its length.
+        let ret = unsafe {
+            libc::pread(
+                fd,
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                offset as libc::off_t,
+            )
+        };
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Native AIO vectored read command (`IOCB_CMD_PREADV`).
+const IOCB_CMD_PREADV: u16 = 7;
+
+/// A native AIO control block (`struct iocb`).
+///
+/// `aio_key` and `aio_rw_flags` swap places on big-endian machines, so
+/// little-endian layout is assumed.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Iocb {
+    aio_data: u64,
+    aio_key: u32,
+    aio_rw_flags: i32,
+    aio_lio_opcode: u16,
+    aio_reqprio: i16,
+    aio_fildes: u32,
+    aio_buf: u64,
+    aio_nbytes: u64,
+    aio_offset: i64,
+    aio_reserved2: u64,
+    aio_flags: u32,
+    aio_resfd: u32,
+}
+
+impl Iocb {
+    /// A vectored read of `bufs` from `offset` with `RWF_*` `flags`,
+    /// tagged with `index`.
+    pub fn preadv(
+        fd: RawFd,
+        bufs: &mut [IoSliceMut<'_>],
+        offset: u64,
+        flags: i32,
+        index: usize,
+    ) -> Self {
+        Self {
+            aio_data: index as u64,
+            aio_rw_flags: flags,
+            aio_lio_opcode: IOCB_CMD_PREADV,
+            aio_fildes: fd as u32,
+            aio_buf: bufs.as_mut_ptr() as u64,
+            aio_nbytes: bufs.len() as u64,
+            aio_offset: offset as i64,
+            ..Default::default()
+        }
+    }
+}
+
+/// A native AIO completion (`struct io_event`).
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IoEvent {
+    data: u64,
+    obj: u64,
+    res: i64,
+    res2: i64,
+}
+
+impl IoEvent {
+    /// Index the completed request was tagged with.
+    pub fn index(&self) -> usize {
+        self.data as usize
+    }
+
+    /// Number of bytes transferred, or the error of the request.
+    pub fn result(&self) -> io::Result<usize> {
+        if self.res < 0 {
+            Err(io::Error::from_raw_os_error(-self.res as i32))
+        } else {
+            Ok(self.res as usize)
+        }
+    }
+}
+
+/// A native AIO context (`aio_context_t`), destroyed on drop.
+#[derive(Debug)]
+pub struct AioContext(libc::c_ulong);
+
+impl AioContext {
+    /// Set up a context for up to `depth` requests in flight.
+    pub fn new(depth: usize) -> io::Result<Self> {
+        let mut ctx: libc::c_ulong = 0;
+        // SAFETY: `ctx` is a valid, zeroed `aio_context_t` output buffer.
+        let ret = unsafe { libc::syscall(libc::SYS_io_setup, depth as libc::c_uint, &mut ctx) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(ctx))
+    }
+
+    /// Submit requests, returning how many were accepted.
+    ///
+    /// # Safety
+    ///
+    /// The buffers referenced by `iocbs` must stay valid until their
+    /// requests complete or the context is dropped. The control blocks
+    /// and iovec arrays are copied by the kernel and may be freed at once.
+    pub unsafe fn submit(&self, iocbs: &[Iocb]) -> io::Result<usize> {
+        if iocbs.is_empty() {
+            return Ok(0);
+        }
+        let ptrs: Vec<*const Iocb> = iocbs.iter().map(|iocb| iocb as *const Iocb).collect();
+        loop {
+            // SAFETY: `ptrs` holds `iocbs.len()` valid control blocks.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_submit,
+                    self.0,
+                    ptrs.len() as libc::c_long,
+                    ptrs.as_ptr(),
+                )
+            };
+            if ret >= 0 {
+                return Ok(ret as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Wait for at least `min` and at most `max` completions.
+    pub fn get_events(&self, min: usize, max: usize) -> io::Result<Vec<IoEvent>> {
+        let mut events = vec![IoEvent::default(); max];
+        loop {
+            // SAFETY: `events` has room for `max` completions; no timeout.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_getevents,
+                    self.0,
+                    min as libc::c_long,
+                    max as libc::c_long,
+                    events.as_mut_ptr(),
+                    std::ptr::null_mut::<libc::timespec>(),
+                )
+            };
+            if ret >= 0 {
+                events.truncate(ret as usize);
+                return Ok(events);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl Drop for AioContext {
+    fn drop(&mut self) {
+        // Cancels outstanding requests and waits for them to complete
+        // SAFETY: the context was set up by `io_setup` and is destroyed once.
+        unsafe { libc::syscall(libc::SYS_io_destroy, self.0) };
+    }
+}
+
+/// Check whether `io_uring` can be set up by this process.
+pub fn io_uring_available() -> bool {
+    // `struct io_uring_params` is 120 bytes; all-zero requests defaults.
+    let mut params = [0u8; 120];
+
+    // SAFETY: `params` is a correctly sized, zeroed `io_uring_params`.
+    let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, 1u32, params.as_mut_ptr()) };
+    if fd < 0 {
+        return false;
+    }
+
+    // SAFETY: `fd` is a file descriptor we just created and own.
+    unsafe { libc::close(fd as RawFd) };
+    true
+}
+
+/// Check whether the effective user may read the given path.
+pub fn can_read(path: &Path) -> bool {
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+
+    // SAFETY: `c_path` is NUL-terminated.
+    unsafe {
+        libc::faccessat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            libc::R_OK,
+            libc::AT_EACCESS,
+        ) == 0
+    }
+}
+
+/// Effective capability set of the process, as a bit mask of capability
+/// numbers.
+pub fn effective_capabilities() -> io::Result<u64> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no CapEff in status"))
+}
+
+/// Effective and supplementary group IDs of the process.
+pub fn group_ids() -> io::Result<Vec<libc::gid_t>> {
+    let mut groups = supplementary_groups()?;
+    // SAFETY: getegid has no preconditions and cannot fail.
+    groups.push(unsafe { libc::getegid() });
+    Ok(groups)
+}
+
+/// Supplementary group IDs of the process.
+fn supplementary_groups() -> io::Result<Vec<libc::gid_t>> {
+    // SAFETY: with a size of 0, getgroups only returns the count.
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut groups = vec![0; count as usize];
+    // SAFETY: `groups` has room for `count` IDs.
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    groups.truncate(count as usize);
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_fiemap_header_size() {
+        assert_eq!(std::mem::size_of::<FiemapHeader>(), 32);
+    }
+
+    #[test]
+    fn test_fiemap_count() {
+        let file = tempfile::tempfile().unwrap();
+        match fiemap_count(file.as_raw_fd(), 0) {
+            Ok(count) => assert_eq!(count, 0),
+            Err(e) => assert!(matches!(
+                e.raw_os_error(),
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY)
+            )),
+        }
+    }
+
+    #[test]
+    fn test_fiemap_sync() {
+        use std::io::Write;
+
+        // Written through the page cache but not yet synced
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[0x5A; 16384]).unwrap();
+        let extents = match fiemap(file.as_raw_fd(), 0, 16384, FIEMAP_FLAG_SYNC) {
+            Ok(extents) => extents,
+            Err(e) => return assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+        };
+        assert!(!extents.is_empty());
+        assert!(extents.iter().all(|extent| !extent.flags.is_delalloc()));
+        let end = extents.last().map(|extent| extent.logical + extent.length);
+        assert!(end >= Some(16384));
+    }
+
+    #[test]
+    fn test_fiemap_paging() {
+        use std::os::unix::fs::FileExt;
+
+        // Data blocks separated by holes cannot be merged into one extent
+        let count = FIEMAP_BATCH * 2 + 88;
+        let file = tempfile::tempfile().unwrap();
+        for i in 0..count as u64 {
+            file.write_all_at(&[0xA5; 4096], i * 8192).unwrap();
+        }
+        file.sync_all().unwrap();
+
+        let extents = fiemap(file.as_raw_fd(), 0, u64::MAX, 0).unwrap();
+        assert!(extents.len() >= FIEMAP_BATCH, "{} extents", extents.len());
+        assert!(extents
+            .windows(2)
+            .all(|pair| pair[0].logical + pair[0].length <= pair[1].logical));
+        let end = extents.last().map(|extent| extent.logical + extent.length);
+        assert_eq!(end, Some((count as u64 - 1) * 8192 + 4096));
+        assert!(extents.last().unwrap().flags.contains(ExtentFlags::LAST));
+    }
+
+    #[test]
+    fn test_statx_dio_align_fd() {
+        let file = tempfile::tempfile().unwrap();
+        // ext4 and XFS report Direct I/O alignment since Linux 6.1
+        let (mem, offset) = statx_dio_align_fd(file.as_raw_fd()).unwrap().unwrap();
+        assert!(mem.is_power_of_two());
+        assert!(offset.is_power_of_two());
+    }
+
+    #[test]
+    fn test_page_cache_probes() {
+        use std::io::Write;
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[0x5A; 16384]).unwrap();
+
+        // cachestat is available since Linux 6.5
+        let stat = cachestat(file.as_raw_fd(), 0, 16384).unwrap();
+        assert!(stat.nr_cache <= 16384 / 4096);
+        assert!(stat.nr_dirty + stat.nr_writeback <= stat.nr_cache);
+
+        let resident = resident_pages(file.as_raw_fd(), 100, 8192).unwrap();
+        assert!(resident <= 3);
+        assert_eq!(resident_pages(file.as_raw_fd(), 0, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sendfile() {
+        use std::io::{Read, Seek, Write};
+
+        let mut input = tempfile::tempfile().unwrap();
+        input.write_all(b"hello world").unwrap();
+        let mut output = tempfile::tempfile().unwrap();
+
+        let copied = sendfile(output.as_raw_fd(), input.as_raw_fd(), 6, 100).unwrap();
+        assert_eq!(copied, 5);
+
+        let mut data = String::new();
+        output.rewind().unwrap();
+        output.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "world");
+    }
+
+    #[test]
+    fn test_iocb_layout() {
+        assert_eq!(std::mem::size_of::<Iocb>(), 64);
+        assert_eq!(std::mem::size_of::<IoEvent>(), 32);
+    }
+
+    #[test]
+    fn test_can_read() {
+        assert!(can_read(Path::new("/proc/self/exe")));
+        assert!(!can_read(Path::new("/nonexistent/path")));
+    }
+}
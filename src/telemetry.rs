@@ -0,0 +1,294 @@
+//! Built-in instrumentation for telemetry crates.
+//!
+//! With the `tracing` feature, every read runs in a `blk_read_at_opt` span
+//! and its stages are reported as events by [`TracingObserver`], which the
+//! reader calls alongside the [`Options::observer`](crate::Options::observer).
+//! Events of device reads are at `DEBUG` level, those of FIEMAP queries and
+//! fills at `TRACE`, all with the `blkreader` target.
+//!
+//! With the `metrics` feature, [`MetricsObserver`] records the latency and
+//! size of device reads and the latency of FIEMAP queries into histograms
+//! of the `metrics` facade, and [`publish_stats`] publishes the counters of
+//! [`stats`](crate::stats) when an exporter is about to be scraped.
+
+use crate::observer::{ExtentReadEvent, FiemapEvent, ReadObserver};
+#[cfg(feature = "tracing")]
+use crate::observer::{FallbackEvent, FillEvent};
+
+use std::io;
+use std::time::Duration;
+
+/// Target of the spans and events of the `tracing` feature.
+#[cfg(feature = "tracing")]
+const TARGET: &str = "blkreader";
+
+/// Span covering one read of `length` bytes at `offset` of `path`.
+///
+/// Its `bytes_read` field is recorded when the read completes.
+#[cfg(feature = "tracing")]
+pub(crate) fn read_span(
+    path: Option<&std::path::Path>,
+    offset: u64,
+    length: usize,
+) -> tracing::Span {
+    tracing::debug_span!(
+        target: TARGET,
+        "blk_read_at_opt",
+        path = path.map(|path| tracing::field::display(path.display())),
+        offset,
+        length,
+        bytes_read = tracing::field::Empty,
+    )
+}
+
+/// Reports the stages of reads as `tracing` events.
+#[cfg(feature = "tracing")]
+#[derive(Debug)]
+pub(crate) struct TracingObserver;
+
+#[cfg(feature = "tracing")]
+impl ReadObserver for TracingObserver {
+    fn on_fiemap(&self, event: &FiemapEvent<'_>) {
+        tracing::trace!(
+            target: TARGET,
+            offset = event.offset,
+            length = event.length,
+            extents = event.extents.len(),
+            duration_us = event.duration.as_micros() as u64,
+            "fiemap"
+        );
+    }
+
+    fn on_extent_read_end(
+        &self,
+        event: &ExtentReadEvent<'_>,
+        result: Result<usize, &io::Error>,
+        duration: Duration,
+    ) {
+        match result {
+            Ok(bytes) => tracing::debug!(
+                target: TARGET,
+                device = %event.device.display(),
+                logical = event.logical,
+                physical = event.physical,
+                length = event.length,
+                duration_us = duration.as_micros() as u64,
+                bytes,
+                "extent read"
+            ),
+            Err(error) => tracing::debug!(
+                target: TARGET,
+                device = %event.device.display(),
+                logical = event.logical,
+                physical = event.physical,
+                length = event.length,
+                duration_us = duration.as_micros() as u64,
+                %error,
+                "extent read failed"
+            ),
+        }
+    }
+
+    fn on_hole_fill(&self, event: &FillEvent) {
+        tracing::trace!(
+            target: TARGET,
+            logical = event.logical,
+            length = event.length,
+            source = ?event.source,
+            "fill"
+        );
+    }
+
+    fn on_fallback(&self, event: &FallbackEvent) {
+        tracing::debug!(
+            target: TARGET,
+            offset = event.offset,
+            length = event.length,
+            bytes_read = event.bytes_read,
+            "fallback read"
+        );
+    }
+}
+
+/// Records reads into histograms of the `metrics` facade.
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+pub(crate) struct MetricsObserver;
+
+#[cfg(feature = "metrics")]
+impl ReadObserver for MetricsObserver {
+    fn on_fiemap(&self, event: &FiemapEvent<'_>) {
+        metrics::histogram!("blkreader_fiemap_seconds").record(event.duration.as_secs_f64());
+    }
+
+    fn on_extent_read_end(
+        &self,
+        _: &ExtentReadEvent<'_>,
+        result: Result<usize, &io::Error>,
+        duration: Duration,
+    ) {
+        metrics::histogram!("blkreader_extent_read_seconds").record(duration.as_secs_f64());
+        if let Ok(bytes) = result {
+            metrics::histogram!("blkreader_extent_read_bytes").record(bytes as f64);
+        }
+    }
+}
+
+/// Publish the process-wide counters of [`stats`](crate::stats) through
+/// the `metrics` facade.
+///
+/// Sets the counters `blkreader_device_bytes_total`,
+/// `blkreader_fallback_bytes_total`, `blkreader_filled_bytes_total`,
+/// `blkreader_fiemap_calls_total` and `blkreader_errors_total`, the last
+/// labelled with the `kind` of error, to their totals since the process
+/// started. Call it before the exporter is scraped, e.g. from its upkeep
+/// hook or a timer. The histograms of reads are recorded as they happen.
+///
+/// Requires the `metrics` feature.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// std::thread::spawn(|| loop {
+///     blkreader::publish_stats();
+///     std::thread::sleep(Duration::from_secs(10));
+/// });
+/// ```
+#[cfg(feature = "metrics")]
+pub fn publish_stats() {
+    let stats = crate::stats();
+    metrics::counter!("blkreader_device_bytes_total").absolute(stats.device_bytes);
+    metrics::counter!("blkreader_fallback_bytes_total").absolute(stats.fallback_bytes);
+    metrics::counter!("blkreader_filled_bytes_total").absolute(stats.filled_bytes);
+    metrics::counter!("blkreader_fiemap_calls_total").absolute(stats.fiemap_calls);
+    for (kind, count) in stats.errors {
+        metrics::counter!("blkreader_errors_total", "kind" => kind.to_string()).absolute(count);
+    }
+}
+
+/// The observers enabled by features, called for every read.
+pub(crate) fn builtin_observers() -> &'static [&'static dyn ReadObserver] {
+    &[
+        #[cfg(feature = "tracing")]
+        &TracingObserver,
+        #[cfg(feature = "metrics")]
+        &MetricsObserver,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buffer::AlignedBuf;
+    use crate::reader::BlkReader;
+    use crate::test_support::file_on_image;
+
+    #[cfg(feature = "tracing")]
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    /// Collects what a subscriber writes.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    #[cfg(feature = "tracing")]
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        let (temp, options) = file_on_image(&[0x5A; 8192]);
+
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+        let mut buf = AlignedBuf::new(4096, 4096);
+        tracing::subscriber::with_default(subscriber, || {
+            temp.path()
+                .blk_read_at_opt(&mut buf, 4096, &options)
+                .unwrap();
+        });
+        assert!(buf.iter().all(|&b| b == 0x5A));
+
+        // Extent reads are reported within the span of the read
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let line = output
+            .lines()
+            .find(|line| line.contains("extent read"))
+            .unwrap_or_else(|| panic!("no extent read in {:?}", output));
+        assert!(line.contains("blk_read_at_opt{"), "{}", line);
+        assert!(line.contains("offset=4096 length=4096"), "{}", line);
+        assert!(line.contains("blkreader:"), "{}", line);
+        assert!(line.contains("bytes=4096"), "{}", line);
+        assert!(
+            line.contains(&format!("device={}", temp.image.path().display())),
+            "{}",
+            line
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let (temp, options) = file_on_image(&[0x5A; 8192]);
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let mut buf = AlignedBuf::new(8192, 4096);
+        metrics::with_local_recorder(&recorder, || {
+            temp.path().blk_read_at_opt(&mut buf, 0, &options).unwrap();
+            super::publish_stats();
+        });
+
+        let snapshot: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| (key.key().name().to_string(), value))
+            .collect();
+        let value = |name: &str| {
+            snapshot
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .unwrap_or_else(|| panic!("no {} in {:?}", name, snapshot))
+        };
+        match value("blkreader_extent_read_bytes") {
+            DebugValue::Histogram(bytes) => {
+                assert_eq!(bytes.iter().map(|b| b.into_inner()).sum::<f64>(), 8192.0)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            value("blkreader_extent_read_seconds"),
+            DebugValue::Histogram(_)
+        ));
+        assert!(matches!(
+            value("blkreader_fiemap_seconds"),
+            DebugValue::Histogram(_)
+        ));
+
+        // The counters are process-wide, so other tests may have added to them
+        match value("blkreader_device_bytes_total") {
+            DebugValue::Counter(total) => assert!(*total >= 8192),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Fixtures shared by the crate's unit tests.
+//!
+//! Reading a file from its block device needs privileges the tests do not
+//! have. The fixtures here stand in for the device: a regular file opened
+//! as a [`DeviceHandle`], or a sparse image holding a file's data where its
+//! extents point, read through [`DeviceSource::Image`].
+
+use crate::backend::DeviceSource;
+use crate::cache::CachedDevice;
+use crate::options::Options;
+use crate::reader::{fiemap_file, DeviceHandle};
+
+use std::fs::File;
+use std::io::Write;
+use std::ops::Deref;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use tempfile::NamedTempFile;
+
+/// A file to read through caller-supplied extents, which are never
+/// checked against its own.
+pub(crate) fn stand_in_file() -> File {
+    tempfile::tempfile().unwrap()
+}
+
+/// A file to read through caller-supplied extents and the fake device
+/// holding `data`, which the extents point into.
+pub(crate) fn fixture(data: &[u8]) -> (File, DeviceHandle) {
+    (stand_in_file(), fake_device(data))
+}
+
+/// Build a device handle backed by a regular temporary file.
+pub(crate) fn fake_device(data: &[u8]) -> DeviceHandle {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(data).unwrap();
+    DeviceHandle::Uncached(CachedDevice {
+        path: PathBuf::from("/dev/fake"),
+        file,
+        info: None,
+        direct: true,
+        base_offset: 0,
+        in_flight: Default::default(),
+    })
+}
+
+/// A temporary file whose data is also on an image, kept alongside it.
+pub(crate) struct ImageFile {
+    file: NamedTempFile,
+    /// The image standing in for the file's device.
+    pub(crate) image: NamedTempFile,
+}
+
+impl Deref for ImageFile {
+    type Target = NamedTempFile;
+
+    fn deref(&self) -> &NamedTempFile {
+        &self.file
+    }
+}
+
+/// A synced temporary file holding `data`, and options reading it from an
+/// image instead of its block device.
+pub(crate) fn file_on_image(data: &[u8]) -> (ImageFile, Options) {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(data).unwrap();
+    file.as_file().sync_all().unwrap();
+    let (image, options) = image_of(file.as_file());
+    (ImageFile { file, image }, options)
+}
+
+/// A sparse image holding the data of `file` where its extents point, and
+/// options reading from it instead of the file's block device.
+///
+/// The file's data must be synced, so that its extents are allocated.
+pub(crate) fn image_of(file: &File) -> (NamedTempFile, Options) {
+    image_at(file, 0)
+}
+
+/// Like [`image_of`], for a disk image holding the device as a partition
+/// starting `offset` bytes into it.
+pub(crate) fn image_at(file: &File, offset: u64) -> (NamedTempFile, Options) {
+    let size = file.metadata().unwrap().len();
+    let image = NamedTempFile::new().unwrap();
+    for extent in fiemap_file(file).unwrap() {
+        // Past the end of the file, the rest of the block reads as zeros
+        let end = (extent.logical + extent.length).min(size);
+        let mut data = vec![0u8; extent.length as usize];
+        let len = end.saturating_sub(extent.logical) as usize;
+        file.read_exact_at(&mut data[..len], extent.logical)
+            .unwrap();
+        image
+            .as_file()
+            .write_all_at(&data, offset + extent.physical)
+            .unwrap();
+    }
+    let options = Options::new().with_device_override(DeviceSource::Image {
+        path: image.path().into(),
+        offset,
+    });
+    (image, options)
+}
@@ -0,0 +1,164 @@
+//! Loop device fixtures for integration tests.
+//!
+//! Testing reads end to end needs a filesystem on a block device the test
+//! controls. [`LoopFixture`] creates one from an image file: it attaches the
+//! image to a loop device, makes a filesystem on it and mounts it, and
+//! undoes all of that when dropped. This needs root and the `losetup`,
+//! `mkfs.<type>`, `mount` and `umount` tools; enable the `test-util`
+//! feature in `[dev-dependencies]` to use it.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A filesystem mounted from a loop device, torn down when dropped.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkReader, LoopFixture, Options};
+///
+/// let fixture = LoopFixture::new("ext4", 64 << 20)?;
+/// let path = fixture.write("data", &[0xAB; 8192])?;
+/// let mut buf = vec![0u8; 4096];
+/// let state = path.blk_read_at_opt(&mut buf, 4096, &Options::new())?;
+/// assert_eq!(state.block_device_path, fixture.device());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct LoopFixture {
+    /// Holds the image and the mount point, removed last.
+    _dir: tempfile::TempDir,
+    image: PathBuf,
+    device: PathBuf,
+    mount_point: PathBuf,
+    mounted: bool,
+}
+
+impl LoopFixture {
+    /// Create a filesystem of type `fstype` (such as `ext4`, `xfs` or
+    /// `btrfs`) on an image of `size` bytes, and mount it.
+    ///
+    /// Fails with [`PermissionDenied`](io::ErrorKind::PermissionDenied)
+    /// when not run as root. Tests using it should be `#[ignore]`d, to be
+    /// run with `cargo test -- --ignored` where root is available.
+    pub fn new(fstype: &str, size: u64) -> io::Result<Self> {
+        // SAFETY: `geteuid` has no preconditions.
+        if unsafe { libc::geteuid() } != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "loop device fixtures need root",
+            ));
+        }
+
+        let dir = tempfile::tempdir()?;
+        let image = dir.path().join("image");
+        File::create(&image)?.set_len(size)?;
+        let attached = run(Command::new("losetup")
+            .args(["--find", "--show"])
+            .arg(&image))?;
+        let mut fixture = LoopFixture {
+            mount_point: dir.path().join("mnt"),
+            device: PathBuf::from(attached.trim()),
+            image,
+            _dir: dir,
+            mounted: false,
+        };
+
+        run(Command::new(format!("mkfs.{}", fstype))
+            .arg("-q")
+            .arg(&fixture.device))?;
+        fs::create_dir(&fixture.mount_point)?;
+        run(Command::new("mount")
+            .arg(&fixture.device)
+            .arg(&fixture.mount_point))?;
+        fixture.mounted = true;
+        Ok(fixture)
+    }
+
+    /// The loop device, such as `/dev/loop0`.
+    pub fn device(&self) -> &Path {
+        &self.device
+    }
+
+    /// The image file backing the loop device.
+    pub fn image(&self) -> &Path {
+        &self.image
+    }
+
+    /// Where the filesystem is mounted.
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    /// Write `data` to the file `name` below the mount point and sync it,
+    /// so that its extents are allocated, returning its path.
+    pub fn write(&self, name: impl AsRef<Path>, data: &[u8]) -> io::Result<PathBuf> {
+        let path = self.mount_point.join(name);
+        let mut file = File::create(&path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        Ok(path)
+    }
+}
+
+impl Drop for LoopFixture {
+    fn drop(&mut self) {
+        // Errors can't be reported from here; leftovers are visible in
+        // `losetup -a` and `/proc/mounts`
+        if self.mounted {
+            let _ = run(Command::new("umount").arg(&self.mount_point));
+        }
+        let _ = run(Command::new("losetup").arg("-d").arg(&self.device));
+    }
+}
+
+/// Run `command`, returning its standard output, or an error with its
+/// standard error if it fails.
+fn run(command: &mut Command) -> io::Result<String> {
+    let output = command.stdin(Stdio::null()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed ({}): {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BlkReader;
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run(Command::new("echo").arg("hi")).unwrap(), "hi\n");
+        let err = run(Command::new("sh").args(["-c", "echo oops >&2; exit 3"])).unwrap_err();
+        assert!(err.to_string().contains("oops"), "{}", err);
+    }
+
+    #[test]
+    #[ignore = "needs root, loop devices, mkfs.ext4 and mount"]
+    fn test_loop_fixture() {
+        let fixture = LoopFixture::new("ext4", 64 << 20).unwrap();
+        let data: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        let path = fixture.write("data", &data).unwrap();
+
+        let mut buf = crate::AlignedBuf::new(4096, 4096);
+        let state = path
+            .blk_read_at_opt(&mut buf, 4096, &crate::Options::new())
+            .unwrap();
+        assert_eq!(state.block_device_path, fixture.device());
+        assert_eq!(&buf[..], &data[4096..]);
+
+        let mount_point = fixture.mount_point().to_path_buf();
+        drop(fixture);
+        let mounts = fs::read_to_string("/proc/mounts").unwrap();
+        assert!(!mounts.contains(&*mount_point.to_string_lossy()));
+        assert!(!mount_point.exists());
+    }
+}
@@ -0,0 +1,141 @@
+//! Throttling of device reads.
+//!
+//! Recovery scans against live systems must leave bandwidth to the other
+//! users of the device. With
+//! [`Options::max_throughput`](crate::Options::max_throughput), device reads
+//! take their size from a [`Throttle`] before they are issued, waiting as
+//! long as it takes for the rate to stay below the limit.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting device reads to a number of bytes per second.
+///
+/// The bucket holds up to one second's worth of bytes, so reads may burst
+/// at the device's speed after an idle second. Clones share the bucket:
+/// reads through [`Options`](crate::Options) cloned from one another count
+/// against the same limit, also when issued concurrently.
+///
+/// With the `serde` feature, a throttle is serialized as its rate.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+/// The state shared by the clones of a [`Throttle`].
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may be read without waiting, negative while reads already
+    /// admitted are paid off.
+    tokens: f64,
+    /// When tokens were last added.
+    refilled: Instant,
+}
+
+impl Throttle {
+    /// Limit reads to `bytes_per_sec` bytes per second.
+    ///
+    /// A rate of 0 is treated as 1.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        Self {
+            bytes_per_sec,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    /// The limit, in bytes per second.
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Take `bytes` from the bucket at time `now`, returning how long the
+    /// read has to wait.
+    fn reserve_at(&self, bytes: u64, now: Instant) -> Duration {
+        let rate = self.bytes_per_sec as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.refilled);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        bucket.refilled = bucket.refilled.max(now);
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    /// Wait until `bytes` may be read.
+    ///
+    /// Returns `false` without waiting beyond `deadline` if the read would
+    /// have to wait longer.
+    pub(crate) fn acquire(&self, bytes: u64, deadline: Option<Instant>) -> bool {
+        let now = Instant::now();
+        let wait = self.reserve_at(bytes, now);
+        match deadline {
+            Some(deadline) if now + wait > deadline => {
+                thread::sleep(deadline.saturating_duration_since(now));
+                false
+            }
+            _ => {
+                thread::sleep(wait);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Throttle {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.bytes_per_sec)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Throttle {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <u64 as serde::Deserialize>::deserialize(deserializer).map(Throttle::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let throttle = Throttle::new(1000);
+        let start = throttle.bucket.lock().unwrap().refilled;
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // The full bucket admits a second's worth at once
+        assert_eq!(throttle.reserve_at(1000, at(0)), Duration::ZERO);
+        assert_eq!(throttle.reserve_at(500, at(0)), Duration::from_millis(500));
+        // Clones share the bucket and its debt
+        let clone = throttle.clone();
+        assert_eq!(clone.reserve_at(100, at(500)), Duration::from_millis(100));
+        assert_eq!(throttle.reserve_at(0, at(600)), Duration::ZERO);
+
+        // Idle time refills at most one second's worth
+        assert_eq!(
+            throttle.reserve_at(3000, at(10_000)),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn test_acquire_until_deadline() {
+        let throttle = Throttle::new(1000);
+        assert!(throttle.acquire(1000, None));
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert!(!throttle.acquire(1000, Some(deadline)));
+        assert!(Instant::now() >= deadline);
+        assert_eq!(Throttle::new(0).bytes_per_sec(), 1);
+    }
+}
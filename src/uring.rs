@@ -0,0 +1,194 @@
+//! `io_uring` submission of batched device reads.
+
+use crate::engine::{Completion, DeviceRead};
+
+use io_uring::{opcode, types, IoUring};
+
+use std::cell::RefCell;
+use std::io;
+use std::os::unix::io::RawFd;
+
+/// Number of submission queue entries, and thus reads in flight at once.
+const RING_ENTRIES: u32 = 64;
+
+/// Maximum number of buffers registered with a ring (`UIO_MAXIOV`).
+const MAX_REGISTERED: usize = 1024;
+
+thread_local! {
+    /// Ring reused by all reads of a thread, set up on first use.
+    static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+}
+
+/// Submit all `reads` on `fd`, reporting each completion to `complete`.
+///
+/// The buffers of single-buffer reads are registered with the ring for the
+/// duration of the batch if possible, which saves mapping their pages on
+/// every request.
+pub(crate) fn read_batch(
+    fd: RawFd,
+    reads: &mut [DeviceRead<'_>],
+    complete: &mut Completion<'_>,
+) -> io::Result<()> {
+    with_ring(|ring| {
+        // Index of the registered buffer used by each read, if any
+        let mut registered = Vec::new();
+        let fixed: Vec<Option<u16>> = reads
+            .iter_mut()
+            .map(|read| match read.bufs.as_mut_slice() {
+                [buf] if registered.len() < MAX_REGISTERED && buf.len() <= u32::MAX as usize => {
+                    registered.push(libc::iovec {
+                        iov_base: buf.as_mut_ptr().cast(),
+                        iov_len: buf.len(),
+                    });
+                    Some((registered.len() - 1) as u16)
+                }
+                _ => None,
+            })
+            .collect();
+
+        // Registration can fail, e.g. due to RLIMIT_MEMLOCK; plain reads still work
+        // SAFETY: the buffers stay valid until they are unregistered below.
+        let use_fixed = !registered.is_empty()
+            && unsafe { ring.submitter().register_buffers(&registered) }.is_ok();
+        let result = submit_all(ring, fd, reads, &fixed, use_fixed, complete);
+        if use_fixed {
+            let _ = ring.submitter().unregister_buffers();
+        }
+        result
+    })
+}
+
+/// Run `f` with this thread's ring.
+///
+/// A nested batch, e.g. one started from a progress callback, gets a ring
+/// of its own.
+fn with_ring<T>(f: impl FnOnce(&mut IoUring) -> io::Result<T>) -> io::Result<T> {
+    RING.with(|cell| match cell.try_borrow_mut() {
+        Ok(mut cached) => {
+            if cached.is_none() {
+                *cached = Some(IoUring::new(RING_ENTRIES)?);
+            }
+            f(cached.as_mut().expect("ring was just set up"))
+        }
+        Err(_) => f(&mut IoUring::new(RING_ENTRIES)?),
+    })
+}
+
+fn submit_all(
+    ring: &mut IoUring,
+    fd: RawFd,
+    reads: &mut [DeviceRead<'_>],
+    fixed: &[Option<u16>],
+    use_fixed: bool,
+    complete: &mut Completion<'_>,
+) -> io::Result<()> {
+    let mut next = 0;
+    let mut in_flight = 0;
+    let mut failure = None;
+
+    while (next < reads.len() && failure.is_none()) || in_flight > 0 {
+        while next < reads.len() && failure.is_none() && in_flight < RING_ENTRIES as usize {
+            let read = &mut reads[next];
+            let entry = match (fixed[next], read.bufs.as_mut_slice()) {
+                (Some(index), [buf]) if use_fixed => {
+                    opcode::ReadFixed::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32, index)
+                        .offset(read.offset)
+                        .rw_flags(read.flags.bits())
+                        .build()
+                }
+                (_, bufs) => opcode::Readv::new(
+                    types::Fd(fd),
+                    bufs.as_ptr() as *const libc::iovec,
+                    bufs.len() as u32,
+                )
+                .offset(read.offset)
+                .rw_flags(read.flags.bits())
+                .build(),
+            };
+
+            // SAFETY: the buffers and the iovec array outlive the request,
+            // which completes before this function returns.
+            unsafe { ring.submission().push(&entry.user_data(next as u64)) }
+                .expect("submission queue has room");
+            next += 1;
+            in_flight += 1;
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EINTR) | Some(libc::EBUSY) | Some(libc::EAGAIN)
+                ) => {}
+            // Keep reaping: the kernel may still write to requests in flight
+            Err(e) => failure = failure.or(Some(e)),
+        }
+
+        for cqe in ring.completion() {
+            in_flight -= 1;
+            if failure.is_some() {
+                continue;
+            }
+            let result = cqe.result();
+            let result = if result < 0 {
+                Err(io::Error::from_raw_os_error(-result))
+            } else {
+                Ok(result as usize)
+            };
+            if let Err(e) = complete(cqe.user_data() as usize, result) {
+                failure = Some(e);
+            }
+        }
+    }
+
+    failure.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ReadFlags;
+    use std::io::{IoSliceMut, Write};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_read_batch() {
+        let data: Vec<u8> = (0..16384).map(|i| (i % 251) as u8).collect();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&data).unwrap();
+
+        let mut buf = vec![0u8; 12288];
+        let mut gap = [0u8; 1024];
+        let (first, rest) = buf.split_at_mut(4096);
+        let (second, third) = rest.split_at_mut(4096);
+        let mut reads = vec![
+            DeviceRead {
+                offset: 8192,
+                bufs: vec![IoSliceMut::new(first)],
+                flags: ReadFlags::empty(),
+            },
+            DeviceRead {
+                offset: 0,
+                bufs: vec![
+                    IoSliceMut::new(second),
+                    IoSliceMut::new(&mut gap),
+                    IoSliceMut::new(third),
+                ],
+                flags: ReadFlags::empty(),
+            },
+        ];
+
+        let mut results = vec![None, None];
+        let outcome = read_batch(file.as_raw_fd(), &mut reads, &mut |index, result| {
+            results[index] = Some(result?);
+            Ok(())
+        });
+        outcome.unwrap();
+        drop(reads);
+        assert_eq!(results, vec![Some(4096), Some(9216)]);
+        assert_eq!(&buf[..4096], &data[8192..12288]);
+        assert_eq!(&buf[4096..8192], &data[..4096]);
+        assert_eq!(&buf[8192..], &data[5120..9216]);
+    }
+}
@@ -0,0 +1,212 @@
+//! Verification of device data against the file's contents.
+//!
+//! Reading a file from its block device is only worth it if the device
+//! holds what the file does. [`BlkReader::blk_verify_at`](crate::BlkReader::blk_verify_at)
+//! reads a range both ways, through the device and through the file, and
+//! reports where they differ, such as after `fallocate` and `fdatasync`
+//! to confirm the data reached the device.
+
+use crate::state::{Segment, SegmentSource, State};
+
+use blkmap::FiemapExtent;
+use std::fmt;
+
+/// A range whose data on the device differs from the file's contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Logical file offset of the first differing byte.
+    pub logical: u64,
+
+    /// Length of the range in bytes.
+    pub length: u64,
+
+    /// Where the device read's data came from, with the physical offset of
+    /// the range's first byte for device reads.
+    pub source: SegmentSource,
+
+    /// The extent holding the range, if any.
+    pub extent: Option<FiemapExtent>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x} ({} bytes): ", self.logical, self.length)?;
+        match self.source {
+            SegmentSource::Device { physical } => write!(f, "device at {:#x}", physical),
+            SegmentSource::Unreadable { physical } => {
+                write!(f, "unreadable device range at {:#x}", physical)
+            }
+            SegmentSource::Hole => write!(f, "filled hole"),
+            SegmentSource::Unwritten => write!(f, "filled unwritten extent"),
+            SegmentSource::Encoded => write!(f, "filled encoded extent"),
+            SegmentSource::Fallback => write!(f, "read through the file"),
+        }
+    }
+}
+
+/// Result of comparing a range read from the device with the file.
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    /// Logical file offset of the range.
+    pub offset: u64,
+
+    /// Number of bytes compared: those both reads returned.
+    pub bytes_compared: usize,
+
+    /// Number of bytes the file returned, which is less than requested at
+    /// its end.
+    pub file_bytes: usize,
+
+    /// Ranges that differ, in logical order.
+    pub mismatches: Vec<Mismatch>,
+
+    /// State of the device read.
+    pub state: State,
+}
+
+impl VerifyReport {
+    /// Whether the device and the file agree on the compared bytes, and
+    /// the device returned as many bytes as the file.
+    pub fn is_consistent(&self) -> bool {
+        self.mismatches.is_empty() && self.state.bytes_read >= self.file_bytes
+    }
+}
+
+/// Find the ranges where `device`, read at `offset` with `state`, and
+/// `file` differ, split where their sources on the device change.
+pub(crate) fn compare(offset: u64, device: &[u8], file: &[u8], state: &State) -> Vec<Mismatch> {
+    let compared = device.len().min(file.len());
+    let mut mismatches = Vec::new();
+    let mut pos = 0;
+    while pos < compared {
+        let Some(start) = (pos..compared).find(|&i| device[i] != file[i]) else {
+            break;
+        };
+        let end = (start..compared)
+            .find(|&i| device[i] == file[i])
+            .unwrap_or(compared);
+        mismatches.extend(locate(offset, start..end, state));
+        pos = end;
+    }
+    mismatches
+}
+
+/// Split the buffer range `range` by the segments of `state`.
+fn locate(offset: u64, range: std::ops::Range<usize>, state: &State) -> Vec<Mismatch> {
+    let extent_at = |logical: u64| {
+        state
+            .extents
+            .iter()
+            .find(|extent| extent.logical <= logical && logical < extent.logical + extent.length)
+            .copied()
+    };
+    let mut located: Vec<Mismatch> = state
+        .segments
+        .iter()
+        .filter(|segment| segment.range.start < range.end && range.start < segment.range.end)
+        .map(|segment| {
+            let mut part: Segment = segment.clone();
+            part.advance(range.start.saturating_sub(segment.range.start));
+            part.range.end = part.range.end.min(range.end);
+            Mismatch {
+                logical: part.logical,
+                length: part.range.len() as u64,
+                source: part.source,
+                extent: extent_at(part.logical),
+            }
+        })
+        .collect();
+    // Reads without segments, such as those of empty ranges, still report
+    // the difference
+    if located.is_empty() {
+        let logical = offset + range.start as u64;
+        located.push(Mismatch {
+            logical,
+            length: range.len() as u64,
+            source: SegmentSource::Fallback,
+            extent: extent_at(logical),
+        });
+    }
+    located
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blkmap::ExtentFlags;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_compare() {
+        let extents = vec![
+            FiemapExtent {
+                logical: 4096,
+                physical: 1 << 20,
+                length: 4096,
+                flags: ExtentFlags::empty(),
+            },
+            FiemapExtent {
+                logical: 8192,
+                physical: 1 << 30,
+                length: 4096,
+                flags: ExtentFlags::UNWRITTEN,
+            },
+        ];
+        let mut state = State::new(PathBuf::from("/dev/sda"), extents.clone(), 8192, false);
+        state.set_segments(vec![
+            Segment {
+                range: 0..4096,
+                logical: 4096,
+                source: SegmentSource::Device { physical: 1 << 20 },
+            },
+            Segment {
+                range: 4096..8192,
+                logical: 8192,
+                source: SegmentSource::Unwritten,
+            },
+        ]);
+
+        let file = vec![0x11u8; 8192];
+        let mut device = file.clone();
+        assert!(compare(4096, &device, &file, &state).is_empty());
+
+        // One byte, and a range spanning both segments
+        device[10] = 0;
+        device[4000..4200].fill(0);
+        let mismatches = compare(4096, &device, &file, &state);
+        assert_eq!(
+            mismatches,
+            [
+                Mismatch {
+                    logical: 4106,
+                    length: 1,
+                    source: SegmentSource::Device {
+                        physical: (1 << 20) + 10
+                    },
+                    extent: Some(extents[0]),
+                },
+                Mismatch {
+                    logical: 8096,
+                    length: 96,
+                    source: SegmentSource::Device {
+                        physical: (1 << 20) + 4000
+                    },
+                    extent: Some(extents[0]),
+                },
+                Mismatch {
+                    logical: 8192,
+                    length: 104,
+                    source: SegmentSource::Unwritten,
+                    extent: Some(extents[1]),
+                },
+            ]
+        );
+        assert_eq!(
+            mismatches[0].to_string(),
+            "0x100a (1 bytes): device at 0x10000a"
+        );
+
+        // Only bytes both reads returned are compared
+        assert!(compare(4096, &device[..5], &file, &state).is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+//! Writing file data directly to block devices.
+//!
+//! This module provides the [`BlkWriter`] trait, the counterpart of
+//! [`BlkReader`](crate::BlkReader), for repairing files whose metadata is
+//! intact but whose data blocks are damaged.
+
+use crate::btrfs::is_btrfs;
+use crate::cache::open_device_writable;
+use crate::dm;
+use crate::error::BlkReadError;
+use crate::options::Options;
+use crate::overlay::OVERLAYFS_SUPER_MAGIC;
+use crate::reader::{fiemap_failed, fiemap_range, FS_ENCRYPT_FL};
+use crate::sys;
+
+use blkmap::FiemapExtent;
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Trait for writing file data directly to block devices.
+///
+/// The logical range is mapped to physical locations with FIEMAP and the
+/// data is written to the block device with Direct I/O, bypassing the
+/// filesystem. Writes are refused unless
+/// [`Options::with_allow_write`](Options::with_allow_write) is set.
+///
+/// The whole range must be backed by plain written extents: holes, unwritten,
+/// delayed, inline, encoded, encrypted and shared extents are rejected with
+/// [`io::ErrorKind::InvalidInput`], since writing to them would either not be
+/// visible through the filesystem or corrupt other files.
+///
+/// Files whose extents don't map directly to the device they are written
+/// to are rejected with [`io::ErrorKind::Unsupported`], whatever the
+/// options: files on btrfs, whose extents map to filesystem addresses and
+/// whose blocks are checksummed, on overlayfs, encrypted with fscrypt, and
+/// on device-mapper (including LVM and dm-crypt), md or loop devices.
+///
+/// The file's dirty pages are written back before its extents are queried,
+/// so that delayed allocations are mapped and later writeback cannot
+/// overwrite the device blocks; if the range is dirtied again before the
+/// write, it fails with [`BlkReadError::DirtyPages`].
+///
+/// Writing the device bypasses the file's permissions, so the file must be
+/// open for writing: paths are opened read-write, and [`File`]s opened
+/// read-only are refused with [`io::ErrorKind::PermissionDenied`].
+///
+/// The same alignment requirements as for Direct I/O reads apply.
+///
+/// # Example
+///
+/// ```no_run
+/// use blkreader::{BlkWriter, Options};
+/// use std::path::Path;
+///
+/// let data = vec![0u8; 4096];
+/// let options = Options::new().with_allow_write(true);
+/// let written = Path::new("/path/to/file")
+///     .blk_write_at(&data, 0, &options)
+///     .unwrap();
+/// ```
+pub trait BlkWriter {
+    /// Write `buf` at the logical `offset` of the file, directly to the device.
+    ///
+    /// Returns the number of bytes written. In dry run mode, the extents are
+    /// checked but nothing is written.
+    fn blk_write_at(&self, buf: &[u8], offset: u64, options: &Options) -> io::Result<usize>;
+}
+
+impl BlkWriter for Path {
+    fn blk_write_at(&self, buf: &[u8], offset: u64, options: &Options) -> io::Result<usize> {
+        let file = File::options().read(true).write(true).open(self)?;
+        file.blk_write_at(buf, offset, options)
+    }
+}
+
+impl BlkWriter for PathBuf {
+    fn blk_write_at(&self, buf: &[u8], offset: u64, options: &Options) -> io::Result<usize> {
+        self.as_path().blk_write_at(buf, offset, options)
+    }
+}
+
+impl BlkWriter for File {
+    fn blk_write_at(&self, buf: &[u8], offset: u64, options: &Options) -> io::Result<usize> {
+        if !options.allow_write {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "writing to the block device requires Options::with_allow_write(true)",
+            ));
+        }
+        if !sys::is_writable_fd(self.as_raw_fd())? {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "writing to the block device requires the file to be open for writing",
+            ));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        check_filesystem(self)?;
+
+        // Map delayed allocations, and keep writeback of pages dirtied
+        // before now from landing on the blocks written below
+        self.sync_data()?;
+        let extents = fiemap_range(self, offset, buf.len() as u64, options)
+            .map_err(fiemap_failed(self, None))?;
+        check_writable(&extents, offset, buf.len() as u64)?;
+        if options.dry_run {
+            return Ok(buf.len());
+        }
+
+        let device = open_device_writable(self)?;
+        if let Some(kind) = dm::block_name(&device.path)
+            .ok()
+            .and_then(|name| stacked_in(Path::new("/sys"), &name.to_string_lossy()))
+        {
+            return Err(unsupported(format!(
+                "writing through {} device {} is not supported",
+                kind,
+                device.path.display()
+            )));
+        }
+        check_clean(self, offset, buf.len() as u64)?;
+        let written = write_extents(&device.file, buf, offset, &extents)?;
+        device.file.sync_data()?;
+
+        // The page cache of the file no longer matches the device
+        // SAFETY: `posix_fadvise` only takes plain integer arguments.
+        unsafe {
+            libc::posix_fadvise(
+                self.as_raw_fd(),
+                offset as libc::off_t,
+                buf.len() as libc::off_t,
+                libc::POSIX_FADV_DONTNEED,
+            );
+        }
+        Ok(written)
+    }
+}
+
+/// Fail with `Unsupported` if `file`'s extents don't map to its device.
+fn check_filesystem(file: &File) -> io::Result<()> {
+    if is_btrfs(file)? {
+        return Err(unsupported(
+            "writing to btrfs, whose extents map to filesystem addresses, is not supported",
+        ));
+    }
+    if sys::fs_type(file.as_raw_fd())? == OVERLAYFS_SUPER_MAGIC {
+        return Err(unsupported("writing to overlayfs is not supported"));
+    }
+    // Filesystems without inode flags don't support fscrypt either
+    if sys::inode_flags(file.as_raw_fd()).is_ok_and(|flags| flags & FS_ENCRYPT_FL != 0) {
+        return Err(unsupported(
+            "writing to fscrypt-encrypted files is not supported",
+        ));
+    }
+    Ok(())
+}
+
+/// Kind of the block device `name` in the sysfs tree at `sys`, if it is
+/// stacked on other devices or files, whose blocks are not where its
+/// offsets point.
+fn stacked_in(sys: &Path, name: &str) -> Option<&'static str> {
+    // Unlike /sys/block, /sys/class/block also lists partitions
+    let device = sys.join("class/block").join(name);
+    [("dm", "device-mapper"), ("md", "md"), ("loop", "loop")]
+        .into_iter()
+        .find(|(attrs, _)| device.join(attrs).is_dir())
+        .map(|(_, kind)| kind)
+}
+
+/// Fail with [`BlkReadError::DirtyPages`] if `offset..offset + length` of
+/// `file` has dirty pages, whose writeback would overwrite the device.
+///
+/// Without `cachestat` (before Linux 6.5), the range was written back just
+/// before and is not checked again.
+fn check_clean(file: &File, offset: u64, length: u64) -> io::Result<()> {
+    let Ok(stat) = sys::cachestat(file.as_raw_fd(), offset, length) else {
+        return Ok(());
+    };
+    let pages = stat.nr_dirty + stat.nr_writeback;
+    if pages == 0 {
+        return Ok(());
+    }
+    Err(BlkReadError::DirtyPages {
+        file_path: None,
+        offset,
+        length,
+        pages,
+    }
+    .into())
+}
+
+fn unsupported(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message.into())
+}
+
+/// Check that `offset..offset + length` is fully backed by plain written extents.
+fn check_writable(extents: &[FiemapExtent], offset: u64, length: u64) -> io::Result<()> {
+    let end = offset + length;
+    let mut current = offset;
+
+    for extent in extents {
+        if extent.logical > current {
+            return Err(not_writable(current, "hole"));
+        }
+
+        let flags = extent.flags;
+        let reason = if flags.is_unwritten() {
+            Some("unwritten extent")
+        } else if flags.is_unknown() || flags.is_delalloc() {
+            Some("unallocated extent")
+        } else if flags.is_inline() {
+            Some("inline extent")
+        } else if flags.is_encoded() || flags.is_encrypted() {
+            Some("encoded extent")
+        } else if flags.is_shared() {
+            Some("shared extent")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(not_writable(current, reason));
+        }
+
+        current = extent.logical + extent.length;
+        if current >= end {
+            return Ok(());
+        }
+    }
+
+    Err(not_writable(current, "hole"))
+}
+
+fn not_writable(logical: u64, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("cannot write at logical offset {:#x}: {}", logical, reason),
+    )
+}
+
+/// Write `buf` to the device at the physical locations of `extents`.
+fn write_extents(
+    device: &File,
+    buf: &[u8],
+    offset: u64,
+    extents: &[FiemapExtent],
+) -> io::Result<usize> {
+    let end = offset + buf.len() as u64;
+    let mut written = 0;
+
+    for extent in extents {
+        let start = extent.logical.max(offset);
+        let stop = (extent.logical + extent.length).min(end);
+        if start >= stop {
+            continue;
+        }
+
+        let physical = extent.physical + (start - extent.logical);
+        let data = &buf[(start - offset) as usize..(stop - offset) as usize];
+        device.write_all_at(data, physical).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "failed to write {} bytes at physical offset {:#x}: {}",
+                    data.len(),
+                    physical,
+                    e
+                ),
+            )
+        })?;
+        written += data.len();
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blkmap::ExtentFlags;
+
+    fn extent(logical: u64, physical: u64, length: u64, flags: ExtentFlags) -> FiemapExtent {
+        FiemapExtent {
+            logical,
+            physical,
+            length,
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_requires_allow_write() {
+        let file = tempfile::tempfile().unwrap();
+        let err = file
+            .blk_write_at(&[0; 512], 0, &Options::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let plain = ExtentFlags::empty();
+        let extents = vec![extent(0, 0, 4096, plain), extent(4096, 8192, 4096, plain)];
+        assert!(check_writable(&extents, 1024, 4096).is_ok());
+
+        // Trailing and leading holes
+        let err = check_writable(&extents, 4096, 8192).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(check_writable(&extents[1..], 0, 8192).is_err());
+
+        for flags in [
+            ExtentFlags::UNWRITTEN,
+            ExtentFlags::DELALLOC,
+            ExtentFlags::SHARED,
+            ExtentFlags::DATA_INLINE,
+        ] {
+            let err = check_writable(&[extent(0, 0, 4096, flags)], 0, 4096).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_stacked_in_sysfs() {
+        let sys = tempfile::tempdir().unwrap();
+        let block = sys.path().join("class/block");
+        for (name, attrs) in [("dm-0", "dm"), ("md0", "md"), ("loop3", "loop")] {
+            std::fs::create_dir_all(block.join(name).join(attrs)).unwrap();
+        }
+        std::fs::create_dir_all(block.join("nvme0n1p1")).unwrap();
+
+        assert_eq!(stacked_in(sys.path(), "dm-0"), Some("device-mapper"));
+        assert_eq!(stacked_in(sys.path(), "md0"), Some("md"));
+        assert_eq!(stacked_in(sys.path(), "loop3"), Some("loop"));
+        assert_eq!(stacked_in(sys.path(), "nvme0n1p1"), None);
+    }
+
+    #[test]
+    fn test_dirty_range_written_back_first() {
+        use std::io::Write;
+
+        // Freshly written data has no mapping until it is written back
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x5A; 8192]).unwrap();
+        let options = Options::new().with_allow_write(true).with_dry_run(true);
+        let file = temp.as_file();
+        assert_eq!(file.blk_write_at(&[0; 4096], 0, &options).unwrap(), 4096);
+
+        let flags = fiemap_range(file, 0, 8192, &options).unwrap()[0].flags;
+        assert!(!flags.is_delalloc());
+        assert_eq!(
+            sys::cachestat(file.as_raw_fd(), 0, 8192).unwrap().nr_dirty,
+            0
+        );
+        check_clean(file, 0, 8192).unwrap();
+    }
+
+    #[test]
+    fn test_requires_writable_file() {
+        use std::io::Write;
+
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(&[0x5A; 8192]).unwrap();
+        temp.as_file().sync_all().unwrap();
+        let options = Options::new().with_allow_write(true).with_dry_run(true);
+
+        // Descriptors opened read-only are refused, even for dry runs
+        let read_only = File::open(temp.path()).unwrap();
+        let err = read_only.blk_write_at(&[0; 4096], 0, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("open for writing"), "{}", err);
+
+        // Paths are opened read-write
+        let written = temp.path().blk_write_at(&[0; 4096], 0, &options).unwrap();
+        assert_eq!(written, 4096);
+    }
+
+    #[test]
+    fn test_write_extents() {
+        let device = tempfile::tempfile().unwrap();
+        device.set_len(16384).unwrap();
+
+        // Logical [0, 8192) is stored at physical 8192 and 0, in that order
+        let plain = ExtentFlags::empty();
+        let extents = vec![extent(0, 8192, 4096, plain), extent(4096, 0, 4096, plain)];
+        let data: Vec<u8> = (0..6144).map(|i| (i % 251) as u8).collect();
+
+        let written = write_extents(&device, &data, 2048, &extents).unwrap();
+        assert_eq!(written, 6144);
+
+        let mut first = vec![0u8; 2048];
+        device.read_exact_at(&mut first, 8192 + 2048).unwrap();
+        assert_eq!(first, &data[..2048]);
+        let mut second = vec![0u8; 4096];
+        device.read_exact_at(&mut second, 0).unwrap();
+        assert_eq!(second, &data[2048..]);
+    }
+}